@@ -6,7 +6,7 @@ use std::vec::IntoIter;
 
 use super::{
     instr::Expr,
-    types::{FuncType, GlobalType, Limits, RefType, ValType},
+    types::{GlobalType, IdxType, Limits, RefType, SubType, ValType},
 };
 
 pub type TypeIdx = u32;
@@ -18,12 +18,17 @@ pub type ElemIdx = u32;
 pub type DataIdx = u32;
 pub type LocalIdx = u32;
 pub type LabelIdx = u32;
+pub type TagIdx = u32;
+pub type FieldIdx = u32;
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Func {
     pub typeidx: TypeIdx,
     pub locals: Vec<ValType>,
     pub body: Expr,
+    /// Code offsets of the instructions of `body`, followed by the one of
+    /// its `end`. See `Func0::offsets`.
+    pub offsets: Vec<u32>,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -65,6 +70,7 @@ pub enum ImportDesc {
     Table(Table),
     Mem(Memory),
     Global(GlobalType),
+    Tag(Tag),
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -73,6 +79,7 @@ pub enum ExportDesc {
     Table(TableIdx),
     Mem(MemIdx),
     Global(GlobalIdx),
+    Tag(TagIdx),
 }
 
 #[derive(Debug, PartialEq)]
@@ -91,6 +98,9 @@ pub struct Local {
 pub struct Func0 {
     pub locals: Vec<Local>,
     pub body: Expr,
+    /// Offsets of the instructions of `body` and of its `end`, relative to
+    /// the start of the contents of the code section as in DWARF.
+    pub offsets: Vec<u32>,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -112,7 +122,17 @@ pub struct Table {
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
-pub struct Memory(pub Limits);
+pub struct Memory {
+    pub idxtype: IdxType,
+    pub limits: Limits,
+    pub shared: bool,
+}
+
+/// Exception tag, whose type gives the values carried by its exceptions.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Tag {
+    pub typeidx: TypeIdx,
+}
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Custom {
@@ -136,7 +156,7 @@ impl<T> IntoIterator for Section<Vec<T>> {
     }
 }
 
-pub type TypeSec = Section<Vec<FuncType>>;
+pub type TypeSec = Section<Vec<SubType>>;
 
 pub type ImportSec = Section<Vec<Import>>;
 
@@ -146,6 +166,8 @@ pub type TableSec = Section<Vec<Table>>;
 
 pub type MemSec = Section<Vec<Memory>>;
 
+pub type TagSec = Section<Vec<Tag>>;
+
 pub type GlobalSec = Section<Vec<Global>>;
 
 pub type ExportSec = Section<Vec<Export>>;
@@ -162,7 +184,92 @@ pub type DataCountSec = Section<u32>;
 
 pub type CustomSec = Section<Custom>;
 
-#[derive(Debug, PartialEq)]
+/// Names assigned to indices, sorted by index.
+pub type NameMap = Vec<(u32, String)>;
+
+/// Name maps for the locals, labels or fields of each function or type,
+/// sorted by the index of the function or type.
+pub type IndirectNameMap = Vec<(u32, NameMap)>;
+
+/// Contents of the `name` custom section, including the subsections of the
+/// extended name section proposal.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Names {
+    pub module: Option<String>,
+    pub funcs: NameMap,
+    pub locals: IndirectNameMap,
+    /// Labels of each function, indexed by the order of the `block`, `loop`,
+    /// `if` and `try_table` instructions in its body.
+    pub labels: IndirectNameMap,
+    pub types: NameMap,
+    pub tables: NameMap,
+    pub mems: NameMap,
+    pub globals: NameMap,
+    pub elems: NameMap,
+    pub datas: NameMap,
+    pub fields: IndirectNameMap,
+    pub tags: NameMap,
+}
+
+impl Names {
+    pub fn func(&self, idx: FuncIdx) -> Option<&str> {
+        lookup(&self.funcs, idx)
+    }
+
+    pub fn local(&self, func: FuncIdx, idx: LocalIdx) -> Option<&str> {
+        lookup_indirect(&self.locals, func, idx)
+    }
+
+    pub fn label(&self, func: FuncIdx, idx: u32) -> Option<&str> {
+        lookup_indirect(&self.labels, func, idx)
+    }
+
+    pub fn type_(&self, idx: TypeIdx) -> Option<&str> {
+        lookup(&self.types, idx)
+    }
+
+    pub fn table(&self, idx: TableIdx) -> Option<&str> {
+        lookup(&self.tables, idx)
+    }
+
+    pub fn mem(&self, idx: MemIdx) -> Option<&str> {
+        lookup(&self.mems, idx)
+    }
+
+    pub fn global(&self, idx: GlobalIdx) -> Option<&str> {
+        lookup(&self.globals, idx)
+    }
+
+    pub fn elem(&self, idx: ElemIdx) -> Option<&str> {
+        lookup(&self.elems, idx)
+    }
+
+    pub fn data(&self, idx: DataIdx) -> Option<&str> {
+        lookup(&self.datas, idx)
+    }
+
+    pub fn field(&self, type_: TypeIdx, idx: FieldIdx) -> Option<&str> {
+        lookup_indirect(&self.fields, type_, idx)
+    }
+
+    pub fn tag(&self, idx: TagIdx) -> Option<&str> {
+        lookup(&self.tags, idx)
+    }
+}
+
+fn lookup(map: &NameMap, idx: u32) -> Option<&str> {
+    map.binary_search_by_key(&idx, |(i, _)| *i)
+        .ok()
+        .map(|i| map[i].1.as_str())
+}
+
+fn lookup_indirect(map: &IndirectNameMap, outer: u32, idx: u32) -> Option<&str> {
+    map.binary_search_by_key(&outer, |(i, _)| *i)
+        .ok()
+        .and_then(|i| lookup(&map[i].1, idx))
+}
+
+#[derive(Debug, PartialEq, Default)]
 pub struct CustomSecList {
     pub sec1: Vec<Custom>,
     pub sec2: Vec<Custom>,
@@ -179,17 +286,48 @@ pub struct CustomSecList {
     pub sec13: Vec<Custom>,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+impl CustomSecList {
+    /// All custom sections in the order they appear in the module.
+    pub fn iter(&self) -> impl Iterator<Item = &Custom> {
+        [
+            &self.sec1,
+            &self.sec2,
+            &self.sec3,
+            &self.sec4,
+            &self.sec5,
+            &self.sec6,
+            &self.sec7,
+            &self.sec8,
+            &self.sec9,
+            &self.sec10,
+            &self.sec11,
+            &self.sec12,
+            &self.sec13,
+        ]
+        .into_iter()
+        .flatten()
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
 pub struct Module {
     pub version: u8,
-    pub types: Vec<FuncType>,
+    pub types: Vec<SubType>,
     pub funcs: Vec<Func>,
     pub tables: Vec<Table>,
     pub mems: Vec<Memory>,
+    pub tags: Vec<Tag>,
     pub globals: Vec<Global>,
     pub elems: Vec<Elem>,
     pub datas: Vec<Data>,
+    pub data_count: Option<u32>,
     pub start: Option<FuncIdx>,
     pub imports: Vec<Import>,
     pub exports: Vec<Export>,
+    /// Debug names from the `name` custom section, empty without one.
+    pub names: Names,
+    /// Line table of the DWARF custom sections, if they are present and
+    /// well-formed.
+    #[cfg(feature = "std")]
+    pub lines: Option<crate::debug::dwarf::LineTable>,
 }
@@ -1,22 +1,91 @@
 #[cfg(not(feature = "std"))]
 use crate::lib::*;
+
+use super::module::TypeIdx;
+
 pub trait FromByte: Sized {
     fn from_byte(b: u8) -> Option<Self>;
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+/// Heap type of a reference, either abstract or a defined type.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum HeapType {
+    Func,
+    NoFunc,
+    Extern,
+    NoExtern,
+    Exn,
+    NoExn,
+    Any,
+    Eq,
+    I31,
+    Struct,
+    Array,
+    None,
+    Type(TypeIdx),
+}
+
+impl FromByte for HeapType {
+    fn from_byte(b: u8) -> Option<Self> {
+        match b {
+            0x70 => Some(HeapType::Func),
+            0x73 => Some(HeapType::NoFunc),
+            0x6F => Some(HeapType::Extern),
+            0x72 => Some(HeapType::NoExtern),
+            0x69 => Some(HeapType::Exn),
+            0x74 => Some(HeapType::NoExn),
+            0x6E => Some(HeapType::Any),
+            0x6D => Some(HeapType::Eq),
+            0x6C => Some(HeapType::I31),
+            0x6B => Some(HeapType::Struct),
+            0x6A => Some(HeapType::Array),
+            0x71 => Some(HeapType::None),
+            _ => None,
+        }
+    }
+}
+
+/// Reference type. `FuncRef`, `ExternRef` and `ExnRef` stand for
+/// `ref null func`, `ref null extern` and `ref null exn`, which are never
+/// written as `Ref`; use `RefType::new` to build reference types.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum RefType {
     FuncRef,
     ExternRef,
+    ExnRef,
+    Ref { nullable: bool, heap: HeapType },
+}
+
+impl RefType {
+    pub fn new(nullable: bool, heap: HeapType) -> Self {
+        match (nullable, heap) {
+            (true, HeapType::Func) => RefType::FuncRef,
+            (true, HeapType::Extern) => RefType::ExternRef,
+            (true, HeapType::Exn) => RefType::ExnRef,
+            (nullable, heap) => RefType::Ref { nullable, heap },
+        }
+    }
+
+    pub fn nullable(&self) -> bool {
+        match self {
+            RefType::FuncRef | RefType::ExternRef | RefType::ExnRef => true,
+            RefType::Ref { nullable, .. } => *nullable,
+        }
+    }
+
+    pub fn heap(&self) -> HeapType {
+        match self {
+            RefType::FuncRef => HeapType::Func,
+            RefType::ExternRef => HeapType::Extern,
+            RefType::ExnRef => HeapType::Exn,
+            RefType::Ref { heap, .. } => *heap,
+        }
+    }
 }
 
 impl FromByte for RefType {
     fn from_byte(b: u8) -> Option<Self> {
-        match b {
-            0x70 => Some(RefType::FuncRef),
-            0x6F => Some(RefType::ExternRef),
-            _ => None,
-        }
+        HeapType::from_byte(b).map(|heap| RefType::new(true, heap))
     }
 }
 
@@ -26,8 +95,33 @@ pub enum ValType {
     I64,
     F32,
     F64,
+    V128,
     FuncRef,
     ExternRef,
+    ExnRef,
+    /// Reference types other than the three above, see `RefType`.
+    Ref {
+        nullable: bool,
+        heap: HeapType,
+    },
+}
+
+impl ValType {
+    pub fn reftype(&self) -> Option<RefType> {
+        match self {
+            ValType::FuncRef => Some(RefType::FuncRef),
+            ValType::ExternRef => Some(RefType::ExternRef),
+            ValType::ExnRef => Some(RefType::ExnRef),
+            ValType::Ref { nullable, heap } => Some(RefType::new(*nullable, *heap)),
+            _ => None,
+        }
+    }
+
+    /// Whether locals of this type can start with a default value, which
+    /// all types but non-nullable references have.
+    pub fn defaultable(&self) -> bool {
+        self.reftype().map_or(true, |t| t.nullable())
+    }
 }
 
 impl FromByte for ValType {
@@ -39,10 +133,20 @@ impl FromByte for ValType {
             0x7D => Some(ValType::F32),
             0x7c => Some(ValType::F64),
             // Vector Type
-            0x70 => Some(ValType::FuncRef),
+            0x7B => Some(ValType::V128),
             // Reference Type
-            0x6F => Some(ValType::ExternRef),
-            _ => None,
+            n => RefType::from_byte(n).map(ValType::from),
+        }
+    }
+}
+
+impl From<RefType> for ValType {
+    fn from(reftype: RefType) -> Self {
+        match reftype {
+            RefType::FuncRef => ValType::FuncRef,
+            RefType::ExternRef => ValType::ExternRef,
+            RefType::ExnRef => ValType::ExnRef,
+            RefType::Ref { nullable, heap } => ValType::Ref { nullable, heap },
         }
     }
 }
@@ -53,33 +157,137 @@ pub struct FuncType(pub ResultType, pub ResultType);
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct ResultType(pub Vec<ValType>);
 
+/// Type of a struct field or array element. Packed types hold `i32` values.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum StorageType {
+    Val(ValType),
+    I8,
+    I16,
+}
+
+impl StorageType {
+    /// Type of the values read from and written to the storage.
+    pub fn unpacked(&self) -> ValType {
+        match self {
+            StorageType::Val(t) => *t,
+            StorageType::I8 | StorageType::I16 => ValType::I32,
+        }
+    }
+
+    /// Size in bytes of the packed types, used by `array.new_data`.
+    pub fn size(&self) -> usize {
+        match self {
+            StorageType::I8 => 1,
+            StorageType::I16 => 2,
+            StorageType::Val(ValType::I32 | ValType::F32) => 4,
+            StorageType::Val(ValType::V128) => 16,
+            StorageType::Val(_) => 8,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct FieldType {
+    pub storage: StorageType,
+    pub mut_: Mut,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum CompType {
+    Func(FuncType),
+    Struct(Vec<FieldType>),
+    Array(FieldType),
+}
+
+/// Entry of the type section. Recursion groups are flattened, as they do
+/// not change type indices.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SubType {
+    pub final_: bool,
+    pub supertypes: Vec<TypeIdx>,
+    pub comp: CompType,
+}
+
+impl SubType {
+    pub fn functype(&self) -> Option<&FuncType> {
+        match &self.comp {
+            CompType::Func(functype) => Some(functype),
+            _ => None,
+        }
+    }
+}
+
+impl SubType {
+    /// A final type without supertypes, the abbreviated form in the binary format.
+    pub fn from_comp(comp: CompType) -> Self {
+        Self {
+            final_: true,
+            supertypes: vec![],
+            comp,
+        }
+    }
+}
+
+impl From<FuncType> for SubType {
+    fn from(functype: FuncType) -> Self {
+        Self::from_comp(CompType::Func(functype))
+    }
+}
+
+/// Type of the addresses of a memory, `i64` for 64-bit memories.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum IdxType {
+    I32,
+    I64,
+}
+
+impl IdxType {
+    pub fn valtype(&self) -> ValType {
+        match self {
+            IdxType::I32 => ValType::I32,
+            IdxType::I64 => ValType::I64,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Limits {
-    Min(u32),
-    MinMax(u32, u32),
+    Min(u64),
+    MinMax(u64, u64),
 }
 
 impl Limits {
-    pub fn set_min(&self, min: u32) -> Self {
+    pub fn set_min(&self, min: u64) -> Self {
         match self {
             Limits::Min(_) => Limits::Min(min),
             Limits::MinMax(_, m) => Limits::MinMax(min, *m),
         }
     }
-    pub fn min(&self) -> u32 {
+    pub fn min(&self) -> u64 {
         match self {
             Limits::Min(v) => *v,
             Limits::MinMax(v, _) => *v,
         }
     }
 
-    pub fn max(&self) -> Option<u32> {
+    pub fn max(&self) -> Option<u64> {
         match self {
             Limits::Min(_) => None,
             Limits::MinMax(_, v) => Some(*v),
         }
     }
 
+    /// Import matching: `self` is at least as large as `expected` and its
+    /// maximum, if `expected` has one, is no larger.
+    pub fn matches(&self, expected: &Limits) -> bool {
+        self.min() >= expected.min()
+            && match (self.max(), expected.max()) {
+                (_, None) => true,
+                (Some(max), Some(expected)) => max <= expected,
+                (None, Some(_)) => false,
+            }
+    }
+
     pub fn valid(&self) -> bool {
         match self {
             Limits::Min(_) => true,
@@ -0,0 +1,491 @@
+#[cfg(not(feature = "std"))]
+use crate::lib::*;
+
+#[cfg(feature = "std")]
+use super::dwarf::Location;
+use crate::binary::{FuncIdx, GlobalIdx, MemIdx};
+use crate::exec::env::Env;
+use crate::exec::runtime::{Addr, ExecState, Instance, Runtime, RuntimeError};
+use crate::exec::stack::{Frame, Stack};
+use crate::exec::store::{FuncInst, Store};
+use crate::exec::trap::{Backtrace, Trap};
+use crate::exec::value::Value;
+use alloc::collections::BTreeMap;
+use core::ops::Range;
+
+/// Why the debugger handed control back.
+#[derive(Debug, PartialEq)]
+pub enum Stop {
+    /// Paused at the start of an invocation or after a step.
+    Paused,
+    /// Paused before the instruction at `offset` of function `func`.
+    Breakpoint(FuncIdx, usize),
+    /// An instruction changed the watched bytes. Paused after it.
+    Watchpoint {
+        mem: MemIdx,
+        addr: u64,
+        old: Vec<u8>,
+        new: Vec<u8>,
+    },
+    /// The invoked function returned these results.
+    Returned(Vec<Value>),
+    /// No invocation is in progress.
+    NotRunning,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct Watchpoint {
+    mem: MemIdx,
+    /// Store address of the memory, resolved when the watchpoint is set.
+    memaddr: Addr,
+    addr: u64,
+    bytes: Vec<u8>,
+}
+
+/// Executes invocations of a runtime one instruction at a time, stopping at
+/// breakpoints and when watched memory changes. Function, global and memory
+/// indices refer to the root instance.
+pub struct Debugger<E: Env> {
+    pub runtime: Runtime,
+    pub store: Store,
+    pub env: E,
+    /// Breakpoints by the pc of their instruction.
+    breakpoints: BTreeMap<usize, (FuncIdx, usize)>,
+    watchpoints: Vec<Watchpoint>,
+    running: bool,
+}
+
+impl<E: Env> Debugger<E> {
+    pub fn new(runtime: Runtime, store: Store, env: E) -> Self {
+        Debugger {
+            runtime,
+            store,
+            env,
+            breakpoints: BTreeMap::new(),
+            watchpoints: vec![],
+            running: false,
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Starts invoking the exported function `name`, pausing before its
+    /// first instruction. Exported host functions are called right away.
+    pub fn invoke(&mut self, name: &str, params: Vec<Value>) -> Result<Stop, RuntimeError> {
+        self.running = false;
+        match self.runtime.attach_invoke(&mut self.store, name, params)? {
+            ExecState::Continue(pc) => {
+                self.runtime.pc = pc;
+                self.running = true;
+                Ok(Stop::Paused)
+            }
+            ExecState::EnvFunc {
+                module,
+                name,
+                params,
+                ..
+            } => self
+                .runtime
+                .call_host(&mut self.store, &mut self.env, &module, &name, params)
+                .map(Stop::Returned)
+                .map_err(RuntimeError::Env),
+            ExecState::Return => unreachable!(),
+        }
+    }
+
+    /// Adds a breakpoint before the instruction at `offset` of function
+    /// `func`, returning false if there is no such instruction.
+    pub fn set_breakpoint(&mut self, func: FuncIdx, offset: usize) -> bool {
+        match self.pc_of(func, offset) {
+            Some(pc) => {
+                self.breakpoints.insert(pc, (func, offset));
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn remove_breakpoint(&mut self, func: FuncIdx, offset: usize) -> bool {
+        match self.pc_of(func, offset) {
+            Some(pc) => self.breakpoints.remove(&pc).is_some(),
+            None => false,
+        }
+    }
+
+    /// Breakpoints as function indices and instruction offsets.
+    pub fn breakpoints(&self) -> impl Iterator<Item = &(FuncIdx, usize)> {
+        self.breakpoints.values()
+    }
+
+    /// Watches `len` bytes at `addr` of memory `mem`, returning false if
+    /// they are out of bounds.
+    pub fn watch(&mut self, mem: MemIdx, addr: u64, len: usize) -> bool {
+        let memaddr = match self.memaddr(mem) {
+            Some(memaddr) => memaddr,
+            None => return false,
+        };
+        match self.read(memaddr, addr, len) {
+            Some(bytes) => {
+                self.watchpoints.push(Watchpoint {
+                    mem,
+                    memaddr,
+                    addr,
+                    bytes,
+                });
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn unwatch(&mut self, mem: MemIdx, addr: u64) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints
+            .retain(|watch| watch.mem != mem || watch.addr != addr);
+        self.watchpoints.len() != len
+    }
+
+    /// Executes one instruction, entering called functions.
+    pub fn step_in(&mut self) -> Result<Stop, RuntimeError> {
+        if !self.running {
+            return Ok(Stop::NotRunning);
+        }
+        Ok(self.step()?.unwrap_or(Stop::Paused))
+    }
+
+    /// Executes one instruction, running called functions to completion.
+    pub fn step_over(&mut self) -> Result<Stop, RuntimeError> {
+        let depth = self.runtime.stack.frames_len();
+        self.run_while(|stack| stack.frames_len() > depth)
+    }
+
+    /// Runs until the current function returns.
+    pub fn step_out(&mut self) -> Result<Stop, RuntimeError> {
+        let depth = self.runtime.stack.frames_len();
+        self.run_while(|stack| stack.frames_len() >= depth)
+    }
+
+    /// Runs until a breakpoint, a watchpoint or the end of the invocation.
+    pub fn cont(&mut self) -> Result<Stop, RuntimeError> {
+        self.run_while(|_| true)
+    }
+
+    /// Steps while `cond` holds for the stack after each instruction.
+    fn run_while<F: Fn(&Stack) -> bool>(&mut self, cond: F) -> Result<Stop, RuntimeError> {
+        if !self.running {
+            return Ok(Stop::NotRunning);
+        }
+        loop {
+            if let Some(stop) = self.step()? {
+                return Ok(stop);
+            }
+            if !cond(&self.runtime.stack) {
+                return Ok(Stop::Paused);
+            }
+            if let Some(&(func, offset)) = self.breakpoints.get(&self.runtime.pc) {
+                return Ok(Stop::Breakpoint(func, offset));
+            }
+        }
+    }
+
+    /// Executes one instruction, returning why execution cannot go on
+    /// without the embedder.
+    fn step(&mut self) -> Result<Option<Stop>, RuntimeError> {
+        let result = match self.runtime.step(&mut self.store) {
+            Ok(ExecState::Continue(_)) => Ok(()),
+            Ok(ExecState::Return) => {
+                self.running = false;
+                return Ok(Some(Stop::Returned(self.runtime.stack.get_returns())));
+            }
+            Ok(ExecState::EnvFunc {
+                module,
+                name,
+                params,
+                ..
+            }) => self
+                .runtime
+                .call_host(&mut self.store, &mut self.env, &module, &name, params)
+                .map(|results| self.runtime.set_results(results))
+                .map_err(Trap::Env),
+            Err(trap) => Err(trap),
+        };
+        if let Err(trap) = result {
+            self.running = false;
+            return Err(self.runtime.trap_error(&self.store, trap));
+        }
+        Ok(self.check_watchpoints())
+    }
+
+    fn check_watchpoints(&mut self) -> Option<Stop> {
+        for i in 0..self.watchpoints.len() {
+            let Watchpoint {
+                mem, memaddr, addr, ..
+            } = self.watchpoints[i];
+            let len = self.watchpoints[i].bytes.len();
+            let new = match self.read(memaddr, addr, len) {
+                Some(bytes) if bytes != self.watchpoints[i].bytes => bytes,
+                _ => continue,
+            };
+            let old = core::mem::replace(&mut self.watchpoints[i].bytes, new.clone());
+            return Some(Stop::Watchpoint {
+                mem,
+                addr,
+                old,
+                new,
+            });
+        }
+        None
+    }
+
+    fn pc_of(&self, func: FuncIdx, offset: usize) -> Option<usize> {
+        let code = self.code(func)?;
+        let pc = code.start + offset;
+        code.contains(&pc).then(|| pc)
+    }
+
+    /// Pcs of the instructions of function `func`, if it is not a host
+    /// function.
+    pub(crate) fn code(&self, func: FuncIdx) -> Option<Range<usize>> {
+        let addr = *self.root()?.funcaddrs.get(func as usize)?;
+        let start = match self.store.funcs[addr] {
+            FuncInst::InnerFunc { start, .. } => start,
+            FuncInst::HostFunc { .. } => return None,
+        };
+        // Functions are allocated one after another.
+        let end = (&self.store.funcs)
+            .into_iter()
+            .filter_map(|func| match func {
+                Some(FuncInst::InnerFunc { start: next, .. }) if *next > start => Some(*next),
+                _ => None,
+            })
+            .min()
+            .unwrap_or(self.runtime.instrs.len());
+        Some(start..end)
+    }
+
+    fn root(&self) -> Option<&Instance> {
+        self.runtime.instances.get(self.runtime.root)
+    }
+
+    fn memaddr(&self, mem: MemIdx) -> Option<Addr> {
+        self.root()?.memaddrs.get(mem as usize).copied()
+    }
+
+    fn read(&self, memaddr: Addr, addr: u64, len: usize) -> Option<Vec<u8>> {
+        let start = usize::try_from(addr).ok()?;
+        self.store.mems[memaddr].data.get(start, len)
+    }
+
+    pub fn frame(&self) -> Option<&Frame> {
+        self.runtime.stack.frames().last()
+    }
+
+    /// Locals of the current frame, starting with its parameters.
+    pub fn locals(&self) -> &[Value] {
+        self.frame().map_or(&[], |frame| &frame.local)
+    }
+
+    /// Operands of the current frame, the top of the stack last.
+    pub fn values(&self) -> &[Value] {
+        let values = self.runtime.stack.values();
+        let offset = self.frame().map_or(0, |frame| frame.stack_offset);
+        values.get(offset..).unwrap_or(&[])
+    }
+
+    pub fn global(&self, idx: GlobalIdx) -> Option<Value> {
+        let addr = *self.root()?.globaladdrs.get(idx as usize)?;
+        Some(self.store.globals[addr].value)
+    }
+
+    /// `len` bytes at `addr` of memory `mem`, if they are in bounds.
+    pub fn memory(&self, mem: MemIdx, addr: u64, len: usize) -> Option<Vec<u8>> {
+        self.read(self.memaddr(mem)?, addr, len)
+    }
+
+    /// Call stack of the invocation, innermost frame first.
+    pub fn backtrace(&self) -> Backtrace {
+        if !self.running {
+            return Backtrace::default();
+        }
+        self.runtime.backtrace(&self.store)
+    }
+
+    /// Source location of the next instruction, if the module has a line
+    /// table.
+    #[cfg(feature = "std")]
+    pub fn location(&self) -> Option<Location> {
+        let frame = self.frame()?;
+        self.runtime.location(frame.instance_addr, self.runtime.pc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Debugger, Stop};
+    use crate::binary::Module;
+    use crate::exec::env::DebugEnv;
+    use crate::exec::importer::Importer;
+    use crate::exec::runtime::Runtime;
+    use crate::exec::store::Store;
+    use crate::exec::value::Value;
+    use crate::loader::parser::Parser;
+    use crate::tests::wat2wasm;
+
+    fn debugger() -> Debugger<DebugEnv> {
+        let wasm = wat2wasm(
+            r#"(module
+                  (memory 1)
+                  (global $g (mut i32) (i32.const 0))
+                  (func $store (param $p i32) (i32.store (i32.const 16) (local.get $p)))
+                  (func (export "main") (param $x i32) (result i32) (local $y i32)
+                      (local.set $y (i32.add (local.get $x) (i32.const 1)))
+                      (call $store (local.get $y))
+                      (global.set $g (local.get $y))
+                      (global.get $g)))"#,
+        )
+        .unwrap();
+        let module = Parser::new(&wasm).module().unwrap();
+        let mut store = Store::new();
+        let mut runtime = Runtime::new("env");
+        runtime
+            .add_module(&mut store, &mut DebugEnv {}, module)
+            .unwrap();
+        Debugger::new(runtime, store, DebugEnv {})
+    }
+
+    #[test]
+    fn step() {
+        let mut debugger = debugger();
+        assert_eq!(debugger.step_in().unwrap(), Stop::NotRunning);
+        assert_eq!(
+            debugger.invoke("main", vec![Value::I32(4)]).unwrap(),
+            Stop::Paused
+        );
+        assert_eq!(debugger.locals(), &[Value::I32(4), Value::I32(0)]);
+        for _ in 0..3 {
+            assert_eq!(debugger.step_in().unwrap(), Stop::Paused);
+        }
+        assert_eq!(debugger.values(), &[Value::I32(5)]);
+        assert_eq!(debugger.step_over().unwrap(), Stop::Paused);
+        assert_eq!(debugger.locals(), &[Value::I32(4), Value::I32(5)]);
+        assert!(debugger.values().is_empty());
+
+        // step into the call and back out of it
+        debugger.step_in().unwrap();
+        debugger.step_in().unwrap();
+        assert_eq!(debugger.backtrace().frames.len(), 2);
+        assert_eq!(debugger.locals(), &[Value::I32(5)]);
+        assert_eq!(debugger.step_out().unwrap(), Stop::Paused);
+        let frames = debugger.backtrace().frames;
+        assert_eq!((frames.len(), frames[0].offset), (1, 6));
+        assert_eq!(debugger.memory(0, 16, 4), Some(vec![5, 0, 0, 0]));
+        assert_eq!(debugger.memory(0, 65535, 4), None);
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut debugger = debugger();
+        assert!(debugger.set_breakpoint(1, 5));
+        assert!(debugger.set_breakpoint(1, 8));
+        assert!(!debugger.set_breakpoint(1, 100));
+        let end = debugger.code(0).unwrap().len();
+        assert!(!debugger.set_breakpoint(0, end));
+        assert!(!debugger.set_breakpoint(2, 0));
+        debugger.invoke("main", vec![Value::I32(4)]).unwrap();
+        assert_eq!(debugger.cont().unwrap(), Stop::Breakpoint(1, 5));
+        assert_eq!(debugger.step_over().unwrap(), Stop::Paused);
+        assert_eq!(debugger.backtrace().frames[0].offset, 6);
+        assert_eq!(debugger.cont().unwrap(), Stop::Breakpoint(1, 8));
+        assert_eq!(debugger.global(0), Some(Value::I32(5)));
+        assert_eq!(
+            debugger.cont().unwrap(),
+            Stop::Returned(vec![Value::I32(5)])
+        );
+        assert_eq!(debugger.cont().unwrap(), Stop::NotRunning);
+
+        assert!(debugger.remove_breakpoint(1, 5));
+        assert_eq!(debugger.breakpoints().collect::<Vec<_>>(), vec![&(1, 8)]);
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut debugger = debugger();
+        assert!(debugger.watch(0, 16, 4));
+        assert!(!debugger.watch(0, 65536, 1));
+        debugger.invoke("main", vec![Value::I32(4)]).unwrap();
+        assert_eq!(
+            debugger.cont().unwrap(),
+            Stop::Watchpoint {
+                mem: 0,
+                addr: 16,
+                old: vec![0; 4],
+                new: vec![5, 0, 0, 0]
+            }
+        );
+        assert_eq!(debugger.backtrace().frames[0].func_idx, Some(0));
+        assert_eq!(
+            debugger.cont().unwrap(),
+            Stop::Returned(vec![Value::I32(5)])
+        );
+
+        // storing the same bytes again does not stop
+        debugger.invoke("main", vec![Value::I32(4)]).unwrap();
+        assert_eq!(
+            debugger.cont().unwrap(),
+            Stop::Returned(vec![Value::I32(5)])
+        );
+        assert!(debugger.unwatch(0, 16));
+        assert!(!debugger.unwatch(0, 16));
+    }
+
+    #[test]
+    fn root_instance() {
+        struct Main;
+        impl Importer for Main {
+            fn import(&mut self, _: &str) -> Option<Module> {
+                let wasm = wat2wasm(
+                    r#"(module
+                          (import "lib" "poke" (func $poke))
+                          (memory 1)
+                          (global i32 (i32.const 3))
+                          (func (export "main") (call $poke)))"#,
+                )
+                .unwrap();
+                Parser::new(&wasm).module().ok()
+            }
+        }
+
+        let wasm = wat2wasm(
+            r#"(module
+                  (memory 1)
+                  (global i32 (i32.const 9))
+                  (func (export "poke") (i32.store8 (i32.const 0) (i32.const 1))))"#,
+        )
+        .unwrap();
+        let mut store = Store::new();
+        let mut runtime = Runtime::new("env");
+        runtime
+            .add_module(
+                &mut store,
+                &mut DebugEnv {},
+                Parser::new(&wasm).module().unwrap(),
+            )
+            .unwrap();
+        runtime.register("lib", runtime.root);
+        runtime
+            .import_module(&mut store, &mut DebugEnv {}, &mut Main, "main")
+            .unwrap();
+        let mut debugger = Debugger::new(runtime, store, DebugEnv {});
+
+        // Indices refer to the root instance even inside the imported function.
+        assert!(debugger.watch(0, 0, 1));
+        debugger.invoke("main", vec![]).unwrap();
+        debugger.step_in().unwrap();
+        assert_eq!(debugger.backtrace().frames.len(), 2);
+        assert_eq!(debugger.global(0), Some(Value::I32(3)));
+        assert_eq!(debugger.cont().unwrap(), Stop::Returned(vec![]));
+        assert_eq!(debugger.memory(0, 0, 1), Some(vec![0]));
+    }
+}
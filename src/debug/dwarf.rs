@@ -0,0 +1,593 @@
+//! Line tables of the DWARF `.debug_line` section, which map code offsets
+//! of a module to source locations. Versions 2 to 5 are supported.
+
+use crate::binary::Custom;
+use crate::loader::leb128::{read_64, Leb128};
+use std::fmt;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    UnexpectedEof,
+    InvalidLeb128,
+    UnsupportedVersion(u16),
+    /// A file or directory entry is encoded with an unsupported form.
+    UnsupportedForm(u64),
+    /// A string offset is out of its section.
+    InvalidString(u64),
+    /// The header declares a `line_range` of 0.
+    InvalidLineRange,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::UnexpectedEof => write!(f, "unexpected end of line program"),
+            Error::InvalidLeb128 => write!(f, "invalid LEB128 number"),
+            Error::UnsupportedVersion(version) => {
+                write!(f, "unsupported line table version {}", version)
+            }
+            Error::UnsupportedForm(form) => write!(f, "unsupported attribute form 0x{:x}", form),
+            Error::InvalidString(offset) => write!(f, "invalid string offset 0x{:x}", offset),
+            Error::InvalidLineRange => write!(f, "invalid line range 0"),
+        }
+    }
+}
+
+/// Source position of an instruction.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Location {
+    pub file: String,
+    pub line: u32,
+    /// Column starting at 1, or 0 when unknown.
+    pub column: u32,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)?;
+        if self.column != 0 {
+            write!(f, ":{}", self.column)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct Row {
+    address: u64,
+    file: usize,
+    line: u32,
+    column: u32,
+}
+
+/// Rows of one sequence, covering the addresses from the first row up to
+/// `end`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct Sequence {
+    rows: Vec<Row>,
+    end: u64,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct LineTable {
+    files: Vec<String>,
+    sequences: Vec<Sequence>,
+}
+
+impl LineTable {
+    /// Line table of the `.debug_line` custom section in `customs`, or
+    /// `None` without one. Strings are read from `.debug_str` and
+    /// `.debug_line_str` when present.
+    pub fn from_customs<'a, I>(customs: I) -> Option<Result<Self, Error>>
+    where
+        I: IntoIterator<Item = &'a Custom>,
+    {
+        let (mut debug_line, mut debug_str, mut debug_line_str) = (None, &[][..], &[][..]);
+        for custom in customs {
+            match custom.name.as_str() {
+                ".debug_line" => debug_line = Some(custom.bytes.as_slice()),
+                ".debug_str" => debug_str = &custom.bytes,
+                ".debug_line_str" => debug_line_str = &custom.bytes,
+                _ => {}
+            }
+        }
+        debug_line.map(|debug_line| Self::parse(debug_line, debug_str, debug_line_str))
+    }
+
+    /// Runs the line programs of all units in `debug_line`.
+    pub fn parse(
+        debug_line: &[u8],
+        debug_str: &[u8],
+        debug_line_str: &[u8],
+    ) -> Result<Self, Error> {
+        let mut table = LineTable::default();
+        let mut reader = Reader::new(debug_line);
+        while !reader.is_empty() {
+            let (length, offset_size) = match reader.u32()? {
+                0xffff_ffff => (reader.u64()?, 8),
+                length => (length as u64, 4),
+            };
+            let unit = reader.take(length as usize)?;
+            let strings = Strings {
+                debug_str,
+                debug_line_str,
+            };
+            table.unit(Reader::new(unit), offset_size, &strings)?;
+        }
+        table.sequences.sort_by_key(|seq| seq.rows[0].address);
+        Ok(table)
+    }
+
+    /// Location of the instruction at code offset `address`.
+    pub fn lookup(&self, address: u64) -> Option<Location> {
+        let seq = self
+            .sequences
+            .iter()
+            .find(|seq| seq.rows[0].address <= address && address < seq.end)?;
+        let i = match seq.rows.binary_search_by_key(&address, |row| row.address) {
+            Ok(i) => i,
+            Err(i) => i - 1,
+        };
+        let row = &seq.rows[i];
+        Some(Location {
+            file: self.files.get(row.file)?.clone(),
+            line: row.line,
+            column: row.column,
+        })
+    }
+
+    fn unit(&mut self, mut r: Reader, offset_size: usize, strings: &Strings) -> Result<(), Error> {
+        let version = r.u16()?;
+        if !(2..=5).contains(&version) {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        if version >= 5 {
+            // address_size, segment_selector_size
+            r.take(2)?;
+        }
+        let header_length = r.offset(offset_size)?;
+        let mut program = r.clone();
+        program.skip(header_length as usize)?;
+
+        let min_inst_length = r.u8()? as u64;
+        if version >= 4 {
+            // maximum_operations_per_instruction, only used by VLIW targets
+            r.u8()?;
+        }
+        // default_is_stmt, statement boundaries are not distinguished
+        r.u8()?;
+        let line_base = r.u8()? as i8 as i64;
+        let line_range = r.u8()? as u64;
+        if line_range == 0 {
+            return Err(Error::InvalidLineRange);
+        }
+        let opcode_base = r.u8()?;
+        let lengths = r.take(opcode_base.saturating_sub(1) as usize)?.to_vec();
+
+        // Indices into `self.files` of the files of this unit.
+        let files = if version >= 5 {
+            let dirs: Vec<String> = r
+                .entries(offset_size, strings)?
+                .into_iter()
+                .map(|(path, _)| path)
+                .collect();
+            r.entries(offset_size, strings)?
+                .into_iter()
+                .map(|(path, dir)| self.file(join(dirs.get(dir as usize), path)))
+                .collect()
+        } else {
+            // Directory 0 is the compilation directory, which is not listed.
+            let mut dirs = vec![];
+            loop {
+                match r.cstr()? {
+                    "" => break,
+                    dir => dirs.push(dir.to_string()),
+                }
+            }
+            let mut files = vec![];
+            loop {
+                match r.cstr()? {
+                    "" => break,
+                    path => {
+                        let dir = r.uleb()? as usize;
+                        // modification time and length
+                        r.uleb()?;
+                        r.uleb()?;
+                        let dir = dir.checked_sub(1).and_then(|dir| dirs.get(dir));
+                        files.push(self.file(join(dir, path.to_string())));
+                    }
+                }
+            }
+            files
+        };
+        // Files are numbered from 1 before version 5.
+        let file_base = if version >= 5 { 0 } else { 1 };
+
+        // Registers wrap around rather than overflow on crafted programs.
+        let mut rows = vec![];
+        let mut state = State::new();
+        while !program.is_empty() {
+            let opcode = program.u8()?;
+            if opcode >= opcode_base {
+                let adjusted = (opcode - opcode_base) as u64;
+                state.advance(adjusted / line_range * min_inst_length);
+                state.line = state
+                    .line
+                    .wrapping_add(line_base + (adjusted % line_range) as i64);
+                rows.push(state.row(&files, file_base));
+                continue;
+            }
+            match opcode {
+                0 => {
+                    let length = program.uleb()? as usize;
+                    let mut ext = Reader::new(program.take(length)?);
+                    match ext.u8()? {
+                        // DW_LNE_end_sequence
+                        1 => {
+                            if !rows.is_empty() {
+                                self.sequences.push(Sequence {
+                                    rows: core::mem::take(&mut rows),
+                                    end: state.address,
+                                });
+                            }
+                            state = State::new();
+                        }
+                        // DW_LNE_set_address
+                        2 => {
+                            state.address = match length - 1 {
+                                4 => ext.u32()? as u64,
+                                _ => ext.u64()?,
+                            }
+                        }
+                        // DW_LNE_define_file, DW_LNE_set_discriminator and
+                        // vendor extensions
+                        _ => {}
+                    }
+                }
+                // DW_LNS_copy
+                1 => rows.push(state.row(&files, file_base)),
+                // DW_LNS_advance_pc
+                2 => state.advance(program.uleb()?.wrapping_mul(min_inst_length)),
+                // DW_LNS_advance_line
+                3 => state.line = state.line.wrapping_add(program.sleb()?),
+                // DW_LNS_set_file
+                4 => state.file = program.uleb()? as usize,
+                // DW_LNS_set_column
+                5 => state.column = program.uleb()? as u32,
+                // DW_LNS_const_add_pc
+                8 => {
+                    let adjusted = (255 - opcode_base) as u64;
+                    state.advance(adjusted / line_range * min_inst_length);
+                }
+                // DW_LNS_fixed_advance_pc
+                9 => state.advance(program.u16()? as u64),
+                // Opcodes without operands, or unknown ones skipped by their
+                // number of LEB128 operands.
+                _ => {
+                    for _ in 0..lengths[opcode as usize - 1] {
+                        program.uleb()?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn file(&mut self, path: String) -> usize {
+        match self.files.iter().position(|file| *file == path) {
+            Some(i) => i,
+            None => {
+                self.files.push(path);
+                self.files.len() - 1
+            }
+        }
+    }
+}
+
+fn join(dir: Option<&String>, path: String) -> String {
+    match dir {
+        Some(dir) if !dir.is_empty() && !path.starts_with('/') => format!("{}/{}", dir, path),
+        _ => path,
+    }
+}
+
+/// Registers of the line number state machine.
+struct State {
+    address: u64,
+    file: usize,
+    line: i64,
+    column: u32,
+}
+
+impl State {
+    fn new() -> Self {
+        State {
+            address: 0,
+            file: 1,
+            line: 1,
+            column: 0,
+        }
+    }
+
+    fn advance(&mut self, delta: u64) {
+        self.address = self.address.wrapping_add(delta);
+    }
+
+    fn row(&self, files: &[usize], file_base: usize) -> Row {
+        Row {
+            address: self.address,
+            file: self
+                .file
+                .checked_sub(file_base)
+                .and_then(|i| files.get(i).copied())
+                .unwrap_or(usize::MAX),
+            line: self.line as u32,
+            column: self.column,
+        }
+    }
+}
+
+/// Sections referred to by string forms.
+struct Strings<'a> {
+    debug_str: &'a [u8],
+    debug_line_str: &'a [u8],
+}
+
+fn string(section: &[u8], offset: u64) -> Result<&str, Error> {
+    section
+        .get(offset as usize..)
+        .and_then(|bytes| Reader::new(bytes).cstr().ok())
+        .ok_or(Error::InvalidString(offset))
+}
+
+#[derive(Clone)]
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if n > self.bytes.len() {
+            return Err(Error::UnexpectedEof);
+        }
+        let (bytes, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(bytes)
+    }
+
+    fn skip(&mut self, n: usize) -> Result<(), Error> {
+        self.take(n).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// Section offset of the DWARF format, 4 or 8 bytes.
+    fn offset(&mut self, size: usize) -> Result<u64, Error> {
+        match size {
+            4 => Ok(self.u32()? as u64),
+            _ => self.u64(),
+        }
+    }
+
+    fn uleb(&mut self) -> Result<u64, Error> {
+        let (value, size) = read_64(self.bytes, false).map_err(|_| Error::InvalidLeb128)?;
+        self.skip(size)?;
+        Ok(value)
+    }
+
+    fn sleb(&mut self) -> Result<i64, Error> {
+        let (value, size) = i64::read_leb128(self.bytes).map_err(|_| Error::InvalidLeb128)?;
+        self.skip(size)?;
+        Ok(value)
+    }
+
+    fn cstr(&mut self) -> Result<&'a str, Error> {
+        let len = self
+            .bytes
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or(Error::UnexpectedEof)?;
+        let bytes = self.take(len + 1)?;
+        Ok(core::str::from_utf8(&bytes[..len]).unwrap_or(""))
+    }
+
+    /// Directory or file name entries of a version 5 header, as paths and
+    /// directory indices.
+    fn entries(
+        &mut self,
+        offset_size: usize,
+        strings: &Strings,
+    ) -> Result<Vec<(String, u64)>, Error> {
+        let mut formats = vec![];
+        for _ in 0..self.u8()? {
+            formats.push((self.uleb()?, self.uleb()?));
+        }
+        let mut entries = vec![];
+        for _ in 0..self.uleb()? {
+            let (mut path, mut dir) = (String::new(), 0);
+            for &(content, form) in formats.iter() {
+                let value = self.form(form, offset_size, strings)?;
+                match (content, value) {
+                    // DW_LNCT_path
+                    (1, Value::Str(s)) => path = s,
+                    // DW_LNCT_directory_index
+                    (2, Value::Int(i)) => dir = i,
+                    _ => {}
+                }
+            }
+            entries.push((path, dir));
+        }
+        Ok(entries)
+    }
+
+    fn form(&mut self, form: u64, offset_size: usize, strings: &Strings) -> Result<Value, Error> {
+        Ok(match form {
+            // DW_FORM_string
+            0x08 => Value::Str(self.cstr()?.to_string()),
+            // DW_FORM_strp
+            0x0e => Value::Str(string(strings.debug_str, self.offset(offset_size)?)?.to_string()),
+            // DW_FORM_line_strp
+            0x1f => {
+                Value::Str(string(strings.debug_line_str, self.offset(offset_size)?)?.to_string())
+            }
+            // DW_FORM_data1, data2, data4, data8, udata
+            0x0b => Value::Int(self.u8()? as u64),
+            0x05 => Value::Int(self.u16()? as u64),
+            0x06 => Value::Int(self.u32()? as u64),
+            0x07 => Value::Int(self.u64()?),
+            0x0f => Value::Int(self.uleb()?),
+            // DW_FORM_data16, used for MD5 digests
+            0x1e => {
+                self.skip(16)?;
+                Value::Other
+            }
+            // DW_FORM_block
+            0x09 => {
+                let len = self.uleb()? as usize;
+                self.skip(len)?;
+                Value::Other
+            }
+            form => return Err(Error::UnsupportedForm(form)),
+        })
+    }
+}
+
+enum Value {
+    Str(String),
+    Int(u64),
+    Other,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Error, LineTable, Location};
+
+    /// Line table unit of `version` with the header fields following
+    /// `header_length`.
+    fn unit(version: u16, header: &[u8], program: &[u8]) -> Vec<u8> {
+        let mut unit = version.to_le_bytes().to_vec();
+        if version >= 5 {
+            // address_size, segment_selector_size
+            unit.extend([4, 0]);
+        }
+        unit.extend((header.len() as u32).to_le_bytes());
+        unit.extend(header);
+        unit.extend(program);
+        let mut bytes = (unit.len() as u32).to_le_bytes().to_vec();
+        bytes.extend(unit);
+        bytes
+    }
+
+    // minimum_instruction_length, maximum_operations_per_instruction,
+    // default_is_stmt, line_base = -5, line_range = 14, opcode_base = 13
+    // and standard_opcode_lengths
+    const PARAMS: [u8; 18] = [1, 1, 1, 0xfb, 14, 13, 0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1];
+
+    fn location(file: &str, line: u32, column: u32) -> Option<Location> {
+        Some(Location {
+            file: file.into(),
+            line,
+            column,
+        })
+    }
+
+    #[test]
+    fn version4() {
+        let mut header = PARAMS.to_vec();
+        header.extend(b"src\0\0");
+        header.extend(b"main.c\0\x01\0\0\0");
+        let program = [
+            0x00, 0x05, 0x02, 0x10, 0x00, 0x00, 0x00, // set_address 0x10
+            0x05, 0x03, // set_column 3
+            0x03, 0x09, // advance_line 9
+            0x01, // copy
+            76,   // address += 4, line += 2
+            0x02, 0x04, // advance_pc 4
+            0x00, 0x01, 0x01, // end_sequence
+        ];
+        let table = LineTable::parse(&unit(4, &header, &program), &[], &[]).unwrap();
+        assert_eq!(table.lookup(0x0f), None);
+        assert_eq!(table.lookup(0x10), location("src/main.c", 10, 3));
+        assert_eq!(table.lookup(0x13), location("src/main.c", 10, 3));
+        assert_eq!(table.lookup(0x17), location("src/main.c", 12, 3));
+        assert_eq!(table.lookup(0x18), None);
+    }
+
+    #[test]
+    fn version5() {
+        let mut header = PARAMS.to_vec();
+        // directories: DW_LNCT_path as DW_FORM_line_strp
+        header.extend([1, 0x01, 0x1f, 1, 0, 0, 0, 0]);
+        // files: DW_LNCT_path as DW_FORM_string and DW_LNCT_directory_index
+        // as DW_FORM_data1
+        header.extend([2, 0x01, 0x08, 0x02, 0x0b, 1]);
+        header.extend(b"lib.rs\0\0");
+        let program = [
+            0x00, 0x05, 0x02, 0x20, 0x00, 0x00, 0x00, // set_address 0x20
+            0x04, 0x00, // set_file 0
+            0x03, 0x04, // advance_line 4
+            0x01, // copy
+            0x02, 0x02, // advance_pc 2
+            0x00, 0x01, 0x01, // end_sequence
+        ];
+        let table = LineTable::parse(&unit(5, &header, &program), &[], b"/work\0").unwrap();
+        assert_eq!(table.lookup(0x21), location("/work/lib.rs", 5, 0));
+        assert_eq!(table.lookup(0x22), None);
+    }
+
+    #[test]
+    fn malformed() {
+        let mut header = PARAMS.to_vec();
+        // line_range
+        header[4] = 0;
+        header.extend(b"\0\0");
+        assert_eq!(
+            LineTable::parse(&unit(4, &header, &[13]), &[], &[]).err(),
+            Some(Error::InvalidLineRange)
+        );
+
+        // Registers wrap instead of overflowing.
+        let mut header = PARAMS.to_vec();
+        header.extend(b"\0\0");
+        let mut program = vec![];
+        for _ in 0..2 {
+            // advance_pc u64::MAX
+            program.push(0x02);
+            program.extend([0xff; 9]);
+            program.push(0x01);
+            // advance_line i64::MAX
+            program.push(0x03);
+            program.extend([0xff; 9]);
+            program.push(0x00);
+        }
+        program.extend([0x01, 0x00, 0x01, 0x01]);
+        assert!(LineTable::parse(&unit(4, &header, &program), &[], &[]).is_ok());
+    }
+}
@@ -0,0 +1,530 @@
+//! Stub for the GDB remote serial protocol, through which GDB and LLDB debug
+//! an invocation paused in a `Debugger`. Besides the standard packets it
+//! serves the `qWasm*` packets of LLDB's wasm support.
+//!
+//! Addresses are those of LLDB: the top two bits select linear memory (0)
+//! or the bytes of a module (1), the next 30 bits the instance address and
+//! the low 32 bits the offset. The pc is the module offset of the next
+//! instruction. Register 0 is the pc and register `n + 1` is local `n` of
+//! the innermost frame.
+
+use super::debugger::{Debugger, Stop};
+use crate::binary::FuncIdx;
+use crate::exec::env::Env;
+use crate::exec::runtime::{Addr, RuntimeError};
+use crate::exec::stack::Frame;
+use crate::exec::value::{Ref, Value};
+use crate::loader::leb128::read_32;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Read, Write};
+
+/// Address space of module bytes.
+const CODE: u64 = 1 << 62;
+
+const HOST_INFO: &str =
+    "vendor:wasper;ostype:wasi;arch:wasm32-unknown-unknown-wasm;endian:little;ptrsize:4;";
+
+const PC_INFO: &str = "name:pc;alt-name:pc;bitsize:64;offset:0;encoding:uint;format:hex;\
+                       set:General Purpose Registers;gcc:16;dwarf:16;generic:pc;";
+
+/// How a debugging session ended.
+#[derive(Debug)]
+pub enum Exit {
+    Returned(Vec<Value>),
+    Trapped(RuntimeError),
+    /// The client detached or killed the invocation, or closed the
+    /// connection.
+    Detached,
+}
+
+/// Serves one client debugging the invocation of `debugger`, whose root
+/// instance was instantiated from the module `bytes`.
+pub struct GdbServer<E: Env> {
+    pub debugger: Debugger<E>,
+    name: String,
+    bytes: Vec<u8>,
+    /// Offset of the contents of the code section in `bytes`.
+    code_start: u32,
+    no_ack: bool,
+    exit: Option<Exit>,
+}
+
+impl<E: Env> GdbServer<E> {
+    pub fn new(debugger: Debugger<E>, name: &str, bytes: Vec<u8>) -> Self {
+        let code_start = code_start(&bytes).unwrap_or(0);
+        GdbServer {
+            debugger,
+            name: name.into(),
+            bytes,
+            code_start,
+            no_ack: false,
+            exit: None,
+        }
+    }
+
+    /// Answers packets until the invocation ends or the client goes away.
+    pub fn serve<R: Read, W: Write>(&mut self, reader: R, mut writer: W) -> io::Result<Exit> {
+        let mut reader = BufReader::new(reader);
+        while let Some(packet) = self.receive(&mut reader, &mut writer)? {
+            if let Some(reply) = self.handle(&packet) {
+                send(&mut writer, &reply)?;
+            }
+            if let Some(exit) = self.exit.take() {
+                return Ok(exit);
+            }
+        }
+        Ok(Exit::Detached)
+    }
+
+    /// Next packet, acknowledged unless in no-ack mode. An interrupt is
+    /// returned as the packet `"\x03"`.
+    fn receive<R: BufRead, W: Write>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+    ) -> io::Result<Option<String>> {
+        loop {
+            let mut byte = [0];
+            if reader.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            match byte[0] {
+                b'$' => {}
+                0x03 => return Ok(Some("\x03".into())),
+                // acknowledgments and noise between packets
+                _ => continue,
+            }
+            let mut data = vec![];
+            reader.read_until(b'#', &mut data)?;
+            if data.pop() != Some(b'#') {
+                return Ok(None);
+            }
+            let mut checksum = [0; 2];
+            reader.read_exact(&mut checksum)?;
+            let valid = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                == Some(sum(&data));
+            if !self.no_ack {
+                writer.write_all(if valid { b"+" } else { b"-" })?;
+                writer.flush()?;
+            }
+            if valid {
+                return Ok(Some(String::from_utf8_lossy(&unescape(&data)).into()));
+            }
+        }
+    }
+
+    /// Reply to `packet`, if it has one. The empty reply marks unsupported
+    /// packets.
+    fn handle(&mut self, packet: &str) -> Option<String> {
+        let reply = match packet {
+            "\x03" | "?" if self.debugger.is_running() => "T05thread:1;".into(),
+            "\x03" | "?" => self.stop(Ok(Stop::NotRunning)),
+            "QStartNoAckMode" => {
+                self.no_ack = true;
+                "OK".into()
+            }
+            "qHostInfo" => HOST_INFO.into(),
+            "qProcessInfo" => format!("pid:1;{}", HOST_INFO),
+            "qfThreadInfo" => "m1".into(),
+            "qsThreadInfo" => "l".into(),
+            "qC" => "QC1".into(),
+            "qAttached" => "1".into(),
+            "vCont?" => "vCont;c;s".into(),
+            "g" => hex(&self.pc(0).unwrap_or(0).to_le_bytes()),
+            "s" | "vCont;s" => {
+                let stop = self.debugger.step_in();
+                self.stop(stop)
+            }
+            "c" | "vCont;c" => {
+                let stop = self.debugger.cont();
+                self.stop(stop)
+            }
+            "k" => {
+                self.exit = Some(Exit::Detached);
+                return None;
+            }
+            _ if packet.starts_with('D') => {
+                self.exit = Some(Exit::Detached);
+                "OK".into()
+            }
+            _ if packet.starts_with('H') || packet.starts_with('T') => "OK".into(),
+            _ if packet.starts_with("qSupported") => {
+                "PacketSize=1000;QStartNoAckMode+;qXfer:libraries:read+;swbreak+".into()
+            }
+            // vCont with a thread id
+            _ if packet.starts_with("vCont;s:") => {
+                let stop = self.debugger.step_in();
+                self.stop(stop)
+            }
+            _ if packet.starts_with("vCont;c:") => {
+                let stop = self.debugger.cont();
+                self.stop(stop)
+            }
+            _ => self.query(packet).unwrap_or_else(|| "E03".into()),
+        };
+        Some(reply)
+    }
+
+    /// Reply to the packets with arguments, `None` if they are invalid.
+    fn query(&mut self, packet: &str) -> Option<String> {
+        if let Some(n) = packet.strip_prefix("qRegisterInfo") {
+            return Some(match parse_hex(n)? {
+                0 => PC_INFO.into(),
+                _ => "E45".into(),
+            });
+        }
+        if let Some(n) = packet.strip_prefix('p') {
+            return match parse_hex(n)? {
+                0 => Some(hex(&self.pc(0)?.to_le_bytes())),
+                n => Some(hex(&value_bytes(
+                    self.debugger.locals().get(n as usize - 1)?,
+                ))),
+            };
+        }
+        if let Some(args) = packet.strip_prefix('m') {
+            let (addr, len) = split2(args, ',')?;
+            return Some(hex(&self.read(addr, len as usize)?));
+        }
+        if let Some(args) = packet.strip_prefix('Z') {
+            return self.breakpoint(args, true);
+        }
+        if let Some(args) = packet.strip_prefix('z') {
+            return self.breakpoint(args, false);
+        }
+        if let Some(args) = packet.strip_prefix("qXfer:libraries:read::") {
+            let (offset, len) = split2(args, ',')?;
+            let xml = format!(
+                "<library-list><library name=\"{}\"><section address=\"0x{:x}\"/></library></library-list>",
+                self.name,
+                CODE | (self.debugger.runtime.root as u64) << 32
+            );
+            let start = (offset as usize).min(xml.len());
+            let end = start.saturating_add(len as usize).min(xml.len());
+            let more = if end < xml.len() { 'm' } else { 'l' };
+            return Some(format!("{}{}", more, &xml[start..end]));
+        }
+        if packet.starts_with("qWasmCallStack") {
+            let frames = self.debugger.runtime.stack.frames_len();
+            let pcs = (0..frames)
+                .filter_map(|n| self.pc(n))
+                .flat_map(u64::to_le_bytes)
+                .collect::<Vec<_>>();
+            return Some(hex(&pcs));
+        }
+        if let Some(args) = packet.strip_prefix("qWasmLocal:") {
+            let (frame, idx) = split2(args, ';')?;
+            let value = self.frame(frame as usize)?.local.get(idx as usize)?;
+            return Some(hex(&value_bytes(value)));
+        }
+        if let Some(args) = packet.strip_prefix("qWasmGlobal:") {
+            let (frame, idx) = split2(args, ';')?;
+            let instance =
+                &self.debugger.runtime.instances[self.frame(frame as usize)?.instance_addr];
+            let addr = *instance.globaladdrs.get(idx as usize)?;
+            return Some(hex(&value_bytes(&self.debugger.store.globals[addr].value)));
+        }
+        if let Some(args) = packet.strip_prefix("qWasmStackValue:") {
+            let (frame, idx) = split2(args, ';')?;
+            return Some(hex(&value_bytes(
+                self.operands(frame as usize)?.get(idx as usize)?,
+            )));
+        }
+        if let Some(args) = packet.strip_prefix("qWasmMem:") {
+            let mut args = args.split(';').map(parse_hex);
+            let (frame, addr, len) = (args.next()??, args.next()??, args.next()??);
+            // The address is an offset into the memory of the frame's instance,
+            // which takes the upper 32 bits.
+            let addr = u64::from(u32::try_from(addr).ok()?);
+            let instance = self.frame(frame as usize)?.instance_addr as u64;
+            return Some(hex(&self.read(instance << 32 | addr, len as usize)?));
+        }
+        Some(String::new())
+    }
+
+    /// Stop reply for the result of running the invocation, recording the
+    /// exit once it has ended.
+    fn stop(&mut self, stop: Result<Stop, RuntimeError>) -> String {
+        let root = self.debugger.runtime.root as u64;
+        let (reply, exit) = match stop {
+            Ok(Stop::Paused) => ("T05thread:1;".into(), None),
+            Ok(Stop::Breakpoint(..)) => ("T05thread:1;swbreak:;".into(), None),
+            Ok(Stop::Watchpoint { addr, .. }) => {
+                (format!("T05thread:1;watch:{:x};", root << 32 | addr), None)
+            }
+            Ok(Stop::Returned(results)) => ("W00".into(), Some(Exit::Returned(results))),
+            Ok(Stop::NotRunning) => ("W00".into(), Some(Exit::Detached)),
+            Err(err) => ("X06".into(), Some(Exit::Trapped(err))),
+        };
+        self.exit = exit;
+        reply
+    }
+
+    /// `Z` and `z` packets for breakpoints and write watchpoints.
+    fn breakpoint(&mut self, args: &str, insert: bool) -> Option<String> {
+        let mut args = args.split(',');
+        let type_ = args.next()?;
+        let addr = parse_hex(args.next()?)?;
+        let len = parse_hex(args.next()?)?;
+        if (addr >> 32 & 0x3fff_ffff) as usize != self.debugger.runtime.root {
+            return None;
+        }
+        let offset = addr as u32;
+        let ok = match type_ {
+            "0" | "1" if addr & CODE != 0 => {
+                let (func, offset) = self.locate(offset.checked_sub(self.code_start)?)?;
+                if insert {
+                    self.debugger.set_breakpoint(func, offset)
+                } else {
+                    self.debugger.remove_breakpoint(func, offset)
+                }
+            }
+            "2" if addr & CODE == 0 && insert => {
+                self.debugger.watch(0, offset as u64, len as usize)
+            }
+            "2" if addr & CODE == 0 => self.debugger.unwatch(0, offset as u64),
+            _ => return Some(String::new()),
+        };
+        ok.then(|| "OK".into())
+    }
+
+    /// Function of the root instance and instruction offset in it of the
+    /// instruction at `code_offset`.
+    fn locate(&self, code_offset: u32) -> Option<(FuncIdx, usize)> {
+        let runtime = &self.debugger.runtime;
+        let instance = runtime.instances.get(runtime.root)?;
+        (0..instance.funcaddrs.len()).find_map(|idx| {
+            let code = self.debugger.code(idx as FuncIdx)?;
+            code.clone()
+                .find(|&pc| runtime.code_offsets[pc] == code_offset)
+                .map(|pc| (idx as FuncIdx, pc - code.start))
+        })
+    }
+
+    /// Frame `n`, counting from the innermost one.
+    fn frame(&self, n: usize) -> Option<&Frame> {
+        self.debugger.runtime.stack.frames().iter().rev().nth(n)
+    }
+
+    /// Operands of frame `n`, the top of the stack last.
+    fn operands(&self, n: usize) -> Option<&[Value]> {
+        let frames = self.debugger.runtime.stack.frames();
+        let values = self.debugger.runtime.stack.values();
+        let i = frames.len().checked_sub(n + 1)?;
+        let end = frames.get(i + 1).map_or(values.len(), |f| f.stack_offset);
+        values.get(frames[i].stack_offset..end)
+    }
+
+    /// Address of the next instruction of frame `n`.
+    fn pc(&self, n: usize) -> Option<u64> {
+        let backtrace = self.debugger.backtrace();
+        let frame = backtrace.frames.get(n)?;
+        let mut offset = frame.code_offset;
+        if frame.instance_addr == self.debugger.runtime.root {
+            offset += self.code_start;
+        }
+        Some(CODE | (frame.instance_addr as u64) << 32 | offset as u64)
+    }
+
+    /// Up to `len` bytes at `addr`, `None` if there are none.
+    fn read(&self, addr: u64, len: usize) -> Option<Vec<u8>> {
+        let instance = (addr >> 32 & 0x3fff_ffff) as Addr;
+        let start = addr as u32 as usize;
+        let bytes = if addr & CODE != 0 {
+            if instance != self.debugger.runtime.root {
+                return None;
+            }
+            let end = start.saturating_add(len).min(self.bytes.len());
+            self.bytes.get(start..end)?.to_vec()
+        } else {
+            let instance = self.debugger.runtime.instances.get(instance)?;
+            let data = &self.debugger.store.mems[*instance.memaddrs.first()?].data;
+            let end = start.saturating_add(len).min(data.len());
+            data.get(start, end.checked_sub(start)?)?
+        };
+        Some(bytes).filter(|bytes| !bytes.is_empty())
+    }
+}
+
+/// Offset of the contents of the code section of a module.
+fn code_start(bytes: &[u8]) -> Option<u32> {
+    let mut offset = 8;
+    while offset < bytes.len() {
+        let id = bytes[offset];
+        let (size, n) = read_32(&bytes[offset + 1..], false).ok()?;
+        let start = offset + 1 + n;
+        if id == 10 {
+            return Some(start as u32);
+        }
+        offset = start + size as usize;
+    }
+    None
+}
+
+/// Little-endian bytes of a value. References are given by their address.
+fn value_bytes(value: &Value) -> Vec<u8> {
+    match *value {
+        Value::I32(v) => v.to_le_bytes().to_vec(),
+        Value::I64(v) => v.to_le_bytes().to_vec(),
+        Value::F32(v) => v.to_le_bytes().to_vec(),
+        Value::F64(v) => v.to_le_bytes().to_vec(),
+        Value::V128(v) => v.to_le_bytes().to_vec(),
+        Value::Ref(Ref::Null) => 0u64.to_le_bytes().to_vec(),
+        Value::Ref(Ref::I31(v)) => (v as u64).to_le_bytes().to_vec(),
+        Value::Ref(
+            Ref::Func(addr)
+            | Ref::Extern(addr)
+            | Ref::Exn(addr)
+            | Ref::Struct(addr)
+            | Ref::Array(addr),
+        ) => (addr as u64).to_le_bytes().to_vec(),
+    }
+}
+
+fn send<W: Write>(writer: &mut W, data: &str) -> io::Result<()> {
+    let mut escaped = vec![];
+    for &byte in data.as_bytes() {
+        match byte {
+            b'#' | b'$' | b'}' | b'*' => escaped.extend([b'}', byte ^ 0x20]),
+            _ => escaped.push(byte),
+        }
+    }
+    write!(writer, "$")?;
+    writer.write_all(&escaped)?;
+    write!(writer, "#{:02x}", sum(&escaped))?;
+    writer.flush()
+}
+
+fn sum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![];
+    let mut iter = data.iter();
+    while let Some(&byte) = iter.next() {
+        match byte {
+            b'}' => bytes.extend(iter.next().map(|byte| byte ^ 0x20)),
+            _ => bytes.push(byte),
+        }
+    }
+    bytes
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        write!(hex, "{:02x}", byte).unwrap();
+    }
+    hex
+}
+
+fn parse_hex(hex: &str) -> Option<u64> {
+    u64::from_str_radix(hex, 16).ok()
+}
+
+fn split2(args: &str, sep: char) -> Option<(u64, u64)> {
+    let (a, b) = args.split_once(sep)?;
+    Some((parse_hex(a)?, parse_hex(b)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sum, Exit, GdbServer, CODE};
+    use crate::debug::debugger::Debugger;
+    use crate::exec::env::DebugEnv;
+    use crate::exec::runtime::Runtime;
+    use crate::exec::store::{FuncInst, Store};
+    use crate::exec::value::Value;
+    use crate::loader::parser::Parser;
+    use crate::tests::wat2wasm;
+
+    #[test]
+    fn session() {
+        let wasm = wat2wasm(
+            r#"(module
+                  (memory 1)
+                  (func $store (param $p i32) (i32.store (i32.const 16) (local.get $p)))
+                  (func (export "main") (param $x i32) (result i32) (local $y i32)
+                      (local.set $y (i32.add (local.get $x) (i32.const 1)))
+                      (call $store (local.get $y))
+                      (local.get $y)))"#,
+        )
+        .unwrap();
+        let module = Parser::new(&wasm).module().unwrap();
+        let mut store = Store::new();
+        let mut runtime = Runtime::new("env");
+        runtime
+            .add_module(&mut store, &mut DebugEnv {}, module)
+            .unwrap();
+        let mut debugger = Debugger::new(runtime, store, DebugEnv {});
+        debugger.invoke("main", vec![Value::I32(4)]).unwrap();
+        let mut server = GdbServer::new(debugger, "app.wasm", wasm);
+
+        // address of the call in main
+        let runtime = &server.debugger.runtime;
+        let funcaddr = runtime.instances[runtime.root].funcaddrs[1];
+        let start = match server.debugger.store.funcs[funcaddr] {
+            FuncInst::InnerFunc { start, .. } => start,
+            _ => unreachable!(),
+        };
+        let call = CODE | (runtime.code_offsets[start + 5] + server.code_start) as u64;
+
+        let requests = [
+            "QStartNoAckMode".to_string(),
+            "qWasmLocal:0;0".into(),
+            "m4000000000000000,4".into(),
+            "qXfer:libraries:read::0,1000".into(),
+            format!("Z0,{:x},1", call),
+            "c".into(),
+            "p2".into(),
+            "qWasmStackValue:0;0".into(),
+            "m10,4".into(),
+            "qWasmMem:0;10;4".into(),
+            "qWasmMem:0;4000000000000000;4".into(),
+            "Z2,10,4".into(),
+            "c".into(),
+            "qWasmCallStack:1".into(),
+            "z2,10,4".into(),
+            "c".into(),
+        ];
+        let input = requests
+            .iter()
+            .map(|data| format!("${}#{:02x}", data, sum(data.as_bytes())))
+            .collect::<String>();
+        let mut output = vec![];
+        let exit = server.serve(input.as_bytes(), &mut output).unwrap();
+        assert!(matches!(exit, Exit::Returned(results) if results == vec![Value::I32(5)]));
+
+        let output = String::from_utf8(output).unwrap();
+        let replies = output
+            .split('$')
+            .skip(1)
+            .map(|packet| packet.split('#').next().unwrap())
+            .collect::<Vec<_>>();
+        assert!(output.starts_with("+$OK#"));
+        assert_eq!(&replies[..3], ["OK", "04000000", "0061736d"]);
+        assert_eq!(
+            replies[3],
+            "l<library-list><library name=\"app.wasm\">\
+             <section address=\"0x4000000000000000\"/></library></library-list>"
+        );
+        assert_eq!(
+            &replies[4..13],
+            [
+                "OK",
+                "T05thread:1;swbreak:;",
+                "05000000",
+                "05000000",
+                "00000000",
+                "00000000",
+                "E03",
+                "OK",
+                "T05thread:1;watch:10;"
+            ]
+        );
+        assert_eq!(replies[13].len(), 32);
+        assert_eq!(&replies[14..], ["OK", "W00"]);
+    }
+}
@@ -0,0 +1,5 @@
+pub mod debugger;
+#[cfg(feature = "std")]
+pub mod dwarf;
+#[cfg(feature = "gdb")]
+pub mod gdb;
@@ -0,0 +1,435 @@
+#[cfg(not(feature = "std"))]
+use crate::lib::*;
+
+use super::{
+    memory::effective_address,
+    runtime::Instance,
+    stack::Stack,
+    store::{MemData, MemInst, Store},
+    trap::Trap,
+    value::LittleEndian,
+};
+use crate::binary::{Instr, MemArg, ValType, ValType::*};
+use core::mem::size_of;
+#[cfg(feature = "std")]
+use std::sync::atomic::{AtomicU16, AtomicU32, AtomicU64, AtomicU8, Ordering};
+
+pub fn is_atomic(instr: &Instr) -> bool {
+    matches!(
+        instr,
+        Instr::MemoryAtomicNotify(_)
+            | Instr::MemoryAtomicWait32(_)
+            | Instr::MemoryAtomicWait64(_)
+            | Instr::AtomicFence
+            | Instr::I32AtomicLoad(_)
+            | Instr::I64AtomicLoad(_)
+            | Instr::I32AtomicLoad8U(_)
+            | Instr::I32AtomicLoad16U(_)
+            | Instr::I64AtomicLoad8U(_)
+            | Instr::I64AtomicLoad16U(_)
+            | Instr::I64AtomicLoad32U(_)
+            | Instr::I32AtomicStore(_)
+            | Instr::I64AtomicStore(_)
+            | Instr::I32AtomicStore8(_)
+            | Instr::I32AtomicStore16(_)
+            | Instr::I64AtomicStore8(_)
+            | Instr::I64AtomicStore16(_)
+            | Instr::I64AtomicStore32(_)
+            | Instr::I32AtomicRmwAdd(_)
+            | Instr::I64AtomicRmwAdd(_)
+            | Instr::I32AtomicRmw8AddU(_)
+            | Instr::I32AtomicRmw16AddU(_)
+            | Instr::I64AtomicRmw8AddU(_)
+            | Instr::I64AtomicRmw16AddU(_)
+            | Instr::I64AtomicRmw32AddU(_)
+            | Instr::I32AtomicRmwSub(_)
+            | Instr::I64AtomicRmwSub(_)
+            | Instr::I32AtomicRmw8SubU(_)
+            | Instr::I32AtomicRmw16SubU(_)
+            | Instr::I64AtomicRmw8SubU(_)
+            | Instr::I64AtomicRmw16SubU(_)
+            | Instr::I64AtomicRmw32SubU(_)
+            | Instr::I32AtomicRmwAnd(_)
+            | Instr::I64AtomicRmwAnd(_)
+            | Instr::I32AtomicRmw8AndU(_)
+            | Instr::I32AtomicRmw16AndU(_)
+            | Instr::I64AtomicRmw8AndU(_)
+            | Instr::I64AtomicRmw16AndU(_)
+            | Instr::I64AtomicRmw32AndU(_)
+            | Instr::I32AtomicRmwOr(_)
+            | Instr::I64AtomicRmwOr(_)
+            | Instr::I32AtomicRmw8OrU(_)
+            | Instr::I32AtomicRmw16OrU(_)
+            | Instr::I64AtomicRmw8OrU(_)
+            | Instr::I64AtomicRmw16OrU(_)
+            | Instr::I64AtomicRmw32OrU(_)
+            | Instr::I32AtomicRmwXor(_)
+            | Instr::I64AtomicRmwXor(_)
+            | Instr::I32AtomicRmw8XorU(_)
+            | Instr::I32AtomicRmw16XorU(_)
+            | Instr::I64AtomicRmw8XorU(_)
+            | Instr::I64AtomicRmw16XorU(_)
+            | Instr::I64AtomicRmw32XorU(_)
+            | Instr::I32AtomicRmwXchg(_)
+            | Instr::I64AtomicRmwXchg(_)
+            | Instr::I32AtomicRmw8XchgU(_)
+            | Instr::I32AtomicRmw16XchgU(_)
+            | Instr::I64AtomicRmw8XchgU(_)
+            | Instr::I64AtomicRmw16XchgU(_)
+            | Instr::I64AtomicRmw32XchgU(_)
+            | Instr::I32AtomicRmwCmpxchg(_)
+            | Instr::I64AtomicRmwCmpxchg(_)
+            | Instr::I32AtomicRmw8CmpxchgU(_)
+            | Instr::I32AtomicRmw16CmpxchgU(_)
+            | Instr::I64AtomicRmw8CmpxchgU(_)
+            | Instr::I64AtomicRmw16CmpxchgU(_)
+            | Instr::I64AtomicRmw32CmpxchgU(_)
+    )
+}
+
+/// Executes an atomic memory instruction. All atomic accesses are
+/// sequentially consistent, which makes `atomic.fence` a no-op.
+pub fn step(
+    instr: &Instr,
+    instance: &Instance,
+    store: &mut Store,
+    stack: &mut Stack,
+) -> Result<(), Trap> {
+    match instr {
+        Instr::MemoryAtomicNotify(m) => notify(m, instance, store, stack)?,
+        Instr::MemoryAtomicWait32(m) => wait::<u32>(m, I32, instance, store, stack)?,
+        Instr::MemoryAtomicWait64(m) => wait::<u64>(m, I64, instance, store, stack)?,
+        Instr::AtomicFence => {}
+        Instr::I32AtomicLoad(m) => load::<u32>(m, I32, instance, store, stack)?,
+        Instr::I64AtomicLoad(m) => load::<u64>(m, I64, instance, store, stack)?,
+        Instr::I32AtomicLoad8U(m) => load::<u8>(m, I32, instance, store, stack)?,
+        Instr::I32AtomicLoad16U(m) => load::<u16>(m, I32, instance, store, stack)?,
+        Instr::I64AtomicLoad8U(m) => load::<u8>(m, I64, instance, store, stack)?,
+        Instr::I64AtomicLoad16U(m) => load::<u16>(m, I64, instance, store, stack)?,
+        Instr::I64AtomicLoad32U(m) => load::<u32>(m, I64, instance, store, stack)?,
+        Instr::I32AtomicStore(m) => write::<u32>(m, I32, instance, store, stack)?,
+        Instr::I64AtomicStore(m) => write::<u64>(m, I64, instance, store, stack)?,
+        Instr::I32AtomicStore8(m) => write::<u8>(m, I32, instance, store, stack)?,
+        Instr::I32AtomicStore16(m) => write::<u16>(m, I32, instance, store, stack)?,
+        Instr::I64AtomicStore8(m) => write::<u8>(m, I64, instance, store, stack)?,
+        Instr::I64AtomicStore16(m) => write::<u16>(m, I64, instance, store, stack)?,
+        Instr::I64AtomicStore32(m) => write::<u32>(m, I64, instance, store, stack)?,
+        Instr::I32AtomicRmwAdd(m) => rmw::<u32>(m, I32, instance, store, stack, u64::wrapping_add)?,
+        Instr::I64AtomicRmwAdd(m) => rmw::<u64>(m, I64, instance, store, stack, u64::wrapping_add)?,
+        Instr::I32AtomicRmw8AddU(m) => {
+            rmw::<u8>(m, I32, instance, store, stack, u64::wrapping_add)?
+        }
+        Instr::I32AtomicRmw16AddU(m) => {
+            rmw::<u16>(m, I32, instance, store, stack, u64::wrapping_add)?
+        }
+        Instr::I64AtomicRmw8AddU(m) => {
+            rmw::<u8>(m, I64, instance, store, stack, u64::wrapping_add)?
+        }
+        Instr::I64AtomicRmw16AddU(m) => {
+            rmw::<u16>(m, I64, instance, store, stack, u64::wrapping_add)?
+        }
+        Instr::I64AtomicRmw32AddU(m) => {
+            rmw::<u32>(m, I64, instance, store, stack, u64::wrapping_add)?
+        }
+        Instr::I32AtomicRmwSub(m) => rmw::<u32>(m, I32, instance, store, stack, u64::wrapping_sub)?,
+        Instr::I64AtomicRmwSub(m) => rmw::<u64>(m, I64, instance, store, stack, u64::wrapping_sub)?,
+        Instr::I32AtomicRmw8SubU(m) => {
+            rmw::<u8>(m, I32, instance, store, stack, u64::wrapping_sub)?
+        }
+        Instr::I32AtomicRmw16SubU(m) => {
+            rmw::<u16>(m, I32, instance, store, stack, u64::wrapping_sub)?
+        }
+        Instr::I64AtomicRmw8SubU(m) => {
+            rmw::<u8>(m, I64, instance, store, stack, u64::wrapping_sub)?
+        }
+        Instr::I64AtomicRmw16SubU(m) => {
+            rmw::<u16>(m, I64, instance, store, stack, u64::wrapping_sub)?
+        }
+        Instr::I64AtomicRmw32SubU(m) => {
+            rmw::<u32>(m, I64, instance, store, stack, u64::wrapping_sub)?
+        }
+        Instr::I32AtomicRmwAnd(m) => rmw::<u32>(m, I32, instance, store, stack, |a, b| a & b)?,
+        Instr::I64AtomicRmwAnd(m) => rmw::<u64>(m, I64, instance, store, stack, |a, b| a & b)?,
+        Instr::I32AtomicRmw8AndU(m) => rmw::<u8>(m, I32, instance, store, stack, |a, b| a & b)?,
+        Instr::I32AtomicRmw16AndU(m) => rmw::<u16>(m, I32, instance, store, stack, |a, b| a & b)?,
+        Instr::I64AtomicRmw8AndU(m) => rmw::<u8>(m, I64, instance, store, stack, |a, b| a & b)?,
+        Instr::I64AtomicRmw16AndU(m) => rmw::<u16>(m, I64, instance, store, stack, |a, b| a & b)?,
+        Instr::I64AtomicRmw32AndU(m) => rmw::<u32>(m, I64, instance, store, stack, |a, b| a & b)?,
+        Instr::I32AtomicRmwOr(m) => rmw::<u32>(m, I32, instance, store, stack, |a, b| a | b)?,
+        Instr::I64AtomicRmwOr(m) => rmw::<u64>(m, I64, instance, store, stack, |a, b| a | b)?,
+        Instr::I32AtomicRmw8OrU(m) => rmw::<u8>(m, I32, instance, store, stack, |a, b| a | b)?,
+        Instr::I32AtomicRmw16OrU(m) => rmw::<u16>(m, I32, instance, store, stack, |a, b| a | b)?,
+        Instr::I64AtomicRmw8OrU(m) => rmw::<u8>(m, I64, instance, store, stack, |a, b| a | b)?,
+        Instr::I64AtomicRmw16OrU(m) => rmw::<u16>(m, I64, instance, store, stack, |a, b| a | b)?,
+        Instr::I64AtomicRmw32OrU(m) => rmw::<u32>(m, I64, instance, store, stack, |a, b| a | b)?,
+        Instr::I32AtomicRmwXor(m) => rmw::<u32>(m, I32, instance, store, stack, |a, b| a ^ b)?,
+        Instr::I64AtomicRmwXor(m) => rmw::<u64>(m, I64, instance, store, stack, |a, b| a ^ b)?,
+        Instr::I32AtomicRmw8XorU(m) => rmw::<u8>(m, I32, instance, store, stack, |a, b| a ^ b)?,
+        Instr::I32AtomicRmw16XorU(m) => rmw::<u16>(m, I32, instance, store, stack, |a, b| a ^ b)?,
+        Instr::I64AtomicRmw8XorU(m) => rmw::<u8>(m, I64, instance, store, stack, |a, b| a ^ b)?,
+        Instr::I64AtomicRmw16XorU(m) => rmw::<u16>(m, I64, instance, store, stack, |a, b| a ^ b)?,
+        Instr::I64AtomicRmw32XorU(m) => rmw::<u32>(m, I64, instance, store, stack, |a, b| a ^ b)?,
+        Instr::I32AtomicRmwXchg(m) => rmw::<u32>(m, I32, instance, store, stack, |_, b| b)?,
+        Instr::I64AtomicRmwXchg(m) => rmw::<u64>(m, I64, instance, store, stack, |_, b| b)?,
+        Instr::I32AtomicRmw8XchgU(m) => rmw::<u8>(m, I32, instance, store, stack, |_, b| b)?,
+        Instr::I32AtomicRmw16XchgU(m) => rmw::<u16>(m, I32, instance, store, stack, |_, b| b)?,
+        Instr::I64AtomicRmw8XchgU(m) => rmw::<u8>(m, I64, instance, store, stack, |_, b| b)?,
+        Instr::I64AtomicRmw16XchgU(m) => rmw::<u16>(m, I64, instance, store, stack, |_, b| b)?,
+        Instr::I64AtomicRmw32XchgU(m) => rmw::<u32>(m, I64, instance, store, stack, |_, b| b)?,
+        Instr::I32AtomicRmwCmpxchg(m) => cmpxchg::<u32>(m, I32, instance, store, stack)?,
+        Instr::I64AtomicRmwCmpxchg(m) => cmpxchg::<u64>(m, I64, instance, store, stack)?,
+        Instr::I32AtomicRmw8CmpxchgU(m) => cmpxchg::<u8>(m, I32, instance, store, stack)?,
+        Instr::I32AtomicRmw16CmpxchgU(m) => cmpxchg::<u16>(m, I32, instance, store, stack)?,
+        Instr::I64AtomicRmw8CmpxchgU(m) => cmpxchg::<u8>(m, I64, instance, store, stack)?,
+        Instr::I64AtomicRmw16CmpxchgU(m) => cmpxchg::<u16>(m, I64, instance, store, stack)?,
+        Instr::I64AtomicRmw32CmpxchgU(m) => cmpxchg::<u32>(m, I64, instance, store, stack)?,
+        _ => unreachable!("{:?}", instr),
+    }
+    Ok(())
+}
+
+/// Integer accessed by an atomic instruction, zero-extended to `u64`.
+pub trait AtomicInt: LittleEndian + Copy {
+    fn to_u64(self) -> u64;
+    fn from_u64(v: u64) -> Self;
+    /// # Safety
+    /// `ptr` must be valid and aligned for `Self`.
+    #[cfg(feature = "std")]
+    unsafe fn atomic_load(ptr: *mut u8) -> Self;
+    /// # Safety
+    /// See `atomic_load`.
+    #[cfg(feature = "std")]
+    unsafe fn atomic_store(ptr: *mut u8, v: Self);
+    /// # Safety
+    /// See `atomic_load`.
+    #[cfg(feature = "std")]
+    unsafe fn compare_exchange(ptr: *mut u8, current: Self, new: Self) -> Result<Self, Self>;
+}
+
+macro_rules! impl_atomic_int {
+    ($t:ty, $atomic:ty) => {
+        impl AtomicInt for $t {
+            fn to_u64(self) -> u64 {
+                self as u64
+            }
+            fn from_u64(v: u64) -> Self {
+                v as $t
+            }
+            #[cfg(feature = "std")]
+            unsafe fn atomic_load(ptr: *mut u8) -> Self {
+                <$t>::from_le((*(ptr as *const $atomic)).load(Ordering::SeqCst))
+            }
+            #[cfg(feature = "std")]
+            unsafe fn atomic_store(ptr: *mut u8, v: Self) {
+                (*(ptr as *const $atomic)).store(v.to_le(), Ordering::SeqCst)
+            }
+            #[cfg(feature = "std")]
+            unsafe fn compare_exchange(
+                ptr: *mut u8,
+                current: Self,
+                new: Self,
+            ) -> Result<Self, Self> {
+                (*(ptr as *const $atomic))
+                    .compare_exchange(
+                        current.to_le(),
+                        new.to_le(),
+                        Ordering::SeqCst,
+                        Ordering::SeqCst,
+                    )
+                    .map(<$t>::from_le)
+                    .map_err(<$t>::from_le)
+            }
+        }
+    };
+}
+
+impl_atomic_int!(u8, AtomicU8);
+impl_atomic_int!(u16, AtomicU16);
+impl_atomic_int!(u32, AtomicU32);
+impl_atomic_int!(u64, AtomicU64);
+
+fn pop(stack: &mut Stack, t: ValType) -> u64 {
+    match t {
+        I32 => stack.pop_value::<i32>() as u32 as u64,
+        _ => stack.pop_value::<i64>() as u64,
+    }
+}
+
+fn push(stack: &mut Stack, t: ValType, v: u64) {
+    match t {
+        I32 => stack.push_value(v as i32),
+        _ => stack.push_value(v as i64),
+    }
+}
+
+fn memory<'a>(memarg: &MemArg, instance: &Instance, store: &'a mut Store) -> &'a mut MemInst {
+    &mut store.mems[instance.memaddrs[memarg.memidx as usize]]
+}
+
+/// Pops the address operand and returns the effective address of an atomic
+/// access to a `T`, which must be aligned.
+fn address<T>(memarg: &MemArg, mem: &MemInst, stack: &mut Stack) -> Result<usize, Trap> {
+    let ea = effective_address(mem, memarg, stack, size_of::<T>())?;
+    if ea % size_of::<T>() != 0 {
+        return Err(Trap::UnalignedAtomic);
+    }
+    Ok(ea)
+}
+
+fn read<T: AtomicInt>(mem: &MemInst, ea: usize) -> u64 {
+    match &mem.data {
+        MemData::Owned(data) => T::read(data, ea).to_u64(),
+        #[cfg(feature = "std")]
+        MemData::Shared(shared) => unsafe { T::atomic_load(shared.as_ptr().add(ea)) }.to_u64(),
+    }
+}
+
+/// Replaces the `T` at `ea` by `f` of its value unless `f` returns `None`,
+/// and returns the previous value.
+fn modify<T: AtomicInt>(mem: &mut MemInst, ea: usize, f: impl Fn(u64) -> Option<u64>) -> u64 {
+    match &mut mem.data {
+        MemData::Owned(data) => {
+            let old = T::read(data, ea).to_u64();
+            if let Some(new) = f(old) {
+                T::write(data, ea, T::from_u64(new));
+            }
+            old
+        }
+        #[cfg(feature = "std")]
+        MemData::Shared(shared) => unsafe {
+            let ptr = shared.as_ptr().add(ea);
+            let mut old = T::atomic_load(ptr);
+            loop {
+                let new = match f(old.to_u64()) {
+                    Some(new) => T::from_u64(new),
+                    None => return old.to_u64(),
+                };
+                match T::compare_exchange(ptr, old, new) {
+                    Ok(_) => return old.to_u64(),
+                    Err(current) => old = current,
+                }
+            }
+        },
+    }
+}
+
+fn load<T: AtomicInt>(
+    memarg: &MemArg,
+    t: ValType,
+    instance: &Instance,
+    store: &mut Store,
+    stack: &mut Stack,
+) -> Result<(), Trap> {
+    let mem = memory(memarg, instance, store);
+    let ea = address::<T>(memarg, mem, stack)?;
+    push(stack, t, read::<T>(mem, ea));
+    Ok(())
+}
+
+fn write<T: AtomicInt>(
+    memarg: &MemArg,
+    t: ValType,
+    instance: &Instance,
+    store: &mut Store,
+    stack: &mut Stack,
+) -> Result<(), Trap> {
+    let mem = memory(memarg, instance, store);
+    let v = T::from_u64(pop(stack, t));
+    let ea = address::<T>(memarg, mem, stack)?;
+    match &mut mem.data {
+        MemData::Owned(data) => T::write(data, ea, v),
+        #[cfg(feature = "std")]
+        MemData::Shared(shared) => unsafe { T::atomic_store(shared.as_ptr().add(ea), v) },
+    }
+    Ok(())
+}
+
+fn rmw<T: AtomicInt>(
+    memarg: &MemArg,
+    t: ValType,
+    instance: &Instance,
+    store: &mut Store,
+    stack: &mut Stack,
+    f: impl Fn(u64, u64) -> u64,
+) -> Result<(), Trap> {
+    let mem = memory(memarg, instance, store);
+    let v = pop(stack, t);
+    let ea = address::<T>(memarg, mem, stack)?;
+    let old = modify::<T>(mem, ea, |old| Some(f(old, v)));
+    push(stack, t, old);
+    Ok(())
+}
+
+fn cmpxchg<T: AtomicInt>(
+    memarg: &MemArg,
+    t: ValType,
+    instance: &Instance,
+    store: &mut Store,
+    stack: &mut Stack,
+) -> Result<(), Trap> {
+    let mem = memory(memarg, instance, store);
+    let replacement = pop(stack, t);
+    // The expected value is wrapped to the accessed width.
+    let expected = T::from_u64(pop(stack, t)).to_u64();
+    let ea = address::<T>(memarg, mem, stack)?;
+    let old = modify::<T>(mem, ea, |old| {
+        if old == expected {
+            Some(replacement)
+        } else {
+            None
+        }
+    });
+    push(stack, t, old);
+    Ok(())
+}
+
+fn notify(
+    memarg: &MemArg,
+    instance: &Instance,
+    store: &mut Store,
+    stack: &mut Stack,
+) -> Result<(), Trap> {
+    let mem = memory(memarg, instance, store);
+    let count = stack.pop_value::<i32>() as u32;
+    let ea = address::<u32>(memarg, mem, stack)?;
+    let woken = match &mem.data {
+        #[cfg(feature = "std")]
+        MemData::Shared(shared) => shared.notify(ea, count),
+        // Nobody can wait on an unshared memory.
+        _ => {
+            let _ = (ea, count);
+            0
+        }
+    };
+    stack.push_value(woken as i32);
+    Ok(())
+}
+
+/// `memory.atomic.wait` blocks the calling thread, which requires `std`.
+/// Without it every memory is unshared and waiting traps.
+fn wait<T: AtomicInt>(
+    memarg: &MemArg,
+    t: ValType,
+    instance: &Instance,
+    store: &mut Store,
+    stack: &mut Stack,
+) -> Result<(), Trap> {
+    let mem = memory(memarg, instance, store);
+    let timeout = stack.pop_value::<i64>();
+    let expected = pop(stack, t);
+    let ea = address::<T>(memarg, mem, stack)?;
+    #[cfg(feature = "std")]
+    if let MemData::Shared(shared) = &mem.data {
+        let ptr = unsafe { shared.as_ptr().add(ea) };
+        let check = || unsafe { T::atomic_load(ptr) }.to_u64() == expected;
+        // A negative timeout waits forever.
+        let timeout = u64::try_from(timeout)
+            .ok()
+            .map(std::time::Duration::from_nanos);
+        let result = shared.wait(ea, check, timeout);
+        stack.push_value(result as i32);
+        return Ok(());
+    }
+    let _ = (ea, expected, timeout);
+    Err(Trap::ExpectedSharedMemory)
+}
@@ -1,16 +1,73 @@
 #[cfg(not(feature = "std"))]
 use crate::lib::*;
 
+use super::runtime::Instance;
 use super::store::MemInst;
 use super::value::Value;
+use crate::binary::ExportDesc;
+use opt_vec::OptVec;
 
 pub trait Env {
+    /// `memory` is the first memory of the instance, if any.
     fn call(
         &mut self,
         name: &str,
         params: Vec<Value>,
         memory: Option<&mut MemInst>,
     ) -> Result<Vec<Value>, &'static str>;
+
+    /// Like `call`, with access to every memory of the instance.
+    fn call_with_memories(
+        &mut self,
+        name: &str,
+        params: Vec<Value>,
+        memories: Memories,
+    ) -> Result<Vec<Value>, &'static str> {
+        self.call(name, params, memories.into_first())
+    }
+}
+
+/// Memories of the instance calling an `Env`.
+pub struct Memories<'a> {
+    mems: &'a mut OptVec<MemInst>,
+    instance: &'a Instance,
+}
+
+impl<'a> Memories<'a> {
+    pub fn new(mems: &'a mut OptVec<MemInst>, instance: &'a Instance) -> Self {
+        Self { mems, instance }
+    }
+
+    pub fn len(&self) -> usize {
+        self.instance.memaddrs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instance.memaddrs.is_empty()
+    }
+
+    pub fn get(&mut self, memidx: u32) -> Option<&mut MemInst> {
+        let addr = *self.instance.memaddrs.get(memidx as usize)?;
+        Some(&mut self.mems[addr])
+    }
+
+    /// Returns the memory exported under `name`.
+    pub fn export(&mut self, name: &str) -> Option<&mut MemInst> {
+        let memidx = self
+            .instance
+            .exports
+            .iter()
+            .find_map(|export| match export.desc {
+                ExportDesc::Mem(idx) if export.name == name => Some(idx),
+                _ => None,
+            })?;
+        self.get(memidx)
+    }
+
+    pub fn into_first(self) -> Option<&'a mut MemInst> {
+        let addr = *self.instance.memaddrs.first()?;
+        Some(&mut self.mems[addr])
+    }
 }
 
 #[derive(Debug)]
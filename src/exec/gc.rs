@@ -0,0 +1,533 @@
+use super::runtime::{Addr, Instance, RuntimeError};
+use super::stack::Stack;
+use super::store::{ArrayInst, Store, StructInst};
+use super::trap::Trap;
+use super::value::{Ref, Value};
+use crate::binary::{CompType, FieldType, HeapType, Instr, RefType, StorageType, TypeIdx, ValType};
+#[cfg(not(feature = "std"))]
+use crate::lib::*;
+
+/// Whether `instr` is executed by `step`.
+pub fn is_gc(instr: &Instr) -> bool {
+    matches!(
+        instr,
+        Instr::StructNew(_)
+            | Instr::StructNewDefault(_)
+            | Instr::StructGet(..)
+            | Instr::StructGetS(..)
+            | Instr::StructGetU(..)
+            | Instr::StructSet(..)
+            | Instr::ArrayNew(_)
+            | Instr::ArrayNewDefault(_)
+            | Instr::ArrayNewFixed(..)
+            | Instr::ArrayNewData(..)
+            | Instr::ArrayNewElem(..)
+            | Instr::ArrayGet(_)
+            | Instr::ArrayGetS(_)
+            | Instr::ArrayGetU(_)
+            | Instr::ArraySet(_)
+            | Instr::ArrayLen
+            | Instr::ArrayFill(_)
+            | Instr::ArrayCopy(..)
+            | Instr::ArrayInitData(..)
+            | Instr::ArrayInitElem(..)
+            | Instr::RefEq
+            | Instr::RefTest(_)
+            | Instr::RefCast(_)
+            | Instr::AnyConvertExtern
+            | Instr::ExternConvertAny
+            | Instr::RefI31
+            | Instr::I31GetS
+            | Instr::I31GetU
+    )
+}
+
+pub fn step(
+    instr: &Instr,
+    instances: &[Instance],
+    instance_addr: Addr,
+    store: &mut Store,
+    stack: &mut Stack,
+) -> Result<(), Trap> {
+    let instance = &instances[instance_addr];
+    match instr {
+        Instr::StructNew(x) => {
+            let fields = struct_type(instance, *x);
+            collect(store, stack);
+            let mut values = vec![];
+            for field in fields.iter().rev() {
+                values.push(pack(&field.storage, stack.pop_value()));
+            }
+            values.reverse();
+            new_struct(store, stack, instance_addr, *x, values);
+        }
+        Instr::StructNewDefault(x) => {
+            let fields = struct_type(instance, *x);
+            let values = fields.iter().map(|f| default(&f.storage)).collect();
+            collect(store, stack);
+            new_struct(store, stack, instance_addr, *x, values);
+        }
+        Instr::StructGet(x, y) | Instr::StructGetS(x, y) | Instr::StructGetU(x, y) => {
+            let storage = &struct_type(instance, *x)[*y as usize].storage;
+            let a = match stack.pop_value::<Ref>() {
+                Ref::Struct(a) => a,
+                _ => return Err(Trap::NullStructReference),
+            };
+            let value = store.structs[a].fields[*y as usize];
+            let signed = matches!(instr, Instr::StructGetS(..));
+            stack.push_value(unpack(storage, value, signed));
+        }
+        Instr::StructSet(x, y) => {
+            let storage = &struct_type(instance, *x)[*y as usize].storage;
+            let value = pack(storage, stack.pop_value());
+            match stack.pop_value::<Ref>() {
+                Ref::Struct(a) => store.structs[a].fields[*y as usize] = value,
+                _ => return Err(Trap::NullStructReference),
+            }
+        }
+        Instr::ArrayNew(x) => {
+            let storage = &array_type(instance, *x).storage;
+            collect(store, stack);
+            let n = array_len(store, stack.pop_value::<i32>() as u32 as usize)?;
+            let value = pack(storage, stack.pop_value());
+            new_array(store, stack, instance_addr, *x, vec![value; n]);
+        }
+        Instr::ArrayNewDefault(x) => {
+            let storage = &array_type(instance, *x).storage;
+            collect(store, stack);
+            let n = array_len(store, stack.pop_value::<i32>() as u32 as usize)?;
+            new_array(store, stack, instance_addr, *x, vec![default(storage); n]);
+        }
+        Instr::ArrayNewFixed(x, n) => {
+            let storage = &array_type(instance, *x).storage;
+            collect(store, stack);
+            let mut values = vec![];
+            for _ in 0..*n {
+                values.push(pack(storage, stack.pop_value()));
+            }
+            values.reverse();
+            new_array(store, stack, instance_addr, *x, values);
+        }
+        Instr::ArrayNewData(x, d) => {
+            let storage = &array_type(instance, *x).storage;
+            collect(store, stack);
+            let n = stack.pop_value::<i32>() as u32 as usize;
+            let s = stack.pop_value::<i32>() as u32 as usize;
+            let values = read_data(data(instance, store, *d), storage, s, n)?;
+            array_len(store, n)?;
+            let values = values.collect();
+            new_array(store, stack, instance_addr, *x, values);
+        }
+        Instr::ArrayNewElem(x, e) => {
+            collect(store, stack);
+            let n = stack.pop_value::<i32>() as u32 as usize;
+            let s = stack.pop_value::<i32>() as u32 as usize;
+            let elem = elem(instance, store, *e);
+            if s.checked_add(n).map_or(true, |end| end > elem.len()) {
+                return Err(Trap::TableOutOfRange);
+            }
+            array_len(store, n)?;
+            let values = elem[s..s + n].iter().map(|r| Value::Ref(*r)).collect();
+            new_array(store, stack, instance_addr, *x, values);
+        }
+        Instr::ArrayGet(x) | Instr::ArrayGetS(x) | Instr::ArrayGetU(x) => {
+            let storage = &array_type(instance, *x).storage;
+            let i = stack.pop_value::<i32>() as u32 as usize;
+            let array = array(store, stack.pop_value())?;
+            let value = *array.elems.get(i).ok_or(Trap::ArrayOutOfBounds)?;
+            let signed = matches!(instr, Instr::ArrayGetS(_));
+            stack.push_value(unpack(storage, value, signed));
+        }
+        Instr::ArraySet(x) => {
+            let storage = &array_type(instance, *x).storage;
+            let value = pack(storage, stack.pop_value());
+            let i = stack.pop_value::<i32>() as u32 as usize;
+            let array = array_mut(store, stack.pop_value())?;
+            *array.elems.get_mut(i).ok_or(Trap::ArrayOutOfBounds)? = value;
+        }
+        Instr::ArrayLen => {
+            let len = array(store, stack.pop_value())?.elems.len();
+            stack.push_value(len as i32);
+        }
+        Instr::ArrayFill(x) => {
+            let storage = &array_type(instance, *x).storage;
+            let n = stack.pop_value::<i32>() as u32 as usize;
+            let value = pack(storage, stack.pop_value());
+            let d = stack.pop_value::<i32>() as u32 as usize;
+            let array = array_mut(store, stack.pop_value())?;
+            let d = range(d, n, array.elems.len())?;
+            array.elems[d..d + n].fill(value);
+        }
+        Instr::ArrayCopy(..) => {
+            let n = stack.pop_value::<i32>() as u32 as usize;
+            let s = stack.pop_value::<i32>() as u32 as usize;
+            let src = stack.pop_value();
+            let d = stack.pop_value::<i32>() as u32 as usize;
+            let dst = stack.pop_value();
+            let d = range(d, n, array(store, dst)?.elems.len())?;
+            let src = array(store, src)?;
+            let s = range(s, n, src.elems.len())?;
+            let values = src.elems[s..s + n].to_vec();
+            array_mut(store, dst)?.elems[d..d + n].copy_from_slice(&values);
+        }
+        Instr::ArrayInitData(x, y) => {
+            let storage = &array_type(instance, *x).storage;
+            let n = stack.pop_value::<i32>() as u32 as usize;
+            let s = stack.pop_value::<i32>() as u32 as usize;
+            let d = stack.pop_value::<i32>() as u32 as usize;
+            let r = stack.pop_value();
+            let len = array(store, r)?.elems.len();
+            let d = range(d, n, len)?;
+            let values: Vec<Value> = read_data(data(instance, store, *y), storage, s, n)?.collect();
+            array_mut(store, r)?.elems[d..d + n].copy_from_slice(&values);
+        }
+        Instr::ArrayInitElem(_, y) => {
+            let n = stack.pop_value::<i32>() as u32 as usize;
+            let s = stack.pop_value::<i32>() as u32 as usize;
+            let d = stack.pop_value::<i32>() as u32 as usize;
+            let r = stack.pop_value();
+            let len = array(store, r)?.elems.len();
+            let d = range(d, n, len)?;
+            let elem = elem(instance, store, *y);
+            if s.checked_add(n).map_or(true, |end| end > elem.len()) {
+                return Err(Trap::TableOutOfRange);
+            }
+            let values: Vec<Value> = elem[s..s + n].iter().map(|r| Value::Ref(*r)).collect();
+            array_mut(store, r)?.elems[d..d + n].copy_from_slice(&values);
+        }
+        Instr::RefEq => {
+            let b = stack.pop_value::<Ref>();
+            let a = stack.pop_value::<Ref>();
+            stack.push_value((a == b) as i32);
+        }
+        Instr::RefTest(rt) => {
+            let r = stack.pop_value();
+            let result = ref_matches(instances, store, instance_addr, r, *rt);
+            stack.push_value(result as i32);
+        }
+        Instr::RefCast(rt) => {
+            let r = stack.pop_value();
+            if !ref_matches(instances, store, instance_addr, r, *rt) {
+                return Err(Trap::CastFailure);
+            }
+            stack.push_value(r);
+        }
+        // References keep their representation when converted between the
+        // `any` and `extern` hierarchies, see `ref_matches`.
+        Instr::AnyConvertExtern | Instr::ExternConvertAny => {}
+        Instr::RefI31 => {
+            let value = stack.pop_value::<i32>() as u32;
+            stack.push_value(Ref::I31(value & 0x7FFF_FFFF));
+        }
+        Instr::I31GetS | Instr::I31GetU => {
+            let value = match stack.pop_value::<Ref>() {
+                Ref::I31(value) => value,
+                _ => return Err(Trap::NullI31Reference),
+            };
+            if let Instr::I31GetS = instr {
+                stack.push_value(((value << 1) as i32) >> 1);
+            } else {
+                stack.push_value(value as i32);
+            }
+        }
+        _ => unreachable!("{:?}", instr),
+    }
+    Ok(())
+}
+
+/// Evaluates the GC instruction `instr` of a constant expression with its
+/// operands on `stack`, allocating into `store` as `step` does.
+pub fn eval_const(
+    instr: &Instr,
+    instance: &Instance,
+    instance_addr: Addr,
+    store: &mut Store,
+    stack: &mut Vec<Value>,
+) -> Result<Value, RuntimeError> {
+    fn pop(stack: &mut Vec<Value>) -> Result<Value, RuntimeError> {
+        stack.pop().ok_or(RuntimeError::ConstantExpression)
+    }
+    fn pop_i32(stack: &mut Vec<Value>) -> Result<i32, RuntimeError> {
+        match pop(stack)? {
+            Value::I32(v) => Ok(v),
+            _ => Err(RuntimeError::ConstantExpression),
+        }
+    }
+
+    let r = match instr {
+        Instr::StructNew(x) => {
+            let fields = struct_type(instance, *x);
+            let mut values = vec![];
+            for field in fields.iter().rev() {
+                values.push(pack(&field.storage, pop(stack)?));
+            }
+            values.reverse();
+            Ref::Struct(store.allocate_struct(StructInst {
+                instance_addr,
+                typeidx: *x,
+                fields: values,
+            }))
+        }
+        Instr::StructNewDefault(x) => {
+            let fields = struct_type(instance, *x);
+            Ref::Struct(store.allocate_struct(StructInst {
+                instance_addr,
+                typeidx: *x,
+                fields: fields.iter().map(|f| default(&f.storage)).collect(),
+            }))
+        }
+        Instr::ArrayNew(x) => {
+            let storage = &array_type(instance, *x).storage;
+            let n = array_len(store, pop_i32(stack)? as u32 as usize)?;
+            let value = pack(storage, pop(stack)?);
+            Ref::Array(store.allocate_array(ArrayInst {
+                instance_addr,
+                typeidx: *x,
+                elems: vec![value; n],
+            }))
+        }
+        Instr::ArrayNewDefault(x) => {
+            let storage = &array_type(instance, *x).storage;
+            let n = array_len(store, pop_i32(stack)? as u32 as usize)?;
+            Ref::Array(store.allocate_array(ArrayInst {
+                instance_addr,
+                typeidx: *x,
+                elems: vec![default(storage); n],
+            }))
+        }
+        Instr::ArrayNewFixed(x, n) => {
+            let storage = &array_type(instance, *x).storage;
+            let start = stack
+                .len()
+                .checked_sub(*n as usize)
+                .ok_or(RuntimeError::ConstantExpression)?;
+            let elems = stack.split_off(start).into_iter().map(|v| pack(storage, v));
+            Ref::Array(store.allocate_array(ArrayInst {
+                instance_addr,
+                typeidx: *x,
+                elems: elems.collect(),
+            }))
+        }
+        Instr::RefI31 => Ref::I31(pop_i32(stack)? as u32 & 0x7FFF_FFFF),
+        Instr::AnyConvertExtern | Instr::ExternConvertAny => return pop(stack),
+        _ => return Err(RuntimeError::ConstantExpression),
+    };
+    Ok(Value::Ref(r))
+}
+
+/// Whether the reference `r` has the type `rt` of the instance at
+/// `instance_addr`, as tested by `ref.test` and `ref.cast`.
+pub fn ref_matches(
+    instances: &[Instance],
+    store: &Store,
+    instance_addr: Addr,
+    r: Ref,
+    rt: RefType,
+) -> bool {
+    match (r, rt.heap()) {
+        (Ref::Null, _) => rt.nullable(),
+        // Host references take part in the `any` hierarchy after
+        // `any.convert_extern`, and wasm objects in the `extern` hierarchy
+        // after `extern.convert_any`.
+        (Ref::Func(_) | Ref::Exn(_), HeapType::Any | HeapType::Extern) => false,
+        (_, HeapType::Any | HeapType::Extern) => true,
+        (Ref::Func(_), HeapType::Func) | (Ref::Exn(_), HeapType::Exn) => true,
+        (Ref::Struct(_) | Ref::Array(_) | Ref::I31(_), HeapType::Eq) => true,
+        (Ref::Struct(_), HeapType::Struct) | (Ref::Array(_), HeapType::Array) => true,
+        (Ref::I31(_), HeapType::I31) => true,
+        (Ref::Func(a), HeapType::Type(t)) => {
+            instances[instance_addr].types[t as usize].functype() == Some(store.funcs[a].functype())
+        }
+        (Ref::Struct(a), HeapType::Type(t)) => {
+            let inst = &store.structs[a];
+            defined_matches(
+                instances,
+                (inst.instance_addr, inst.typeidx),
+                (instance_addr, t),
+            )
+        }
+        (Ref::Array(a), HeapType::Type(t)) => {
+            let inst = &store.arrays[a];
+            defined_matches(
+                instances,
+                (inst.instance_addr, inst.typeidx),
+                (instance_addr, t),
+            )
+        }
+        _ => false,
+    }
+}
+
+/// Whether the defined type `a` is `b` or one of its subtypes. Types of
+/// different instances are compared by their definitions.
+fn defined_matches(instances: &[Instance], a: (Addr, TypeIdx), b: (Addr, TypeIdx)) -> bool {
+    let (instance_addr, mut idx) = a;
+    let types = &instances[instance_addr].types;
+    let expected = &instances[b.0].types[b.1 as usize];
+    loop {
+        if (instance_addr, idx) == b || &types[idx as usize] == expected {
+            return true;
+        }
+        match types[idx as usize].supertypes.first() {
+            Some(&supertype) => idx = supertype,
+            None => return false,
+        }
+    }
+}
+
+fn struct_type(instance: &Instance, x: TypeIdx) -> Vec<FieldType> {
+    match &instance.types[x as usize].comp {
+        CompType::Struct(fields) => fields.clone(),
+        _ => unreachable!(),
+    }
+}
+
+fn array_type(instance: &Instance, x: TypeIdx) -> FieldType {
+    match &instance.types[x as usize].comp {
+        CompType::Array(field) => field.clone(),
+        _ => unreachable!(),
+    }
+}
+
+/// Runs the collector before an allocation if it is due. Operands of the
+/// allocating instruction are still on the stack and thus kept alive.
+fn collect(store: &mut Store, stack: &Stack) {
+    if store.should_collect() {
+        store.collect(stack.roots());
+    }
+}
+
+fn new_struct(
+    store: &mut Store,
+    stack: &mut Stack,
+    instance_addr: Addr,
+    x: TypeIdx,
+    fields: Vec<Value>,
+) {
+    let a = store.allocate_struct(StructInst {
+        instance_addr,
+        typeidx: x,
+        fields,
+    });
+    stack.push_value(Ref::Struct(a));
+}
+
+fn new_array(
+    store: &mut Store,
+    stack: &mut Stack,
+    instance_addr: Addr,
+    x: TypeIdx,
+    elems: Vec<Value>,
+) {
+    let a = store.allocate_array(ArrayInst {
+        instance_addr,
+        typeidx: x,
+        elems,
+    });
+    stack.push_value(Ref::Array(a));
+}
+
+/// Length operand of an array allocation, trapping if the array would not
+/// fit in the store's memory limit.
+fn array_len(store: &Store, n: usize) -> Result<usize, Trap> {
+    n.checked_mul(core::mem::size_of::<Value>())
+        .filter(|&bytes| bytes <= store.memory_limit())
+        .map(|_| n)
+        .ok_or(Trap::OutOfMemory)
+}
+
+fn array(store: &Store, r: Ref) -> Result<&ArrayInst, Trap> {
+    match r {
+        Ref::Array(a) => Ok(&store.arrays[a]),
+        _ => Err(Trap::NullArrayReference),
+    }
+}
+
+fn array_mut(store: &mut Store, r: Ref) -> Result<&mut ArrayInst, Trap> {
+    match r {
+        Ref::Array(a) => Ok(&mut store.arrays[a]),
+        _ => Err(Trap::NullArrayReference),
+    }
+}
+
+/// Checks that `n` elements from `i` are within `len`.
+fn range(i: usize, n: usize, len: usize) -> Result<usize, Trap> {
+    match i.checked_add(n) {
+        Some(end) if end <= len => Ok(i),
+        _ => Err(Trap::ArrayOutOfBounds),
+    }
+}
+
+/// Bytes of data segment `x`, which are empty once dropped.
+fn data<'a>(instance: &Instance, store: &'a Store, x: u32) -> &'a [u8] {
+    let a = instance.dataaddrs[x as usize];
+    match (&store.datas).into_iter().nth(a) {
+        Some(Some(data)) => &data.data,
+        _ => &[],
+    }
+}
+
+/// References of element segment `x`, which are empty once dropped.
+fn elem<'a>(instance: &Instance, store: &'a Store, x: u32) -> &'a [Ref] {
+    let a = instance.elemaddrs[x as usize];
+    match (&store.elems).into_iter().nth(a) {
+        Some(Some(elem)) => &elem.elem,
+        _ => &[],
+    }
+}
+
+/// Reads `n` values of the numeric or vector type `storage` from `s`.
+fn read_data<'a>(
+    data: &'a [u8],
+    storage: &'a StorageType,
+    s: usize,
+    n: usize,
+) -> Result<impl Iterator<Item = Value> + 'a, Trap> {
+    let size = storage.size();
+    let end = n
+        .checked_mul(size)
+        .and_then(|len| len.checked_add(s))
+        .ok_or(Trap::MemoryOutOfBounds)?;
+    if end > data.len() {
+        return Err(Trap::MemoryOutOfBounds);
+    }
+    Ok(data[s..end].chunks(size).map(move |bytes| {
+        let mut buf = [0; 16];
+        buf[..size].copy_from_slice(bytes);
+        let bits = u128::from_le_bytes(buf);
+        match storage.unpacked() {
+            ValType::I32 => Value::I32(bits as u32 as i32),
+            ValType::I64 => Value::I64(bits as u64 as i64),
+            ValType::F32 => Value::F32(f32::from_bits(bits as u32)),
+            ValType::F64 => Value::F64(f64::from_bits(bits as u64)),
+            _ => Value::V128(bits),
+        }
+    }))
+}
+
+fn default(storage: &StorageType) -> Value {
+    match storage.unpacked() {
+        ValType::I32 => Value::I32(0),
+        ValType::I64 => Value::I64(0),
+        ValType::F32 => Value::F32(0.0),
+        ValType::F64 => Value::F64(0.0),
+        ValType::V128 => Value::V128(0),
+        _ => Value::Ref(Ref::Null),
+    }
+}
+
+fn pack(storage: &StorageType, value: Value) -> Value {
+    match (storage, value) {
+        (StorageType::I8, Value::I32(v)) => Value::I32(v & 0xFF),
+        (StorageType::I16, Value::I32(v)) => Value::I32(v & 0xFFFF),
+        (_, value) => value,
+    }
+}
+
+fn unpack(storage: &StorageType, value: Value, signed: bool) -> Value {
+    match (storage, value) {
+        (StorageType::I8, Value::I32(v)) if signed => Value::I32(v as i8 as i32),
+        (StorageType::I16, Value::I32(v)) if signed => Value::I32(v as i16 as i32),
+        (_, value) => value,
+    }
+}
@@ -1,12 +1,12 @@
-use super::runtime::{ExecState, Instance};
+use super::runtime::{Addr, ExecState, Instance};
 use super::stack::{Frame, Label, Stack};
-use super::store::{FuncInst, Store};
+use super::store::{ExnInst, FuncInst, Store};
 use super::table::*;
 use super::trap::Trap;
 use super::value::{Ref, Value};
-use super::{cast, memory};
-use crate::binary::Instr;
+use super::{atomic, cast, gc, memory, simd};
 use crate::binary::ValType;
+use crate::binary::{Catch, Instr};
 #[cfg(not(feature = "std"))]
 use crate::lib::*;
 use core::ops::Neg;
@@ -30,17 +30,45 @@ pub fn step(
         Instr::Block { bt, end_offset } => {
             stack.push_label(Label {
                 n: instance.block_to_arity(bt),
-                stack_offset: stack.values_len(),
+                stack_offset: stack.values_len() - instance.block_to_params(bt),
                 pc: end_offset + pc,
                 cont: false,
+                handler: None,
             });
         }
-        Instr::Loop { bt } => {
+        Instr::TryTable { bt, end_offset, .. } => {
             stack.push_label(Label {
                 n: instance.block_to_arity(bt),
-                stack_offset: stack.values_len(),
+                stack_offset: stack.values_len() - instance.block_to_params(bt),
+                pc: end_offset + pc,
+                cont: false,
+                handler: Some(pc),
+            });
+        }
+        Instr::Throw(x) => {
+            let tag = instance.tagaddrs[*x as usize];
+            let mut fields = vec![];
+            for _ in 0..store.tags[tag].functype.0 .0.len() {
+                fields.push(stack.pop_value::<Value>());
+            }
+            fields.reverse();
+            let exn = store.exns.push(ExnInst { tag, fields });
+            return throw(instances, instrs, store, stack, exn);
+        }
+        Instr::ThrowRef => match stack.pop_value::<Ref>() {
+            Ref::Exn(exn) => return throw(instances, instrs, store, stack, exn),
+            _ => return Err(Trap::NullExceptionReference),
+        },
+        Instr::Loop { bt } => {
+            // A branch to a loop jumps back to its start, taking the
+            // parameters of the block rather than its results.
+            let params = instance.block_to_params(bt);
+            stack.push_label(Label {
+                n: params,
+                stack_offset: stack.values_len() - params,
                 pc,
                 cont: true,
+                handler: None,
             });
         }
         Instr::If {
@@ -52,16 +80,18 @@ pub fn step(
             if c != 0 {
                 stack.push_label(Label {
                     n: instance.block_to_arity(bt),
-                    stack_offset: stack.values_len(),
+                    stack_offset: stack.values_len() - instance.block_to_params(bt),
                     pc: end_offset + pc,
                     cont: false,
+                    handler: None,
                 });
             } else if let Some(else_offset) = else_offset {
                 stack.push_label(Label {
                     n: instance.block_to_arity(bt),
-                    stack_offset: stack.values_len(),
+                    stack_offset: stack.values_len() - instance.block_to_params(bt),
                     pc: end_offset + pc,
                     cont: false,
+                    handler: None,
                 });
                 return Ok(ExecState::Continue(else_offset + pc));
             } else {
@@ -69,7 +99,7 @@ pub fn step(
             }
         }
         Instr::Br(l) => {
-            if *l as usize >= stack.labels_len() {
+            if *l as usize >= stack.labels_len() - frame.label_offset {
                 return match unwind_stack(&frame, stack) {
                     Some(new_pc) => Ok(ExecState::Continue(new_pc)),
                     None => Ok(ExecState::Return),
@@ -81,7 +111,7 @@ pub fn step(
         Instr::BrIf(l) => {
             let c = stack.pop_value::<i32>();
             if c != 0 {
-                if *l as usize >= stack.labels_len() {
+                if *l as usize >= stack.labels_len() - frame.label_offset {
                     return match unwind_stack(&frame, stack) {
                         Some(new_pc) => Ok(ExecState::Continue(new_pc)),
                         None => Ok(ExecState::Return),
@@ -95,7 +125,7 @@ pub fn step(
             let i = stack.pop_value::<i32>() as usize;
             return if i < indexs.len() {
                 let l = indexs[i] as usize;
-                if l >= stack.labels_len() {
+                if l >= stack.labels_len() - frame.label_offset {
                     return match unwind_stack(&frame, stack) {
                         Some(new_pc) => Ok(ExecState::Continue(new_pc)),
                         None => Ok(ExecState::Return),
@@ -105,7 +135,7 @@ pub fn step(
                 Ok(ExecState::Continue(new_pc))
             } else {
                 let l = *default as usize;
-                if l >= stack.labels_len() {
+                if l >= stack.labels_len() - frame.label_offset {
                     return match unwind_stack(&frame, stack) {
                         Some(new_pc) => Ok(ExecState::Continue(new_pc)),
                         None => Ok(ExecState::Return),
@@ -123,27 +153,45 @@ pub fn step(
         }
         Instr::Call(a) => {
             let addr = instance.funcaddrs[*a as usize];
-            let func = &store.funcs[addr];
-
-            return attach(func, stack, pc);
+            return attach(store, addr, stack, pc);
         }
         Instr::CallIndirect(typeidx, tableidx) => {
-            let ta = instance.tableaddrs[*tableidx as usize];
-            let tab = &store.tables[ta];
-            let ft = &instance.types[*typeidx as usize];
-            let i = stack.pop_value::<i32>() as usize;
-            if i >= tab.elem.len() {
-                return Err(Trap::UndefinedElement);
+            let addr = indirect_func(instance, store, stack, *typeidx, *tableidx)?;
+            return attach(store, addr, stack, pc);
+        }
+        Instr::ReturnCall(a) => {
+            let addr = instance.funcaddrs[*a as usize];
+            return tail_attach(store, addr, &frame, stack, pc);
+        }
+        Instr::ReturnCallIndirect(typeidx, tableidx) => {
+            let addr = indirect_func(instance, store, stack, *typeidx, *tableidx)?;
+            return tail_attach(store, addr, &frame, stack, pc);
+        }
+        Instr::CallRef(_) => match stack.pop_value::<Ref>() {
+            Ref::Func(a) => return attach(store, a, stack, pc),
+            _ => return Err(Trap::NullFunctionReference),
+        },
+        Instr::ReturnCallRef(_) => match stack.pop_value::<Ref>() {
+            Ref::Func(a) => return tail_attach(store, a, &frame, stack, pc),
+            _ => return Err(Trap::NullFunctionReference),
+        },
+        Instr::BrOnNull(l) => match stack.pop_value::<Ref>() {
+            Ref::Null => return Ok(branch(&frame, stack, *l as usize)),
+            r => stack.push_value(r),
+        },
+        Instr::BrOnNonNull(l) => match stack.pop_value::<Ref>() {
+            Ref::Null => {}
+            r => {
+                stack.push_value(r);
+                return Ok(branch(&frame, stack, *l as usize));
             }
-            let r = tab.elem[i];
-            if let Ref::Func(a) = r {
-                let func = &store.funcs[a];
-                if func.functype() != ft {
-                    return Err(Trap::IndirectCallTypeMismatch);
-                }
-                return attach(func, stack, pc);
-            } else {
-                return Err(Trap::NotFundRef);
+        },
+        Instr::BrOnCast(l, _, rt) | Instr::BrOnCastFail(l, _, rt) => {
+            let r = stack.pop_value();
+            stack.push_value(r);
+            let matches = gc::ref_matches(instances, store, frame.instance_addr, r, *rt);
+            if matches == matches!(instrs[pc], Instr::BrOnCast(..)) {
+                return Ok(branch(&frame, stack, *l as usize));
             }
         }
 
@@ -162,6 +210,10 @@ pub fn step(
             let addr = instance.funcaddrs[*x as usize];
             stack.push_value(Value::Ref(Ref::Func(addr)));
         }
+        Instr::RefAsNonNull => match stack.pop_value::<Ref>() {
+            Ref::Null => return Err(Trap::NullReference),
+            r => stack.push_value(r),
+        },
 
         /////////////////////////////
         // Parametric Instructions //
@@ -169,7 +221,7 @@ pub fn step(
         Instr::Drop => {
             stack.pop_value::<Value>();
         }
-        Instr::Select => {
+        Instr::Select | Instr::SelectT(_) => {
             let c = stack.pop_value::<i32>();
             let val2 = stack.pop_value::<Value>();
             let val1 = stack.pop_value::<Value>();
@@ -244,12 +296,12 @@ pub fn step(
         Instr::I64Store8(memarg) => memory::i64_store_8(memarg, instance, store, stack)?,
         Instr::I64Store16(memarg) => memory::i64_store_16(memarg, instance, store, stack)?,
         Instr::I64Store32(memarg) => memory::i64_store_32(memarg, instance, store, stack)?,
-        Instr::MemorySize => memory::memory_size(instance, store, stack),
-        Instr::MemoryGrow => memory::memory_grow(instance, store, stack),
-        Instr::MemoryInit(x) => memory::memory_init(x, instance, store, stack)?,
+        Instr::MemorySize(m) => memory::memory_size(m, instance, store, stack),
+        Instr::MemoryGrow(m) => memory::memory_grow(m, instance, store, stack),
+        Instr::MemoryInit(x, m) => memory::memory_init(x, m, instance, store, stack)?,
         Instr::DataDrop(x) => memory::data_drop(x, instance, store),
-        Instr::MemoryCopy => memory::memory_copy(instance, store, stack)?,
-        Instr::MemoryFill => memory::memory_fill(instance, store, stack)?,
+        Instr::MemoryCopy(d, s) => memory::memory_copy(d, s, instance, store, stack)?,
+        Instr::MemoryFill(m) => memory::memory_fill(m, instance, store, stack)?,
 
         //////////////////////////
         // Numeric Instructions //
@@ -545,10 +597,37 @@ pub fn step(
         Instr::PopLabel => {
             stack.pop_label();
         }
+
+        //////////////////////////
+        // Atomic Instructions ///
+        //////////////////////////
+        instr if atomic::is_atomic(instr) => atomic::step(instr, instance, store, stack)?,
+
+        /////////////////////////////////////////
+        // Aggregate and i31 Instructions ///////
+        /////////////////////////////////////////
+        instr if gc::is_gc(instr) => gc::step(instr, instances, frame.instance_addr, store, stack)?,
+
+        //////////////////////////
+        // Vector Instructions ///
+        //////////////////////////
+        instr => simd::step(instr, instance, store, stack)?,
     }
     Ok(ExecState::Continue(pc + 1))
 }
 
+/// Branches to label `l`, returning from the function if it is the label
+/// of the function body.
+fn branch(frame: &Frame, stack: &mut Stack, l: usize) -> ExecState {
+    if l >= stack.labels_len() - frame.label_offset {
+        return match unwind_stack(frame, stack) {
+            Some(new_pc) => ExecState::Continue(new_pc),
+            None => ExecState::Return,
+        };
+    }
+    ExecState::Continue(stack.jump(l))
+}
+
 pub fn unwind_stack(frame: &Frame, stack: &mut Stack) -> Option<usize> {
     let n = frame.n;
     let mut results: Vec<Value> = vec![];
@@ -556,6 +635,7 @@ pub fn unwind_stack(frame: &Frame, stack: &mut Stack) -> Option<usize> {
         results.push(stack.pop_value());
     }
     stack.values_unwind(frame.stack_offset);
+    stack.labels_unwind(frame.label_offset);
     for _ in 0..n {
         stack.push_value(results.pop().unwrap());
     }
@@ -567,9 +647,126 @@ pub fn unwind_stack(frame: &Frame, stack: &mut Stack) -> Option<usize> {
     }
 }
 
-pub fn attach(func: &FuncInst, stack: &mut Stack, pc: usize) -> Result<ExecState, Trap> {
-    match func {
-        FuncInst::HostFunc { name, functype } => {
+/// Unwinds labels and frames up to the innermost `try_table` with a
+/// handler for `exn` and branches to the label of that handler.
+fn throw(
+    instances: &[Instance],
+    instrs: &[Instr],
+    store: &Store,
+    stack: &mut Stack,
+    exn: Addr,
+) -> Result<ExecState, Trap> {
+    let ExnInst { tag, fields } = &store.exns[exn];
+    while stack.frames_len() > 0 {
+        let frame = stack.top_frame().clone();
+        let instance = &instances[frame.instance_addr];
+        while stack.labels_len() > frame.label_offset {
+            let label = stack.pop_label();
+            let catches = match label.handler.map(|pc| &instrs[pc]) {
+                Some(Instr::TryTable { catches, .. }) => catches,
+                _ => continue,
+            };
+            let caught = catches.iter().find(|catch| match catch {
+                Catch::Catch(x, _) | Catch::CatchRef(x, _) => {
+                    instance.tagaddrs[*x as usize] == *tag
+                }
+                Catch::CatchAll(_) | Catch::CatchAllRef(_) => true,
+            });
+            let catch = match caught {
+                Some(catch) => catch,
+                None => continue,
+            };
+            stack.values_unwind(label.stack_offset);
+            let l = match *catch {
+                Catch::Catch(_, l) | Catch::CatchRef(_, l) => {
+                    stack.extend_values(fields.clone());
+                    l
+                }
+                Catch::CatchAll(l) | Catch::CatchAllRef(l) => l,
+            } as usize;
+            if let Catch::CatchRef(..) | Catch::CatchAllRef(_) = catch {
+                stack.push_value(Ref::Exn(exn));
+            }
+            if l >= stack.labels_len() - frame.label_offset {
+                return match unwind_stack(&frame, stack) {
+                    Some(new_pc) => Ok(ExecState::Continue(new_pc)),
+                    None => Ok(ExecState::Return),
+                };
+            }
+            return Ok(ExecState::Continue(stack.jump(l)));
+        }
+        stack.values_unwind(frame.stack_offset);
+        stack.pop_frame();
+    }
+    Err(Trap::UncaughtException(store.exns[exn].clone()))
+}
+
+/// Pops the element index of `call_indirect` and looks up the address of
+/// its callee.
+fn indirect_func(
+    instance: &Instance,
+    store: &Store,
+    stack: &mut Stack,
+    typeidx: u32,
+    tableidx: u32,
+) -> Result<Addr, Trap> {
+    let ta = instance.tableaddrs[tableidx as usize];
+    let tab = &store.tables[ta];
+    let ft = instance.functype(typeidx);
+    let i = stack.pop_value::<i32>() as usize;
+    if i >= tab.elem.len() {
+        return Err(Trap::UndefinedElement);
+    }
+    match tab.elem[i] {
+        Ref::Func(a) => {
+            let func = &store.funcs[a];
+            if func.functype() != ft {
+                return Err(Trap::IndirectCallTypeMismatch);
+            }
+            Ok(a)
+        }
+        Ref::Null => Err(Trap::UninitializedElement),
+        _ => Err(Trap::NotFundRef),
+    }
+}
+
+/// Replaces `frame` by a frame of `func` which returns to the caller of
+/// `frame`. Host functions are called like `attach`, the parser places a
+/// `Return` after tail calls for them.
+pub fn tail_attach(
+    store: &Store,
+    addr: Addr,
+    frame: &Frame,
+    stack: &mut Stack,
+    pc: usize,
+) -> Result<ExecState, Trap> {
+    match &store.funcs[addr] {
+        FuncInst::HostFunc { .. } => attach(store, addr, stack, pc),
+        FuncInst::InnerFunc { functype, .. } => {
+            let mut args = vec![];
+            for _ in 0..functype.0 .0.len() {
+                args.push(stack.pop_value::<Value>());
+            }
+            args.reverse();
+            stack.values_unwind(frame.stack_offset);
+            stack.labels_unwind(frame.label_offset);
+            stack.pop_frame();
+            stack.extend_values(args);
+            // The new frame returns to the instruction after `frame.pc - 1`.
+            attach(store, addr, stack, frame.pc - 1)
+        }
+    }
+}
+
+/// Calls the function at `addr`, pushing its frame for the instruction at
+/// `pc`.
+pub fn attach(store: &Store, addr: Addr, stack: &mut Stack, pc: usize) -> Result<ExecState, Trap> {
+    match &store.funcs[addr] {
+        FuncInst::HostFunc {
+            module,
+            name,
+            functype,
+        } => {
             let mut local = vec![];
             for _ in 0..functype.0 .0.len() {
                 local.push(stack.pop_value());
@@ -577,8 +774,10 @@ pub fn attach(func: &FuncInst, stack: &mut Stack, pc: usize) -> Result<ExecState
             local.reverse();
 
             Ok(ExecState::EnvFunc {
+                module: module.clone(),
                 name: name.clone(),
                 params: local,
+                result_types: functype.1 .0.clone(),
             })
         }
         FuncInst::InnerFunc {
@@ -598,14 +797,20 @@ pub fn attach(func: &FuncInst, stack: &mut Stack, pc: usize) -> Result<ExecState
                     ValType::I64 => local.push(Value::I64(0)),
                     ValType::F32 => local.push(Value::F32(0.0)),
                     ValType::F64 => local.push(Value::F64(0.0)),
-                    _ => todo!(),
+                    ValType::V128 => local.push(Value::V128(0)),
+                    ValType::FuncRef
+                    | ValType::ExternRef
+                    | ValType::ExnRef
+                    | ValType::Ref { .. } => local.push(Value::Ref(Ref::Null)),
                 }
             }
             let new_frame = Frame {
                 n: functype.1 .0.len(),
+                func_addr: addr,
                 instance_addr: *instance_addr,
                 local,
                 stack_offset: stack.values_len(),
+                label_offset: stack.labels_len(),
                 pc: pc + 1,
             };
             stack.push_frame(new_frame);
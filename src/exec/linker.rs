@@ -0,0 +1,502 @@
+#[cfg(not(feature = "std"))]
+use crate::lib::*;
+
+use super::runtime::{Addr, PAGE_SIZE};
+use super::store::{MemInst, Store};
+use super::value::Value;
+use crate::binary::{FuncType, ImportDesc, Memory, ResultType, ValType};
+use alloc::collections::BTreeMap;
+use core::fmt::Debug;
+
+pub type HostFn =
+    Box<dyn FnMut(Vec<Value>, Option<&mut MemInst>) -> Result<Vec<Value>, &'static str>>;
+
+struct HostFuncEntry {
+    functype: FuncType,
+    func: HostFn,
+}
+
+/// Memory, table or global allocated in the `Store` by the embedder.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Extern {
+    Memory(Addr),
+    Table(Addr),
+    Global(Addr),
+}
+
+impl Extern {
+    /// Checks the extern against the type declared by an import.
+    pub fn matches(&self, store: &Store, desc: &ImportDesc) -> bool {
+        match (self, desc) {
+            (
+                Extern::Memory(addr),
+                ImportDesc::Mem(Memory {
+                    idxtype,
+                    limits,
+                    shared,
+                }),
+            ) => {
+                let mem = &store.mems[*addr];
+                let pages = (mem.data.len() / PAGE_SIZE) as u64;
+                mem.idxtype == *idxtype
+                    && mem.data.is_shared() == *shared
+                    && mem.limits.set_min(pages).matches(limits)
+            }
+            (Extern::Table(addr), ImportDesc::Table(table)) => {
+                let inst = &store.tables[*addr];
+                inst.tabletype.reftype == table.reftype
+                    && inst
+                        .tabletype
+                        .limits
+                        .set_min(inst.elem.len() as u64)
+                        .matches(&table.limits)
+            }
+            (Extern::Global(addr), ImportDesc::Global(globaltype)) => {
+                &store.globals[*addr].globaltype == globaltype
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Registry of host functions and externs keyed by `(module, name)`.
+///
+/// Imports resolved through the linker are checked against the declared
+/// import type when the importing module is instantiated.
+#[derive(Default)]
+pub struct Linker {
+    funcs: BTreeMap<(String, String), HostFuncEntry>,
+    externs: BTreeMap<(String, String), Extern>,
+}
+
+impl Debug for Linker {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Linker")
+            .field("funcs", &self.funcs.keys())
+            .field("externs", &self.externs)
+            .finish()
+    }
+}
+
+impl Linker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers an untyped host function which also receives the memory
+    /// of the calling instance.
+    pub fn define<F>(&mut self, module: &str, name: &str, functype: FuncType, func: F)
+    where
+        F: FnMut(Vec<Value>, Option<&mut MemInst>) -> Result<Vec<Value>, &'static str> + 'static,
+    {
+        self.funcs.insert(
+            (module.into(), name.into()),
+            HostFuncEntry {
+                functype,
+                func: Box::new(func),
+            },
+        );
+    }
+
+    /// Registers a closure such as `|a: i32, b: i64| -> f32`, deriving its
+    /// `FuncType` from the argument and result types.
+    pub fn func<Params, Results, F>(&mut self, module: &str, name: &str, func: F)
+    where
+        F: IntoHostFunc<Params, Results>,
+    {
+        let (functype, func) = func.into_host_func();
+        self.funcs.insert(
+            (module.into(), name.into()),
+            HostFuncEntry { functype, func },
+        );
+    }
+
+    /// Provides a memory created with `Store::allocate_mem`.
+    pub fn memory(&mut self, module: &str, name: &str, addr: Addr) {
+        self.externs
+            .insert((module.into(), name.into()), Extern::Memory(addr));
+    }
+
+    /// Provides a table created with `Store::allocate_table`.
+    pub fn table(&mut self, module: &str, name: &str, addr: Addr) {
+        self.externs
+            .insert((module.into(), name.into()), Extern::Table(addr));
+    }
+
+    /// Provides a global created with `Store::allocate_host_global`.
+    pub fn global(&mut self, module: &str, name: &str, addr: Addr) {
+        self.externs
+            .insert((module.into(), name.into()), Extern::Global(addr));
+    }
+
+    pub fn get_extern(&self, module: &str, name: &str) -> Option<Extern> {
+        self.externs.get(&(module.into(), name.into())).copied()
+    }
+
+    pub fn functype(&self, module: &str, name: &str) -> Option<&FuncType> {
+        self.funcs
+            .get(&(module.into(), name.into()))
+            .map(|entry| &entry.functype)
+    }
+
+    pub fn contains(&self, module: &str, name: &str) -> bool {
+        self.functype(module, name).is_some()
+    }
+
+    pub fn call(
+        &mut self,
+        module: &str,
+        name: &str,
+        params: Vec<Value>,
+        memory: Option<&mut MemInst>,
+    ) -> Result<Vec<Value>, &'static str> {
+        match self.funcs.get_mut(&(module.into(), name.into())) {
+            Some(entry) => (entry.func)(params, memory),
+            None => Err("not found"),
+        }
+    }
+}
+
+pub trait WasmTy: From<Value> + Into<Value> {
+    fn valtype() -> ValType;
+}
+
+macro_rules! impl_wasm_ty {
+    ($t:ty, $valtype:expr) => {
+        impl WasmTy for $t {
+            fn valtype() -> ValType {
+                $valtype
+            }
+        }
+    };
+}
+
+impl_wasm_ty!(i32, ValType::I32);
+impl_wasm_ty!(i64, ValType::I64);
+impl_wasm_ty!(f32, ValType::F32);
+impl_wasm_ty!(f64, ValType::F64);
+impl_wasm_ty!(u128, ValType::V128);
+
+pub trait WasmResults {
+    fn valtypes() -> Vec<ValType>;
+    fn into_values(self) -> Result<Vec<Value>, &'static str>;
+}
+
+impl WasmResults for () {
+    fn valtypes() -> Vec<ValType> {
+        vec![]
+    }
+
+    fn into_values(self) -> Result<Vec<Value>, &'static str> {
+        Ok(vec![])
+    }
+}
+
+impl<T: WasmTy> WasmResults for T {
+    fn valtypes() -> Vec<ValType> {
+        vec![T::valtype()]
+    }
+
+    fn into_values(self) -> Result<Vec<Value>, &'static str> {
+        Ok(vec![self.into()])
+    }
+}
+
+impl<R: WasmResults> WasmResults for Result<R, &'static str> {
+    fn valtypes() -> Vec<ValType> {
+        R::valtypes()
+    }
+
+    fn into_values(self) -> Result<Vec<Value>, &'static str> {
+        self.and_then(|results| results.into_values())
+    }
+}
+
+macro_rules! impl_wasm_results {
+    ($($r:ident),+) => {
+        impl<$($r: WasmTy),+> WasmResults for ($($r,)+) {
+            fn valtypes() -> Vec<ValType> {
+                vec![$($r::valtype()),+]
+            }
+
+            #[allow(non_snake_case)]
+            fn into_values(self) -> Result<Vec<Value>, &'static str> {
+                let ($($r,)+) = self;
+                Ok(vec![$($r.into()),+])
+            }
+        }
+    };
+}
+
+impl_wasm_results!(A);
+impl_wasm_results!(A, B);
+impl_wasm_results!(A, B, C);
+impl_wasm_results!(A, B, C, D);
+
+pub trait IntoHostFunc<Params, Results> {
+    fn into_host_func(self) -> (FuncType, HostFn);
+}
+
+macro_rules! impl_into_host_func {
+    ($($p:ident),*) => {
+        impl<F, R, $($p),*> IntoHostFunc<($($p,)*), R> for F
+        where
+            F: FnMut($($p),*) -> R + 'static,
+            R: WasmResults,
+            $($p: WasmTy,)*
+        {
+            #[allow(non_snake_case, unused_mut, unused_variables)]
+            fn into_host_func(mut self) -> (FuncType, HostFn) {
+                let functype = FuncType(
+                    ResultType(vec![$($p::valtype()),*]),
+                    ResultType(R::valtypes()),
+                );
+                let func = move |params: Vec<Value>, _: Option<&mut MemInst>| {
+                    let mut params = params.into_iter();
+                    $(let $p: $p = params.next().ok_or("missing argument")?.into();)*
+                    self($($p),*).into_values()
+                };
+                (functype, Box::new(func))
+            }
+        }
+    };
+}
+
+impl_into_host_func!();
+impl_into_host_func!(P1);
+impl_into_host_func!(P1, P2);
+impl_into_host_func!(P1, P2, P3);
+impl_into_host_func!(P1, P2, P3, P4);
+impl_into_host_func!(P1, P2, P3, P4, P5);
+impl_into_host_func!(P1, P2, P3, P4, P5, P6);
+
+#[cfg(test)]
+mod tests {
+    use super::Linker;
+    use crate::binary::{FuncType, GlobalType, Limits, Memory, Mut, RefType, ResultType};
+    use crate::binary::{IdxType, Table, ValType};
+    use crate::exec::env::DebugEnv;
+    use crate::exec::runtime::{Runtime, RuntimeError};
+    use crate::exec::store::Store;
+    use crate::exec::value::Value;
+    use crate::loader::parser::Parser;
+    use crate::tests::wat2wasm;
+
+    fn instantiate(wat: &str, linker: Linker) -> Result<(Runtime, Store), RuntimeError> {
+        let wasm = wat2wasm(wat).unwrap();
+        let module = Parser::new(&wasm).module().unwrap();
+        let mut store = Store::new();
+        let mut runtime = Runtime::new("env");
+        runtime.linker = linker;
+        runtime.add_module(&mut store, &mut DebugEnv {}, module)?;
+        Ok((runtime, store))
+    }
+
+    #[test]
+    fn typed_funcs() {
+        let mut linker = Linker::new();
+        linker.func("math", "scale", |a: i32, b: i64| -> f32 {
+            a as f32 * b as f32
+        });
+        linker.func("math", "split", |a: i64| (a as i32, (a >> 32) as i32));
+        let (mut runtime, mut store) = instantiate(
+            r#"(module
+                  (import "math" "scale" (func $scale (param i32 i64) (result f32)))
+                  (import "math" "split" (func $split (param i64) (result i32 i32)))
+                  (func (export "scale") (param i32 i64) (result f32)
+                      local.get 0
+                      local.get 1
+                      call $scale)
+                  (func (export "split") (param i64) (result i32)
+                      local.get 0
+                      call $split
+                      i32.add))"#,
+            linker,
+        )
+        .unwrap();
+        let mut env = DebugEnv {};
+        assert_eq!(
+            runtime.invoke(
+                &mut store,
+                &mut env,
+                "scale",
+                vec![Value::I32(3), Value::I64(4)]
+            ),
+            Ok(vec![Value::F32(12.0)])
+        );
+        assert_eq!(
+            runtime.invoke(
+                &mut store,
+                &mut env,
+                "split",
+                vec![Value::I64(0x2_0000_0005)]
+            ),
+            Ok(vec![Value::I32(7)])
+        );
+    }
+
+    #[test]
+    fn define_with_memory() {
+        let mut linker = Linker::new();
+        let functype = FuncType(
+            ResultType(vec![ValType::I32]),
+            ResultType(vec![ValType::I32]),
+        );
+        linker.define("host", "load", functype, |params, memory| {
+            let addr = i32::from(params[0]) as usize;
+            let memory = memory.ok_or("no memory")?;
+            Ok(vec![Value::I32(memory.data.load::<u8>(addr) as i32)])
+        });
+        let (mut runtime, mut store) = instantiate(
+            r#"(module
+                  (import "host" "load" (func $load (param i32) (result i32)))
+                  (memory 1)
+                  (data (i32.const 8) "\2a")
+                  (func (export "main") (result i32)
+                      i32.const 8
+                      call $load))"#,
+            linker,
+        )
+        .unwrap();
+        assert_eq!(
+            runtime.invoke(&mut store, &mut DebugEnv {}, "main", vec![]),
+            Ok(vec![Value::I32(42)])
+        );
+    }
+
+    #[test]
+    fn host_error_traps() {
+        let mut linker = Linker::new();
+        linker.func("host", "fail", || -> Result<(), &'static str> {
+            Err("failed")
+        });
+        let (mut runtime, mut store) = instantiate(
+            r#"(module
+                  (import "host" "fail" (func $fail))
+                  (func (export "main") call $fail))"#,
+            linker,
+        )
+        .unwrap();
+        assert_eq!(
+            runtime
+                .invoke(&mut store, &mut DebugEnv {}, "main", vec![])
+                .unwrap_err()
+                .trap(),
+            Some(&crate::exec::trap::Trap::Env("failed"))
+        );
+    }
+
+    #[test]
+    fn incompatible_import() {
+        let mut linker = Linker::new();
+        linker.func("math", "scale", |a: i32, b: i64| -> f32 {
+            a as f32 * b as f32
+        });
+        let err = instantiate(
+            r#"(module
+                  (import "math" "scale" (func (param i32 i32) (result f32))))"#,
+            linker,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            RuntimeError::IncompatibleImport("math".into(), "scale".into())
+        );
+    }
+
+    #[test]
+    fn host_externs() {
+        let mut store = Store::new();
+        let mut linker = Linker::new();
+        let mem = store
+            .allocate_mem(&Memory {
+                idxtype: IdxType::I32,
+                limits: Limits::MinMax(1, 2),
+                shared: false,
+            })
+            .unwrap();
+        store.mems[mem].data.write(0, &[7]);
+        linker.memory("env", "memory", mem);
+        let table = store.allocate_table(Table {
+            reftype: RefType::FuncRef,
+            limits: Limits::Min(4),
+        });
+        linker.table("env", "table", table);
+        let globaltype = GlobalType {
+            valtype: ValType::I32,
+            mut_: Mut::Var,
+        };
+        let global = store.allocate_host_global(globaltype, Value::I32(35));
+        linker.global("env", "counter", global);
+
+        let wasm = wat2wasm(
+            r#"(module
+                  (import "env" "memory" (memory 1))
+                  (import "env" "table" (table 2 funcref))
+                  (import "env" "counter" (global $counter (mut i32)))
+                  (func (export "main") (result i32)
+                      (global.set $counter
+                          (i32.add (global.get $counter) (i32.load8_u (i32.const 0))))
+                      (i32.add (global.get $counter) (table.size))))"#,
+        )
+        .unwrap();
+        let module = Parser::new(&wasm).module().unwrap();
+        let mut runtime = Runtime::new("env");
+        runtime.linker = linker;
+        runtime
+            .add_module(&mut store, &mut DebugEnv {}, module)
+            .unwrap();
+        assert_eq!(
+            runtime.invoke(&mut store, &mut DebugEnv {}, "main", vec![]),
+            Ok(vec![Value::I32(46)])
+        );
+        assert_eq!(store.globals[global].value, Value::I32(42));
+    }
+
+    #[test]
+    fn incompatible_externs() {
+        let cases = [
+            r#"(module (import "env" "memory" (memory 2)))"#,
+            r#"(module (import "env" "memory" (memory 0 1)))"#,
+            r#"(module (import "env" "table" (table 1 externref)))"#,
+            r#"(module (import "env" "counter" (global i32)))"#,
+            r#"(module (import "env" "counter" (memory 0)))"#,
+        ];
+        for wat in cases {
+            let mut store = Store::new();
+            let mut linker = Linker::new();
+            linker.memory(
+                "env",
+                "memory",
+                store
+                    .allocate_mem(&Memory {
+                        idxtype: IdxType::I32,
+                        limits: Limits::Min(1),
+                        shared: false,
+                    })
+                    .unwrap(),
+            );
+            let table = store.allocate_table(Table {
+                reftype: RefType::FuncRef,
+                limits: Limits::Min(1),
+            });
+            linker.table("env", "table", table);
+            let globaltype = GlobalType {
+                valtype: ValType::I32,
+                mut_: Mut::Var,
+            };
+            linker.global(
+                "env",
+                "counter",
+                store.allocate_host_global(globaltype, Value::I32(0)),
+            );
+            let module = Parser::new(&wat2wasm(wat).unwrap()).module().unwrap();
+            let mut runtime = Runtime::new("env");
+            runtime.linker = linker;
+            assert!(matches!(
+                runtime.add_module(&mut store, &mut DebugEnv {}, module),
+                Err(RuntimeError::IncompatibleImport(..))
+            ));
+        }
+    }
+}
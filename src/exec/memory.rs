@@ -7,11 +7,41 @@ use super::{
 #[cfg(not(feature = "std"))]
 use crate::lib::*;
 use crate::{
-    binary::{Data, MemArg},
-    exec::{runtime::PAGE_SIZE, value::LittleEndian},
+    binary::{IdxType, MemArg},
+    exec::runtime::PAGE_SIZE,
 };
 use opt_vec::OptVec;
 
+/// Pops an address or length operand of the index type of `mem`.
+pub fn pop_index(mem: &MemInst, stack: &mut Stack) -> u64 {
+    match mem.idxtype {
+        IdxType::I32 => stack.pop_value::<i32>() as u32 as u64,
+        IdxType::I64 => stack.pop_value::<i64>() as u64,
+    }
+}
+
+/// Pops the address operand and returns the effective address of an access
+/// of `size` bytes.
+pub fn effective_address(
+    mem: &MemInst,
+    memarg: &MemArg,
+    stack: &mut Stack,
+    size: usize,
+) -> Result<usize, Trap> {
+    let ea = pop_index(mem, stack)
+        .checked_add(memarg.offset)
+        .ok_or(Trap::MemoryOutOfBounds)?;
+    range(ea, size as u64, mem.data.len())
+}
+
+/// Checks that `n` bytes from `i` are within `len` and returns `i`.
+fn range(i: u64, n: u64, len: usize) -> Result<usize, Trap> {
+    match i.checked_add(n) {
+        Some(end) if end <= len as u64 => Ok(i as usize),
+        _ => Err(Trap::MemoryOutOfBounds),
+    }
+}
+
 macro_rules! impl_load {
     ($fnname: ident, $t:ty, $sx:ty) => {
         pub fn $fnname(
@@ -20,17 +50,10 @@ macro_rules! impl_load {
             store: &mut Store,
             stack: &mut Stack,
         ) -> Result<(), Trap> {
-            let a = instance.memaddr.unwrap();
+            let a = instance.memaddrs[memarg.memidx as usize];
             let mem = &store.mems[a];
-            let i = stack.pop_value::<i32>() as usize;
-            let ea = i
-                .checked_add(memarg.offset as usize)
-                .ok_or(Trap::MemoryOutOfBounds)?;
-            const SIZE: usize = core::mem::size_of::<$sx>();
-            if ea.checked_add(SIZE).ok_or(Trap::MemoryOutOfBounds)? > mem.data.len() {
-                return Err(Trap::MemoryOutOfBounds);
-            }
-            let c: $sx = LittleEndian::read(&mem.data, ea);
+            let ea = effective_address(mem, memarg, stack, core::mem::size_of::<$sx>())?;
+            let c: $sx = mem.data.load(ea);
             stack.push_value(c as $t);
             Ok(())
         }
@@ -60,18 +83,11 @@ macro_rules! impl_store {
             store: &mut Store,
             stack: &mut Stack,
         ) -> Result<(), Trap> {
-            let a = instance.memaddr.unwrap();
+            let a = instance.memaddrs[memarg.memidx as usize];
             let mem = &mut store.mems[a];
             let c = stack.pop_value::<$t>();
-            let i = stack.pop_value::<i32>() as usize;
-            let ea = i
-                .checked_add(memarg.offset as usize)
-                .ok_or(Trap::MemoryOutOfBounds)?;
-            const SIZE: usize = core::mem::size_of::<$sx>();
-            if ea.checked_add(SIZE).ok_or(Trap::MemoryOutOfBounds)? > mem.data.len() {
-                return Err(Trap::MemoryOutOfBounds);
-            }
-            LittleEndian::write(&mut mem.data, ea, c as $sx);
+            let ea = effective_address(mem, memarg, stack, core::mem::size_of::<$sx>())?;
+            mem.data.store(ea, c as $sx);
             Ok(())
         }
     };
@@ -87,114 +103,133 @@ impl_store!(i64_store_8, i64, u8);
 impl_store!(i64_store_16, i64, u16);
 impl_store!(i64_store_32, i64, u32);
 
-pub fn memory_size(instance: &Instance, store: &Store, stack: &mut Stack) {
-    let a = instance.memaddr.unwrap();
+pub fn memory_size(m: &u32, instance: &Instance, store: &Store, stack: &mut Stack) {
+    let a = instance.memaddrs[*m as usize];
     let mem = &store.mems[a];
-    stack.push_value(mem.limits.min() as i32);
+    push_index(mem, stack, (mem.data.len() / PAGE_SIZE) as u64);
 }
 
-pub fn memory_grow(instance: &Instance, store: &mut Store, stack: &mut Stack) {
-    let a = instance.memaddr.unwrap();
-    const ERR: i32 = -1;
+pub fn memory_grow(m: &u32, instance: &Instance, store: &mut Store, stack: &mut Stack) {
+    let a = instance.memaddrs[*m as usize];
+    const ERR: i64 = -1;
+    let limit = store.memory_limit().min(isize::MAX as usize);
     let mem = &mut store.mems[a];
-    let sz = mem.limits.min();
-    let n = stack.pop_value::<i32>() as u32;
-    let len = sz + n;
-    if len > u16::MAX as u32 + 1 {
-        stack.push_value(ERR);
-        return;
-    }
+    // Shared memories may have been grown by other threads since.
+    let sz = (mem.data.len() / PAGE_SIZE) as u64;
+    let n = pop_index(mem, stack);
+    let max_pages = match mem.idxtype {
+        IdxType::I32 => u16::MAX as u64 + 1,
+        IdxType::I64 => 1 << 48,
+    };
+    let len = match sz.checked_add(n) {
+        Some(len) if len <= max_pages => len,
+        _ => return push_index(mem, stack, ERR as u64),
+    };
     let limits_ = mem.limits.set_min(len);
     if !limits_.valid() {
-        stack.push_value(ERR);
-        return;
+        return push_index(mem, stack, ERR as u64);
     }
-    for _ in 0..(n * PAGE_SIZE as u32) {
-        mem.data.push(0);
+    // Growing fails rather than aborts when the pages cannot be addressed
+    // or exceed the memory limit of the store.
+    let bytes = match usize::try_from(n)
+        .ok()
+        .and_then(|n| n.checked_mul(PAGE_SIZE))
+    {
+        Some(bytes) if mem.data.len().saturating_add(bytes) <= limit => bytes,
+        _ => return push_index(mem, stack, ERR as u64),
+    };
+    let sz = match mem.data.grow(bytes) {
+        Some(old) => (old / PAGE_SIZE) as u64,
+        None => return push_index(mem, stack, ERR as u64),
+    };
+    mem.limits = mem.limits.set_min(sz + n);
+    push_index(mem, stack, sz);
+}
+
+fn push_index(mem: &MemInst, stack: &mut Stack, value: u64) {
+    match mem.idxtype {
+        IdxType::I32 => stack.push_value(value as i32),
+        IdxType::I64 => stack.push_value(value as i64),
     }
-    mem.limits = limits_;
-    stack.push_value(sz as i32);
 }
 
-pub fn memory_fill(instance: &Instance, store: &mut Store, stack: &mut Stack) -> Result<(), Trap> {
-    let ma = instance.memaddr.unwrap();
+pub fn memory_fill(
+    m: &u32,
+    instance: &Instance,
+    store: &mut Store,
+    stack: &mut Stack,
+) -> Result<(), Trap> {
+    let ma = instance.memaddrs[*m as usize];
     let mem = &mut store.mems[ma];
-    let n = stack.pop_value::<i32>() as usize;
+    let n = pop_index(mem, stack);
     let val = stack.pop_value::<i32>();
-    let d = stack.pop_value::<i32>() as usize;
-    if d + n > mem.data.len() {
-        return Err(Trap::MemoryOutOfBounds);
-    }
-    if n == 0 {
-        return Ok(());
-    }
-    for i in 0..n {
-        LittleEndian::write(&mut mem.data, d + i, val);
-    }
+    let d = range(pop_index(mem, stack), n, mem.data.len())?;
+    mem.data.fill(d, n as usize, val as u8);
     Ok(())
 }
 
-pub fn memory_copy(instance: &Instance, store: &mut Store, stack: &mut Stack) -> Result<(), Trap> {
-    let ma = instance.memaddr.unwrap();
-    let mem = &mut store.mems[ma];
-    let n = stack.pop_value::<i32>() as usize;
-    let s = stack.pop_value::<i32>() as usize;
-    let d = stack.pop_value::<i32>() as usize;
+pub fn memory_copy(
+    dst: &u32,
+    src: &u32,
+    instance: &Instance,
+    store: &mut Store,
+    stack: &mut Stack,
+) -> Result<(), Trap> {
+    let da = instance.memaddrs[*dst as usize];
+    let sa = instance.memaddrs[*src as usize];
+    // The length has the smaller index type of the two memories.
+    let n = match (store.mems[da].idxtype, store.mems[sa].idxtype) {
+        (IdxType::I64, IdxType::I64) => stack.pop_value::<i64>() as u64,
+        _ => stack.pop_value::<i32>() as u32 as u64,
+    };
+    let s = range(
+        pop_index(&store.mems[sa], stack),
+        n,
+        store.mems[sa].data.len(),
+    )?;
+    let d = range(
+        pop_index(&store.mems[da], stack),
+        n,
+        store.mems[da].data.len(),
+    )?;
+    let n = n as usize;
 
-    if s + n > mem.data.len() || d + n > mem.data.len() {
-        return Err(Trap::MemoryOutOfBounds);
-    }
     if n == 0 {
         return Ok(());
     }
-    if d <= s {
-        for i in 0..n {
-            mem.data[d + i] = mem.data[s + i];
-        }
-    } else {
-        for i in (0..n).rev() {
-            mem.data[d + n - 1 - i] = mem.data[s + n - 1 - i];
-        }
+    if da != sa {
+        let mut bytes = vec![0; n];
+        store.mems[sa].data.read(s, &mut bytes);
+        store.mems[da].data.write(d, &bytes);
+        return Ok(());
     }
+    store.mems[da].data.copy_within(s, d, n);
     Ok(())
 }
 
 pub fn memory_init(
     x: &u32,
+    m: &u32,
     instance: &Instance,
     store: &mut Store,
     stack: &mut Stack,
 ) -> Result<(), Trap> {
-    let ma = instance.memaddr.unwrap();
+    let ma = instance.memaddrs[*m as usize];
     let mem = &mut store.mems[ma];
     let da = instance.dataaddrs[*x as usize];
     let data = &store.datas[da];
-    let n = stack.pop_value::<i32>() as usize;
-    let s = stack.pop_value::<i32>() as usize;
-    let d = stack.pop_value::<i32>() as usize;
-    if s + n > data.data.len() || d + n > mem.data.len() {
-        return Err(Trap::MemoryOutOfBounds);
-    }
-    if n == 0 {
-        return Ok(());
-    }
-    for i in 0..n {
-        mem.data[d + i] = data.data[s + i];
-    }
+    let n = stack.pop_value::<i32>() as u32 as u64;
+    let s = range(stack.pop_value::<i32>() as u32 as u64, n, data.data.len())?;
+    let d = range(pop_index(mem, stack), n, mem.data.len())?;
+    mem.data.write(d, &data.data[s..s + n as usize]);
     Ok(())
 }
 
 pub fn data_drop(x: &u32, instance: &mut Instance, store: &mut Store) {
     let a = instance.dataaddrs[*x as usize];
-    store.datas.remove(a);
+    store.datas[a].data.clear();
 }
 
-pub fn data_passiv(datas: &mut OptVec<DataInst>, data: Data) -> Addr {
-    datas.push(DataInst { data: data.init })
-}
-
-pub fn data_active(mem: &mut MemInst, data: Data, offset: usize) {
-    for i in 0..data.init.len() {
-        mem.data[offset + i] = data.init[i];
-    }
+pub fn data_passiv(datas: &mut OptVec<DataInst>, data: Vec<u8>) -> Addr {
+    datas.push(DataInst { data })
 }
@@ -1,11 +1,20 @@
+pub mod atomic;
 pub mod cast;
 pub mod env;
+pub mod gc;
 pub mod importer;
 pub mod instr;
+pub mod linker;
 pub mod memory;
 pub mod runtime;
+#[cfg(feature = "std")]
+pub mod shared;
+pub mod simd;
 pub mod stack;
 pub mod store;
+pub mod suspend;
 pub mod table;
 pub mod trap;
 pub mod value;
+#[cfg(feature = "std")]
+pub mod wasi;
@@ -1,16 +1,22 @@
 #[cfg(not(feature = "std"))]
 use crate::lib::*;
 
-use super::env::Env;
+use super::env::{Env, Memories};
+use super::gc;
 use super::importer::Importer;
 use super::instr::{attach, step};
+use super::linker::{Extern, Linker};
 use super::stack::Stack;
-use super::store::{FuncInst, Store};
-use super::trap::Trap;
+use super::store::{ExnInst, FuncInst, MemInst, Store};
+use super::suspend::{Execution, InterruptHandle, SuspendReason, Suspended};
+use super::trap::{Backtrace, BacktraceFrame, Trap};
 use super::value::{Ref, Value};
-use crate::binary::{Block, Export, Import};
+use crate::binary::{Block, DataMode, ElemMode, Export, Import};
 use crate::binary::{ExportDesc, FuncType, ImportDesc, Instr, Module};
-use crate::binary::{Expr, ValType};
+use crate::binary::{Expr, Names, SubType, TypeIdx, ValType};
+#[cfg(feature = "std")]
+use crate::debug::dwarf::{LineTable, Location};
+use alloc::collections::BTreeMap;
 use core::fmt::Debug;
 
 pub type Addr = usize;
@@ -20,31 +26,52 @@ pub const PAGE_SIZE: usize = 65536;
 pub enum ExecState {
     Continue(usize),
     Return,
-    EnvFunc { name: String, params: Vec<Value> },
+    EnvFunc {
+        module: String,
+        name: String,
+        params: Vec<Value>,
+        /// Result types of the host function.
+        result_types: Vec<ValType>,
+    },
 }
 
 #[derive(Debug, PartialEq, Default, Clone)]
 pub struct Instance {
     pub globaladdrs: Vec<Addr>,
     pub tableaddrs: Vec<Addr>,
-    // In the current version of WebAssembly, all memory instructions
-    // implicitly operate on memory index 0. This restriction may be
-    // lifted in future versions.
-    pub memaddr: Option<Addr>,
-    pub types: Vec<FuncType>,
+    pub memaddrs: Vec<Addr>,
+    pub types: Vec<SubType>,
     pub dataaddrs: Vec<Addr>,
     pub funcaddrs: Vec<Addr>,
     pub elemaddrs: Vec<Addr>,
+    pub tagaddrs: Vec<Addr>,
     pub start: Option<usize>,
     pub exports: Vec<Export>,
+    /// Debug names of the module, used to symbolize backtraces.
+    pub names: Names,
+    /// Line table of the module, used to find source locations.
+    #[cfg(feature = "std")]
+    pub lines: Option<LineTable>,
 }
 
 impl Instance {
+    /// Function type `idx`, which validation guarantees to be one.
+    pub fn functype(&self, idx: TypeIdx) -> &FuncType {
+        self.types[idx as usize].functype().expect("function type")
+    }
+
     pub fn block_to_arity(&self, bt: &Block) -> usize {
         match bt {
             Block::Empty => 0,
             Block::ValType(_) => 1,
-            Block::TypeIdx(idx) => self.types[*idx as usize].1 .0.len(),
+            Block::TypeIdx(idx) => self.functype(*idx).1 .0.len(),
+        }
+    }
+
+    pub fn block_to_params(&self, bt: &Block) -> usize {
+        match bt {
+            Block::Empty | Block::ValType(_) => 0,
+            Block::TypeIdx(idx) => self.functype(*idx).0 .0.len(),
         }
     }
 }
@@ -52,11 +79,25 @@ impl Instance {
 #[derive(Debug)]
 pub struct Runtime {
     pub instrs: Vec<Instr>,
+    /// Code offset of each instruction of `instrs`, see `Func::offsets`.
+    pub code_offsets: Vec<u32>,
     pub instances: Vec<Instance>,
     pub root: usize,
     pub stack: Stack,
     pub pc: usize,
     pub env_name: &'static str,
+    pub linker: Linker,
+    /// Instances shared by module name, see `register`.
+    pub registry: BTreeMap<String, Addr>,
+    /// Remaining fuel, or `None` when execution is not metered.
+    fuel: Option<u64>,
+    cost: fn(&Instr) -> u64,
+    interrupt: InterruptHandle,
+}
+
+/// Fuel consumed by each instruction unless a cost table is set.
+pub fn unit_cost(_: &Instr) -> u64 {
+    1
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -66,7 +107,64 @@ pub enum RuntimeError {
     Env(&'static str),
     ConstantExpression,
     NoStartFunction,
-    Trap(Trap),
+    IncompatibleImport(String, String),
+    /// A trap, with the call stack at the trapping instruction. The
+    /// backtrace is empty for traps outside of function execution.
+    Trap(Trap, Backtrace),
+    /// An exception was thrown and not caught by any handler.
+    Exception(ExnInst),
+    /// The bytes of a memory could not be allocated.
+    OutOfMemory,
+}
+
+impl core::fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            RuntimeError::ModuleNotFound(name) => write!(f, "module not found: {}", name),
+            RuntimeError::NotFound(ImportType::Func(name)) => {
+                write!(f, "function not found: {}", name)
+            }
+            RuntimeError::NotFound(ImportType::Table(name)) => {
+                write!(f, "table not found: {}", name)
+            }
+            RuntimeError::NotFound(ImportType::Global(name)) => {
+                write!(f, "global not found: {}", name)
+            }
+            RuntimeError::NotFound(ImportType::Mem) => write!(f, "memory not found"),
+            RuntimeError::NotFound(ImportType::Tag(name)) => write!(f, "tag not found: {}", name),
+            RuntimeError::Env(err) => write!(f, "environment error: {}", err),
+            RuntimeError::ConstantExpression => write!(f, "invalid constant expression"),
+            RuntimeError::NoStartFunction => write!(f, "no start function"),
+            RuntimeError::IncompatibleImport(module, name) => {
+                write!(f, "incompatible import type: {}.{}", module, name)
+            }
+            RuntimeError::Trap(trap, backtrace) if backtrace.frames.is_empty() => {
+                write!(f, "{}", trap)
+            }
+            RuntimeError::Trap(trap, backtrace) => write!(f, "{}\n{}", trap, backtrace),
+            RuntimeError::Exception(_) => write!(f, "uncaught exception"),
+            RuntimeError::OutOfMemory => write!(f, "failed to allocate memory"),
+        }
+    }
+}
+
+impl From<Trap> for RuntimeError {
+    fn from(trap: Trap) -> Self {
+        match trap {
+            Trap::UncaughtException(exn) => RuntimeError::Exception(exn),
+            trap => RuntimeError::Trap(trap, Backtrace::default()),
+        }
+    }
+}
+
+impl RuntimeError {
+    /// The trap, if this error is one.
+    pub fn trap(&self) -> Option<&Trap> {
+        match self {
+            RuntimeError::Trap(trap, _) => Some(trap),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -75,18 +173,84 @@ pub enum ImportType {
     Table(String),
     Global(String),
     Mem,
+    Tag(String),
 }
 
-pub fn eval_const(expr: &Expr) -> Result<Value, RuntimeError> {
-    Ok(match expr.0[0] {
-        Instr::I32Const(value) => Value::I32(value),
-        Instr::I64Const(value) => Value::I64(value),
-        Instr::F32Const(value) => Value::F32(value),
-        Instr::F64Const(value) => Value::F64(value),
-        Instr::RefNull(_) => Value::Ref(Ref::Null),
-        Instr::RefFunc(idx) => Value::I32(idx as i32),
-        _ => return Err(RuntimeError::ConstantExpression),
-    })
+/// Evaluates the constant expression `expr` in `instance`, whose globals and
+/// functions must already be allocated in `store`. Besides constants this
+/// covers `global.get`, `ref.func`, the extended-const integer arithmetic and
+/// the GC allocations, whose objects belong to the instance at `instance_addr`.
+pub fn eval_const(
+    expr: &Expr,
+    instance: &Instance,
+    instance_addr: Addr,
+    store: &mut Store,
+) -> Result<Value, RuntimeError> {
+    let mut stack: Vec<Value> = vec![];
+    for instr in expr.0.iter() {
+        let value = match *instr {
+            Instr::I32Const(value) => Value::I32(value),
+            Instr::I64Const(value) => Value::I64(value),
+            Instr::F32Const(value) => Value::F32(value),
+            Instr::F64Const(value) => Value::F64(value),
+            Instr::V128Const(value) => Value::V128(value),
+            Instr::RefNull(_) => Value::Ref(Ref::Null),
+            Instr::RefFunc(idx) => match instance.funcaddrs.get(idx as usize) {
+                Some(&addr) => Value::Ref(Ref::Func(addr)),
+                None => return Err(RuntimeError::ConstantExpression),
+            },
+            Instr::GlobalGet(idx) => match instance.globaladdrs.get(idx as usize) {
+                Some(&addr) => store.globals[addr].value,
+                None => return Err(RuntimeError::ConstantExpression),
+            },
+            Instr::I32Add
+            | Instr::I32Sub
+            | Instr::I32Mul
+            | Instr::I64Add
+            | Instr::I64Sub
+            | Instr::I64Mul => {
+                let rhs = stack.pop();
+                let lhs = stack.pop();
+                match (instr, lhs, rhs) {
+                    (Instr::I32Add, Some(Value::I32(a)), Some(Value::I32(b))) => {
+                        Value::I32(a.wrapping_add(b))
+                    }
+                    (Instr::I32Sub, Some(Value::I32(a)), Some(Value::I32(b))) => {
+                        Value::I32(a.wrapping_sub(b))
+                    }
+                    (Instr::I32Mul, Some(Value::I32(a)), Some(Value::I32(b))) => {
+                        Value::I32(a.wrapping_mul(b))
+                    }
+                    (Instr::I64Add, Some(Value::I64(a)), Some(Value::I64(b))) => {
+                        Value::I64(a.wrapping_add(b))
+                    }
+                    (Instr::I64Sub, Some(Value::I64(a)), Some(Value::I64(b))) => {
+                        Value::I64(a.wrapping_sub(b))
+                    }
+                    (Instr::I64Mul, Some(Value::I64(a)), Some(Value::I64(b))) => {
+                        Value::I64(a.wrapping_mul(b))
+                    }
+                    _ => return Err(RuntimeError::ConstantExpression),
+                }
+            }
+            Instr::StructNew(_)
+            | Instr::StructNewDefault(_)
+            | Instr::ArrayNew(_)
+            | Instr::ArrayNewDefault(_)
+            | Instr::ArrayNewFixed(..)
+            | Instr::RefI31
+            | Instr::AnyConvertExtern
+            | Instr::ExternConvertAny => {
+                gc::eval_const(instr, instance, instance_addr, store, &mut stack)?
+            }
+            _ => return Err(RuntimeError::ConstantExpression),
+        };
+        stack.push(value);
+    }
+    match (stack.pop(), stack.is_empty()) {
+        (Some(value), true) => Ok(value),
+        _ => Err(RuntimeError::ConstantExpression),
+    }
 }
 
 impl Runtime {
@@ -95,12 +259,16 @@ impl Runtime {
         functype: FuncType,
         locals: Vec<ValType>,
         instrs: Vec<Instr>,
+        offsets: Vec<u32>,
         instance_addr: Addr,
         store: &mut Store,
     ) -> Addr {
         let start = self.instrs.len();
         self.instrs.extend(instrs);
         self.instrs.extend(vec![Instr::Return]);
+        // Functions built without offsets map to offset 0.
+        self.code_offsets.extend(offsets);
+        self.code_offsets.resize(self.instrs.len(), 0);
         store.funcs.push(FuncInst::InnerFunc {
             instance_addr,
             start,
@@ -117,14 +285,27 @@ impl Runtime {
         Runtime {
             root: 0,
             instrs: vec![],
+            code_offsets: vec![],
             instances: vec![],
             stack: Stack::new(),
             pc: 0,
             env_name,
+            linker: Linker::new(),
+            registry: BTreeMap::new(),
+            fuel: None,
+            cost: unit_cost,
+            interrupt: InterruptHandle::new(),
         }
     }
 
-    pub fn add_module(&mut self, store: &mut Store, module: Module) -> Result<(), RuntimeError> {
+    /// Instantiates `module`, which may only import host functions, and
+    /// runs its start function with `env`.
+    pub fn add_module<E: Env>(
+        &mut self,
+        store: &mut Store,
+        env: &mut E,
+        module: Module,
+    ) -> Result<(), RuntimeError> {
         struct EmptyImporter {}
         impl Importer for EmptyImporter {
             fn import(&mut self, _: &str) -> Option<Module> {
@@ -133,11 +314,46 @@ impl Runtime {
         }
 
         let mut importer = EmptyImporter {};
-        let instance = self.new_instance(store, module, &mut importer)?;
+        self.root = self.new_instance(store, env, module, &mut importer)?;
+        Ok(())
+    }
 
-        self.instances.push(instance);
+    /// Adds fuel, enabling metering if it was disabled. Execution traps with
+    /// `Trap::OutOfFuel` before an instruction whose cost exceeds the
+    /// remaining fuel.
+    pub fn add_fuel(&mut self, fuel: u64) {
+        self.fuel = Some(self.fuel.unwrap_or(0).saturating_add(fuel));
+    }
+
+    /// Remaining fuel, or `None` when execution is not metered.
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
 
-        self.root = self.instances.len() - 1;
+    /// Disables metering.
+    pub fn remove_fuel(&mut self) {
+        self.fuel = None;
+    }
+
+    /// Sets the fuel consumed by each instruction.
+    pub fn set_cost_table(&mut self, cost: fn(&Instr) -> u64) {
+        self.cost = cost;
+    }
+
+    /// Handle which stops execution of this runtime with
+    /// `Trap::Interrupted`, or suspends a resumable invocation.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
+    }
+
+    fn consume_fuel(&mut self) -> Result<(), Trap> {
+        if let Some(fuel) = self.fuel {
+            let cost = (self.cost)(&self.instrs[self.pc]);
+            if cost > fuel {
+                return Err(Trap::OutOfFuel);
+            }
+            self.fuel = Some(fuel - cost);
+        }
         Ok(())
     }
 
@@ -149,233 +365,487 @@ impl Runtime {
         self.pc += 1;
     }
 
-    pub fn import_module<I: Importer>(
+    /// Instantiates the module provided by `importer` as `modname` and runs
+    /// its start function with `env`.
+    pub fn import_module<I: Importer, E: Env>(
         &mut self,
         store: &mut Store,
+        env: &mut E,
         importer: &mut I,
         modname: &str,
     ) -> Result<(), RuntimeError> {
         let module = importer
             .import(modname)
             .ok_or(RuntimeError::ModuleNotFound(modname.into()))?;
-        let instance = self.new_instance(store, module, importer)?;
-
-        self.instances.push(instance);
-
-        self.root = self.instances.len() - 1;
+        self.root = self.new_instance(store, env, module, importer)?;
         Ok(())
     }
 
-    fn new_instance<I: Importer>(
+    /// Instantiates `module` following the order of the specification and
+    /// returns the address of the new instance.
+    ///
+    /// If an import cannot be resolved, a constant expression fails or a
+    /// segment is out of bounds, the store is left as it was before. If the
+    /// start function traps, the instance stays allocated since its segments
+    /// may already have been written to imported tables and memories.
+    fn new_instance<I: Importer, E: Env>(
         &mut self,
         store: &mut Store,
+        env: &mut E,
         module: Module,
         importer: &mut I,
-    ) -> Result<Instance, RuntimeError> {
-        let mut funcaddrs = vec![];
-        let mut globaladdrs = vec![];
-        let mut tableaddrs = vec![];
-        let mut memaddr = None;
+    ) -> Result<Addr, RuntimeError> {
+        let mut host_funcs = vec![];
+        let mut instance = Instance::default();
+        if let Err(err) = self.resolve_imports(
+            store,
+            env,
+            &module,
+            importer,
+            &mut instance,
+            &mut host_funcs,
+        ) {
+            store.free_instance(&Instance {
+                funcaddrs: host_funcs,
+                ..Instance::default()
+            });
+            return Err(err);
+        }
+
+        let imports = instance.clone();
+        let instrs = self.instrs.len();
+        if let Err(err) = self.allocate_instance(store, module, &mut instance) {
+            let mut allocated = Instance {
+                funcaddrs: host_funcs,
+                elemaddrs: instance.elemaddrs,
+                dataaddrs: instance.dataaddrs,
+                ..Instance::default()
+            };
+            allocated
+                .funcaddrs
+                .extend_from_slice(&instance.funcaddrs[imports.funcaddrs.len()..]);
+            allocated.globaladdrs = instance.globaladdrs[imports.globaladdrs.len()..].to_vec();
+            allocated.tableaddrs = instance.tableaddrs[imports.tableaddrs.len()..].to_vec();
+            allocated.memaddrs = instance.memaddrs[imports.memaddrs.len()..].to_vec();
+            allocated.tagaddrs = instance.tagaddrs[imports.tagaddrs.len()..].to_vec();
+            store.free_instance(&allocated);
+            self.instrs.truncate(instrs);
+            self.code_offsets.truncate(instrs);
+            return Err(err);
+        }
+
+        self.instances.push(instance);
+        let addr = self.instances.len() - 1;
+        if self.instances[addr].start.is_some() {
+            let root = self.root;
+            self.root = addr;
+            let result = self.start(store, env);
+            self.root = root;
+            result?;
+        }
+        Ok(addr)
+    }
 
-        for import in module.imports {
+    fn resolve_imports<I: Importer, E: Env>(
+        &mut self,
+        store: &mut Store,
+        env: &mut E,
+        module: &Module,
+        importer: &mut I,
+        instance: &mut Instance,
+        host_funcs: &mut Vec<Addr>,
+    ) -> Result<(), RuntimeError> {
+        for import in module.imports.iter() {
+            if let ImportDesc::Func(ty) = import.desc {
+                if let Some(functype) = self.linker.functype(&import.module, &import.name) {
+                    if module.types[ty as usize].functype() != Some(functype) {
+                        return Err(RuntimeError::IncompatibleImport(
+                            import.module.clone(),
+                            import.name.clone(),
+                        ));
+                    }
+                    let addr = self.import_env_func(
+                        store,
+                        functype.clone(),
+                        import.module.clone(),
+                        import.name.clone(),
+                    );
+                    host_funcs.push(addr);
+                    instance.funcaddrs.push(addr);
+                    continue;
+                }
+            }
+            if let Some(ext) = self.linker.get_extern(&import.module, &import.name) {
+                if !ext.matches(store, &import.desc) {
+                    return Err(RuntimeError::IncompatibleImport(
+                        import.module.clone(),
+                        import.name.clone(),
+                    ));
+                }
+                match ext {
+                    Extern::Memory(addr) => instance.memaddrs.push(addr),
+                    Extern::Table(addr) => instance.tableaddrs.push(addr),
+                    Extern::Global(addr) => instance.globaladdrs.push(addr),
+                }
+                continue;
+            }
             if import.module == self.env_name {
                 match import.desc {
-                    ImportDesc::Func(ty) => funcaddrs.push(self.import_env_func(
-                        store,
-                        module.types[ty as usize].clone(),
-                        import.name,
-                    )),
-                    ImportDesc::Table(_) => {}
-                    ImportDesc::Mem(_) => {}
-                    ImportDesc::Global(_) => {}
+                    ImportDesc::Func(ty) => {
+                        let addr = self.import_env_func(
+                            store,
+                            module.types[ty as usize].functype().unwrap().clone(),
+                            import.module.clone(),
+                            import.name.clone(),
+                        );
+                        host_funcs.push(addr);
+                        instance.funcaddrs.push(addr);
+                    }
+                    ImportDesc::Table(_) => {
+                        return Err(RuntimeError::NotFound(ImportType::Table(
+                            import.name.clone(),
+                        )))
+                    }
+                    ImportDesc::Mem(_) => return Err(RuntimeError::NotFound(ImportType::Mem)),
+                    ImportDesc::Global(_) => {
+                        return Err(RuntimeError::NotFound(ImportType::Global(
+                            import.name.clone(),
+                        )))
+                    }
+                    ImportDesc::Tag(_) => {
+                        return Err(RuntimeError::NotFound(ImportType::Tag(import.name.clone())))
+                    }
                 }
             } else {
+                // Exports of other instances are checked like linker externs.
+                let incompatible =
+                    || RuntimeError::IncompatibleImport(import.module.clone(), import.name.clone());
                 match import.desc {
-                    ImportDesc::Func(_) => {
-                        funcaddrs.push(self.import_func(store, &import, importer)?)
+                    ImportDesc::Func(ty) => {
+                        let addr = self.import_func(store, env, import, importer)?;
+                        if module.types[ty as usize].functype()
+                            != Some(store.funcs[addr].functype())
+                        {
+                            return Err(incompatible());
+                        }
+                        instance.funcaddrs.push(addr)
                     }
                     ImportDesc::Mem(_) => {
-                        memaddr = Some(self.import_memory(store, &import, importer)?)
+                        let addr = self.import_memory(store, env, import, importer)?;
+                        if !Extern::Memory(addr).matches(store, &import.desc) {
+                            return Err(incompatible());
+                        }
+                        instance.memaddrs.push(addr)
                     }
                     ImportDesc::Table(_) => {
-                        tableaddrs.push(self.import_table(store, &import, importer)?)
+                        let addr = self.import_table(store, env, import, importer)?;
+                        if !Extern::Table(addr).matches(store, &import.desc) {
+                            return Err(incompatible());
+                        }
+                        instance.tableaddrs.push(addr)
                     }
                     ImportDesc::Global(_) => {
-                        globaladdrs.push(self.import_global(store, &import, importer)?)
+                        let addr = self.import_global(store, env, import, importer)?;
+                        if !Extern::Global(addr).matches(store, &import.desc) {
+                            return Err(incompatible());
+                        }
+                        instance.globaladdrs.push(addr)
+                    }
+                    ImportDesc::Tag(ref tag) => {
+                        let addr = self.import_tag(store, env, import, importer)?;
+                        let functype = module.types[tag.typeidx as usize].functype();
+                        if functype != Some(&store.tags[addr].functype) {
+                            return Err(incompatible());
+                        }
+                        instance.tagaddrs.push(addr)
                     }
                 }
             }
         }
+        Ok(())
+    }
+
+    /// Allocates the entities defined by `module` into `instance`, which
+    /// holds its imports, then initializes tables and memories from the
+    /// active segments once all of them are known to be in bounds.
+    fn allocate_instance(
+        &mut self,
+        store: &mut Store,
+        module: Module,
+        instance: &mut Instance,
+    ) -> Result<(), RuntimeError> {
+        let instance_addr = self.instances.len();
+        for func in module.funcs {
+            let functype = module.types[func.typeidx as usize]
+                .functype()
+                .unwrap()
+                .clone();
+            instance.funcaddrs.push(self.allocate_func(
+                functype,
+                func.locals,
+                func.body.0,
+                func.offsets,
+                instance_addr,
+                store,
+            ));
+        }
+
+        for tag in module.tags.iter() {
+            let functype = module.types[tag.typeidx as usize].functype().unwrap();
+            instance.tagaddrs.push(store.allocate_tag(functype.clone()));
+        }
+
+        instance.types = module.types;
+        instance.start = module.start.map(|idx| idx as usize);
+        instance.exports = module.exports;
+        instance.names = module.names;
+        #[cfg(feature = "std")]
+        {
+            instance.lines = module.lines;
+        }
 
+        // Each global initializer sees the globals defined before it.
         for global in module.globals {
-            globaladdrs.push(store.allocate_global(global)?);
+            let addr = store.allocate_global(global, instance, instance_addr)?;
+            instance.globaladdrs.push(addr);
         }
 
         for table in module.tables {
-            tableaddrs.push(store.allocate_table(table));
+            instance.tableaddrs.push(store.allocate_table(table));
+        }
+
+        for mem in module.mems.iter() {
+            instance.memaddrs.push(store.allocate_mem(mem)?);
         }
 
-        let mut elemaddrs = vec![];
-        for elem in module.elems {
-            if let Some(addr) = store.allocate_elem(elem)? {
-                elemaddrs.push(addr);
+        for elem in module.elems.iter() {
+            let addr = store.allocate_elem(elem, instance, instance_addr)?;
+            instance.elemaddrs.push(addr);
+        }
+
+        for data in module.datas.iter() {
+            instance.dataaddrs.push(store.allocate_data(data));
+        }
+
+        let mut elem_inits = vec![];
+        for (elem, &elemaddr) in module.elems.iter().zip(instance.elemaddrs.iter()) {
+            if let ElemMode::Active { tableidx, offset } = &elem.mode {
+                let offset = match eval_const(offset, instance, instance_addr, store)? {
+                    Value::I32(v) => v as u32 as usize,
+                    _ => return Err(RuntimeError::ConstantExpression),
+                };
+                let tableaddr = instance.tableaddrs[*tableidx as usize];
+                let len = store.elems[elemaddr].elem.len();
+                if offset + len > store.tables[tableaddr].elem.len() {
+                    return Err(Trap::TableOutOfRange.into());
+                }
+                elem_inits.push((tableaddr, offset, elemaddr));
             }
         }
 
-        let mut inner_funcaddr = vec![];
-        for func in module.funcs {
-            let functype = module.types[func.typeidx as usize].clone();
-            let addr = self.allocate_func(
-                functype,
-                func.locals,
-                func.body.0,
-                self.instances.len(),
-                store,
-            );
-            inner_funcaddr.push(addr);
-            funcaddrs.push(addr);
+        let mut data_inits = vec![];
+        for (data, &dataaddr) in module.datas.iter().zip(instance.dataaddrs.iter()) {
+            if let DataMode::Active { memidx, offset } = &data.mode {
+                let offset = match eval_const(offset, instance, instance_addr, store)? {
+                    Value::I32(v) => v as u32 as u64,
+                    Value::I64(v) => v as u64,
+                    _ => return Err(RuntimeError::ConstantExpression),
+                };
+                let memaddr = instance.memaddrs[*memidx as usize];
+                let len = store.datas[dataaddr].data.len() as u64;
+                match offset.checked_add(len) {
+                    Some(end) if end <= store.mems[memaddr].data.len() as u64 => {}
+                    _ => return Err(Trap::MemoryOutOfBounds.into()),
+                }
+                data_inits.push((memaddr, offset as usize, dataaddr));
+            }
         }
-        let instance_addr = self.instances.len();
-        store.update_func_inst(&inner_funcaddr, instance_addr);
 
-        if module.mems.len() > 0 {
-            memaddr = Some(store.allocate_mem(&module.mems[0]))
+        for (tableaddr, offset, elemaddr) in elem_inits {
+            let elem = &store.elems[elemaddr].elem;
+            store.tables[tableaddr].elem[offset..offset + elem.len()].copy_from_slice(elem);
+        }
+        for (memaddr, offset, dataaddr) in data_inits {
+            let data = &store.datas[dataaddr].data;
+            store.mems[memaddr].data.write(offset, data);
         }
 
-        let mut dataaddrs = vec![];
-        for data in module.datas {
-            let memidx = memaddr.unwrap();
-            if let Some(addr) = store.allocate_data(memidx, data)? {
-                dataaddrs.push(addr);
+        // Active and declarative segments are dropped once applied.
+        for (elem, &elemaddr) in module.elems.iter().zip(instance.elemaddrs.iter()) {
+            if elem.mode != ElemMode::Passiv {
+                store.elems[elemaddr].elem.clear();
+            }
+        }
+        for (data, &dataaddr) in module.datas.iter().zip(instance.dataaddrs.iter()) {
+            if let DataMode::Active { .. } = data.mode {
+                store.datas[dataaddr].data.clear();
             }
         }
+        Ok(())
+    }
 
-        Ok(Instance {
-            funcaddrs,
-            types: module.types,
-            globaladdrs,
-            tableaddrs,
-            elemaddrs,
-            memaddr,
-            dataaddrs,
-            start: module.start.map(|idx| idx as usize),
-            exports: module.exports,
+    pub fn import_env_func(
+        &mut self,
+        store: &mut Store,
+        functype: FuncType,
+        module: String,
+        name: String,
+    ) -> Addr {
+        store.funcs.push(FuncInst::HostFunc {
+            functype,
+            module,
+            name,
         })
     }
 
-    pub fn import_env_func(&mut self, store: &mut Store, functype: FuncType, name: String) -> Addr {
-        store.funcs.push(FuncInst::HostFunc { functype, name })
+    /// Calls a host function, preferring functions registered in the linker
+    /// over the name-only `Env`.
+    pub(crate) fn call_host<E: Env>(
+        &mut self,
+        store: &mut Store,
+        env: &mut E,
+        module: &str,
+        name: &str,
+        params: Vec<Value>,
+    ) -> Result<Vec<Value>, &'static str> {
+        if self.linker.contains(module, name) {
+            let memory = self.host_memory(store);
+            self.linker.call(module, name, params, memory)
+        } else {
+            let memories = Memories::new(&mut store.mems, self.caller());
+            env.call_with_memories(name, params, memories)
+        }
+    }
+
+    /// Instance of the innermost frame, whose memories host functions
+    /// access, or the root instance for a host function invoked directly.
+    fn caller(&self) -> &Instance {
+        &self.instances[self.caller_addr()]
+    }
+
+    fn caller_addr(&self) -> Addr {
+        self.stack
+            .frames()
+            .last()
+            .map_or(self.root, |frame| frame.instance_addr)
+    }
+
+    fn host_memory<'a>(&self, store: &'a mut Store) -> Option<&'a mut MemInst> {
+        self.caller().memaddrs.first().map(|&a| &mut store.mems[a])
+    }
+
+    /// Makes the exports of `instance` available to modules importing from
+    /// `name`, like the `register` command of the spec test suite.
+    pub fn register(&mut self, name: &str, instance: Addr) {
+        self.registry.insert(name.into(), instance);
     }
 
-    pub fn import_func<I: Importer>(
+    /// Returns the instance registered under `modname`, instantiating the
+    /// module provided by the importer and registering it on first use.
+    fn resolve_instance<I: Importer, E: Env>(
         &mut self,
         store: &mut Store,
-        import: &Import,
+        env: &mut E,
+        modname: &str,
         importer: &mut I,
-    ) -> Result<usize, RuntimeError> {
+    ) -> Result<Addr, RuntimeError> {
+        if let Some(&addr) = self.registry.get(modname) {
+            return Ok(addr);
+        }
         let module = importer
-            .import(&import.module)
-            .ok_or_else(|| RuntimeError::ModuleNotFound(import.module.clone()))?;
-        let instance = self.new_instance(store, module, importer)?;
-        if let Some(desc) = instance
+            .import(modname)
+            .ok_or_else(|| RuntimeError::ModuleNotFound(modname.into()))?;
+        let addr = self.new_instance(store, env, module, importer)?;
+        self.register(modname, addr);
+        Ok(addr)
+    }
+
+    fn resolve_export<I: Importer, E: Env>(
+        &mut self,
+        store: &mut Store,
+        env: &mut E,
+        import: &Import,
+        importer: &mut I,
+    ) -> Result<(&Instance, Option<ExportDesc>), RuntimeError> {
+        let addr = self.resolve_instance(store, env, &import.module, importer)?;
+        let instance = &self.instances[addr];
+        let desc = instance
             .exports
             .iter()
-            .filter(|export| export.name == import.name)
-            .map(|export| &export.desc)
-            .next()
-        {
-            if let ExportDesc::Func(index) = desc {
-                let ret = Ok(instance.funcaddrs[*index as usize]);
-                self.instances.push(instance);
-                return ret;
-            }
+            .find(|export| export.name == import.name)
+            .map(|export| export.desc.clone());
+        Ok((instance, desc))
+    }
+
+    pub fn import_func<I: Importer, E: Env>(
+        &mut self,
+        store: &mut Store,
+        env: &mut E,
+        import: &Import,
+        importer: &mut I,
+    ) -> Result<usize, RuntimeError> {
+        match self.resolve_export(store, env, import, importer)? {
+            (instance, Some(ExportDesc::Func(index))) => Ok(instance.funcaddrs[index as usize]),
+            _ => Err(RuntimeError::NotFound(ImportType::Func(
+                import.name.clone(),
+            ))),
         }
-        Err(RuntimeError::NotFound(ImportType::Func(
-            import.name.clone(),
-        )))
     }
 
-    pub fn import_memory<I: Importer>(
+    pub fn import_memory<I: Importer, E: Env>(
         &mut self,
         store: &mut Store,
+        env: &mut E,
         import: &Import,
         importer: &mut I,
     ) -> Result<Addr, RuntimeError> {
-        let module = importer
-            .import(&import.module)
-            .ok_or_else(|| RuntimeError::ModuleNotFound(import.module.clone()))?;
-        let instance = self.new_instance(store, module, importer)?;
-        if let Some(desc) = instance
-            .exports
-            .iter()
-            .filter(|e| e.name == import.name)
-            .map(|export| &export.desc)
-            .next()
-        {
-            if let ExportDesc::Mem(_) = desc {
-                if let Some(addr) = instance.memaddr {
-                    self.instances.push(instance);
-                    return Ok(addr);
-                }
-            }
+        match self.resolve_export(store, env, import, importer)? {
+            (instance, Some(ExportDesc::Mem(index))) => Ok(instance.memaddrs[index as usize]),
+            _ => Err(RuntimeError::NotFound(ImportType::Mem)),
         }
-        Err(RuntimeError::NotFound(ImportType::Mem))
     }
 
-    pub fn import_table<I: Importer>(
+    pub fn import_table<I: Importer, E: Env>(
         &mut self,
         store: &mut Store,
+        env: &mut E,
         import: &Import,
         importer: &mut I,
     ) -> Result<Addr, RuntimeError> {
-        let module = importer
-            .import(&import.module)
-            .ok_or_else(|| RuntimeError::ModuleNotFound(import.module.clone()))?;
-        let instance = self.new_instance(store, module, importer)?;
-        if let Some(desc) = instance
-            .exports
-            .iter()
-            .filter(|export| export.name == import.name)
-            .map(|export| &export.desc)
-            .next()
-        {
-            if let ExportDesc::Table(addr) = desc {
-                let ret = Ok(instance.tableaddrs[*addr as usize]);
-                self.instances.push(instance);
-                return ret;
-            }
+        match self.resolve_export(store, env, import, importer)? {
+            (instance, Some(ExportDesc::Table(index))) => Ok(instance.tableaddrs[index as usize]),
+            _ => Err(RuntimeError::NotFound(ImportType::Table(
+                import.name.clone(),
+            ))),
+        }
+    }
+
+    pub fn import_global<I: Importer, E: Env>(
+        &mut self,
+        store: &mut Store,
+        env: &mut E,
+        import: &Import,
+        importer: &mut I,
+    ) -> Result<Addr, RuntimeError> {
+        match self.resolve_export(store, env, import, importer)? {
+            (instance, Some(ExportDesc::Global(index))) => Ok(instance.globaladdrs[index as usize]),
+            _ => Err(RuntimeError::NotFound(ImportType::Global(
+                import.name.clone(),
+            ))),
         }
-        Err(RuntimeError::NotFound(ImportType::Table(
-            import.name.clone(),
-        )))
     }
 
-    pub fn import_global<I: Importer>(
+    pub fn import_tag<I: Importer, E: Env>(
         &mut self,
         store: &mut Store,
+        env: &mut E,
         import: &Import,
         importer: &mut I,
     ) -> Result<Addr, RuntimeError> {
-        let module = importer
-            .import(&import.module)
-            .ok_or_else(|| RuntimeError::ModuleNotFound(import.module.clone()))?;
-        let instance = self.new_instance(store, module, importer)?;
-        if let Some(desc) = instance
-            .exports
-            .iter()
-            .filter(|export| export.name == import.name)
-            .map(|export| &export.desc)
-            .next()
-        {
-            if let ExportDesc::Global(addr) = desc {
-                let ret = Ok(instance.globaladdrs[*addr as usize]);
-                self.instances.push(instance);
-                return ret;
-            }
+        match self.resolve_export(store, env, import, importer)? {
+            (instance, Some(ExportDesc::Tag(index))) => Ok(instance.tagaddrs[index as usize]),
+            _ => Err(RuntimeError::NotFound(ImportType::Tag(import.name.clone()))),
         }
-        Err(RuntimeError::NotFound(ImportType::Global(
-            import.name.clone(),
-        )))
     }
 
     pub fn start<E: Env>(&mut self, store: &mut Store, env: &mut E) -> Result<(), RuntimeError> {
@@ -383,12 +853,15 @@ impl Runtime {
             ExecState::Continue(pc) => {
                 self.pc = pc;
                 self.exec(store, env)
-                    .map_err(|trap| RuntimeError::Trap(trap))?;
+                    .map_err(|trap| self.trap_error(store, trap))?;
             }
-            ExecState::EnvFunc { name, params } => {
-                let instance = &self.instances[self.root];
-                let memory = instance.memaddr.map(|a| &mut store.mems[a]);
-                env.call(&name, params, memory)
+            ExecState::EnvFunc {
+                module,
+                name,
+                params,
+                ..
+            } => {
+                self.call_host(store, env, &module, &name, params)
                     .map_err(|err| RuntimeError::Env(err))?;
             }
             _ => {}
@@ -407,24 +880,27 @@ impl Runtime {
             ExecState::Continue(pc) => {
                 self.pc = pc;
                 self.exec(store, env)
-                    .map_err(|trap| RuntimeError::Trap(trap))
+                    .map_err(|trap| self.trap_error(store, trap))
             }
             ExecState::Return => unreachable!(),
-            ExecState::EnvFunc { name, params } => {
-                let instance = &self.instances[self.root];
-                let memory = instance.memaddr.map(|a| &mut store.mems[a]);
-                env.call(&name, params, memory)
-                    .map_err(|err| RuntimeError::Env(err))
-            }
+            ExecState::EnvFunc {
+                module,
+                name,
+                params,
+                ..
+            } => self
+                .call_host(store, env, &module, &name, params)
+                .map_err(|err| RuntimeError::Env(err)),
         }
     }
 
     fn attach(
-        func: &FuncInst,
+        store: &Store,
+        addr: Addr,
         stack: &mut Stack,
         pc: &mut usize,
     ) -> Result<ExecState, RuntimeError> {
-        match attach(func, stack, *pc).map_err(|trap| RuntimeError::Trap(trap)) {
+        match attach(store, addr, stack, *pc).map_err(RuntimeError::from) {
             Ok(state) => {
                 if let ExecState::Continue(start) = state {
                     *pc = start;
@@ -441,13 +917,31 @@ impl Runtime {
         let instance = &self.instances[self.root];
         self.stack = Stack::new();
         if let Some(index) = instance.start {
-            let func = &store.funcs[index];
-            Self::attach(func, &mut self.stack, &mut self.pc)
+            Self::attach(
+                store,
+                instance.funcaddrs[index],
+                &mut self.stack,
+                &mut self.pc,
+            )
         } else {
             Err(RuntimeError::NoStartFunction)
         }
     }
 
+    pub fn export_functype<'a>(&self, store: &'a Store, name: &str) -> Option<&'a FuncType> {
+        let instance = &self.instances[self.root];
+        instance
+            .exports
+            .iter()
+            .find(|export| export.name == name)
+            .and_then(|export| match export.desc {
+                ExportDesc::Func(index) => {
+                    Some(store.funcs[instance.funcaddrs[index as usize]].functype())
+                }
+                _ => None,
+            })
+    }
+
     pub fn attach_invoke(
         &mut self,
         store: &mut Store,
@@ -464,9 +958,9 @@ impl Runtime {
         {
             match export.desc {
                 ExportDesc::Func(index) => {
-                    let func = &store.funcs[instance.funcaddrs[index as usize]];
+                    let addr = instance.funcaddrs[index as usize];
                     self.stack.extend_values(params);
-                    Self::attach(func, &mut self.stack, &mut self.pc)
+                    Self::attach(store, addr, &mut self.stack, &mut self.pc)
                 }
                 _ => Err(RuntimeError::NotFound(ImportType::Func(name.into()))),
             }
@@ -475,8 +969,38 @@ impl Runtime {
         }
     }
 
+    /// Continues an invocation stopped by `Trap::OutOfFuel`, typically after
+    /// `add_fuel`, or by `Trap::Interrupted`.
+    pub fn resume<E: Env>(
+        &mut self,
+        store: &mut Store,
+        env: &mut E,
+    ) -> Result<Vec<Value>, RuntimeError> {
+        self.exec(store, env)
+            .map_err(|trap| self.trap_error(store, trap))
+    }
+
     fn exec<E: Env>(&mut self, store: &mut Store, env: &mut E) -> Result<Vec<Value>, Trap> {
+        while let ExecState::EnvFunc { name, params, .. } = self.run(store)? {
+            let memories = Memories::new(&mut store.mems, self.caller());
+            let results = env
+                .call_with_memories(&name, params, memories)
+                .map_err(Trap::Env)?;
+            self.stack.extend_values(results);
+            self.pc += 1;
+        }
+        Ok(self.stack.get_returns())
+    }
+
+    /// Executes until the outermost function returns or a host function
+    /// which is not registered in the linker is called. In the latter case
+    /// pc stays at the call.
+    fn run(&mut self, store: &mut Store) -> Result<ExecState, Trap> {
         loop {
+            if self.interrupt.take() {
+                return Err(Trap::Interrupted);
+            }
+            self.consume_fuel()?;
             match step(
                 &mut self.instances,
                 &self.instrs,
@@ -487,24 +1011,138 @@ impl Runtime {
                 ExecState::Continue(pc) => {
                     self.pc = pc;
                 }
-                ExecState::Return => break,
-                ExecState::EnvFunc { params, name } => {
-                    let instance = &self.instances[self.root];
-                    let memory = instance.memaddr.map(|a| &mut store.mems[a]);
-                    let results = env
-                        .call(&name, params, memory)
-                        .map_err(|err| Trap::Env(err))?;
-                    for result in results {
-                        self.stack.push_value(result);
-                    }
+                ExecState::EnvFunc {
+                    module,
+                    name,
+                    params,
+                    ..
+                } if self.linker.contains(&module, &name) => {
+                    let memory = self.host_memory(store);
+                    let results = self
+                        .linker
+                        .call(&module, &name, params, memory)
+                        .map_err(Trap::Env)?;
+                    self.stack.extend_values(results);
                     self.pc += 1;
                 }
+                state => return Ok(state),
             }
         }
-        Ok(self.stack.get_returns())
+    }
+
+    /// Like `invoke`, but returns `Execution::Suspended` instead of calling
+    /// an `Env` for host functions missing from the linker, when fuel runs
+    /// out or when interrupted.
+    pub fn invoke_resumable(
+        &mut self,
+        store: &mut Store,
+        name: &str,
+        params: Vec<Value>,
+    ) -> Result<Execution, RuntimeError> {
+        match self.attach_invoke(store, name, params)? {
+            ExecState::Continue(pc) => {
+                self.pc = pc;
+                self.drive(store)
+            }
+            ExecState::Return => unreachable!(),
+            ExecState::EnvFunc {
+                module,
+                name,
+                params,
+                result_types,
+            } => {
+                if self.linker.contains(&module, &name) {
+                    let memory = self.host_memory(store);
+                    let results = self
+                        .linker
+                        .call(&module, &name, params, memory)
+                        .map_err(RuntimeError::Env)?;
+                    return Ok(Execution::Complete(results));
+                }
+                Ok(Execution::Suspended(Suspended {
+                    reason: SuspendReason::HostCall {
+                        module,
+                        name,
+                        params,
+                    },
+                    result_types,
+                }))
+            }
+        }
+    }
+
+    /// Resumes a suspended invocation. `results` are the results of the
+    /// host function for `SuspendReason::HostCall`, which must match its
+    /// result types, and are ignored otherwise.
+    pub fn resume_with(
+        &mut self,
+        store: &mut Store,
+        suspended: Suspended,
+        results: Vec<Value>,
+    ) -> Result<Execution, RuntimeError> {
+        if let SuspendReason::HostCall { .. } = suspended.reason {
+            if !self.results_match(store, &results, &suspended.result_types) {
+                return Err(RuntimeError::Env("host function results mismatch"));
+            }
+            // An exported host function was invoked directly.
+            if self.stack.frames_len() == 0 {
+                return Ok(Execution::Complete(results));
+            }
+            self.stack.extend_values(results);
+            self.pc += 1;
+        }
+        self.drive(store)
+    }
+
+    /// Whether the host function `results` are of `types`. References are
+    /// checked in the types of the calling instance.
+    fn results_match(&self, store: &Store, results: &[Value], types: &[ValType]) -> bool {
+        let addr = self.caller_addr();
+        results.len() == types.len()
+            && results
+                .iter()
+                .zip(types)
+                .all(|(value, t)| match (value, t) {
+                    (Value::I32(_), ValType::I32)
+                    | (Value::I64(_), ValType::I64)
+                    | (Value::F32(_), ValType::F32)
+                    | (Value::F64(_), ValType::F64)
+                    | (Value::V128(_), ValType::V128) => true,
+                    (Value::Ref(r), t) => t.reftype().map_or(false, |rt| {
+                        gc::ref_matches(&self.instances, store, addr, *r, rt)
+                    }),
+                    _ => false,
+                })
+    }
+
+    fn drive(&mut self, store: &mut Store) -> Result<Execution, RuntimeError> {
+        let (reason, result_types) = match self.run(store) {
+            Ok(ExecState::EnvFunc {
+                module,
+                name,
+                params,
+                result_types,
+            }) => (
+                SuspendReason::HostCall {
+                    module,
+                    name,
+                    params,
+                },
+                result_types,
+            ),
+            Ok(_) => return Ok(Execution::Complete(self.stack.get_returns())),
+            Err(Trap::OutOfFuel) => (SuspendReason::OutOfFuel, vec![]),
+            Err(Trap::Interrupted) => (SuspendReason::Interrupted, vec![]),
+            Err(trap) => return Err(self.trap_error(store, trap)),
+        };
+        Ok(Execution::Suspended(Suspended {
+            reason,
+            result_types,
+        }))
     }
 
     pub fn step(&mut self, store: &mut Store) -> Result<ExecState, Trap> {
+        self.consume_fuel()?;
         match step(
             &mut self.instances,
             &self.instrs,
@@ -530,17 +1168,70 @@ impl Runtime {
     pub fn set_results(&mut self, results: Vec<Value>) {
         self.stack.extend_values(results);
     }
-}
+
+    /// Call stack of the current execution, innermost frame first. The
+    /// innermost frame is at `pc` and the others at their calls.
+    pub fn backtrace(&self, store: &Store) -> Backtrace {
+        let mut pc = self.pc;
+        let mut frames = vec![];
+        for frame in self.stack.frames().iter().rev() {
+            let instance = &self.instances[frame.instance_addr];
+            let func_idx = instance
+                .funcaddrs
+                .iter()
+                .position(|&addr| addr == frame.func_addr)
+                .map(|idx| idx as u32);
+            let start = match store.funcs[frame.func_addr] {
+                FuncInst::InnerFunc { start, .. } => start,
+                FuncInst::HostFunc { .. } => pc,
+            };
+            frames.push(BacktraceFrame {
+                func_addr: frame.func_addr,
+                instance_addr: frame.instance_addr,
+                func_idx,
+                offset: pc - start,
+                code_offset: self.code_offsets[pc],
+                #[cfg(feature = "std")]
+                location: self.location(frame.instance_addr, pc),
+                module_name: instance.names.module.clone(),
+                func_name: func_idx
+                    .and_then(|idx| instance.names.func(idx))
+                    .map(String::from),
+            });
+            // The frame returns to the instruction after its call.
+            pc = frame.pc - 1;
+        }
+        Backtrace { frames }
+    }
+
+    /// Source location of the instruction at `pc` of a function of the
+    /// instance at `instance_addr`, if the module has a line table.
+    #[cfg(feature = "std")]
+    pub fn location(&self, instance_addr: Addr, pc: usize) -> Option<Location> {
+        let lines = self.instances.get(instance_addr)?.lines.as_ref()?;
+        lines.lookup(*self.code_offsets.get(pc)? as u64)
+    }
+
+    pub(crate) fn trap_error(&self, store: &Store, trap: Trap) -> RuntimeError {
+        match trap {
+            Trap::UncaughtException(exn) => RuntimeError::Exception(exn),
+            trap => RuntimeError::Trap(trap, self.backtrace(store)),
+        }
+    }
+}
 
 #[cfg(test)]
 mod tests {
-    use super::Runtime;
-    use crate::binary::Module;
-    use crate::exec::env::DebugEnv;
+    use super::{Runtime, RuntimeError};
+    use crate::binary::{Instr, Module};
+    use crate::exec::env::{DebugEnv, Env, Memories};
     use crate::exec::importer::Importer;
-    use crate::exec::store::Store;
-    use crate::exec::value::Value;
+    use crate::exec::store::{MemInst, Store};
+    use crate::exec::suspend::Execution;
+    use crate::exec::trap::Trap;
+    use crate::exec::value::{Ref, Value};
     use crate::loader::parser::Parser;
+    use crate::loader::validate;
     use crate::tests::wat2wasm;
 
     #[test]
@@ -549,7 +1240,7 @@ mod tests {
             r#"(module
                   (memory 1)
                   (global $x (mut i32) (i32.const -12))
-                  (table 2 anyfunc)
+                  (table 2 funcref)
                   (func $f1 (result i32) i32.const 42)
                   (func $f2 (result i32) i32.const 13)
                   (elem (i32.const 0) $f1 $f2)
@@ -582,7 +1273,7 @@ mod tests {
         let mut impoter = TestImporter { module };
         let mut runtime = Runtime::new("env");
         runtime
-            .import_module(&mut store, &mut impoter, "debug")
+            .import_module(&mut store, &mut DebugEnv {}, &mut impoter, "debug")
             .unwrap();
         let mut env = DebugEnv {};
         assert_eq!(
@@ -597,4 +1288,1241 @@ mod tests {
         assert_eq!(store.mems.to_vec().len(), 0);
         assert_eq!(store.tables.to_vec().len(), 0);
     }
+
+    struct ModuleImporter {
+        modules: Vec<(&'static str, Module)>,
+    }
+
+    impl Importer for ModuleImporter {
+        fn import(&mut self, modname: &str) -> Option<Module> {
+            self.modules
+                .iter()
+                .find(|(name, _)| *name == modname)
+                .map(|(_, module)| module.clone())
+        }
+    }
+
+    /// Parses and validates `wat`, like the CLI does before running it.
+    fn module(wat: &str) -> Module {
+        let module = Parser::new(&wat2wasm(wat).unwrap()).module().unwrap();
+        validate(&module).unwrap();
+        module
+    }
+
+    const LIB: &str = r#"(module
+          (memory (export "memory") 1)
+          (global $count (export "count") (mut i32) (i32.const 0))
+          (func (export "inc") (result i32)
+              (global.set $count (i32.add (global.get $count) (i32.const 1)))
+              (global.get $count))
+          (func (export "get") (result i32) (global.get $count)))"#;
+
+    #[test]
+    fn incompatible_imports() {
+        let exports = module(
+            r#"(module
+                  (memory (export "memory") 1)
+                  (table (export "table") 1 funcref)
+                  (global (export "const") i32 (i32.const 0))
+                  (func (export "func") (result i32) (i32.const 1)))"#,
+        );
+        let imports = [
+            ("func", "(func (param i32) (result i32))", false),
+            ("func", "(func (result i32))", true),
+            ("const", "(global (mut i32))", false),
+            ("const", "(global i32)", true),
+            ("memory", "(memory 2)", false),
+            ("memory", "(memory 1 2)", false),
+            ("memory", "(memory 0)", true),
+            ("table", "(table 1 externref)", false),
+            ("table", "(table 1 funcref)", true),
+        ];
+        for (name, desc, ok) in imports {
+            let wat = format!(r#"(module (import "lib" "{}" {}))"#, name, desc);
+            let mut importer = ModuleImporter {
+                modules: vec![("lib", exports.clone()), ("main", module(&wat))],
+            };
+            let mut store = Store::new();
+            let mut runtime = Runtime::new("env");
+            let result = runtime.import_module(&mut store, &mut DebugEnv {}, &mut importer, "main");
+            let expected = if ok {
+                Ok(())
+            } else {
+                Err(RuntimeError::IncompatibleImport("lib".into(), name.into()))
+            };
+            assert_eq!(result, expected, "{}", wat);
+        }
+    }
+
+    #[test]
+    fn shared_import_instance() {
+        let mut importer = ModuleImporter {
+            modules: vec![
+                ("lib", module(LIB)),
+                (
+                    "main",
+                    module(
+                        r#"(module
+                              (import "lib" "inc" (func $inc (result i32)))
+                              (import "lib" "get" (func $get (result i32)))
+                              (import "lib" "memory" (memory 1))
+                              (import "lib" "count" (global $count (mut i32)))
+                              (func (export "main") (result i32)
+                                  call $inc
+                                  drop
+                                  call $inc
+                                  drop
+                                  (i32.store (i32.const 0) (global.get $count))
+                                  call $get))"#,
+                    ),
+                ),
+            ],
+        };
+        let mut store = Store::new();
+        let mut runtime = Runtime::new("env");
+        runtime
+            .import_module(&mut store, &mut DebugEnv {}, &mut importer, "main")
+            .unwrap();
+        assert_eq!(runtime.instances.len(), 2);
+        assert_eq!(
+            runtime.invoke(&mut store, &mut DebugEnv {}, "main", vec![]),
+            Ok(vec![Value::I32(2)])
+        );
+        let lib = runtime.registry["lib"];
+        let memaddr = runtime.instances[lib].memaddrs[0];
+        assert_eq!(store.mems[memaddr].data.load::<u8>(0), 2);
+    }
+
+    #[test]
+    fn register_instance() {
+        let mut store = Store::new();
+        let mut runtime = Runtime::new("env");
+        runtime
+            .add_module(&mut store, &mut DebugEnv {}, module(LIB))
+            .unwrap();
+        runtime.register("counter", runtime.root);
+        runtime
+            .invoke(&mut store, &mut DebugEnv {}, "inc", vec![])
+            .unwrap();
+
+        let mut importer = ModuleImporter {
+            modules: vec![(
+                "main",
+                module(
+                    r#"(module
+                          (import "counter" "inc" (func $inc (result i32)))
+                          (func (export "main") (result i32) call $inc))"#,
+                ),
+            )],
+        };
+        runtime
+            .import_module(&mut store, &mut DebugEnv {}, &mut importer, "main")
+            .unwrap();
+        assert_eq!(
+            runtime.invoke(&mut store, &mut DebugEnv {}, "main", vec![]),
+            Ok(vec![Value::I32(2)])
+        );
+    }
+
+    const COUNTDOWN: &str = r#"(module
+          (func (export "countdown") (param i32) (result i32)
+              (loop $l
+                  (local.set 0 (i32.sub (local.get 0) (i32.const 1)))
+                  (br_if $l (local.get 0)))
+              (i32.const 42)))"#;
+
+    #[test]
+    fn fuel() {
+        let mut store = Store::new();
+        let mut runtime = Runtime::new("env");
+        runtime
+            .add_module(&mut store, &mut DebugEnv {}, module(COUNTDOWN))
+            .unwrap();
+        runtime.add_fuel(100);
+        assert_eq!(
+            runtime
+                .invoke(
+                    &mut store,
+                    &mut DebugEnv {},
+                    "countdown",
+                    vec![Value::I32(1000)]
+                )
+                .unwrap_err()
+                .trap(),
+            Some(&Trap::OutOfFuel)
+        );
+        assert_eq!(runtime.fuel(), Some(0));
+
+        runtime.add_fuel(100_000);
+        assert_eq!(
+            runtime.resume(&mut store, &mut DebugEnv {}),
+            Ok(vec![Value::I32(42)])
+        );
+        assert!(runtime.fuel().unwrap() < 100_000 - 100);
+    }
+
+    #[test]
+    fn fuel_cost_table() {
+        let mut store = Store::new();
+        let mut runtime = Runtime::new("env");
+        runtime
+            .add_module(&mut store, &mut DebugEnv {}, module(COUNTDOWN))
+            .unwrap();
+        runtime.set_cost_table(|instr| match instr {
+            Instr::BrIf(_) => 10,
+            _ => 0,
+        });
+        runtime.add_fuel(50);
+        assert_eq!(
+            runtime.invoke(
+                &mut store,
+                &mut DebugEnv {},
+                "countdown",
+                vec![Value::I32(5)]
+            ),
+            Ok(vec![Value::I32(42)])
+        );
+        assert_eq!(runtime.fuel(), Some(0));
+        assert_eq!(
+            runtime
+                .invoke(
+                    &mut store,
+                    &mut DebugEnv {},
+                    "countdown",
+                    vec![Value::I32(1)]
+                )
+                .unwrap_err()
+                .trap(),
+            Some(&Trap::OutOfFuel)
+        );
+    }
+
+    #[test]
+    fn simd() {
+        let mut store = Store::new();
+        let mut runtime = Runtime::new("env");
+        runtime
+            .add_module(
+                &mut store,
+                &mut DebugEnv {},
+                module(
+                    r#"(module
+                          (memory 1)
+                          (data (i32.const 0) "\01\00\02\00\03\00\04\00\05\00\06\00\07\00\08\00")
+                          ;; dot product of the first eight i16 with themselves
+                          (func (export "dot") (result i32) (local v128)
+                              (local.set 0 (v128.load (i32.const 0)))
+                              (local.set 0 (i32x4.dot_i16x8_s (local.get 0) (local.get 0)))
+                              (i32.add
+                                  (i32.add (i32x4.extract_lane 0 (local.get 0))
+                                           (i32x4.extract_lane 1 (local.get 0)))
+                                  (i32.add (i32x4.extract_lane 2 (local.get 0))
+                                           (i32x4.extract_lane 3 (local.get 0)))))
+                          (func (export "max") (param v128 v128) (result v128)
+                              (f32x4.max (local.get 0) (local.get 1))))"#,
+                ),
+            )
+            .unwrap();
+        let mut env = DebugEnv {};
+        assert_eq!(
+            runtime.invoke(&mut store, &mut env, "dot", vec![]),
+            Ok(vec![Value::I32(204)])
+        );
+        let lanes = |lanes: [f32; 4]| {
+            let mut v = 0;
+            for (i, lane) in lanes.iter().enumerate() {
+                v |= (lane.to_bits() as u128) << (32 * i);
+            }
+            Value::V128(v)
+        };
+        assert_eq!(
+            runtime.invoke(
+                &mut store,
+                &mut env,
+                "max",
+                vec![lanes([1.0, -0.0, 3.0, 4.0]), lanes([2.0, 0.0, -3.0, 4.0])]
+            ),
+            Ok(vec![lanes([2.0, 0.0, 3.0, 4.0])])
+        );
+    }
+
+    #[test]
+    fn tail_calls() {
+        let mut store = Store::new();
+        let mut runtime = Runtime::new("env");
+        runtime.linker.func("host", "double", |x: i32| x * 2);
+        runtime
+            .add_module(
+                &mut store,
+                &mut DebugEnv {},
+                module(
+                    r#"(module
+                          (import "host" "double" (func $double (param i32) (result i32)))
+                          (type $t (func (param i64 i64) (result i64)))
+                          (table funcref (elem $sum))
+                          (func $sum (export "sum") (param i64 i64) (result i64)
+                              (if (result i64) (i64.eqz (local.get 0))
+                                  (then (local.get 1))
+                                  (else
+                                      (return_call_indirect (type $t)
+                                          (i64.sub (local.get 0) (i64.const 1))
+                                          (i64.add (local.get 1) (local.get 0))
+                                          (i32.const 0)))))
+                          (func $even (export "even") (param i32) (result i32)
+                              (if (result i32) (local.get 0)
+                                  (then (return_call $odd (i32.sub (local.get 0) (i32.const 1))))
+                                  (else (i32.const 1))))
+                          (func $odd (param i32) (result i32)
+                              (if (result i32) (local.get 0)
+                                  (then (return_call $even (i32.sub (local.get 0) (i32.const 1))))
+                                  (else (i32.const 0))))
+                          (func (export "quadruple") (param i32) (result i32)
+                              (return_call $double (call $double (local.get 0)))))"#,
+                ),
+            )
+            .unwrap();
+        let mut env = DebugEnv {};
+        assert_eq!(
+            runtime.invoke(&mut store, &mut env, "even", vec![Value::I32(100_001)]),
+            Ok(vec![Value::I32(0)])
+        );
+        assert_eq!(
+            runtime.invoke(&mut store, &mut env, "quadruple", vec![Value::I32(5)]),
+            Ok(vec![Value::I32(20)])
+        );
+
+        // Tail calls run in constant stack space.
+        runtime.add_fuel(10_000);
+        let execution = runtime
+            .invoke_resumable(&mut store, "sum", vec![Value::I64(100_000), Value::I64(0)])
+            .unwrap();
+        let suspended = match execution {
+            Execution::Suspended(suspended) => suspended,
+            _ => panic!("sum should run out of fuel"),
+        };
+        assert_eq!(runtime.stack.frames_len(), 1);
+        runtime.add_fuel(u64::MAX / 2);
+        assert_eq!(
+            runtime.resume_with(&mut store, suspended, vec![]),
+            Ok(Execution::Complete(vec![Value::I64(5_000_050_000)]))
+        );
+    }
+
+    #[test]
+    fn exceptions() {
+        let mut store = Store::new();
+        let mut runtime = Runtime::new("env");
+        runtime
+            .add_module(
+                &mut store,
+                &mut DebugEnv {},
+                module(
+                    r#"(module
+                          (tag $e (param i32))
+                          (tag $empty)
+                          (func $throw (param i32)
+                              (block (throw $e (local.get 0))))
+                          (func (export "catch") (param i32) (result i32)
+                              (block $h (result i32)
+                                  (try_table (catch $e $h)
+                                      (call $throw (local.get 0)))
+                                  (i32.const 0)))
+                          (func (export "rethrow") (param i32) (result i32)
+                              (block $outer (result i32)
+                                  (try_table (result i32) (catch $e $outer)
+                                      (block $h (result exnref)
+                                          (try_table (catch_all_ref $h)
+                                              (call $throw (local.get 0)))
+                                          (unreachable))
+                                      (throw_ref))))
+                          (func (export "catch_all") (result i32)
+                              (drop (block $i (result i32)
+                                  (block $h
+                                      (try_table (catch $e $i) (catch_all $h)
+                                          (throw $empty))
+                                      (return (i32.const 0)))
+                                  (return (i32.const 1))))
+                              (i32.const 2))
+                          (func (export "uncaught") (param i32) (result i32)
+                              (call $throw (local.get 0))
+                              (i32.const 0))
+                          (func (export "null")
+                              (throw_ref (ref.null exn))))"#,
+                ),
+            )
+            .unwrap();
+        let mut env = DebugEnv {};
+        assert_eq!(
+            runtime.invoke(&mut store, &mut env, "catch", vec![Value::I32(42)]),
+            Ok(vec![Value::I32(42)])
+        );
+        assert_eq!(
+            runtime.invoke(&mut store, &mut env, "rethrow", vec![Value::I32(7)]),
+            Ok(vec![Value::I32(7)])
+        );
+        assert_eq!(
+            runtime.invoke(&mut store, &mut env, "catch_all", vec![]),
+            Ok(vec![Value::I32(1)])
+        );
+        let exn = match runtime.invoke(&mut store, &mut env, "uncaught", vec![Value::I32(3)]) {
+            Err(RuntimeError::Exception(exn)) => exn,
+            _ => panic!("exception should not be caught"),
+        };
+        assert_eq!(exn.fields, vec![Value::I32(3)]);
+        assert_eq!(exn.tag, runtime.instances[runtime.root].tagaddrs[0]);
+        assert!(runtime.stack.is_empty());
+        assert_eq!(
+            runtime
+                .invoke(&mut store, &mut env, "null", vec![])
+                .unwrap_err()
+                .trap(),
+            Some(&Trap::NullExceptionReference)
+        );
+    }
+
+    #[test]
+    fn references() {
+        let mut store = Store::new();
+        let mut runtime = Runtime::new("env");
+        runtime
+            .add_module(
+                &mut store,
+                &mut DebugEnv {},
+                module(
+                    r#"(module
+                          (func $f)
+                          (elem declare func $f)
+                          (func (export "select") (param externref externref i32) (result externref)
+                              (select (result externref) (local.get 0) (local.get 1) (local.get 2)))
+                          (func (export "null") (result i32 i32)
+                              (local funcref externref)
+                              (ref.is_null (local.get 0))
+                              (ref.is_null (local.get 1)))
+                          (func (export "func") (result i32)
+                              (local funcref)
+                              (local.set 0 (select (result funcref)
+                                  (ref.func $f) (local.get 0) (i32.const 1)))
+                              (ref.is_null (local.get 0))))"#,
+                ),
+            )
+            .unwrap();
+        let mut env = DebugEnv {};
+        let args = vec![Value::Ref(Ref::Extern(1)), Value::Ref(Ref::Null)];
+        for (c, result) in [(1, Ref::Extern(1)), (0, Ref::Null)] {
+            let mut args = args.clone();
+            args.push(Value::I32(c));
+            assert_eq!(
+                runtime.invoke(&mut store, &mut env, "select", args),
+                Ok(vec![Value::Ref(result)])
+            );
+        }
+        assert_eq!(
+            runtime.invoke(&mut store, &mut env, "null", vec![]),
+            Ok(vec![Value::I32(1), Value::I32(1)])
+        );
+        assert_eq!(
+            runtime.invoke(&mut store, &mut env, "func", vec![]),
+            Ok(vec![Value::I32(0)])
+        );
+    }
+
+    #[test]
+    fn extended_const() {
+        let mut importer = ModuleImporter {
+            modules: vec![
+                (
+                    "lib",
+                    module(r#"(module (global (export "base") i32 (i32.const 8)))"#),
+                ),
+                (
+                    "main",
+                    module(
+                        r#"(module
+                              (import "lib" "base" (global $base i32))
+                              (global $off i32 (i32.add (global.get $base) (i32.const 4)))
+                              (global $big i64 (i64.mul (i64.const 3) (i64.sub (i64.const 10) (i64.const 3))))
+                              (global $fn funcref (ref.func $f))
+                              (table 16 funcref)
+                              (memory 1)
+                              (func $f (result i32) (i32.const 42))
+                              (elem (offset (i32.mul (global.get $base) (i32.const 1))) func $f)
+                              (data (offset (i32.sub (global.get $off) (i32.const 2))) "\2a")
+                              (func (export "off") (result i32) (global.get $off))
+                              (func (export "big") (result i64) (global.get $big))
+                              (func (export "call") (result i32)
+                                  (call_indirect (result i32) (i32.const 8)))
+                              (func (export "load") (result i32) (i32.load8_u (i32.const 10)))
+                              (func (export "ref") (result i32)
+                                  (ref.is_null (global.get $fn))))"#,
+                    ),
+                ),
+            ],
+        };
+        let mut store = Store::new();
+        let mut runtime = Runtime::new("env");
+        runtime
+            .import_module(&mut store, &mut DebugEnv {}, &mut importer, "main")
+            .unwrap();
+        let mut env = DebugEnv {};
+        for (name, result) in [
+            ("off", Value::I32(12)),
+            ("big", Value::I64(21)),
+            ("call", Value::I32(42)),
+            ("load", Value::I32(42)),
+            ("ref", Value::I32(0)),
+        ] {
+            assert_eq!(
+                runtime.invoke(&mut store, &mut env, name, vec![]),
+                Ok(vec![result])
+            );
+        }
+    }
+
+    #[test]
+    fn instantiation() {
+        let mut importer = ModuleImporter {
+            modules: vec![
+                ("lib", module(LIB)),
+                (
+                    "partial",
+                    module(
+                        r#"(module
+                              (import "lib" "memory" (memory 1))
+                              (global i32 (i32.const 1))
+                              (func)
+                              (data (i32.const 0) "\01")
+                              (data (i32.const 65536) "\02"))"#,
+                    ),
+                ),
+                (
+                    "start",
+                    module(
+                        r#"(module
+                              (import "lib" "inc" (func $inc (result i32)))
+                              (func $start (drop (call $inc)))
+                              (start $start))"#,
+                    ),
+                ),
+                (
+                    "trap",
+                    module(r#"(module (func $start unreachable) (start $start))"#),
+                ),
+            ],
+        };
+        let mut store = Store::new();
+        let mut runtime = Runtime::new("env");
+        let mut env = DebugEnv {};
+        runtime
+            .import_module(&mut store, &mut env, &mut importer, "lib")
+            .unwrap();
+        runtime.register("lib", runtime.root);
+        let lib = runtime.root;
+
+        let (funcs, globals, datas) = (store.funcs.len(), store.globals.len(), store.datas.len());
+        assert_eq!(
+            runtime
+                .import_module(&mut store, &mut env, &mut importer, "partial")
+                .unwrap_err()
+                .trap(),
+            Some(&Trap::MemoryOutOfBounds)
+        );
+        assert_eq!(runtime.root, lib);
+        assert_eq!(store.funcs.len(), funcs);
+        assert_eq!(store.globals.len(), globals);
+        assert_eq!(store.datas.len(), datas);
+        let memaddr = runtime.instances[lib].memaddrs[0];
+        assert_eq!(store.mems[memaddr].data.load::<u8>(0), 0);
+
+        runtime
+            .import_module(&mut store, &mut env, &mut importer, "start")
+            .unwrap();
+        runtime.root = lib;
+        assert_eq!(
+            runtime.invoke(&mut store, &mut env, "get", vec![]),
+            Ok(vec![Value::I32(1)])
+        );
+
+        assert_eq!(
+            runtime
+                .import_module(&mut store, &mut env, &mut importer, "trap")
+                .unwrap_err()
+                .trap(),
+            Some(&Trap::Unreachable)
+        );
+        assert_eq!(runtime.root, lib);
+    }
+
+    #[test]
+    fn tables() {
+        let mut store = Store::new();
+        let mut runtime = Runtime::new("env");
+        runtime
+            .add_module(
+                &mut store,
+                &mut DebugEnv {},
+                module(
+                    r#"(module
+                          (type $t (func (result i32)))
+                          (table 4 funcref)
+                          (func $a (result i32) (i32.const 1))
+                          (func $b (result i32) (i32.const 2))
+                          (elem $e func $a $b)
+                          (func (export "init") (param i32 i32 i32)
+                              (table.init $e (local.get 0) (local.get 1) (local.get 2)))
+                          (func (export "copy") (param i32 i32 i32)
+                              (table.copy (local.get 0) (local.get 1) (local.get 2)))
+                          (func (export "fill") (param i32 i32)
+                              (table.fill (local.get 0) (ref.null func) (local.get 1)))
+                          (func (export "is_null") (param i32) (result i32)
+                              (ref.is_null (table.get (local.get 0))))
+                          (func (export "set") (param i32)
+                              (table.set (local.get 0) (ref.func $b)))
+                          (func (export "call") (param i32) (result i32)
+                              (call_indirect (type $t) (local.get 0))))"#,
+                ),
+            )
+            .unwrap();
+        let mut env = DebugEnv {};
+        let mut invoke = |name: &str, params: Vec<i32>| {
+            let params = params.into_iter().map(Value::I32).collect();
+            runtime.invoke(&mut store, &mut env, name, params)
+        };
+        let trap = |result: Result<Vec<Value>, RuntimeError>| match result {
+            Err(RuntimeError::Trap(trap, _)) => Some(trap),
+            _ => None,
+        };
+
+        // [null, a, b, null]
+        assert_eq!(invoke("init", vec![1, 0, 2]), Ok(vec![]));
+        assert_eq!(invoke("call", vec![1]), Ok(vec![Value::I32(1)]));
+        assert_eq!(invoke("call", vec![2]), Ok(vec![Value::I32(2)]));
+        assert_eq!(
+            trap(invoke("call", vec![0])),
+            Some(Trap::UninitializedElement)
+        );
+        assert_eq!(trap(invoke("call", vec![4])), Some(Trap::UndefinedElement));
+        assert_eq!(
+            trap(invoke("init", vec![3, 0, 2])),
+            Some(Trap::TableOutOfRange)
+        );
+
+        // Overlapping copy: [null, a, a, b]
+        assert_eq!(invoke("copy", vec![2, 1, 2]), Ok(vec![]));
+        assert_eq!(invoke("call", vec![2]), Ok(vec![Value::I32(1)]));
+        assert_eq!(invoke("call", vec![3]), Ok(vec![Value::I32(2)]));
+        assert_eq!(
+            trap(invoke("copy", vec![3, 0, 2])),
+            Some(Trap::TableOutOfRange)
+        );
+
+        // [null, null, null, b]
+        assert_eq!(invoke("fill", vec![1, 2]), Ok(vec![]));
+        assert_eq!(invoke("is_null", vec![2]), Ok(vec![Value::I32(1)]));
+        assert_eq!(invoke("is_null", vec![3]), Ok(vec![Value::I32(0)]));
+        assert_eq!(
+            trap(invoke("fill", vec![-1, 2])),
+            Some(Trap::TableOutOfRange)
+        );
+
+        assert_eq!(invoke("set", vec![0]), Ok(vec![]));
+        assert_eq!(invoke("call", vec![0]), Ok(vec![Value::I32(2)]));
+        assert_eq!(
+            trap(invoke("is_null", vec![4])),
+            Some(Trap::TableOutOfRange)
+        );
+        assert_eq!(trap(invoke("set", vec![-1])), Some(Trap::TableOutOfRange));
+    }
+
+    #[test]
+    fn backtrace() {
+        let mut store = Store::new();
+        let mut runtime = Runtime::new("env");
+        runtime
+            .add_module(
+                &mut store,
+                &mut DebugEnv {},
+                module(
+                    r#"(module $app
+                          (memory 1)
+                          (func $load (result i32) (i32.load (i32.const 70000)))
+                          (func $outer (result i32) nop (call $load))
+                          (func (export "main") (result i32) (call $outer)))"#,
+                ),
+            )
+            .unwrap();
+        let err = runtime
+            .invoke(&mut store, &mut DebugEnv {}, "main", vec![])
+            .unwrap_err();
+        let frames = match &err {
+            RuntimeError::Trap(Trap::MemoryOutOfBounds, backtrace) => &backtrace.frames,
+            err => panic!("unexpected error {:?}", err),
+        };
+        assert_eq!(
+            frames
+                .iter()
+                .map(|frame| (frame.func_idx, frame.offset, frame.func_name.as_deref()))
+                .collect::<Vec<_>>(),
+            vec![
+                (Some(0), 1, Some("load")),
+                (Some(1), 1, Some("outer")),
+                (Some(2), 0, None)
+            ]
+        );
+        assert_eq!(
+            err.to_string(),
+            "out of bounds memory access\n\
+             wasm backtrace:\n   \
+             0: app!load +1\n   \
+             1: app!outer +1\n   \
+             2: app!func[2] +0"
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn source_location() {
+        use crate::debug::dwarf::LineTable;
+
+        let mut module = module(
+            r#"(module
+                  (memory 1)
+                  (func (export "main") (result i32) (i32.load (i32.const 70000))))"#,
+        );
+        // i32.const at code offset 3 on line 1, i32.load at 7 on line 2
+        let mut debug_line = vec![50, 0, 0, 0, 4, 0, 30, 0, 0, 0];
+        debug_line.extend([1, 1, 1, 0xfb, 14, 13, 0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1]);
+        debug_line.extend(b"\0main.c\0\0\0\0\0");
+        debug_line.extend([
+            0x00, 0x05, 0x02, 3, 0, 0, 0, 0x01, 75, 0x02, 0x03, 0x00, 0x01, 0x01,
+        ]);
+        module.lines = Some(LineTable::parse(&debug_line, &[], &[]).unwrap());
+
+        let mut store = Store::new();
+        let mut runtime = Runtime::new("env");
+        runtime
+            .add_module(&mut store, &mut DebugEnv {}, module)
+            .unwrap();
+        let err = runtime
+            .invoke(&mut store, &mut DebugEnv {}, "main", vec![])
+            .unwrap_err();
+        match &err {
+            RuntimeError::Trap(_, backtrace) => assert_eq!(backtrace.frames[0].code_offset, 7),
+            err => panic!("unexpected error {:?}", err),
+        }
+        assert!(err.to_string().ends_with("0: func[0] +1 at main.c:2"));
+    }
+
+    #[test]
+    fn memory64() {
+        let mut store = Store::new();
+        let mut runtime = Runtime::new("env");
+        runtime
+            .add_module(
+                &mut store,
+                &mut DebugEnv {},
+                module(
+                    r#"(module
+                          (memory i64 1)
+                          (data (i64.const 8) "\2a")
+                          (func (export "load") (param i64) (result i32)
+                              (i32.load8_u offset=4 (local.get 0)))
+                          (func (export "store") (param i64 i64)
+                              (i64.store (local.get 0) (local.get 1)))
+                          (func (export "size") (result i64) (memory.size))
+                          (func (export "grow") (param i64) (result i64)
+                              (memory.grow (local.get 0)))
+                          (func (export "fill") (param i64 i32 i64)
+                              (memory.fill (local.get 0) (local.get 1) (local.get 2))))"#,
+                ),
+            )
+            .unwrap();
+        let mut env = DebugEnv {};
+        assert_eq!(
+            runtime.invoke(&mut store, &mut env, "load", vec![Value::I64(4)]),
+            Ok(vec![Value::I32(0x2a)])
+        );
+        assert_eq!(
+            runtime.invoke(&mut store, &mut env, "size", vec![]),
+            Ok(vec![Value::I64(1)])
+        );
+        assert_eq!(
+            runtime.invoke(&mut store, &mut env, "grow", vec![Value::I64(2)]),
+            Ok(vec![Value::I64(1)])
+        );
+        assert_eq!(
+            runtime.invoke(
+                &mut store,
+                &mut env,
+                "store",
+                vec![Value::I64(0x2fff8), Value::I64(-1)]
+            ),
+            Ok(vec![])
+        );
+        assert_eq!(
+            runtime.invoke(&mut store, &mut env, "load", vec![Value::I64(0x2fffb)]),
+            Ok(vec![Value::I32(0xff)])
+        );
+        // Addresses beyond 4 GiB and offsets overflowing 64 bits trap.
+        for addr in [1 << 32, -1] {
+            assert_eq!(
+                runtime
+                    .invoke(&mut store, &mut env, "load", vec![Value::I64(addr)])
+                    .unwrap_err()
+                    .trap(),
+                Some(&Trap::MemoryOutOfBounds)
+            );
+        }
+        assert_eq!(
+            runtime
+                .invoke(
+                    &mut store,
+                    &mut env,
+                    "fill",
+                    vec![Value::I64(0x2ffff), Value::I32(1), Value::I64(2)]
+                )
+                .unwrap_err()
+                .trap(),
+            Some(&Trap::MemoryOutOfBounds)
+        );
+        assert_eq!(
+            runtime.invoke(&mut store, &mut env, "grow", vec![Value::I64(1 << 48)]),
+            Ok(vec![Value::I64(-1)])
+        );
+        // Growing past the memory limit of the store fails instead of
+        // allocating.
+        assert_eq!(
+            runtime.invoke(&mut store, &mut env, "grow", vec![Value::I64(0x1_0000)]),
+            Ok(vec![Value::I64(-1)])
+        );
+        store.set_memory_limit(4 * super::PAGE_SIZE);
+        assert_eq!(
+            runtime.invoke(&mut store, &mut env, "grow", vec![Value::I64(1)]),
+            Ok(vec![Value::I64(3)])
+        );
+        assert_eq!(
+            runtime.invoke(&mut store, &mut env, "grow", vec![Value::I64(1)]),
+            Ok(vec![Value::I64(-1)])
+        );
+    }
+
+    #[test]
+    fn gc() {
+        let mut store = Store::new();
+        let mut runtime = Runtime::new("env");
+        runtime
+            .add_module(
+&mut store,
+&mut DebugEnv {},
+                module(
+                    r#"(module
+                          (type $point (struct (field $x (mut i32)) (field $y i8)))
+                          (type $bytes (array (mut i8)))
+                          (type $fn (func (param i32) (result i32)))
+                          (type $base (sub (struct (field i32))))
+                          (type $derived (sub $base (struct (field i32) (field i64))))
+                          (data $d "\01\ff")
+                          (global $keep (mut (ref null $point)) (ref.null $point))
+                          (global $origin (ref $point) (struct.new $point (i32.const 3) (i32.const 260)))
+                          (global $pair (ref $bytes) (array.new_fixed $bytes 2 (i32.const 1) (i32.const 2)))
+                          (global $i31 (ref i31) (ref.i31 (i32.const -1)))
+                          (elem declare func $double)
+                          (func $double (type $fn) (i32.mul (local.get 0) (i32.const 2)))
+                          (func (export "packed") (result i32)
+                              (i32.add
+                                  (struct.get_s $point $y (struct.new $point (i32.const 0) (i32.const 255)))
+                                  (struct.get_u $point $y (struct.new $point (i32.const 0) (i32.const 255)))))
+                          (func (export "data") (result i32)
+                              (array.get_s $bytes
+                                  (array.new_data $bytes $d (i32.const 0) (i32.const 2))
+                                  (i32.const 1)))
+                          (func (export "out_of_bounds") (result i32)
+                              (array.get_u $bytes (array.new_default $bytes (i32.const 1)) (i32.const 1)))
+                          (func (export "globals") (result i32 i32 i32 i32)
+                              (struct.get_u $point $y (global.get $origin))
+                              (array.get_u $bytes (global.get $pair) (i32.const 1))
+                              (array.len (global.get $pair))
+                              (i31.get_s (global.get $i31)))
+                          (func (export "too_large") (result i32)
+                              (array.len (array.new_default $bytes (i32.const -1))))
+                          (func (export "call_ref") (param i32) (result i32)
+                              (call_ref $fn (local.get 0) (ref.func $double)))
+                          (func (export "null_call") (result i32)
+                              (call_ref $fn (i32.const 0) (ref.null $fn)))
+                          (func (export "test") (result i32)
+                              (ref.test (ref $base) (struct.new $derived (i32.const 1) (i64.const 2))))
+                          (func (export "cast") (result i64)
+                              (struct.get $derived 1
+                                  (ref.cast (ref $derived) (struct.new $base (i32.const 1)))))
+                          (func (export "i31") (param i32) (result i32)
+                              (i31.get_s (ref.i31 (local.get 0))))
+                          (func (export "br_on_null") (param (ref null $point)) (result i32)
+                              (block $null
+                                  (br_on_null $null (local.get 0))
+                                  (drop)
+                                  (return (i32.const 1)))
+                              (i32.const 0))
+                          (func (export "churn") (param i32)
+                              (global.set $keep (struct.new $point (i32.const 7) (i32.const 0)))
+                              (loop $l
+                                  (drop (struct.new $point (local.get 0) (i32.const 0)))
+                                  (br_if $l (local.tee 0 (i32.sub (local.get 0) (i32.const 1))))))
+                          (func (export "kept") (result i32)
+                              (struct.get $point $x (global.get $keep)))
+                          (func (export "point") (param i32) (result (ref $point))
+                              (struct.new $point (local.get 0) (i32.const 0)))
+                          (func (export "x") (param (ref $point)) (result i32)
+                              (struct.get $point $x (local.get 0))))"#,
+                ),
+            )
+            .unwrap();
+        let mut env = DebugEnv {};
+        let mut invoke = |store: &mut Store, name: &str, params: Vec<Value>| {
+            runtime.invoke(store, &mut env, name, params)
+        };
+        assert_eq!(
+            invoke(&mut store, "packed", vec![]),
+            Ok(vec![Value::I32(254)])
+        );
+        assert_eq!(invoke(&mut store, "data", vec![]), Ok(vec![Value::I32(-1)]));
+        assert_eq!(
+            invoke(&mut store, "out_of_bounds", vec![])
+                .unwrap_err()
+                .trap(),
+            Some(&Trap::ArrayOutOfBounds)
+        );
+        assert_eq!(
+            invoke(&mut store, "globals", vec![]),
+            Ok(vec![
+                Value::I32(4),
+                Value::I32(2),
+                Value::I32(2),
+                Value::I32(-1)
+            ])
+        );
+        assert_eq!(
+            invoke(&mut store, "too_large", vec![]).unwrap_err().trap(),
+            Some(&Trap::OutOfMemory)
+        );
+        assert_eq!(
+            invoke(&mut store, "call_ref", vec![Value::I32(21)]),
+            Ok(vec![Value::I32(42)])
+        );
+        assert_eq!(
+            invoke(&mut store, "null_call", vec![]).unwrap_err().trap(),
+            Some(&Trap::NullFunctionReference)
+        );
+        assert_eq!(invoke(&mut store, "test", vec![]), Ok(vec![Value::I32(1)]));
+        assert_eq!(
+            invoke(&mut store, "cast", vec![]).unwrap_err().trap(),
+            Some(&Trap::CastFailure)
+        );
+        assert_eq!(
+            invoke(&mut store, "i31", vec![Value::I32(-5)]),
+            Ok(vec![Value::I32(-5)])
+        );
+        assert_eq!(
+            invoke(&mut store, "br_on_null", vec![Value::Ref(Ref::Null)]),
+            Ok(vec![Value::I32(0)])
+        );
+
+        // Collections during the loop keep the heap small and the global alive.
+        assert_eq!(
+            invoke(&mut store, "churn", vec![Value::I32(5000)]),
+            Ok(vec![])
+        );
+        assert!(store.structs.len() < 5000);
+        assert_eq!(invoke(&mut store, "kept", vec![]), Ok(vec![Value::I32(7)]));
+
+        // References held by the embedder survive collections once rooted.
+        let point = invoke(&mut store, "point", vec![Value::I32(3)]).unwrap();
+        store.root(&point[0]);
+        assert_eq!(
+            invoke(&mut store, "churn", vec![Value::I32(5000)]),
+            Ok(vec![])
+        );
+        assert_eq!(
+            invoke(&mut store, "x", point.clone()),
+            Ok(vec![Value::I32(3)])
+        );
+        store.unroot(&point[0]);
+        store.collect(core::iter::empty());
+        // `$keep`, `$origin` and `$pair` remain.
+        assert_eq!(store.structs.len(), 2);
+        assert_eq!(store.arrays.len(), 1);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn threads() {
+        use crate::binary::{IdxType, Limits, Memory};
+        use crate::exec::shared::SharedMemory;
+
+        let shared = SharedMemory::new(&Memory {
+            idxtype: IdxType::I32,
+            limits: Limits::MinMax(1, 2),
+            shared: true,
+        })
+        .unwrap();
+        let wasm = module(
+            r#"(module
+                  (import "env" "memory" (memory 1 2 shared))
+                  (func (export "inc") (param i32)
+                      (loop $l
+                          (drop (i32.atomic.rmw.add (i32.const 0) (i32.const 1)))
+                          (br_if $l (local.tee 0 (i32.sub (local.get 0) (i32.const 1))))))
+                  (func (export "load") (param i32) (result i32)
+                      (i32.atomic.load (local.get 0)))
+                  (func (export "wait") (param i32 i64) (result i32)
+                      (memory.atomic.wait32 (i32.const 8) (local.get 0) (local.get 1)))
+                  (func (export "notify") (result i32)
+                      (memory.atomic.notify (i32.const 8) (i32.const 1)))
+                  (func (export "grow") (result i32) (memory.grow (i32.const 1)))
+                  (func (export "size") (result i32) (memory.size)))"#,
+        );
+        let spawn = |name: &'static str, params: Vec<Value>| {
+            let (shared, wasm) = (shared.clone(), wasm.clone());
+            std::thread::spawn(move || {
+                let mut store = Store::new();
+                let mut runtime = Runtime::new("env");
+                let memory = store.allocate_shared_mem(shared);
+                runtime.linker.memory("env", "memory", memory);
+                runtime
+                    .add_module(&mut store, &mut DebugEnv {}, wasm)
+                    .unwrap();
+                runtime.invoke(&mut store, &mut DebugEnv {}, name, params)
+            })
+        };
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| spawn("inc", vec![Value::I32(1000)]))
+            .collect();
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), Ok(vec![]));
+        }
+        let waiter = spawn("wait", vec![Value::I32(0), Value::I64(-1)]);
+        while spawn("notify", vec![]).join().unwrap() != Ok(vec![Value::I32(1)]) {}
+        assert_eq!(waiter.join().unwrap(), Ok(vec![Value::I32(0)]));
+
+        assert_eq!(
+            spawn("load", vec![Value::I32(0)]).join().unwrap(),
+            Ok(vec![Value::I32(4000)])
+        );
+        assert_eq!(
+            spawn("load", vec![Value::I32(2)])
+                .join()
+                .unwrap()
+                .unwrap_err()
+                .trap(),
+            Some(&Trap::UnalignedAtomic)
+        );
+        assert_eq!(
+            spawn("wait", vec![Value::I32(1), Value::I64(-1)])
+                .join()
+                .unwrap(),
+            Ok(vec![Value::I32(1)])
+        );
+        assert_eq!(
+            spawn("wait", vec![Value::I32(0), Value::I64(1000)])
+                .join()
+                .unwrap(),
+            Ok(vec![Value::I32(2)])
+        );
+        assert_eq!(
+            spawn("grow", vec![]).join().unwrap(),
+            Ok(vec![Value::I32(1)])
+        );
+        assert_eq!(
+            spawn("size", vec![]).join().unwrap(),
+            Ok(vec![Value::I32(2)])
+        );
+        assert_eq!(
+            spawn("grow", vec![]).join().unwrap(),
+            Ok(vec![Value::I32(-1)])
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn shared_memory_reservation() {
+        // The maximum of 2^48 pages cannot be reserved.
+        let mut store = Store::new();
+        let mut runtime = Runtime::new("env");
+        let err = runtime
+            .add_module(
+                &mut store,
+                &mut DebugEnv {},
+                module("(module (memory i64 1 0x1_0000_0000_0000 shared))"),
+            )
+            .unwrap_err();
+        assert_eq!(err, RuntimeError::OutOfMemory);
+        assert_eq!(store.mems.len(), 0);
+    }
+
+    #[test]
+    fn multi_value_blocks() {
+        let mut store = Store::new();
+        let mut runtime = Runtime::new("env");
+        runtime
+            .add_module(
+                &mut store,
+                &mut DebugEnv {},
+                module(
+                    r#"(module
+                          (func $swap (param i32 i32) (result i32 i32)
+                              (local.get 1) (local.get 0))
+                          (func (export "block") (result i32)
+                              (i32.const 1) (i32.const 2)
+                              (block (param i32 i32) (result i32 i32)
+                                  (br 0 (call $swap)))
+                              (i32.sub))
+                          (func (export "if") (param i32) (result i32)
+                              (i32.const 10) (i32.const 3)
+                              (if (param i32 i32) (result i32) (local.get 0)
+                                  (then (i32.add))
+                                  (else (i32.sub))))
+                          ;; factorial with the accumulator and counter
+                          ;; carried as loop parameters
+                          (func (export "fac") (param i64) (result i64)
+                              (i64.const 1) (local.get 0)
+                              (loop $l (param i64 i64) (result i64)
+                                  (local.set 0)
+                                  (i64.mul (local.get 0))
+                                  (i64.sub (local.get 0) (i64.const 1))
+                                  (br_if $l (i64.gt_u (local.get 0) (i64.const 1)))
+                                  (drop)))
+                          (func $early (result i32)
+                              (block (block (return (i32.const 5))))
+                              (i32.const 0))
+                          (func (export "early") (result i32)
+                              (block (result i32)
+                                  (call $early)
+                                  (br 0))))"#,
+                ),
+            )
+            .unwrap();
+        let mut env = DebugEnv {};
+        let mut invoke = |name: &str, params| runtime.invoke(&mut store, &mut env, name, params);
+        assert_eq!(invoke("block", vec![]), Ok(vec![Value::I32(1)]));
+        assert_eq!(invoke("if", vec![Value::I32(1)]), Ok(vec![Value::I32(13)]));
+        assert_eq!(invoke("if", vec![Value::I32(0)]), Ok(vec![Value::I32(7)]));
+        assert_eq!(
+            invoke("fac", vec![Value::I64(5)]),
+            Ok(vec![Value::I64(120)])
+        );
+        assert_eq!(invoke("early", vec![]), Ok(vec![Value::I32(5)]));
+    }
+
+    #[test]
+    fn multi_memory() {
+        struct PeekEnv {}
+        impl Env for PeekEnv {
+            fn call(
+                &mut self,
+                _: &str,
+                _: Vec<Value>,
+                _: Option<&mut MemInst>,
+            ) -> Result<Vec<Value>, &'static str> {
+                unreachable!()
+            }
+
+            fn call_with_memories(
+                &mut self,
+                _: &str,
+                params: Vec<Value>,
+                mut memories: Memories,
+            ) -> Result<Vec<Value>, &'static str> {
+                assert_eq!(memories.len(), 2);
+                let addr = i32::from(params[0]) as usize;
+                let mem = memories.export("second").ok_or("no memory")?;
+                Ok(vec![Value::I32(mem.data.load::<u8>(addr) as i32)])
+            }
+        }
+
+        let mut store = Store::new();
+        let mut runtime = Runtime::new("env");
+        runtime
+            .add_module(
+                &mut store,
+                &mut DebugEnv {},
+                module(
+                    r#"(module
+                          (import "env" "peek" (func $peek (param i32) (result i32)))
+                          (memory $a 1)
+                          (memory $b (export "second") 2)
+                          (data (memory $b) (i32.const 4) "\07")
+                          (func (export "main") (result i32)
+                              (memory.copy $a $b (i32.const 0) (i32.const 4) (i32.const 1))
+                              (memory.fill $b (i32.const 5) (i32.const 9) (i32.const 1))
+                              (i32.add (i32.load8_u $a (i32.const 0))
+                                  (i32.add (memory.size $b) (call $peek (i32.const 5))))))"#,
+                ),
+            )
+            .unwrap();
+        assert_eq!(
+            runtime.invoke(&mut store, &mut PeekEnv {}, "main", vec![]),
+            Ok(vec![Value::I32(18)])
+        );
+    }
+
+    #[test]
+    fn host_memories_of_caller() {
+        struct PeekEnv {}
+        impl Env for PeekEnv {
+            fn call(
+                &mut self,
+                _: &str,
+                _: Vec<Value>,
+                _: Option<&mut MemInst>,
+            ) -> Result<Vec<Value>, &'static str> {
+                unreachable!()
+            }
+
+            fn call_with_memories(
+                &mut self,
+                _: &str,
+                _: Vec<Value>,
+                mut memories: Memories,
+            ) -> Result<Vec<Value>, &'static str> {
+                let mem = memories.get(0).ok_or("no memory")?;
+                Ok(vec![Value::I32(mem.data.load::<u8>(0) as i32)])
+            }
+        }
+
+        let mut importer = ModuleImporter {
+            modules: vec![
+                (
+                    "lib",
+                    module(
+                        r#"(module
+                              (import "env" "peek" (func $peek (result i32)))
+                              (memory 1)
+                              (data (i32.const 0) "\02")
+                              (func (export "peek") (result i32) (call $peek)))"#,
+                    ),
+                ),
+                (
+                    "main",
+                    module(
+                        r#"(module
+                              (import "env" "peek" (func $peek (result i32)))
+                              (import "lib" "peek" (func $lib_peek (result i32)))
+                              (memory 1)
+                              (data (i32.const 0) "\01")
+                              (func (export "main") (result i32 i32)
+                                  (call $peek)
+                                  (call $lib_peek)))"#,
+                    ),
+                ),
+            ],
+        };
+        let mut store = Store::new();
+        let mut runtime = Runtime::new("env");
+        runtime
+            .import_module(&mut store, &mut PeekEnv {}, &mut importer, "main")
+            .unwrap();
+        assert_eq!(
+            runtime.invoke(&mut store, &mut PeekEnv {}, "main", vec![]),
+            Ok(vec![Value::I32(1), Value::I32(2)])
+        );
+    }
 }
@@ -0,0 +1,234 @@
+use super::runtime::PAGE_SIZE;
+use crate::binary::Memory;
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Bytes of a shared memory, which may be imported by instances in several
+/// stores running on different threads.
+///
+/// The bytes for the maximum size are reserved up front so that growing never
+/// moves them while other threads access the memory.
+#[derive(Clone)]
+pub struct SharedMemory(Arc<Inner>);
+
+struct Inner {
+    memory: Memory,
+    ptr: *mut u8,
+    layout: Layout,
+    len: AtomicUsize,
+    /// Serializes `grow` so that concurrent growths add up.
+    grow: Mutex<()>,
+    waiters: Mutex<Vec<Waiter>>,
+    notified: Condvar,
+}
+
+struct Waiter {
+    id: usize,
+    addr: usize,
+    woken: bool,
+}
+
+// The bytes are only accessed through atomics: the instructions of the
+// threads proposal through atomics of their width, everything else byte by
+// byte through relaxed `AtomicU8`s. No references to them are handed out,
+// so racing accesses of wasm threads are never data races in Rust.
+unsafe impl Send for Inner {}
+unsafe impl Sync for Inner {}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.ptr, self.layout) }
+    }
+}
+
+impl SharedMemory {
+    /// Allocates a shared memory of the minimum size of `memory`, which
+    /// must have a maximum. Returns `None` if the bytes for the maximum
+    /// cannot be reserved.
+    pub fn new(memory: &Memory) -> Option<Self> {
+        let pages = memory.limits.max().unwrap_or(memory.limits.min());
+        let capacity = usize::try_from(pages).ok()?.checked_mul(PAGE_SIZE)?.max(8);
+        let layout = Layout::from_size_align(capacity, 8).ok()?;
+        let ptr = unsafe { alloc_zeroed(layout) };
+        if ptr.is_null() {
+            return None;
+        }
+        Some(Self(Arc::new(Inner {
+            memory: memory.clone(),
+            ptr,
+            layout,
+            len: AtomicUsize::new(memory.limits.min() as usize * PAGE_SIZE),
+            grow: Mutex::new(()),
+            waiters: Mutex::new(vec![]),
+            notified: Condvar::new(),
+        })))
+    }
+
+    /// The type the memory was created with.
+    pub fn memory(&self) -> &Memory {
+        &self.0.memory
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len.load(Ordering::SeqCst)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub(crate) fn as_ptr(&self) -> *mut u8 {
+        self.0.ptr
+    }
+
+    /// The byte at `i`, which must be below the length. Bytes stay
+    /// allocated at the same address until the memory is dropped.
+    fn byte(&self, i: usize) -> &AtomicU8 {
+        unsafe { &*(self.0.ptr.add(i) as *const AtomicU8) }
+    }
+
+    /// Panics unless `len` bytes at `offset` are within the memory.
+    fn check(&self, offset: usize, len: usize) {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.len() => {}
+            _ => panic!("shared memory access out of bounds"),
+        }
+    }
+
+    /// Copies the bytes at `offset` into `buf`.
+    pub fn read(&self, offset: usize, buf: &mut [u8]) {
+        self.check(offset, buf.len());
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = self.byte(offset + i).load(Ordering::Relaxed);
+        }
+    }
+
+    /// Copies `bytes` to `offset`.
+    pub fn write(&self, offset: usize, bytes: &[u8]) {
+        self.check(offset, bytes.len());
+        for (i, &byte) in bytes.iter().enumerate() {
+            self.byte(offset + i).store(byte, Ordering::Relaxed);
+        }
+    }
+
+    pub fn fill(&self, offset: usize, len: usize, value: u8) {
+        self.check(offset, len);
+        for i in offset..offset + len {
+            self.byte(i).store(value, Ordering::Relaxed);
+        }
+    }
+
+    /// Copies `len` bytes from `src` to `dst` as if through a buffer.
+    pub fn copy_within(&self, src: usize, dst: usize, len: usize) {
+        self.check(src, len);
+        self.check(dst, len);
+        let copy = |i: usize| {
+            let byte = self.byte(src + i).load(Ordering::Relaxed);
+            self.byte(dst + i).store(byte, Ordering::Relaxed);
+        };
+        if dst <= src {
+            (0..len).for_each(copy);
+        } else {
+            (0..len).rev().for_each(copy);
+        }
+    }
+
+    /// Grows the memory by `bytes` and returns the previous length, or
+    /// `None` if it would exceed the reserved maximum.
+    pub fn grow(&self, bytes: usize) -> Option<usize> {
+        let _guard = self.0.grow.lock().unwrap();
+        let len = self.len();
+        match len.checked_add(bytes) {
+            Some(new_len) if new_len <= self.0.layout.size() => {
+                self.0.len.store(new_len, Ordering::SeqCst);
+                Some(len)
+            }
+            _ => None,
+        }
+    }
+
+    /// Blocks until `addr` is notified or `timeout` elapses, provided that
+    /// `check` holds. Returns 0 when woken, 1 when `check` fails and 2 on
+    /// timeout, like `memory.atomic.wait`.
+    pub fn wait(
+        &self,
+        addr: usize,
+        check: impl FnOnce() -> bool,
+        timeout: Option<Duration>,
+    ) -> u32 {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+        // Notifiers take the lock after storing, so checking the value
+        // under the lock cannot miss a notification.
+        let mut waiters = self.0.waiters.lock().unwrap();
+        if !check() {
+            return 1;
+        }
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        waiters.push(Waiter {
+            id,
+            addr,
+            woken: false,
+        });
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let i = waiters.iter().position(|w| w.id == id).unwrap();
+            if waiters[i].woken {
+                waiters.remove(i);
+                return 0;
+            }
+            waiters = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        waiters.remove(i);
+                        return 2;
+                    }
+                    self.0
+                        .notified
+                        .wait_timeout(waiters, deadline - now)
+                        .unwrap()
+                        .0
+                }
+                None => self.0.notified.wait(waiters).unwrap(),
+            };
+        }
+    }
+
+    /// Wakes up to `count` threads waiting on `addr` and returns how many
+    /// were woken.
+    pub fn notify(&self, addr: usize, count: u32) -> u32 {
+        let mut waiters = self.0.waiters.lock().unwrap();
+        let mut woken = 0;
+        for waiter in waiters.iter_mut() {
+            if woken == count {
+                break;
+            }
+            if waiter.addr == addr && !waiter.woken {
+                waiter.woken = true;
+                woken += 1;
+            }
+        }
+        if woken > 0 {
+            self.0.notified.notify_all();
+        }
+        woken
+    }
+}
+
+impl PartialEq for SharedMemory {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl core::fmt::Debug for SharedMemory {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("SharedMemory")
+            .field("memory", &self.0.memory)
+            .field("len", &self.len())
+            .finish()
+    }
+}
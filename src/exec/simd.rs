@@ -0,0 +1,755 @@
+use super::{
+    memory::effective_address,
+    runtime::Instance,
+    stack::Stack,
+    store::Store,
+    trap::Trap,
+    value::{LittleEndian, Value},
+};
+use crate::binary::{Instr, MemArg};
+#[cfg(not(feature = "std"))]
+use crate::lib::*;
+use core::mem::size_of;
+use num_traits::float::Float;
+
+/// Executes a vector instruction. A `v128` is held as a `u128` whose little
+/// endian bytes are the lanes, lane 0 first.
+pub fn step(
+    instr: &Instr,
+    instance: &Instance,
+    store: &mut Store,
+    stack: &mut Stack,
+) -> Result<(), Trap> {
+    match instr {
+        Instr::V128Load(m) => load::<u128>(m, instance, store, stack)?,
+        Instr::V128Load8x8S(m) => load_extend::<i8, i16>(m, instance, store, stack, |a| a as i16)?,
+        Instr::V128Load8x8U(m) => load_extend::<u8, u16>(m, instance, store, stack, |a| a as u16)?,
+        Instr::V128Load16x4S(m) => {
+            load_extend::<i16, i32>(m, instance, store, stack, |a| a as i32)?
+        }
+        Instr::V128Load16x4U(m) => {
+            load_extend::<u16, u32>(m, instance, store, stack, |a| a as u32)?
+        }
+        Instr::V128Load32x2S(m) => {
+            load_extend::<i32, i64>(m, instance, store, stack, |a| a as i64)?
+        }
+        Instr::V128Load32x2U(m) => {
+            load_extend::<u32, u64>(m, instance, store, stack, |a| a as u64)?
+        }
+        Instr::V128Load8Splat(m) => load_splat::<u8>(m, instance, store, stack)?,
+        Instr::V128Load16Splat(m) => load_splat::<u16>(m, instance, store, stack)?,
+        Instr::V128Load32Splat(m) => load_splat::<u32>(m, instance, store, stack)?,
+        Instr::V128Load64Splat(m) => load_splat::<u64>(m, instance, store, stack)?,
+        Instr::V128Store(m) => store_lane::<u128>(m, 0, instance, store, stack)?,
+        Instr::V128Const(v) => stack.push_value(*v),
+        Instr::I8x16Shuffle(lanes) => shuffle(lanes, stack),
+        Instr::I8x16Swizzle => swizzle(stack),
+        Instr::I8x16Splat => splat(stack, |x: i32| -> u8 { x as u8 }),
+        Instr::I16x8Splat => splat(stack, |x: i32| -> u16 { x as u16 }),
+        Instr::I32x4Splat => splat(stack, |x: i32| -> i32 { x }),
+        Instr::I64x2Splat => splat(stack, |x: i64| -> i64 { x }),
+        Instr::F32x4Splat => splat(stack, |x: f32| -> f32 { x }),
+        Instr::F64x2Splat => splat(stack, |x: f64| -> f64 { x }),
+        Instr::I8x16ExtractLaneS(l) => extract_lane(stack, *l, |x: i8| -> i32 { x as i32 }),
+        Instr::I8x16ExtractLaneU(l) => extract_lane(stack, *l, |x: u8| -> i32 { x as i32 }),
+        Instr::I8x16ReplaceLane(l) => replace_lane(stack, *l, |x: i32| -> u8 { x as u8 }),
+        Instr::I16x8ExtractLaneS(l) => extract_lane(stack, *l, |x: i16| -> i32 { x as i32 }),
+        Instr::I16x8ExtractLaneU(l) => extract_lane(stack, *l, |x: u16| -> i32 { x as i32 }),
+        Instr::I16x8ReplaceLane(l) => replace_lane(stack, *l, |x: i32| -> u16 { x as u16 }),
+        Instr::I32x4ExtractLane(l) => extract_lane(stack, *l, |x: i32| -> i32 { x }),
+        Instr::I32x4ReplaceLane(l) => replace_lane(stack, *l, |x: i32| -> i32 { x }),
+        Instr::I64x2ExtractLane(l) => extract_lane(stack, *l, |x: i64| -> i64 { x }),
+        Instr::I64x2ReplaceLane(l) => replace_lane(stack, *l, |x: i64| -> i64 { x }),
+        Instr::F32x4ExtractLane(l) => extract_lane(stack, *l, |x: f32| -> f32 { x }),
+        Instr::F32x4ReplaceLane(l) => replace_lane(stack, *l, |x: f32| -> f32 { x }),
+        Instr::F64x2ExtractLane(l) => extract_lane(stack, *l, |x: f64| -> f64 { x }),
+        Instr::F64x2ReplaceLane(l) => replace_lane(stack, *l, |x: f64| -> f64 { x }),
+        Instr::I8x16Eq => relop(stack, |a: i8, b| a == b),
+        Instr::I8x16Ne => relop(stack, |a: i8, b| a != b),
+        Instr::I8x16LtS => relop(stack, |a: i8, b| a < b),
+        Instr::I8x16LtU => relop(stack, |a: u8, b| a < b),
+        Instr::I8x16GtS => relop(stack, |a: i8, b| a > b),
+        Instr::I8x16GtU => relop(stack, |a: u8, b| a > b),
+        Instr::I8x16LeS => relop(stack, |a: i8, b| a <= b),
+        Instr::I8x16LeU => relop(stack, |a: u8, b| a <= b),
+        Instr::I8x16GeS => relop(stack, |a: i8, b| a >= b),
+        Instr::I8x16GeU => relop(stack, |a: u8, b| a >= b),
+        Instr::I16x8Eq => relop(stack, |a: i16, b| a == b),
+        Instr::I16x8Ne => relop(stack, |a: i16, b| a != b),
+        Instr::I16x8LtS => relop(stack, |a: i16, b| a < b),
+        Instr::I16x8LtU => relop(stack, |a: u16, b| a < b),
+        Instr::I16x8GtS => relop(stack, |a: i16, b| a > b),
+        Instr::I16x8GtU => relop(stack, |a: u16, b| a > b),
+        Instr::I16x8LeS => relop(stack, |a: i16, b| a <= b),
+        Instr::I16x8LeU => relop(stack, |a: u16, b| a <= b),
+        Instr::I16x8GeS => relop(stack, |a: i16, b| a >= b),
+        Instr::I16x8GeU => relop(stack, |a: u16, b| a >= b),
+        Instr::I32x4Eq => relop(stack, |a: i32, b| a == b),
+        Instr::I32x4Ne => relop(stack, |a: i32, b| a != b),
+        Instr::I32x4LtS => relop(stack, |a: i32, b| a < b),
+        Instr::I32x4LtU => relop(stack, |a: u32, b| a < b),
+        Instr::I32x4GtS => relop(stack, |a: i32, b| a > b),
+        Instr::I32x4GtU => relop(stack, |a: u32, b| a > b),
+        Instr::I32x4LeS => relop(stack, |a: i32, b| a <= b),
+        Instr::I32x4LeU => relop(stack, |a: u32, b| a <= b),
+        Instr::I32x4GeS => relop(stack, |a: i32, b| a >= b),
+        Instr::I32x4GeU => relop(stack, |a: u32, b| a >= b),
+        Instr::F32x4Eq => relop(stack, |a: f32, b| a == b),
+        Instr::F32x4Ne => relop(stack, |a: f32, b| a != b),
+        Instr::F32x4Lt => relop(stack, |a: f32, b| a < b),
+        Instr::F32x4Gt => relop(stack, |a: f32, b| a > b),
+        Instr::F32x4Le => relop(stack, |a: f32, b| a <= b),
+        Instr::F32x4Ge => relop(stack, |a: f32, b| a >= b),
+        Instr::F64x2Eq => relop(stack, |a: f64, b| a == b),
+        Instr::F64x2Ne => relop(stack, |a: f64, b| a != b),
+        Instr::F64x2Lt => relop(stack, |a: f64, b| a < b),
+        Instr::F64x2Gt => relop(stack, |a: f64, b| a > b),
+        Instr::F64x2Le => relop(stack, |a: f64, b| a <= b),
+        Instr::F64x2Ge => relop(stack, |a: f64, b| a >= b),
+        Instr::V128Not => stack.unop(|a: u128| !a),
+        Instr::V128And => stack.binop(|a: u128, b| a & b),
+        Instr::V128Andnot => stack.binop(|a: u128, b| a & !b),
+        Instr::V128Or => stack.binop(|a: u128, b| a | b),
+        Instr::V128Xor => stack.binop(|a: u128, b| a ^ b),
+        Instr::V128Bitselect => bitselect(stack),
+        Instr::V128AnyTrue => stack.testop(|a: u128| (a != 0) as i32),
+        Instr::V128Load8Lane(m, l) => load_lane::<u8>(m, *l, instance, store, stack)?,
+        Instr::V128Load16Lane(m, l) => load_lane::<u16>(m, *l, instance, store, stack)?,
+        Instr::V128Load32Lane(m, l) => load_lane::<u32>(m, *l, instance, store, stack)?,
+        Instr::V128Load64Lane(m, l) => load_lane::<u64>(m, *l, instance, store, stack)?,
+        Instr::V128Store8Lane(m, l) => store_lane::<u8>(m, *l, instance, store, stack)?,
+        Instr::V128Store16Lane(m, l) => store_lane::<u16>(m, *l, instance, store, stack)?,
+        Instr::V128Store32Lane(m, l) => store_lane::<u32>(m, *l, instance, store, stack)?,
+        Instr::V128Store64Lane(m, l) => store_lane::<u64>(m, *l, instance, store, stack)?,
+        Instr::V128Load32Zero(m) => load::<u32>(m, instance, store, stack)?,
+        Instr::V128Load64Zero(m) => load::<u64>(m, instance, store, stack)?,
+        Instr::F32x4DemoteF64x2Zero => convert(stack, 0, |a: f64| a as f32),
+        Instr::F64x2PromoteLowF32x4 => convert(stack, 0, |a: f32| a as f64),
+        Instr::I8x16Abs => unop(stack, |a: i8| a.wrapping_abs()),
+        Instr::I8x16Neg => unop(stack, |a: i8| a.wrapping_neg()),
+        Instr::I8x16Popcnt => unop(stack, |a: u8| a.count_ones() as u8),
+        Instr::I8x16AllTrue => all_true(stack, |a: i8| a != 0),
+        Instr::I8x16Bitmask => bitmask(stack, |a: i8| a < 0),
+        Instr::I8x16NarrowI16x8S => narrow(stack, |a: i16| {
+            a.clamp(i8::MIN as i16, i8::MAX as i16) as i8
+        }),
+        Instr::I8x16NarrowI16x8U => narrow(stack, |a: i16| a.clamp(0, u8::MAX as i16) as u8),
+        Instr::F32x4Ceil => unop(stack, |a: f32| Float::ceil(a)),
+        Instr::F32x4Floor => unop(stack, |a: f32| Float::floor(a)),
+        Instr::F32x4Trunc => unop(stack, |a: f32| Float::trunc(a)),
+        Instr::F32x4Nearest => unop(stack, |a: f32| nearest(a)),
+        Instr::I8x16Shl => shift(stack, |a: i8, n| a.wrapping_shl(n)),
+        Instr::I8x16ShrS => shift(stack, |a: i8, n| a.wrapping_shr(n)),
+        Instr::I8x16ShrU => shift(stack, |a: u8, n| a.wrapping_shr(n)),
+        Instr::I8x16Add => binop(stack, |a: i8, b| a.wrapping_add(b)),
+        Instr::I8x16AddSatS => binop(stack, |a: i8, b| a.saturating_add(b)),
+        Instr::I8x16AddSatU => binop(stack, |a: u8, b| a.saturating_add(b)),
+        Instr::I8x16Sub => binop(stack, |a: i8, b| a.wrapping_sub(b)),
+        Instr::I8x16SubSatS => binop(stack, |a: i8, b| a.saturating_sub(b)),
+        Instr::I8x16SubSatU => binop(stack, |a: u8, b| a.saturating_sub(b)),
+        Instr::F64x2Ceil => unop(stack, |a: f64| Float::ceil(a)),
+        Instr::F64x2Floor => unop(stack, |a: f64| Float::floor(a)),
+        Instr::I8x16MinS => binop(stack, |a: i8, b| a.min(b)),
+        Instr::I8x16MinU => binop(stack, |a: u8, b| a.min(b)),
+        Instr::I8x16MaxS => binop(stack, |a: i8, b| a.max(b)),
+        Instr::I8x16MaxU => binop(stack, |a: u8, b| a.max(b)),
+        Instr::F64x2Trunc => unop(stack, |a: f64| Float::trunc(a)),
+        Instr::I8x16AvgrU => binop(stack, |a: u8, b| ((a as u16 + b as u16 + 1) / 2) as u8),
+        Instr::I16x8ExtaddPairwiseI8x16S => {
+            extadd_pairwise(stack, |a: i8, b: i8| a as i16 + b as i16)
+        }
+        Instr::I16x8ExtaddPairwiseI8x16U => {
+            extadd_pairwise(stack, |a: u8, b: u8| a as u16 + b as u16)
+        }
+        Instr::I32x4ExtaddPairwiseI16x8S => {
+            extadd_pairwise(stack, |a: i16, b: i16| a as i32 + b as i32)
+        }
+        Instr::I32x4ExtaddPairwiseI16x8U => {
+            extadd_pairwise(stack, |a: u16, b: u16| a as u32 + b as u32)
+        }
+        Instr::I16x8Abs => unop(stack, |a: i16| a.wrapping_abs()),
+        Instr::I16x8Neg => unop(stack, |a: i16| a.wrapping_neg()),
+        Instr::I16x8Q15mulrSatS => binop(stack, |a: i16, b| {
+            ((a as i32 * b as i32 + 0x4000) >> 15).clamp(i16::MIN as i32, i16::MAX as i32) as i16
+        }),
+        Instr::I16x8AllTrue => all_true(stack, |a: i16| a != 0),
+        Instr::I16x8Bitmask => bitmask(stack, |a: i16| a < 0),
+        Instr::I16x8NarrowI32x4S => narrow(stack, |a: i32| {
+            a.clamp(i16::MIN as i32, i16::MAX as i32) as i16
+        }),
+        Instr::I16x8NarrowI32x4U => narrow(stack, |a: i32| a.clamp(0, u16::MAX as i32) as u16),
+        Instr::I16x8ExtendLowI8x16S => convert(stack, 0, |a: i8| a as i16),
+        Instr::I16x8ExtendHighI8x16S => convert(stack, 8, |a: i8| a as i16),
+        Instr::I16x8ExtendLowI8x16U => convert(stack, 0, |a: u8| a as u16),
+        Instr::I16x8ExtendHighI8x16U => convert(stack, 8, |a: u8| a as u16),
+        Instr::I16x8Shl => shift(stack, |a: i16, n| a.wrapping_shl(n)),
+        Instr::I16x8ShrS => shift(stack, |a: i16, n| a.wrapping_shr(n)),
+        Instr::I16x8ShrU => shift(stack, |a: u16, n| a.wrapping_shr(n)),
+        Instr::I16x8Add => binop(stack, |a: i16, b| a.wrapping_add(b)),
+        Instr::I16x8AddSatS => binop(stack, |a: i16, b| a.saturating_add(b)),
+        Instr::I16x8AddSatU => binop(stack, |a: u16, b| a.saturating_add(b)),
+        Instr::I16x8Sub => binop(stack, |a: i16, b| a.wrapping_sub(b)),
+        Instr::I16x8SubSatS => binop(stack, |a: i16, b| a.saturating_sub(b)),
+        Instr::I16x8SubSatU => binop(stack, |a: u16, b| a.saturating_sub(b)),
+        Instr::F64x2Nearest => unop(stack, |a: f64| nearest(a)),
+        Instr::I16x8Mul => binop(stack, |a: i16, b| a.wrapping_mul(b)),
+        Instr::I16x8MinS => binop(stack, |a: i16, b| a.min(b)),
+        Instr::I16x8MinU => binop(stack, |a: u16, b| a.min(b)),
+        Instr::I16x8MaxS => binop(stack, |a: i16, b| a.max(b)),
+        Instr::I16x8MaxU => binop(stack, |a: u16, b| a.max(b)),
+        Instr::I16x8AvgrU => binop(stack, |a: u16, b| ((a as u32 + b as u32 + 1) / 2) as u16),
+        Instr::I16x8ExtmulLowI8x16S => extmul(stack, false, |a: i8, b: i8| a as i16 * b as i16),
+        Instr::I16x8ExtmulHighI8x16S => extmul(stack, true, |a: i8, b: i8| a as i16 * b as i16),
+        Instr::I16x8ExtmulLowI8x16U => extmul(stack, false, |a: u8, b: u8| a as u16 * b as u16),
+        Instr::I16x8ExtmulHighI8x16U => extmul(stack, true, |a: u8, b: u8| a as u16 * b as u16),
+        Instr::I32x4Abs => unop(stack, |a: i32| a.wrapping_abs()),
+        Instr::I32x4Neg => unop(stack, |a: i32| a.wrapping_neg()),
+        Instr::I32x4AllTrue => all_true(stack, |a: i32| a != 0),
+        Instr::I32x4Bitmask => bitmask(stack, |a: i32| a < 0),
+        Instr::I32x4ExtendLowI16x8S => convert(stack, 0, |a: i16| a as i32),
+        Instr::I32x4ExtendHighI16x8S => convert(stack, 4, |a: i16| a as i32),
+        Instr::I32x4ExtendLowI16x8U => convert(stack, 0, |a: u16| a as u32),
+        Instr::I32x4ExtendHighI16x8U => convert(stack, 4, |a: u16| a as u32),
+        Instr::I32x4Shl => shift(stack, |a: i32, n| a.wrapping_shl(n)),
+        Instr::I32x4ShrS => shift(stack, |a: i32, n| a.wrapping_shr(n)),
+        Instr::I32x4ShrU => shift(stack, |a: u32, n| a.wrapping_shr(n)),
+        Instr::I32x4Add => binop(stack, |a: i32, b| a.wrapping_add(b)),
+        Instr::I32x4Sub => binop(stack, |a: i32, b| a.wrapping_sub(b)),
+        Instr::I32x4Mul => binop(stack, |a: i32, b| a.wrapping_mul(b)),
+        Instr::I32x4MinS => binop(stack, |a: i32, b| a.min(b)),
+        Instr::I32x4MinU => binop(stack, |a: u32, b| a.min(b)),
+        Instr::I32x4MaxS => binop(stack, |a: i32, b| a.max(b)),
+        Instr::I32x4MaxU => binop(stack, |a: u32, b| a.max(b)),
+        Instr::I32x4DotI16x8S => dot(stack),
+        Instr::I32x4ExtmulLowI16x8S => extmul(stack, false, |a: i16, b: i16| a as i32 * b as i32),
+        Instr::I32x4ExtmulHighI16x8S => extmul(stack, true, |a: i16, b: i16| a as i32 * b as i32),
+        Instr::I32x4ExtmulLowI16x8U => extmul(stack, false, |a: u16, b: u16| a as u32 * b as u32),
+        Instr::I32x4ExtmulHighI16x8U => extmul(stack, true, |a: u16, b: u16| a as u32 * b as u32),
+        Instr::I64x2Abs => unop(stack, |a: i64| a.wrapping_abs()),
+        Instr::I64x2Neg => unop(stack, |a: i64| a.wrapping_neg()),
+        Instr::I64x2AllTrue => all_true(stack, |a: i64| a != 0),
+        Instr::I64x2Bitmask => bitmask(stack, |a: i64| a < 0),
+        Instr::I64x2ExtendLowI32x4S => convert(stack, 0, |a: i32| a as i64),
+        Instr::I64x2ExtendHighI32x4S => convert(stack, 2, |a: i32| a as i64),
+        Instr::I64x2ExtendLowI32x4U => convert(stack, 0, |a: u32| a as u64),
+        Instr::I64x2ExtendHighI32x4U => convert(stack, 2, |a: u32| a as u64),
+        Instr::I64x2Shl => shift(stack, |a: i64, n| a.wrapping_shl(n)),
+        Instr::I64x2ShrS => shift(stack, |a: i64, n| a.wrapping_shr(n)),
+        Instr::I64x2ShrU => shift(stack, |a: u64, n| a.wrapping_shr(n)),
+        Instr::I64x2Add => binop(stack, |a: i64, b| a.wrapping_add(b)),
+        Instr::I64x2Sub => binop(stack, |a: i64, b| a.wrapping_sub(b)),
+        Instr::I64x2Mul => binop(stack, |a: i64, b| a.wrapping_mul(b)),
+        Instr::I64x2Eq => relop(stack, |a: i64, b| a == b),
+        Instr::I64x2Ne => relop(stack, |a: i64, b| a != b),
+        Instr::I64x2LtS => relop(stack, |a: i64, b| a < b),
+        Instr::I64x2GtS => relop(stack, |a: i64, b| a > b),
+        Instr::I64x2LeS => relop(stack, |a: i64, b| a <= b),
+        Instr::I64x2GeS => relop(stack, |a: i64, b| a >= b),
+        Instr::I64x2ExtmulLowI32x4S => extmul(stack, false, |a: i32, b: i32| a as i64 * b as i64),
+        Instr::I64x2ExtmulHighI32x4S => extmul(stack, true, |a: i32, b: i32| a as i64 * b as i64),
+        Instr::I64x2ExtmulLowI32x4U => extmul(stack, false, |a: u32, b: u32| a as u64 * b as u64),
+        Instr::I64x2ExtmulHighI32x4U => extmul(stack, true, |a: u32, b: u32| a as u64 * b as u64),
+        Instr::F32x4Abs => unop(stack, |a: f32| Float::abs(a)),
+        Instr::F32x4Neg => unop(stack, |a: f32| -a),
+        Instr::F32x4Sqrt => unop(stack, |a: f32| Float::sqrt(a)),
+        Instr::F32x4Add => binop(stack, |a: f32, b| a + b),
+        Instr::F32x4Sub => binop(stack, |a: f32, b| a - b),
+        Instr::F32x4Mul => binop(stack, |a: f32, b| a * b),
+        Instr::F32x4Div => binop(stack, |a: f32, b| a / b),
+        Instr::F32x4Min => binop(stack, |a: f32, b| fmin(a, b)),
+        Instr::F32x4Max => binop(stack, |a: f32, b| fmax(a, b)),
+        Instr::F32x4Pmin => binop(stack, |a: f32, b| if b < a { b } else { a }),
+        Instr::F32x4Pmax => binop(stack, |a: f32, b| if a < b { b } else { a }),
+        Instr::F64x2Abs => unop(stack, |a: f64| Float::abs(a)),
+        Instr::F64x2Neg => unop(stack, |a: f64| -a),
+        Instr::F64x2Sqrt => unop(stack, |a: f64| Float::sqrt(a)),
+        Instr::F64x2Add => binop(stack, |a: f64, b| a + b),
+        Instr::F64x2Sub => binop(stack, |a: f64, b| a - b),
+        Instr::F64x2Mul => binop(stack, |a: f64, b| a * b),
+        Instr::F64x2Div => binop(stack, |a: f64, b| a / b),
+        Instr::F64x2Min => binop(stack, |a: f64, b| fmin(a, b)),
+        Instr::F64x2Max => binop(stack, |a: f64, b| fmax(a, b)),
+        Instr::F64x2Pmin => binop(stack, |a: f64, b| if b < a { b } else { a }),
+        Instr::F64x2Pmax => binop(stack, |a: f64, b| if a < b { b } else { a }),
+        Instr::I32x4TruncSatF32x4S => convert(stack, 0, |a: f32| a as i32),
+        Instr::I32x4TruncSatF32x4U => convert(stack, 0, |a: f32| a as u32),
+        Instr::F32x4ConvertI32x4S => convert(stack, 0, |a: i32| a as f32),
+        Instr::F32x4ConvertI32x4U => convert(stack, 0, |a: u32| a as f32),
+        Instr::I32x4TruncSatF64x2SZero => convert(stack, 0, |a: f64| a as i32),
+        Instr::I32x4TruncSatF64x2UZero => convert(stack, 0, |a: f64| a as u32),
+        Instr::F64x2ConvertLowI32x4S => convert(stack, 0, |a: i32| a as f64),
+        Instr::F64x2ConvertLowI32x4U => convert(stack, 0, |a: u32| a as f64),
+        _ => unreachable!("{:?}", instr),
+    }
+    Ok(())
+}
+
+/// Number of lanes of type `T` in a `v128`.
+fn count<T>() -> usize {
+    16 / size_of::<T>()
+}
+
+fn lane<T: LittleEndian>(v: &[u8; 16], i: usize) -> T {
+    T::read(v, i * size_of::<T>())
+}
+
+fn set_lane<T: LittleEndian>(v: &mut [u8; 16], i: usize, x: T) {
+    T::write(v, i * size_of::<T>(), x)
+}
+
+fn pop(stack: &mut Stack) -> [u8; 16] {
+    stack.pop_value::<u128>().to_le_bytes()
+}
+
+fn push(stack: &mut Stack, v: [u8; 16]) {
+    stack.push_value(u128::from_le_bytes(v))
+}
+
+/// Maps lanes of `T` starting at lane `offset` to lanes of `U`. When `U`
+/// has more lanes than `T`, the remaining lanes are zero.
+fn map<T: LittleEndian, U: LittleEndian>(
+    v: [u8; 16],
+    offset: usize,
+    f: impl Fn(T) -> U,
+) -> [u8; 16] {
+    let mut out = [0; 16];
+    for i in 0..count::<U>().min(count::<T>()) {
+        set_lane(&mut out, i, f(lane(&v, i + offset)));
+    }
+    out
+}
+
+fn convert<T: LittleEndian, U: LittleEndian>(stack: &mut Stack, offset: usize, f: impl Fn(T) -> U) {
+    let v = pop(stack);
+    push(stack, map(v, offset, f))
+}
+
+fn unop<T: LittleEndian>(stack: &mut Stack, f: impl Fn(T) -> T) {
+    convert(stack, 0, f)
+}
+
+fn binop<T: LittleEndian>(stack: &mut Stack, f: impl Fn(T, T) -> T) {
+    let b = pop(stack);
+    let a = pop(stack);
+    let mut out = [0; 16];
+    for i in 0..count::<T>() {
+        set_lane(&mut out, i, f(lane(&a, i), lane(&b, i)));
+    }
+    push(stack, out)
+}
+
+/// Lanes for which `f` holds are set to all ones, the others to zero.
+fn relop<T: LittleEndian>(stack: &mut Stack, f: impl Fn(T, T) -> bool) {
+    let b = pop(stack);
+    let a = pop(stack);
+    let mut out = [0; 16];
+    let size = size_of::<T>();
+    for i in 0..count::<T>() {
+        if f(lane(&a, i), lane(&b, i)) {
+            out[i * size..(i + 1) * size].fill(0xFF);
+        }
+    }
+    push(stack, out)
+}
+
+/// The shift count is taken modulo the lane width by the wrapping shifts.
+fn shift<T: LittleEndian>(stack: &mut Stack, f: impl Fn(T, u32) -> T) {
+    let n = stack.pop_value::<i32>() as u32;
+    unop(stack, |a: T| f(a, n))
+}
+
+fn splat_bytes<T: LittleEndian + Copy>(x: T) -> [u8; 16] {
+    let mut out = [0; 16];
+    for i in 0..count::<T>() {
+        set_lane(&mut out, i, x);
+    }
+    out
+}
+
+fn splat<S: From<Value>, T: LittleEndian + Copy>(stack: &mut Stack, f: impl Fn(S) -> T) {
+    let x = f(stack.pop_value());
+    push(stack, splat_bytes(x))
+}
+
+fn extract_lane<T: LittleEndian, S: Into<Value>>(stack: &mut Stack, l: u8, f: impl Fn(T) -> S) {
+    let v = pop(stack);
+    stack.push_value(f(lane(&v, l as usize)))
+}
+
+fn replace_lane<S: From<Value>, T: LittleEndian>(stack: &mut Stack, l: u8, f: impl Fn(S) -> T) {
+    let x = f(stack.pop_value());
+    let mut v = pop(stack);
+    set_lane(&mut v, l as usize, x);
+    push(stack, v)
+}
+
+/// Narrows the lanes of both operands with saturation, `a` in the low half.
+fn narrow<T: LittleEndian, U: LittleEndian>(stack: &mut Stack, f: impl Fn(T) -> U) {
+    let b = pop(stack);
+    let a = pop(stack);
+    let n = count::<T>();
+    let mut out = [0; 16];
+    for i in 0..n {
+        set_lane(&mut out, i, f(lane(&a, i)));
+        set_lane(&mut out, i + n, f(lane(&b, i)));
+    }
+    push(stack, out)
+}
+
+fn extmul<T: LittleEndian, U: LittleEndian>(stack: &mut Stack, high: bool, f: impl Fn(T, T) -> U) {
+    let b = pop(stack);
+    let a = pop(stack);
+    let n = count::<U>();
+    let offset = if high { n } else { 0 };
+    let mut out = [0; 16];
+    for i in 0..n {
+        set_lane(&mut out, i, f(lane(&a, i + offset), lane(&b, i + offset)));
+    }
+    push(stack, out)
+}
+
+fn extadd_pairwise<T: LittleEndian, U: LittleEndian>(stack: &mut Stack, f: impl Fn(T, T) -> U) {
+    let a = pop(stack);
+    let mut out = [0; 16];
+    for i in 0..count::<U>() {
+        set_lane(&mut out, i, f(lane(&a, 2 * i), lane(&a, 2 * i + 1)));
+    }
+    push(stack, out)
+}
+
+fn dot(stack: &mut Stack) {
+    let b = pop(stack);
+    let a = pop(stack);
+    let mut out = [0; 16];
+    for i in 0..4 {
+        let product = |j| lane::<i16>(&a, j) as i32 * lane::<i16>(&b, j) as i32;
+        set_lane(&mut out, i, product(2 * i).wrapping_add(product(2 * i + 1)));
+    }
+    push(stack, out)
+}
+
+fn bitmask<T: LittleEndian>(stack: &mut Stack, f: impl Fn(T) -> bool) {
+    let v = pop(stack);
+    let mask = (0..count::<T>()).fold(0, |mask, i| mask | ((f(lane(&v, i)) as i32) << i));
+    stack.push_value(mask)
+}
+
+fn all_true<T: LittleEndian>(stack: &mut Stack, f: impl Fn(T) -> bool) {
+    let v = pop(stack);
+    let all = (0..count::<T>()).all(|i| f(lane(&v, i)));
+    stack.push_value(all as i32)
+}
+
+fn bitselect(stack: &mut Stack) {
+    let c = stack.pop_value::<u128>();
+    let b = stack.pop_value::<u128>();
+    let a = stack.pop_value::<u128>();
+    stack.push_value((a & c) | (b & !c))
+}
+
+/// Indices out of range select zero.
+fn swizzle(stack: &mut Stack) {
+    let s = pop(stack);
+    let a = pop(stack);
+    push(stack, s.map(|i| a.get(i as usize).copied().unwrap_or(0)))
+}
+
+/// Indices select from the 32 bytes of both operands, `a` first.
+fn shuffle(lanes: &[u8; 16], stack: &mut Stack) {
+    let b = pop(stack);
+    let a = pop(stack);
+    push(
+        stack,
+        lanes.map(|i| {
+            if i < 16 {
+                a[i as usize]
+            } else {
+                b[i as usize - 16]
+            }
+        }),
+    )
+}
+
+/// NaN propagating minimum which orders -0 below +0.
+fn fmin<F: Float>(a: F, b: F) -> F {
+    if a.is_nan() || b.is_nan() {
+        a + b
+    } else if a == b {
+        if a.is_sign_negative() {
+            a
+        } else {
+            b
+        }
+    } else {
+        a.min(b)
+    }
+}
+
+/// NaN propagating maximum which orders -0 below +0.
+fn fmax<F: Float>(a: F, b: F) -> F {
+    if a.is_nan() || b.is_nan() {
+        a + b
+    } else if a == b {
+        if a.is_sign_positive() {
+            a
+        } else {
+            b
+        }
+    } else {
+        a.max(b)
+    }
+}
+
+/// Rounds to the nearest integer, ties to even.
+fn nearest<F: Float>(v: F) -> F {
+    let two = F::one() + F::one();
+    let fround = v.round();
+    if (v - fround).abs() == F::one() / two && fround % two != F::zero() {
+        v.trunc()
+    } else {
+        fround
+    }
+}
+
+fn read<T: LittleEndian>(
+    memarg: &MemArg,
+    instance: &Instance,
+    store: &Store,
+    stack: &mut Stack,
+) -> Result<T, Trap> {
+    let mem = &store.mems[instance.memaddrs[memarg.memidx as usize]];
+    let ea = effective_address(mem, memarg, stack, size_of::<T>())?;
+    Ok(mem.data.load(ea))
+}
+
+fn write<T: LittleEndian>(
+    memarg: &MemArg,
+    instance: &Instance,
+    store: &mut Store,
+    stack: &mut Stack,
+    x: T,
+) -> Result<(), Trap> {
+    let mem = &mut store.mems[instance.memaddrs[memarg.memidx as usize]];
+    let ea = effective_address(mem, memarg, stack, size_of::<T>())?;
+    mem.data.store(ea, x);
+    Ok(())
+}
+
+/// Loads a `T` into the low bytes of a zeroed `v128`.
+fn load<T: LittleEndian + Into<u128>>(
+    memarg: &MemArg,
+    instance: &Instance,
+    store: &Store,
+    stack: &mut Stack,
+) -> Result<(), Trap> {
+    let v: u128 = read::<T>(memarg, instance, store, stack)?.into();
+    stack.push_value(v);
+    Ok(())
+}
+
+/// Loads 64 bits and extends each lane of `T` to `U`.
+fn load_extend<T: LittleEndian, U: LittleEndian>(
+    memarg: &MemArg,
+    instance: &Instance,
+    store: &Store,
+    stack: &mut Stack,
+    f: impl Fn(T) -> U,
+) -> Result<(), Trap> {
+    let v = read::<u64>(memarg, instance, store, stack)? as u128;
+    push(stack, map(v.to_le_bytes(), 0, f));
+    Ok(())
+}
+
+fn load_splat<T: LittleEndian + Copy>(
+    memarg: &MemArg,
+    instance: &Instance,
+    store: &Store,
+    stack: &mut Stack,
+) -> Result<(), Trap> {
+    let x = read::<T>(memarg, instance, store, stack)?;
+    push(stack, splat_bytes(x));
+    Ok(())
+}
+
+fn load_lane<T: LittleEndian>(
+    memarg: &MemArg,
+    l: u8,
+    instance: &Instance,
+    store: &Store,
+    stack: &mut Stack,
+) -> Result<(), Trap> {
+    let mut v = pop(stack);
+    let x = read::<T>(memarg, instance, store, stack)?;
+    set_lane(&mut v, l as usize, x);
+    push(stack, v);
+    Ok(())
+}
+
+/// Stores lane `l` of type `T`; `v128.store` is the single lane of `u128`.
+fn store_lane<T: LittleEndian>(
+    memarg: &MemArg,
+    l: u8,
+    instance: &Instance,
+    store: &mut Store,
+    stack: &mut Stack,
+) -> Result<(), Trap> {
+    let v = pop(stack);
+    write(memarg, instance, store, stack, lane::<T>(&v, l as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::step;
+    use crate::binary::{Instr, MemArg};
+    use crate::exec::{runtime::Instance, stack::Stack, store::Store, value::Value};
+
+    fn run(instrs: Vec<Instr>) -> Vec<Value> {
+        let mut stack = Stack::new();
+        let mut store = Store::new();
+        let instance = Instance::default();
+        for instr in instrs.iter() {
+            match instr {
+                Instr::I32Const(v) => stack.push_value(*v),
+                Instr::F32Const(v) => stack.push_value(*v),
+                instr => step(instr, &instance, &mut store, &mut stack).unwrap(),
+            }
+        }
+        stack.values().clone()
+    }
+
+    fn v128(lanes: [i32; 4]) -> Instr {
+        let mut v = 0;
+        for (i, lane) in lanes.iter().enumerate() {
+            v |= (*lane as u32 as u128) << (32 * i);
+        }
+        Instr::V128Const(v)
+    }
+
+    #[test]
+    fn lanes() {
+        assert_eq!(
+            run(vec![
+                v128([1, 2, 3, 4]),
+                v128([10, 20, 30, 40]),
+                Instr::I32x4Add
+            ]),
+            vec![Value::V128(0x0000002C_00000021_00000016_0000000B)]
+        );
+        assert_eq!(
+            run(vec![v128([1, -2, 3, 4]), Instr::I32x4ExtractLane(1)]),
+            vec![Value::I32(-2)]
+        );
+        assert_eq!(
+            run(vec![
+                Instr::I32Const(0x7F),
+                Instr::I8x16Splat,
+                Instr::I32Const(1),
+                Instr::I8x16Splat,
+                Instr::I8x16AddSatS,
+                Instr::I8x16ExtractLaneS(15)
+            ]),
+            vec![Value::I32(0x7F)]
+        );
+        assert_eq!(
+            run(vec![v128([-1, 0, -1, 0]), Instr::I32x4Bitmask]),
+            vec![Value::I32(0b0101)]
+        );
+    }
+
+    #[test]
+    fn compare_and_select() {
+        assert_eq!(
+            run(vec![
+                v128([1, 5, 3, 7]),
+                v128([4, 2, 6, 0]),
+                v128([1, 5, 3, 7]),
+                v128([4, 2, 6, 0]),
+                Instr::I32x4GtS,
+                Instr::V128Bitselect
+            ]),
+            run(vec![
+                v128([1, 5, 3, 7]),
+                v128([4, 2, 6, 0]),
+                Instr::I32x4MaxS
+            ])
+        );
+    }
+
+    #[test]
+    fn float() {
+        let values = run(vec![
+            Instr::F32Const(-0.0),
+            Instr::F32x4Splat,
+            Instr::F32Const(0.0),
+            Instr::F32x4Splat,
+            Instr::F32x4Min,
+            Instr::F32x4ExtractLane(0),
+        ]);
+        assert!(matches!(values[..], [Value::F32(v)] if v == 0.0 && v.is_sign_negative()));
+        assert_eq!(
+            run(vec![
+                Instr::F32Const(2.5),
+                Instr::F32x4Splat,
+                Instr::F32x4Nearest,
+                Instr::I32x4TruncSatF32x4S,
+                Instr::I32x4ExtractLane(3)
+            ]),
+            vec![Value::I32(2)]
+        );
+    }
+
+    #[test]
+    fn shuffle() {
+        let mut lanes = [0; 16];
+        for (i, lane) in lanes.iter_mut().enumerate() {
+            *lane = (31 - i) as u8;
+        }
+        assert_eq!(
+            run(vec![
+                Instr::V128Const(0x0f0e0d0c_0b0a0908_07060504_03020100),
+                Instr::V128Const(0x1f1e1d1c_1b1a1918_17161514_13121110),
+                Instr::I8x16Shuffle(lanes),
+            ]),
+            // Byte i of the result is byte 31 - i of the concatenated operands.
+            vec![Value::V128(u128::from_le_bytes(lanes))]
+        );
+    }
+
+    #[test]
+    fn memory() {
+        let memarg = MemArg {
+            align: 0,
+            offset: 0,
+            memidx: 0,
+        };
+        let mut stack = Stack::new();
+        let mut store = Store::new();
+        let mut instance = Instance::default();
+        instance
+            .memaddrs
+            .push(store.mems.push(crate::exec::store::MemInst {
+                idxtype: crate::binary::IdxType::I32,
+                limits: crate::binary::Limits::Min(1),
+                data: vec![0; 16].into(),
+            }));
+        let instrs = [
+            Instr::I32Const(0),
+            Instr::I32Const(-1),
+            Instr::I32x4Splat,
+            Instr::V128Store8Lane(memarg.clone(), 3),
+            Instr::I32Const(0),
+            Instr::V128Load8x8U(memarg.clone()),
+            Instr::I32Const(15),
+            Instr::V128Load(memarg),
+        ];
+        let mut result = Ok(());
+        for instr in instrs.iter() {
+            match instr {
+                Instr::I32Const(v) => stack.push_value(*v),
+                instr => result = step(instr, &instance, &mut store, &mut stack),
+            }
+        }
+        assert_eq!(result, Err(crate::exec::trap::Trap::MemoryOutOfBounds));
+        assert_eq!(stack.values(), &vec![Value::V128(0xFF)]);
+    }
+}
@@ -9,15 +9,20 @@ pub struct Label {
     pub stack_offset: usize,
     pub pc: usize,
     pub cont: bool,
+    /// pc of the `try_table` whose handlers catch exceptions thrown inside.
+    pub handler: Option<usize>,
 }
 
 #[derive(Debug, PartialEq, Clone, Default)]
 pub struct Frame {
     pub n: usize,
+    /// Address of the function executing in this frame.
+    pub func_addr: Addr,
     pub instance_addr: Addr,
     pub local: Vec<Value>,
     pub pc: usize,
     pub stack_offset: usize,
+    pub label_offset: usize,
 }
 
 #[derive(Debug, PartialEq, Default, Clone)]
@@ -52,12 +57,24 @@ impl Stack {
         &self.frames
     }
 
+    /// Values on the stack and in the locals of all frames, the roots of
+    /// the garbage collector.
+    pub fn roots(&self) -> impl Iterator<Item = &Value> {
+        self.values
+            .iter()
+            .chain(self.frames.iter().flat_map(|frame| frame.local.iter()))
+    }
+
     pub fn values_unwind(&mut self, offset: usize) {
         while self.values_len() > offset {
             self.pop_value::<Value>();
         }
     }
 
+    pub fn labels_unwind(&mut self, offset: usize) {
+        self.labels.truncate(offset);
+    }
+
     pub fn values_len(&self) -> usize {
         self.values.len()
     }
@@ -192,18 +209,16 @@ impl Stack {
 
     pub fn jump(&mut self, l: usize) -> usize {
         let label = self.th_label(l);
-        if !label.cont {
-            let mut values: Vec<Value> = vec![];
-            for _ in 0..label.n {
-                let v = self.pop_value();
-                values.push(v);
-            }
+        let mut values: Vec<Value> = vec![];
+        for _ in 0..label.n {
+            let v = self.pop_value();
+            values.push(v);
+        }
 
-            self.values_unwind(label.stack_offset);
+        self.values_unwind(label.stack_offset);
 
-            for value in values.into_iter().rev() {
-                self.push_value(value);
-            }
+        for value in values.into_iter().rev() {
+            self.push_value(value);
         }
 
         for _ in 0..(l + 1) {
@@ -227,12 +242,14 @@ mod tests {
             stack_offset: 0,
             pc: 10,
             cont: false,
+            handler: None,
         };
         let label2 = Label {
             n: 0,
             stack_offset: 1,
             pc: 0,
             cont: false,
+            handler: None,
         };
         let mut stack = Stack::new();
         stack.push_label(label1);
@@ -244,6 +261,7 @@ mod tests {
                 stack_offset: 1,
                 pc: 0,
                 cont: false,
+                handler: None,
             }
         );
         assert_eq!(
@@ -253,6 +271,7 @@ mod tests {
                 stack_offset: 0,
                 pc: 10,
                 cont: false,
+                handler: None,
             }
         );
 
@@ -263,16 +282,20 @@ mod tests {
     fn stack_frame() {
         let frame1 = Frame {
             n: 0,
+            func_addr: 0,
             instance_addr: 0,
             local: vec![],
             stack_offset: 0,
+            label_offset: 0,
             pc: 0,
         };
         let frame2 = Frame {
             n: 0,
+            func_addr: 0,
             instance_addr: 0,
             local: vec![Value::I32(1), Value::F32(3.0)],
             stack_offset: 0,
+            label_offset: 0,
             pc: 0,
         };
         let mut stack = Stack::new();
@@ -283,9 +306,11 @@ mod tests {
             stack.pop_frame(),
             Frame {
                 n: 0,
+                func_addr: 0,
                 instance_addr: 0,
                 local: vec![Value::I32(1), Value::F32(3.0)],
                 stack_offset: 0,
+                label_offset: 0,
                 pc: 0
             }
         );
@@ -293,9 +318,11 @@ mod tests {
             stack.pop_frame(),
             Frame {
                 n: 0,
+                func_addr: 0,
                 instance_addr: 0,
                 local: vec![],
                 stack_offset: 0,
+                label_offset: 0,
                 pc: 0
             }
         );
@@ -1,12 +1,14 @@
-use super::memory::{data_active, data_passiv};
-use super::runtime::{eval_const, Addr, Runtime, RuntimeError, PAGE_SIZE};
-use super::table::{elem_active, elem_passiv};
-use super::value::{Ref, Value};
+use super::memory::data_passiv;
+use super::runtime::{eval_const, Addr, Instance, Runtime, RuntimeError, PAGE_SIZE};
+#[cfg(feature = "std")]
+use super::shared::SharedMemory;
+use super::table::{elem_passiv, elem_refs};
+use super::value::{LittleEndian, Ref, Value};
 use crate::binary::FuncType;
 use crate::binary::ValType;
-use crate::binary::{Data, DataMode, Elem, Limits, Memory, Table};
-use crate::binary::{ElemMode, RefType};
+use crate::binary::{Data, Elem, IdxType, Limits, Memory, Table};
 use crate::binary::{Global, GlobalType};
+use crate::binary::{RefType, TypeIdx};
 #[cfg(not(feature = "std"))]
 use crate::lib::*;
 use core::fmt::Debug;
@@ -22,6 +24,7 @@ pub enum FuncInst {
     },
     HostFunc {
         functype: FuncType,
+        module: String,
         name: String,
     },
 }
@@ -54,8 +57,146 @@ pub struct ElemInst {
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct MemInst {
+    pub idxtype: IdxType,
     pub limits: Limits,
-    pub data: Vec<u8>,
+    pub data: MemData,
+}
+
+/// Bytes of a memory instance. Without `std`, shared memories are owned by
+/// their store like any other memory.
+///
+/// Bytes are copied in and out rather than borrowed, since other threads
+/// may access a shared memory at the same time. Accesses panic when out of
+/// bounds, so callers check them first.
+#[derive(Debug, PartialEq, Clone)]
+pub enum MemData {
+    Owned(Vec<u8>),
+    #[cfg(feature = "std")]
+    Shared(SharedMemory),
+}
+
+impl MemData {
+    /// Appends `bytes` zeroed bytes and returns the previous length, or
+    /// `None` if the memory cannot grow that much.
+    pub fn grow(&mut self, bytes: usize) -> Option<usize> {
+        match self {
+            MemData::Owned(data) => {
+                let len = data.len();
+                data.resize(len + bytes, 0);
+                Some(len)
+            }
+            #[cfg(feature = "std")]
+            MemData::Shared(shared) => shared.grow(bytes),
+        }
+    }
+
+    #[cfg(feature = "std")]
+    pub fn shared(&self) -> Option<&SharedMemory> {
+        match self {
+            MemData::Shared(shared) => Some(shared),
+            _ => None,
+        }
+    }
+
+    pub fn is_shared(&self) -> bool {
+        match self {
+            MemData::Owned(_) => false,
+            #[cfg(feature = "std")]
+            MemData::Shared(_) => true,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            MemData::Owned(data) => data.len(),
+            #[cfg(feature = "std")]
+            MemData::Shared(shared) => shared.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Copies the bytes at `offset` into `buf`.
+    pub fn read(&self, offset: usize, buf: &mut [u8]) {
+        match self {
+            MemData::Owned(data) => buf.copy_from_slice(&data[offset..offset + buf.len()]),
+            #[cfg(feature = "std")]
+            MemData::Shared(shared) => shared.read(offset, buf),
+        }
+    }
+
+    /// Copies `bytes` to `offset`.
+    pub fn write(&mut self, offset: usize, bytes: &[u8]) {
+        match self {
+            MemData::Owned(data) => data[offset..offset + bytes.len()].copy_from_slice(bytes),
+            #[cfg(feature = "std")]
+            MemData::Shared(shared) => shared.write(offset, bytes),
+        }
+    }
+
+    /// Copy of the `len` bytes at `offset`, `None` if they are out of
+    /// bounds.
+    pub fn get(&self, offset: usize, len: usize) -> Option<Vec<u8>> {
+        if offset.checked_add(len)? > self.len() {
+            return None;
+        }
+        let mut bytes = vec![0; len];
+        self.read(offset, &mut bytes);
+        Some(bytes)
+    }
+
+    /// Reads the little-endian `T` at `offset`.
+    pub fn load<T: LittleEndian>(&self, offset: usize) -> T {
+        match self {
+            MemData::Owned(data) => T::read(data, offset),
+            #[cfg(feature = "std")]
+            MemData::Shared(shared) => {
+                let mut buf = [0; 16];
+                let buf = &mut buf[..core::mem::size_of::<T>()];
+                shared.read(offset, buf);
+                T::read(buf, 0)
+            }
+        }
+    }
+
+    /// Writes `value` at `offset` in little-endian order.
+    pub fn store<T: LittleEndian>(&mut self, offset: usize, value: T) {
+        match self {
+            MemData::Owned(data) => T::write(data, offset, value),
+            #[cfg(feature = "std")]
+            MemData::Shared(shared) => {
+                let mut buf = [0; 16];
+                let buf = &mut buf[..core::mem::size_of::<T>()];
+                T::write(buf, 0, value);
+                shared.write(offset, buf);
+            }
+        }
+    }
+
+    pub fn fill(&mut self, offset: usize, len: usize, value: u8) {
+        match self {
+            MemData::Owned(data) => data[offset..offset + len].fill(value),
+            #[cfg(feature = "std")]
+            MemData::Shared(shared) => shared.fill(offset, len, value),
+        }
+    }
+
+    /// Copies `len` bytes from `src` to `dst`, which may overlap.
+    pub fn copy_within(&mut self, src: usize, dst: usize, len: usize) {
+        match self {
+            MemData::Owned(data) => data.copy_within(src..src + len, dst),
+            #[cfg(feature = "std")]
+            MemData::Shared(shared) => shared.copy_within(src, dst, len),
+        }
+    }
+}
+
+impl From<Vec<u8>> for MemData {
+    fn from(data: Vec<u8>) -> Self {
+        MemData::Owned(data)
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -63,6 +204,42 @@ pub struct DataInst {
     pub data: Vec<u8>,
 }
 
+#[derive(Debug, PartialEq, Clone)]
+pub struct TagInst {
+    pub functype: FuncType,
+}
+
+/// An exception thrown by `throw`, referenced by `Ref::Exn` values.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ExnInst {
+    pub tag: Addr,
+    pub fields: Vec<Value>,
+}
+
+/// A struct allocated by `struct.new`, referenced by `Ref::Struct` values.
+/// Its type is `typeidx` in the instance at `instance_addr`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct StructInst {
+    pub instance_addr: Addr,
+    pub typeidx: TypeIdx,
+    pub fields: Vec<Value>,
+}
+
+/// An array allocated by `array.new`, referenced by `Ref::Array` values.
+/// Packed elements are stored as zero-extended `i32` values.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ArrayInst {
+    pub instance_addr: Addr,
+    pub typeidx: TypeIdx,
+    pub elems: Vec<Value>,
+}
+
+/// Number of structs and arrays allocated before the first collection.
+const GC_THRESHOLD: usize = 1024;
+
+/// Default of `Store::set_memory_limit`, the size of a full 32-bit memory.
+pub const DEFAULT_MEMORY_LIMIT: usize = (u32::MAX as usize).saturating_add(1);
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Store {
     pub globals: OptVec<GlobalInst>,
@@ -71,6 +248,16 @@ pub struct Store {
     pub funcs: OptVec<FuncInst>,
     pub elems: OptVec<ElemInst>,
     pub datas: OptVec<DataInst>,
+    pub tags: OptVec<TagInst>,
+    pub exns: OptVec<ExnInst>,
+    pub structs: OptVec<StructInst>,
+    pub arrays: OptVec<ArrayInst>,
+    /// References the embedder keeps alive, see `root`.
+    roots: Vec<Ref>,
+    /// Structs and arrays allocated since the last collection.
+    allocated: usize,
+    threshold: usize,
+    memory_limit: usize,
 }
 
 impl Store {
@@ -82,6 +269,14 @@ impl Store {
             mems: OptVec::new(),
             elems: OptVec::new(),
             datas: OptVec::new(),
+            tags: OptVec::new(),
+            exns: OptVec::new(),
+            structs: OptVec::new(),
+            arrays: OptVec::new(),
+            roots: vec![],
+            allocated: 0,
+            threshold: GC_THRESHOLD,
+            memory_limit: DEFAULT_MEMORY_LIMIT,
         }
     }
 
@@ -99,13 +294,25 @@ impl Store {
         }
     }
 
-    pub fn allocate_global(&mut self, global: Global) -> Result<Addr, RuntimeError> {
+    pub fn allocate_global(
+        &mut self,
+        global: Global,
+        instance: &Instance,
+        instance_addr: Addr,
+    ) -> Result<Addr, RuntimeError> {
+        let value = eval_const(&global.value, instance, instance_addr, self)?;
         Ok(self.globals.push(GlobalInst {
             globaltype: global.type_,
-            value: eval_const(&global.value)?,
+            value,
         }))
     }
 
+    /// Allocates a global owned by the embedder, e.g. to be provided as an
+    /// import through the `Linker`.
+    pub fn allocate_host_global(&mut self, globaltype: GlobalType, value: Value) -> Addr {
+        self.globals.push(GlobalInst { globaltype, value })
+    }
+
     pub fn allocate_table(&mut self, table: Table) -> Addr {
         let min = table.limits.min() as usize;
         self.tables.push(TableInst {
@@ -114,67 +321,203 @@ impl Store {
         })
     }
 
-    pub fn allocate_elem(&mut self, elem: Elem) -> Result<Option<Addr>, RuntimeError> {
-        match &elem.mode {
-            ElemMode::Passiv => Ok(Some(elem_passiv(&mut self.elems, elem)?)),
-            ElemMode::Active { tableidx, offset } => {
-                let offset = match eval_const(&offset)? {
-                    Value::I32(v) => v,
-                    _ => unreachable!(),
-                } as usize;
-                elem_active(&mut self.tables[*tableidx as usize], offset, elem)?;
-                Ok(None)
-            }
-            ElemMode::Declarative => Ok(None),
+    /// Allocates an element instance for every segment, including active
+    /// and declarative ones which are dropped after instantiation.
+    pub fn allocate_elem(
+        &mut self,
+        elem: &Elem,
+        instance: &Instance,
+        instance_addr: Addr,
+    ) -> Result<Addr, RuntimeError> {
+        let refs = elem_refs(elem, instance, instance_addr, self)?;
+        Ok(elem_passiv(&mut self.elems, elem.type_, refs))
+    }
+
+    /// Limits the size of each memory and GC array to `bytes`. Instantiating
+    /// a memory whose minimum size is larger fails, `memory.grow` returns -1
+    /// rather than growing past it, and allocating a larger array traps.
+    pub fn set_memory_limit(&mut self, bytes: usize) {
+        self.memory_limit = bytes;
+    }
+
+    pub fn memory_limit(&self) -> usize {
+        self.memory_limit
+    }
+
+    /// Allocates a memory of the minimum size of `mem`. Shared memories
+    /// reserve the bytes for their maximum size up front.
+    pub fn allocate_mem(&mut self, mem: &Memory) -> Result<Addr, RuntimeError> {
+        let bytes = usize::try_from(mem.limits.min())
+            .ok()
+            .and_then(|min| min.checked_mul(PAGE_SIZE))
+            .filter(|&bytes| bytes <= self.memory_limit)
+            .ok_or(RuntimeError::OutOfMemory)?;
+        #[cfg(feature = "std")]
+        if mem.shared {
+            let shared = SharedMemory::new(mem).ok_or(RuntimeError::OutOfMemory)?;
+            return Ok(self.allocate_shared_mem(shared));
         }
+        Ok(self.mems.push(MemInst {
+            idxtype: mem.idxtype,
+            limits: mem.limits.clone(),
+            data: MemData::Owned(vec![0; bytes]),
+        }))
     }
 
-    pub fn allocate_mem(&mut self, mem: &Memory) -> Addr {
-        let min = mem.0.min() as usize;
+    /// Allocates a memory instance for a shared memory, which may already be
+    /// used by other stores, e.g. to be provided as an import through the
+    /// `Linker` on each thread.
+    #[cfg(feature = "std")]
+    pub fn allocate_shared_mem(&mut self, shared: SharedMemory) -> Addr {
+        let memory = shared.memory();
         self.mems.push(MemInst {
-            limits: mem.0.clone(),
-            data: vec![0; min * PAGE_SIZE],
+            idxtype: memory.idxtype,
+            limits: memory.limits.set_min((shared.len() / PAGE_SIZE) as u64),
+            data: MemData::Shared(shared),
         })
     }
 
-    pub fn allocate_data(
-        &mut self,
-        memidx: usize,
-        data: Data,
-    ) -> Result<Option<Addr>, RuntimeError> {
-        match &data.mode {
-            DataMode::Passive => Ok(Some(data_passiv(&mut self.datas, data))),
-            DataMode::Active { offset, .. } => {
-                let offset = match eval_const(&offset)? {
-                    Value::I32(v) => v,
-                    _ => unreachable!(),
-                } as usize;
-                data_active(&mut self.mems[memidx], data, offset);
-                Ok(None)
-            }
+    pub fn allocate_tag(&mut self, functype: FuncType) -> Addr {
+        self.tags.push(TagInst { functype })
+    }
+
+    pub fn allocate_struct(&mut self, inst: StructInst) -> Addr {
+        self.allocated += 1;
+        self.structs.push(inst)
+    }
+
+    pub fn allocate_array(&mut self, inst: ArrayInst) -> Addr {
+        self.allocated += 1;
+        self.arrays.push(inst)
+    }
+
+    /// Whether enough structs and arrays were allocated since the last
+    /// collection to make another one worthwhile.
+    pub fn should_collect(&self) -> bool {
+        self.allocated >= self.threshold
+    }
+
+    /// Keeps the struct, array or exception `value` refers to alive until a
+    /// matching `unroot`.
+    ///
+    /// Collections only see the stack of the running `Runtime`, so references
+    /// the embedder holds on to between invocations, such as results of
+    /// `invoke` passed back later, must be rooted.
+    pub fn root(&mut self, value: &Value) {
+        if let Value::Ref(r) = value {
+            self.roots.push(*r);
         }
     }
 
-    pub fn free_runtime(&mut self, runtime: Runtime) {
-        for inst in runtime.instances() {
-            for faddr in inst.funcaddrs {
-                self.funcs.remove(faddr);
-            }
-            for daddr in inst.dataaddrs {
-                self.datas.remove(daddr);
-            }
-            for eaddr in inst.elemaddrs {
-                self.elems.remove(eaddr);
+    /// Releases one `root` of `value`.
+    pub fn unroot(&mut self, value: &Value) {
+        if let Value::Ref(r) = value {
+            if let Some(i) = self.roots.iter().position(|root| root == r) {
+                self.roots.swap_remove(i);
             }
-            for gaddr in inst.globaladdrs {
-                self.globals.remove(gaddr);
+        }
+    }
+
+    /// Frees the structs, arrays and exceptions which cannot be reached from
+    /// `roots`, from references rooted with `root` or from the globals, tables
+    /// and element segments of the store.
+    ///
+    /// Interpreters collect with their own stack as roots, so references held
+    /// only by the suspended stack of another `Runtime` sharing the store must
+    /// be rooted or kept in a global or a table.
+    pub fn collect<'a, I: IntoIterator<Item = &'a Value>>(&mut self, roots: I) {
+        let mut pending: Vec<Ref> = self.roots.clone();
+        for value in roots {
+            if let Value::Ref(r) = value {
+                pending.push(*r);
             }
-            for taddr in inst.tableaddrs {
-                self.tables.remove(taddr);
+        }
+        for global in self.globals.into_iter().flatten() {
+            if let Value::Ref(r) = global.value {
+                pending.push(r);
             }
-            if let Some(maddr) = inst.memaddr {
-                self.mems.remove(maddr);
+        }
+        for table in self.tables.into_iter().flatten() {
+            pending.extend(table.elem.iter().copied());
+        }
+        for elem in self.elems.into_iter().flatten() {
+            pending.extend(elem.elem.iter().copied());
+        }
+
+        let mut structs = vec![false; self.structs.inner_len()];
+        let mut arrays = vec![false; self.arrays.inner_len()];
+        let mut exns = vec![false; self.exns.inner_len()];
+        while let Some(r) = pending.pop() {
+            let values = match r {
+                Ref::Struct(a) if !structs[a] => {
+                    structs[a] = true;
+                    &self.structs[a].fields
+                }
+                Ref::Array(a) if !arrays[a] => {
+                    arrays[a] = true;
+                    &self.arrays[a].elems
+                }
+                Ref::Exn(a) if !exns[a] => {
+                    exns[a] = true;
+                    &self.exns[a].fields
+                }
+                _ => continue,
+            };
+            for value in values.iter() {
+                if let Value::Ref(r) = value {
+                    pending.push(*r);
+                }
             }
         }
+
+        for (a, _) in structs.iter().enumerate().filter(|(_, marked)| !**marked) {
+            self.structs.remove(a);
+        }
+        for (a, _) in arrays.iter().enumerate().filter(|(_, marked)| !**marked) {
+            self.arrays.remove(a);
+        }
+        for (a, _) in exns.iter().enumerate().filter(|(_, marked)| !**marked) {
+            self.exns.remove(a);
+        }
+        // Collect again once the heap has doubled.
+        self.allocated = 0;
+        self.threshold = GC_THRESHOLD.max(self.structs.len() + self.arrays.len());
+    }
+
+    /// Allocates a data instance for every segment, including active ones
+    /// which are dropped after instantiation.
+    pub fn allocate_data(&mut self, data: &Data) -> Addr {
+        data_passiv(&mut self.datas, data.init.clone())
+    }
+
+    pub fn free_runtime(&mut self, runtime: Runtime) {
+        for inst in runtime.instances() {
+            self.free_instance(&inst);
+        }
+    }
+
+    /// Frees every address held by `inst`.
+    pub fn free_instance(&mut self, inst: &Instance) {
+        for &faddr in inst.funcaddrs.iter() {
+            self.funcs.remove(faddr);
+        }
+        for &daddr in inst.dataaddrs.iter() {
+            self.datas.remove(daddr);
+        }
+        for &eaddr in inst.elemaddrs.iter() {
+            self.elems.remove(eaddr);
+        }
+        for &gaddr in inst.globaladdrs.iter() {
+            self.globals.remove(gaddr);
+        }
+        for &taddr in inst.tableaddrs.iter() {
+            self.tables.remove(taddr);
+        }
+        for &maddr in inst.memaddrs.iter() {
+            self.mems.remove(maddr);
+        }
+        for &taddr in inst.tagaddrs.iter() {
+            self.tags.remove(taddr);
+        }
     }
 }
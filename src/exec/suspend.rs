@@ -0,0 +1,210 @@
+#[cfg(not(feature = "std"))]
+use crate::lib::*;
+
+use super::value::Value;
+use crate::binary::ValType;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Result of `Runtime::invoke_resumable` and `Runtime::resume_with`.
+#[derive(Debug, PartialEq)]
+pub enum Execution {
+    Complete(Vec<Value>),
+    Suspended(Suspended),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum SuspendReason {
+    /// Call to a host function which is not registered in the `Linker`.
+    /// The embedder resumes with its results.
+    HostCall {
+        module: String,
+        name: String,
+        params: Vec<Value>,
+    },
+    /// The remaining fuel is less than the cost of the next instruction.
+    OutOfFuel,
+    /// `InterruptHandle::interrupt` was called.
+    Interrupted,
+}
+
+/// Execution paused in the `Runtime` which returned it. The runtime keeps
+/// its stack and pc until the handle is passed back to `resume_with`.
+#[must_use]
+#[derive(Debug, PartialEq)]
+pub struct Suspended {
+    pub(crate) reason: SuspendReason,
+    /// Result types of the host function of `SuspendReason::HostCall`.
+    pub(crate) result_types: Vec<ValType>,
+}
+
+impl Suspended {
+    pub fn reason(&self) -> &SuspendReason {
+        &self.reason
+    }
+}
+
+/// Requests a running `Runtime` to stop before its next instruction. It can
+/// be cloned and used from another thread.
+#[derive(Debug, Clone, Default)]
+pub struct InterruptHandle {
+    flag: Arc<AtomicBool>,
+}
+
+impl InterruptHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn interrupt(&self) {
+        self.flag.store(true, Ordering::Relaxed);
+    }
+
+    /// Clears a pending interrupt, returning whether one was pending.
+    pub(crate) fn take(&self) -> bool {
+        self.flag.load(Ordering::Relaxed) && self.flag.swap(false, Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Execution, SuspendReason};
+    use crate::exec::env::DebugEnv;
+    use crate::exec::runtime::{Runtime, RuntimeError};
+    use crate::exec::store::Store;
+    use crate::exec::value::{Ref, Value};
+    use crate::loader::parser::Parser;
+    use crate::tests::wat2wasm;
+
+    fn instantiate(store: &mut Store, wat: &str) -> Runtime {
+        let module = Parser::new(&wat2wasm(wat).unwrap()).module().unwrap();
+        let mut runtime = Runtime::new("env");
+        runtime.add_module(store, &mut DebugEnv {}, module).unwrap();
+        runtime
+    }
+
+    fn suspended(execution: Execution) -> super::Suspended {
+        match execution {
+            Execution::Suspended(suspended) => suspended,
+            Execution::Complete(results) => panic!("completed with {:?}", results),
+        }
+    }
+
+    #[test]
+    fn host_call() {
+        let mut store = Store::new();
+        let mut runtime = instantiate(
+            &mut store,
+            r#"(module
+                  (import "env" "double" (func $double (param i32) (result i32)))
+                  (func (export "main") (param i32) (result i32)
+                      (i32.add (call $double (local.get 0)) (i32.const 1))))"#,
+        );
+        let execution = runtime
+            .invoke_resumable(&mut store, "main", vec![Value::I32(21)])
+            .unwrap();
+        let suspended = suspended(execution);
+        assert_eq!(
+            suspended.reason(),
+            &SuspendReason::HostCall {
+                module: "env".into(),
+                name: "double".into(),
+                params: vec![Value::I32(21)],
+            }
+        );
+        assert_eq!(
+            runtime.resume_with(&mut store, suspended, vec![Value::I32(42)]),
+            Ok(Execution::Complete(vec![Value::I32(43)]))
+        );
+    }
+
+    #[test]
+    fn host_call_results_mismatch() {
+        let wat = r#"(module
+              (import "env" "double" (func $double (param i32) (result i32)))
+              (import "env" "host" (func $host (result (ref extern))))
+              (func (export "double") (result i32) (call $double (i32.const 1)))
+              (func (export "host") (result (ref extern)) (call $host)))"#;
+        let cases = [
+            ("double", vec![]),
+            ("double", vec![Value::I64(2)]),
+            ("double", vec![Value::I32(2), Value::I32(2)]),
+            ("host", vec![Value::Ref(Ref::Null)]),
+        ];
+        for (name, results) in cases {
+            let mut store = Store::new();
+            let mut runtime = instantiate(&mut store, wat);
+            let execution = runtime.invoke_resumable(&mut store, name, vec![]).unwrap();
+            assert_eq!(
+                runtime.resume_with(&mut store, suspended(execution), results),
+                Err(RuntimeError::Env("host function results mismatch"))
+            );
+        }
+
+        let mut store = Store::new();
+        let mut runtime = instantiate(&mut store, wat);
+        let execution = runtime
+            .invoke_resumable(&mut store, "host", vec![])
+            .unwrap();
+        let results = vec![Value::Ref(Ref::Extern(0))];
+        assert_eq!(
+            runtime.resume_with(&mut store, suspended(execution), results.clone()),
+            Ok(Execution::Complete(results))
+        );
+    }
+
+    #[test]
+    fn cooperative_scheduling() {
+        let wat = r#"(module
+              (func (export "sum") (param i32) (result i32) (local i32)
+                  (loop $l
+                      (local.set 1 (i32.add (local.get 1) (local.get 0)))
+                      (local.set 0 (i32.sub (local.get 0) (i32.const 1)))
+                      (br_if $l (local.get 0)))
+                  (local.get 1)))"#;
+        let mut store = Store::new();
+        let mut tasks = vec![];
+        for n in [100, 10] {
+            let mut runtime = instantiate(&mut store, wat);
+            runtime.add_fuel(50);
+            let execution = runtime
+                .invoke_resumable(&mut store, "sum", vec![Value::I32(n)])
+                .unwrap();
+            tasks.push((runtime, execution));
+        }
+
+        let mut finished = vec![];
+        while !tasks.is_empty() {
+            let (mut runtime, execution) = tasks.remove(0);
+            match execution {
+                Execution::Complete(results) => finished.push(results),
+                Execution::Suspended(suspended) => {
+                    assert_eq!(suspended.reason(), &SuspendReason::OutOfFuel);
+                    runtime.add_fuel(50);
+                    let execution = runtime.resume_with(&mut store, suspended, vec![]).unwrap();
+                    tasks.push((runtime, execution));
+                }
+            }
+        }
+        assert_eq!(finished, vec![vec![Value::I32(55)], vec![Value::I32(5050)]]);
+    }
+
+    #[test]
+    fn interrupt() {
+        let mut store = Store::new();
+        let mut runtime = instantiate(
+            &mut store,
+            r#"(module (func (export "main") (result i32) (i32.const 7)))"#,
+        );
+        runtime.interrupt_handle().interrupt();
+        let execution = runtime
+            .invoke_resumable(&mut store, "main", vec![])
+            .unwrap();
+        let suspended = suspended(execution);
+        assert_eq!(suspended.reason(), &SuspendReason::Interrupted);
+        assert_eq!(
+            runtime.resume_with(&mut store, suspended, vec![]),
+            Ok(Execution::Complete(vec![Value::I32(7)]))
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use crate::binary::Elem;
+use crate::binary::{Elem, RefType};
 #[cfg(not(feature = "std"))]
 use crate::lib::*;
 
@@ -9,8 +9,19 @@ use super::{
     trap::Trap,
     value::{Ref, Value},
 };
+use core::ops::Range;
 use opt_vec::OptVec;
 
+/// Indices `start..start + n` of a table or segment of length `len`, which
+/// trap when out of bounds. Operands are unsigned.
+fn range(start: i32, n: i32, len: usize) -> Result<Range<usize>, Trap> {
+    let start = start as u32 as usize;
+    match start.checked_add(n as u32 as usize) {
+        Some(end) if end <= len => Ok(start..end),
+        _ => Err(Trap::TableOutOfRange),
+    }
+}
+
 pub fn table_get(
     x: &u32,
     instance: &mut Instance,
@@ -19,10 +30,7 @@ pub fn table_get(
 ) -> Result<(), Trap> {
     let a = instance.tableaddrs[*x as usize];
     let tab = &mut store.tables[a];
-    let i = stack.pop_value::<i32>() as usize;
-    if i < tab.elem.len() {
-        return Err(Trap::TableOutOfRange);
-    }
+    let i = range(stack.pop_value(), 1, tab.elem.len())?.start;
     stack.push_value(Value::Ref(tab.elem[i]));
     Ok(())
 }
@@ -36,10 +44,7 @@ pub fn table_set(
     let a = instance.tableaddrs[*x as usize];
     let tab = &mut store.tables[a];
     let val = stack.pop_value::<Ref>();
-    let i = stack.pop_value::<i32>() as usize;
-    if i < tab.elem.len() {
-        return Err(Trap::TableOutOfRange);
-    }
+    let i = range(stack.pop_value(), 1, tab.elem.len())?.start;
     tab.elem[i] = val;
     Ok(())
 }
@@ -56,7 +61,7 @@ pub fn table_grow(x: &u32, instance: &mut Instance, store: &mut Store, stack: &m
         stack.push_value(ERR);
         return;
     }
-    let limits_ = tab.tabletype.limits.set_min(len as u32);
+    let limits_ = tab.tabletype.limits.set_min(len);
     if !limits_.valid() {
         stack.push_value(ERR);
         return;
@@ -78,12 +83,9 @@ pub fn table_fill(
     let tab = &mut store.tables[ta];
     let n = stack.pop_value::<i32>();
     let val = stack.pop_value::<Ref>();
-    let i = stack.pop_value::<i32>();
-    if i + n > tab.elem.len() as i32 {
-        return Err(Trap::TableOutOfRange);
-    }
-    for j in i..(i + n) {
-        tab.elem[j as usize] = val;
+    let range = range(stack.pop_value(), n, tab.elem.len())?;
+    for elem in tab.elem[range].iter_mut() {
+        *elem = val;
     }
     Ok(())
 }
@@ -99,21 +101,15 @@ pub fn table_copy(
     let tab_x = &store.tables[ta_x];
     let ta_y = instance.tableaddrs[*y as usize];
     let tab_y = &store.tables[ta_y];
-    let n = stack.pop_value::<i32>() as usize;
-    let s = stack.pop_value::<i32>() as usize;
-    let d = stack.pop_value::<i32>() as usize;
-    if s + n > tab_y.elem.len() || d + n > tab_x.elem.len() {
-        return Err(Trap::TableOutOfRange);
-    }
+    let n = stack.pop_value::<i32>();
+    let s = range(stack.pop_value(), n, tab_y.elem.len())?;
+    let d = range(stack.pop_value(), n, tab_x.elem.len())?;
 
-    if d <= s {
-        for i in 0..n {
-            store.tables[ta_x].elem[d + i] = store.tables[ta_y].elem[s + i];
-        }
+    if ta_x == ta_y {
+        store.tables[ta_x].elem.copy_within(s, d.start);
     } else {
-        for i in (0..n).rev() {
-            store.tables[ta_x].elem[d + n - 1 - i] = store.tables[ta_y].elem[s + n - 1 - i];
-        }
+        let elems = store.tables[ta_y].elem[s].to_vec();
+        store.tables[ta_x].elem[d].copy_from_slice(&elems);
     }
 
     Ok(())
@@ -130,16 +126,10 @@ pub fn table_init(
     let tab = &mut store.tables[ta];
     let ea = instance.elemaddrs[*y as usize];
     let elem = &store.elems[ea];
-    let n = stack.pop_value::<i32>() as usize;
-    let s = stack.pop_value::<i32>() as usize;
-    let d = stack.pop_value::<i32>() as usize;
-    if s + n > elem.elem.len() || d + n > elem.elem.len() {
-        return Err(Trap::TableOutOfRange);
-    }
-
-    for i in 0..n {
-        tab.elem[d + i] = elem.elem[d + s];
-    }
+    let n = stack.pop_value::<i32>();
+    let s = range(stack.pop_value(), n, elem.elem.len())?;
+    let d = range(stack.pop_value(), n, tab.elem.len())?;
+    tab.elem[d].copy_from_slice(&elem.elem[s]);
     Ok(())
 }
 
@@ -151,7 +141,7 @@ pub fn table_init_manual(tab: &mut TableInst, offset: usize, elems: &Vec<Ref>) {
 
 pub fn elem_drop(x: &u32, instance: &mut Instance, store: &mut Store) {
     let a = instance.elemaddrs[*x as usize];
-    store.elems.remove(a);
+    store.elems[a].elem.clear();
 }
 
 pub fn table_size(x: &u32, instance: &mut Instance, store: &mut Store, stack: &mut Stack) {
@@ -161,44 +151,27 @@ pub fn table_size(x: &u32, instance: &mut Instance, store: &mut Store, stack: &m
     stack.push_value(sz);
 }
 
-pub fn elem_passiv(elems: &mut OptVec<ElemInst>, elem: Elem) -> Result<Addr, RuntimeError> {
-    let vals = elem
-        .init
+/// Evaluates the initializers of `elem` to references.
+pub fn elem_refs(
+    elem: &Elem,
+    instance: &Instance,
+    instance_addr: Addr,
+    store: &mut Store,
+) -> Result<Vec<Ref>, RuntimeError> {
+    elem.init
         .iter()
-        .map(|expr| eval_const(expr))
-        .collect::<Result<Vec<_>, _>>()?;
-    let refs = vals
-        .into_iter()
-        .map(|value| match value {
-            Value::I32(addr) => Ref::Func(addr as Addr),
-            Value::I64(addr) => Ref::Func(addr as Addr),
-            Value::F32(addr) => Ref::Func(addr as Addr),
-            Value::F64(addr) => Ref::Func(addr as Addr),
-            Value::Ref(r) => r,
-        })
-        .collect();
-    Ok(elems.push(ElemInst {
-        reftype: elem.type_.clone(),
-        elem: refs,
-    }))
+        .map(
+            |expr| match eval_const(expr, instance, instance_addr, store)? {
+                Value::Ref(r) => Ok(r),
+                _ => Err(RuntimeError::ConstantExpression),
+            },
+        )
+        .collect()
 }
 
-pub fn elem_active(table: &mut TableInst, offset: usize, elem: Elem) -> Result<(), RuntimeError> {
-    let vals = elem
-        .init
-        .iter()
-        .map(|expr| eval_const(expr))
-        .collect::<Result<Vec<_>, _>>()?;
-    let refs = vals
-        .into_iter()
-        .map(|value| match value {
-            Value::I32(addr) => Ref::Func(addr as Addr),
-            Value::I64(addr) => Ref::Func(addr as Addr),
-            Value::F32(addr) => Ref::Func(addr as Addr),
-            Value::F64(addr) => Ref::Func(addr as Addr),
-            Value::Ref(r) => r,
-        })
-        .collect();
-    table_init_manual(table, offset, &refs);
-    Ok(())
+pub fn elem_passiv(elems: &mut OptVec<ElemInst>, reftype: RefType, refs: Vec<Ref>) -> Addr {
+    elems.push(ElemInst {
+        reftype,
+        elem: refs,
+    })
 }
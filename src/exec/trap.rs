@@ -1,7 +1,17 @@
+#[cfg(not(feature = "std"))]
+use crate::lib::*;
+
+use super::runtime::Addr;
+use super::store::ExnInst;
+#[cfg(feature = "std")]
+use crate::debug::dwarf::Location;
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum Trap {
     Unreachable,
     UndefinedElement,
+    /// `call_indirect` of a null table entry.
+    UninitializedElement,
     IntegerOverflow,
     InvalidConversionInt,
     DivideByZeroInt,
@@ -11,7 +21,24 @@ pub enum Trap {
     IndirectCallTypeMismatch,
     NoStartFunction,
     NotFundRef,
+    OutOfFuel,
+    Interrupted,
     Env(&'static str),
+    NullExceptionReference,
+    UnalignedAtomic,
+    ExpectedSharedMemory,
+    NullReference,
+    NullFunctionReference,
+    NullStructReference,
+    NullArrayReference,
+    NullI31Reference,
+    ArrayOutOfBounds,
+    CastFailure,
+    /// A GC array larger than `Store::memory_limit`.
+    OutOfMemory,
+    /// An exception reached the outermost frame. Mapped to
+    /// `RuntimeError::Exception` by the runtime.
+    UncaughtException(ExnInst),
 }
 
 impl core::fmt::Display for Trap {
@@ -19,16 +46,84 @@ impl core::fmt::Display for Trap {
         match self {
             Trap::Unreachable => write!(f, "unreachable"),
             Trap::UndefinedElement => write!(f, "undefined element"),
+            Trap::UninitializedElement => write!(f, "uninitialized element"),
             Trap::IntegerOverflow => write!(f, "integer overflow"),
             Trap::InvalidConversionInt => write!(f, "invalid conversion to integer"),
             Trap::DivideByZeroInt => write!(f, "integer divide by zero"),
-            Trap::TableOutOfRange => write!(f, "failed to refer to table: out of range"),
+            Trap::TableOutOfRange => write!(f, "out of bounds table access"),
             Trap::TableNullRef => write!(f, "failed to refer to table: null reference"),
             Trap::MemoryOutOfBounds => write!(f, "out of bounds memory access"),
             Trap::NotFundRef => write!(f, "attempted to call null or external reference"),
             Trap::IndirectCallTypeMismatch => write!(f, "indirect call type mismatch"),
             Trap::NoStartFunction => write!(f, "no start function"),
+            Trap::OutOfFuel => write!(f, "all fuel consumed"),
+            Trap::Interrupted => write!(f, "interrupted"),
             Trap::Env(env) => write!(f, "environment error: {}", env),
+            Trap::NullExceptionReference => write!(f, "null exception reference"),
+            Trap::UnalignedAtomic => write!(f, "unaligned atomic"),
+            Trap::ExpectedSharedMemory => write!(f, "expected shared memory"),
+            Trap::NullReference => write!(f, "null reference"),
+            Trap::NullFunctionReference => write!(f, "null function reference"),
+            Trap::NullStructReference => write!(f, "null structure reference"),
+            Trap::NullArrayReference => write!(f, "null array reference"),
+            Trap::NullI31Reference => write!(f, "null i31 reference"),
+            Trap::ArrayOutOfBounds => write!(f, "out of bounds array access"),
+            Trap::CastFailure => write!(f, "cast failure"),
+            Trap::OutOfMemory => write!(f, "out of memory"),
+            Trap::UncaughtException(_) => write!(f, "uncaught exception"),
+        }
+    }
+}
+
+/// Call stack of a trap, innermost frame first.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct Backtrace {
+    pub frames: Vec<BacktraceFrame>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct BacktraceFrame {
+    pub func_addr: Addr,
+    pub instance_addr: Addr,
+    /// Index of the function in its instance.
+    pub func_idx: Option<u32>,
+    /// Offset of the executing instruction from the first instruction of
+    /// the function, in the instruction sequence of the runtime.
+    pub offset: usize,
+    /// Offset of the executing instruction in the code section.
+    pub code_offset: u32,
+    #[cfg(feature = "std")]
+    pub location: Option<Location>,
+    /// Names of the module and the function from the `name` section.
+    pub module_name: Option<String>,
+    pub func_name: Option<String>,
+}
+
+impl core::fmt::Display for BacktraceFrame {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if let Some(module) = &self.module_name {
+            write!(f, "{}!", module)?;
+        }
+        match (&self.func_name, self.func_idx) {
+            (Some(name), _) => write!(f, "{}", name)?,
+            (None, Some(idx)) => write!(f, "func[{}]", idx)?,
+            (None, None) => write!(f, "<func@{}>", self.func_addr)?,
+        }
+        write!(f, " +{}", self.offset)?;
+        #[cfg(feature = "std")]
+        if let Some(location) = &self.location {
+            write!(f, " at {}", location)?;
+        }
+        Ok(())
+    }
+}
+
+impl core::fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "wasm backtrace:")?;
+        for (i, frame) in self.frames.iter().enumerate() {
+            write!(f, "\n  {:>2}: {}", i, frame)?;
         }
+        Ok(())
     }
 }
@@ -6,6 +6,7 @@ pub enum Value {
     I64(i64),
     F32(f32),
     F64(f64),
+    V128(u128),
     Ref(Ref),
 }
 
@@ -26,6 +27,7 @@ impl PartialEq for Value {
                     || (a.is_infinite() && b.is_infinite())
                     || a == b
             }
+            (Value::V128(a), Value::V128(b)) => a == b,
             (Value::Ref(a), Value::Ref(b)) => a == b,
             _ => false,
         }
@@ -39,6 +41,11 @@ pub enum Ref {
     Null,
     Func(Addr),
     Extern(Addr),
+    Exn(Addr),
+    Struct(Addr),
+    Array(Addr),
+    /// Unboxed 31-bit integer, kept zero-extended.
+    I31(u32),
 }
 
 impl From<Value> for Ref {
@@ -121,6 +128,22 @@ impl Into<Value> for f64 {
     }
 }
 
+impl From<Value> for u128 {
+    fn from(value: Value) -> Self {
+        if let Value::V128(value) = value {
+            value
+        } else {
+            unreachable!("{:?}", value)
+        }
+    }
+}
+
+impl Into<Value> for u128 {
+    fn into(self) -> Value {
+        Value::V128(self)
+    }
+}
+
 pub trait LittleEndian {
     fn read(buf: &[u8], addr: usize) -> Self;
     fn write(buf: &mut [u8], addr: usize, v: Self);
@@ -173,6 +196,7 @@ impl LittleEndian for u8 {
 }
 impl_le_rw!(u16);
 impl_le_rw!(u32);
+impl_le_rw!(u128);
 
 // Trait to handle f32 and f64 in the same way
 pub(crate) trait Float: Clone + Copy + PartialEq + PartialOrd {
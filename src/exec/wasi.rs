@@ -0,0 +1,733 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use super::env::Env;
+use super::store::MemInst;
+use super::value::Value;
+
+/// Module name of the WASI imports handled by `WasiEnv`.
+pub const WASI_MODULE: &str = "wasi_snapshot_preview1";
+
+type Errno = i32;
+
+const ERRNO_SUCCESS: Errno = 0;
+const ERRNO_ACCES: Errno = 2;
+const ERRNO_BADF: Errno = 8;
+const ERRNO_EXIST: Errno = 20;
+const ERRNO_FAULT: Errno = 21;
+const ERRNO_INVAL: Errno = 28;
+const ERRNO_IO: Errno = 29;
+const ERRNO_ISDIR: Errno = 31;
+const ERRNO_NOENT: Errno = 44;
+const ERRNO_NOTDIR: Errno = 54;
+const ERRNO_OVERFLOW: Errno = 61;
+const ERRNO_SPIPE: Errno = 70;
+const ERRNO_NOTCAPABLE: Errno = 76;
+
+const CLOCK_REALTIME: i32 = 0;
+const CLOCK_MONOTONIC: i32 = 1;
+const CLOCK_PROCESS_CPUTIME_ID: i32 = 2;
+const CLOCK_THREAD_CPUTIME_ID: i32 = 3;
+
+const FILETYPE_CHARACTER_DEVICE: u8 = 2;
+const FILETYPE_DIRECTORY: u8 = 3;
+const FILETYPE_REGULAR_FILE: u8 = 4;
+
+const WHENCE_SET: i32 = 0;
+const WHENCE_CUR: i32 = 1;
+const WHENCE_END: i32 = 2;
+
+const OFLAGS_CREAT: i32 = 1;
+const OFLAGS_DIRECTORY: i32 = 2;
+const OFLAGS_EXCL: i32 = 4;
+const OFLAGS_TRUNC: i32 = 8;
+
+const FDFLAGS_APPEND: i32 = 1;
+
+const RIGHTS_FD_READ: i64 = 1 << 1;
+const RIGHTS_FD_WRITE: i64 = 1 << 6;
+const RIGHTS_ALL: i64 = (1 << 30) - 1;
+
+enum Descriptor {
+    Input(Box<dyn Read>),
+    Output(Box<dyn Write>),
+    File(File),
+    Dir {
+        path: PathBuf,
+        /// The preopened directory this one was opened from.
+        root: PathBuf,
+        preopen: Option<String>,
+    },
+}
+
+/// Host environment implementing `wasi_snapshot_preview1`.
+///
+/// File system access is restricted to the directories registered with
+/// `preopen_dir`. When the guest calls `proc_exit`, execution stops with
+/// `Trap::Env("proc_exit")` and the code is available from `exit_code`.
+pub struct WasiEnv {
+    args: Vec<String>,
+    envs: Vec<(String, String)>,
+    fds: Vec<Option<Descriptor>>,
+    start: Instant,
+    exit_code: Option<i32>,
+}
+
+impl WasiEnv {
+    pub fn new(args: Vec<String>) -> Self {
+        Self {
+            args,
+            envs: vec![],
+            fds: vec![
+                Some(Descriptor::Input(Box::new(io::stdin()))),
+                Some(Descriptor::Output(Box::new(io::stdout()))),
+                Some(Descriptor::Output(Box::new(io::stderr()))),
+            ],
+            start: Instant::now(),
+            exit_code: None,
+        }
+    }
+
+    pub fn push_env(&mut self, key: &str, value: &str) {
+        self.envs.push((key.into(), value.into()));
+    }
+
+    /// Makes the host directory `host` visible to the guest as `guest`.
+    pub fn preopen_dir<P: AsRef<Path>>(&mut self, host: P, guest: &str) -> io::Result<()> {
+        let path = host.as_ref().canonicalize()?;
+        if !path.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::Other, "not a directory"));
+        }
+        self.fds.push(Some(Descriptor::Dir {
+            root: path.clone(),
+            path,
+            preopen: Some(guest.into()),
+        }));
+        Ok(())
+    }
+
+    pub fn set_stdin(&mut self, stdin: Box<dyn Read>) {
+        self.fds[0] = Some(Descriptor::Input(stdin));
+    }
+
+    pub fn set_stdout(&mut self, stdout: Box<dyn Write>) {
+        self.fds[1] = Some(Descriptor::Output(stdout));
+    }
+
+    pub fn set_stderr(&mut self, stderr: Box<dyn Write>) {
+        self.fds[2] = Some(Descriptor::Output(stderr));
+    }
+
+    /// The code passed to `proc_exit`, if the guest has exited.
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    fn fd(&mut self, fd: i32) -> Result<&mut Descriptor, Errno> {
+        self.fds
+            .get_mut(fd as u32 as usize)
+            .and_then(|d| d.as_mut())
+            .ok_or(ERRNO_BADF)
+    }
+
+    fn environ(&self) -> Vec<String> {
+        self.envs
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect()
+    }
+
+    fn strings_get(
+        strings: &[String],
+        mem: &mut MemInst,
+        mut ptrs: u32,
+        mut buf: u32,
+    ) -> Result<(), Errno> {
+        for s in strings {
+            write_u32(mem, ptrs, buf)?;
+            write_bytes(mem, buf, s.as_bytes())?;
+            write_bytes(mem, buf + s.len() as u32, &[0])?;
+            ptrs += 4;
+            buf += s.len() as u32 + 1;
+        }
+        Ok(())
+    }
+
+    fn strings_sizes_get(
+        strings: &[String],
+        mem: &mut MemInst,
+        count: u32,
+        size: u32,
+    ) -> Result<(), Errno> {
+        write_u32(mem, count, strings.len() as u32)?;
+        let total: usize = strings.iter().map(|s| s.len() + 1).sum();
+        write_u32(mem, size, total as u32)
+    }
+
+    fn clock_time_get(&self, id: i32, mem: &mut MemInst, time: u32) -> Result<(), Errno> {
+        let nanos = match id {
+            CLOCK_REALTIME => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_err(|_| ERRNO_IO)?
+                .as_nanos(),
+            CLOCK_MONOTONIC | CLOCK_PROCESS_CPUTIME_ID | CLOCK_THREAD_CPUTIME_ID => {
+                self.start.elapsed().as_nanos()
+            }
+            _ => return Err(ERRNO_INVAL),
+        };
+        write_u64(mem, time, nanos as u64)
+    }
+
+    fn clock_res_get(&self, id: i32, mem: &mut MemInst, res: u32) -> Result<(), Errno> {
+        match id {
+            CLOCK_REALTIME
+            | CLOCK_MONOTONIC
+            | CLOCK_PROCESS_CPUTIME_ID
+            | CLOCK_THREAD_CPUTIME_ID => write_u64(mem, res, 1),
+            _ => Err(ERRNO_INVAL),
+        }
+    }
+
+    fn random_get(&mut self, mem: &mut MemInst, buf: u32, len: u32) -> Result<(), Errno> {
+        check(mem, buf, len)?;
+        let mut bytes = vec![0; len as usize];
+        File::open("/dev/urandom")
+            .and_then(|mut source| source.read_exact(&mut bytes))
+            .map_err(|err| io_errno(&err))?;
+        write_bytes(mem, buf, &bytes)
+    }
+
+    fn fd_read(
+        &mut self,
+        fd: i32,
+        mem: &mut MemInst,
+        iovs: u32,
+        iovs_len: u32,
+        nread: u32,
+    ) -> Result<(), Errno> {
+        let desc = self.fd(fd)?;
+        let mut total = 0u32;
+        for i in 0..iovs_len {
+            let iov = i
+                .checked_mul(8)
+                .and_then(|offset| iovs.checked_add(offset))
+                .ok_or(ERRNO_FAULT)?;
+            let buf = read_u32(mem, iov)?;
+            let len = read_u32(mem, iov.checked_add(4).ok_or(ERRNO_FAULT)?)?;
+            check(mem, buf, len)?;
+            let mut dst = vec![0; len as usize];
+            let n = match desc {
+                Descriptor::Input(input) => input.read(&mut dst),
+                Descriptor::File(file) => file.read(&mut dst),
+                Descriptor::Output(_) => return Err(ERRNO_BADF),
+                Descriptor::Dir { .. } => return Err(ERRNO_ISDIR),
+            }
+            .map_err(|_| ERRNO_IO)?;
+            write_bytes(mem, buf, &dst[..n])?;
+            total = total.checked_add(n as u32).ok_or(ERRNO_OVERFLOW)?;
+            if n < len as usize {
+                break;
+            }
+        }
+        write_u32(mem, nread, total)
+    }
+
+    fn fd_write(
+        &mut self,
+        fd: i32,
+        mem: &mut MemInst,
+        iovs: u32,
+        iovs_len: u32,
+        nwritten: u32,
+    ) -> Result<(), Errno> {
+        let desc = self.fd(fd)?;
+        let mut total = 0u32;
+        for i in 0..iovs_len {
+            let iov = i
+                .checked_mul(8)
+                .and_then(|offset| iovs.checked_add(offset))
+                .ok_or(ERRNO_FAULT)?;
+            let buf = read_u32(mem, iov)?;
+            let len = read_u32(mem, iov.checked_add(4).ok_or(ERRNO_FAULT)?)?;
+            total = total.checked_add(len).ok_or(ERRNO_OVERFLOW)?;
+            let src = read_bytes(mem, buf, len)?;
+            match desc {
+                Descriptor::Output(output) => output.write_all(&src),
+                Descriptor::File(file) => file.write_all(&src),
+                Descriptor::Input(_) => return Err(ERRNO_BADF),
+                Descriptor::Dir { .. } => return Err(ERRNO_ISDIR),
+            }
+            .map_err(|_| ERRNO_IO)?;
+        }
+        if let Descriptor::Output(output) = desc {
+            output.flush().map_err(|_| ERRNO_IO)?;
+        }
+        write_u32(mem, nwritten, total)
+    }
+
+    fn fd_seek(
+        &mut self,
+        fd: i32,
+        mem: &mut MemInst,
+        offset: i64,
+        whence: i32,
+        newoffset: u32,
+    ) -> Result<(), Errno> {
+        let file = match self.fd(fd)? {
+            Descriptor::File(file) => file,
+            Descriptor::Dir { .. } => return Err(ERRNO_BADF),
+            _ => return Err(ERRNO_SPIPE),
+        };
+        let pos = match whence {
+            WHENCE_SET if offset >= 0 => SeekFrom::Start(offset as u64),
+            WHENCE_CUR => SeekFrom::Current(offset),
+            WHENCE_END => SeekFrom::End(offset),
+            _ => return Err(ERRNO_INVAL),
+        };
+        let pos = file.seek(pos).map_err(|_| ERRNO_INVAL)?;
+        write_u64(mem, newoffset, pos)
+    }
+
+    fn fd_close(&mut self, fd: i32) -> Result<(), Errno> {
+        self.fd(fd)?;
+        self.fds[fd as usize] = None;
+        Ok(())
+    }
+
+    fn fd_fdstat_get(&mut self, fd: i32, mem: &mut MemInst, stat: u32) -> Result<(), Errno> {
+        let filetype = match self.fd(fd)? {
+            Descriptor::Input(_) | Descriptor::Output(_) => FILETYPE_CHARACTER_DEVICE,
+            Descriptor::File(_) => FILETYPE_REGULAR_FILE,
+            Descriptor::Dir { .. } => FILETYPE_DIRECTORY,
+        };
+        let mut bytes = [0; 24];
+        bytes[0] = filetype;
+        write_bytes(mem, stat, &bytes)?;
+        write_u64(mem, stat + 8, RIGHTS_ALL as u64)?;
+        write_u64(mem, stat + 16, RIGHTS_ALL as u64)
+    }
+
+    fn fd_prestat_get(&mut self, fd: i32, mem: &mut MemInst, prestat: u32) -> Result<(), Errno> {
+        match self.fd(fd)? {
+            Descriptor::Dir {
+                preopen: Some(name),
+                ..
+            } => {
+                let len = name.len() as u32;
+                write_u32(mem, prestat, 0)?;
+                write_u32(mem, prestat + 4, len)
+            }
+            _ => Err(ERRNO_BADF),
+        }
+    }
+
+    fn fd_prestat_dir_name(
+        &mut self,
+        fd: i32,
+        mem: &mut MemInst,
+        path: u32,
+        path_len: u32,
+    ) -> Result<(), Errno> {
+        match self.fd(fd)? {
+            Descriptor::Dir {
+                preopen: Some(name),
+                ..
+            } => {
+                check(mem, path, path_len)?;
+                if name.len() > path_len as usize {
+                    return Err(ERRNO_INVAL);
+                }
+                write_bytes(mem, path, name.as_bytes())
+            }
+            _ => Err(ERRNO_BADF),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn path_open(
+        &mut self,
+        dirfd: i32,
+        mem: &mut MemInst,
+        path: u32,
+        path_len: u32,
+        oflags: i32,
+        rights: i64,
+        fdflags: i32,
+        opened: u32,
+    ) -> Result<(), Errno> {
+        let (base, root) = match self.fd(dirfd)? {
+            Descriptor::Dir { path, root, .. } => (path.clone(), root.clone()),
+            _ => return Err(ERRNO_NOTDIR),
+        };
+        let name = read_bytes(mem, path, path_len)?;
+        let name = core::str::from_utf8(&name).map_err(|_| ERRNO_INVAL)?;
+        let name = Path::new(name);
+        // Paths must stay inside the directory they are resolved against.
+        if name
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(ERRNO_NOTCAPABLE);
+        }
+        let full = confine(&root, &base.join(name))?;
+
+        let desc = if oflags & OFLAGS_DIRECTORY != 0 || full.is_dir() {
+            if !full.is_dir() {
+                return Err(ERRNO_NOTDIR);
+            }
+            Descriptor::Dir {
+                path: full,
+                root,
+                preopen: None,
+            }
+        } else {
+            let write = rights & RIGHTS_FD_WRITE != 0;
+            let file = OpenOptions::new()
+                .read(rights & RIGHTS_FD_READ != 0 || !write)
+                .write(write)
+                .append(fdflags & FDFLAGS_APPEND != 0)
+                .create(oflags & OFLAGS_CREAT != 0)
+                .create_new(oflags & OFLAGS_CREAT != 0 && oflags & OFLAGS_EXCL != 0)
+                .truncate(oflags & OFLAGS_TRUNC != 0)
+                .open(&full)
+                .map_err(|err| io_errno(&err))?;
+            Descriptor::File(file)
+        };
+
+        let fd = match self.fds.iter().position(|d| d.is_none()) {
+            Some(fd) => {
+                self.fds[fd] = Some(desc);
+                fd
+            }
+            None => {
+                self.fds.push(Some(desc));
+                self.fds.len() - 1
+            }
+        };
+        write_u32(mem, opened, fd as u32)
+    }
+}
+
+/// Resolves symlinks in `path` and checks that the result is still inside
+/// `root`, so a link cannot be used to escape a preopened directory.
+fn confine(root: &Path, path: &Path) -> Result<PathBuf, Errno> {
+    let resolved = match path.canonicalize() {
+        Ok(resolved) => resolved,
+        // The file does not exist yet: resolve the directory it would be
+        // created in. A dangling symlink is refused outright.
+        Err(_) if path.symlink_metadata().is_err() => match (path.parent(), path.file_name()) {
+            (Some(parent), Some(file)) => parent
+                .canonicalize()
+                .map_err(|err| io_errno(&err))?
+                .join(file),
+            _ => return Err(ERRNO_NOENT),
+        },
+        Err(_) => return Err(ERRNO_NOTCAPABLE),
+    };
+    if resolved.starts_with(root) {
+        Ok(resolved)
+    } else {
+        Err(ERRNO_NOTCAPABLE)
+    }
+}
+
+fn io_errno(err: &io::Error) -> Errno {
+    match err.kind() {
+        io::ErrorKind::NotFound => ERRNO_NOENT,
+        io::ErrorKind::PermissionDenied => ERRNO_ACCES,
+        io::ErrorKind::AlreadyExists => ERRNO_EXIST,
+        _ => ERRNO_IO,
+    }
+}
+
+/// Checks that `len` bytes at `ptr` are within `mem`.
+fn check(mem: &MemInst, ptr: u32, len: u32) -> Result<(), Errno> {
+    match (ptr as usize).checked_add(len as usize) {
+        Some(end) if end <= mem.data.len() => Ok(()),
+        _ => Err(ERRNO_FAULT),
+    }
+}
+
+fn read_bytes(mem: &MemInst, ptr: u32, len: u32) -> Result<Vec<u8>, Errno> {
+    mem.data.get(ptr as usize, len as usize).ok_or(ERRNO_FAULT)
+}
+
+fn write_bytes(mem: &mut MemInst, ptr: u32, bytes: &[u8]) -> Result<(), Errno> {
+    check(mem, ptr, bytes.len() as u32)?;
+    mem.data.write(ptr as usize, bytes);
+    Ok(())
+}
+
+fn read_u32(mem: &MemInst, ptr: u32) -> Result<u32, Errno> {
+    check(mem, ptr, 4)?;
+    Ok(mem.data.load(ptr as usize))
+}
+
+fn write_u32(mem: &mut MemInst, ptr: u32, value: u32) -> Result<(), Errno> {
+    check(mem, ptr, 4)?;
+    mem.data.store(ptr as usize, value);
+    Ok(())
+}
+
+fn write_u64(mem: &mut MemInst, ptr: u32, value: u64) -> Result<(), Errno> {
+    check(mem, ptr, 8)?;
+    mem.data.store(ptr as usize, value);
+    Ok(())
+}
+
+fn param_i32(params: &[Value], i: usize) -> Result<i32, &'static str> {
+    match params.get(i) {
+        Some(Value::I32(v)) => Ok(*v),
+        _ => Err("invalid argument"),
+    }
+}
+
+fn param_u32(params: &[Value], i: usize) -> Result<u32, &'static str> {
+    param_i32(params, i).map(|v| v as u32)
+}
+
+fn param_i64(params: &[Value], i: usize) -> Result<i64, &'static str> {
+    match params.get(i) {
+        Some(Value::I64(v)) => Ok(*v),
+        _ => Err("invalid argument"),
+    }
+}
+
+impl Env for WasiEnv {
+    fn call(
+        &mut self,
+        name: &str,
+        params: Vec<Value>,
+        memory: Option<&mut MemInst>,
+    ) -> Result<Vec<Value>, &'static str> {
+        let p = &params;
+        match name {
+            "proc_exit" => {
+                self.exit_code = Some(param_i32(p, 0)?);
+                return Err("proc_exit");
+            }
+            "sched_yield" => return Ok(vec![Value::I32(ERRNO_SUCCESS)]),
+            _ => {}
+        }
+
+        let mem = memory.ok_or("memory not found")?;
+        let result = match name {
+            "args_get" => Self::strings_get(&self.args, mem, param_u32(p, 0)?, param_u32(p, 1)?),
+            "args_sizes_get" => {
+                Self::strings_sizes_get(&self.args, mem, param_u32(p, 0)?, param_u32(p, 1)?)
+            }
+            "environ_get" => {
+                Self::strings_get(&self.environ(), mem, param_u32(p, 0)?, param_u32(p, 1)?)
+            }
+            "environ_sizes_get" => {
+                Self::strings_sizes_get(&self.environ(), mem, param_u32(p, 0)?, param_u32(p, 1)?)
+            }
+            "clock_res_get" => self.clock_res_get(param_i32(p, 0)?, mem, param_u32(p, 1)?),
+            "clock_time_get" => self.clock_time_get(param_i32(p, 0)?, mem, param_u32(p, 2)?),
+            "random_get" => self.random_get(mem, param_u32(p, 0)?, param_u32(p, 1)?),
+            "fd_read" => self.fd_read(
+                param_i32(p, 0)?,
+                mem,
+                param_u32(p, 1)?,
+                param_u32(p, 2)?,
+                param_u32(p, 3)?,
+            ),
+            "fd_write" => self.fd_write(
+                param_i32(p, 0)?,
+                mem,
+                param_u32(p, 1)?,
+                param_u32(p, 2)?,
+                param_u32(p, 3)?,
+            ),
+            "fd_seek" => self.fd_seek(
+                param_i32(p, 0)?,
+                mem,
+                param_i64(p, 1)?,
+                param_i32(p, 2)?,
+                param_u32(p, 3)?,
+            ),
+            "fd_close" => self.fd_close(param_i32(p, 0)?),
+            "fd_fdstat_get" => self.fd_fdstat_get(param_i32(p, 0)?, mem, param_u32(p, 1)?),
+            "fd_prestat_get" => self.fd_prestat_get(param_i32(p, 0)?, mem, param_u32(p, 1)?),
+            "fd_prestat_dir_name" => {
+                self.fd_prestat_dir_name(param_i32(p, 0)?, mem, param_u32(p, 1)?, param_u32(p, 2)?)
+            }
+            "path_open" => self.path_open(
+                param_i32(p, 0)?,
+                mem,
+                param_u32(p, 2)?,
+                param_u32(p, 3)?,
+                param_i32(p, 4)?,
+                param_i64(p, 5)?,
+                param_i32(p, 7)?,
+                param_u32(p, 8)?,
+            ),
+            _ => return Err("not found"),
+        };
+        Ok(vec![Value::I32(result.err().unwrap_or(ERRNO_SUCCESS))])
+    }
+}
+
+impl Drop for WasiEnv {
+    fn drop(&mut self) {
+        for desc in self.fds.iter_mut().flatten() {
+            if let Descriptor::Output(output) = desc {
+                output.flush().ok();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{WasiEnv, WASI_MODULE};
+    use crate::exec::runtime::{Runtime, RuntimeError};
+    use crate::exec::store::Store;
+    use crate::exec::trap::Trap;
+    use crate::exec::value::Value;
+    use crate::loader::parse;
+    use crate::tests::wat2wasm;
+    use std::cell::RefCell;
+    use std::io::Write;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct Capture(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn run(wat: &str, env: &mut WasiEnv) -> Result<Vec<Value>, RuntimeError> {
+        let module = parse(&wat2wasm(wat).unwrap()).unwrap();
+        let mut store = Store::new();
+        let mut runtime = Runtime::new(WASI_MODULE);
+        runtime.add_module(&mut store, env, module).unwrap();
+        runtime.invoke(&mut store, env, "_start", vec![])
+    }
+
+    #[test]
+    fn hello_world() {
+        let stdout = Capture::default();
+        let mut env = WasiEnv::new(vec!["hello".into(), "world".into()]);
+        env.set_stdout(Box::new(stdout.clone()));
+        let result = run(
+            r#"(module
+                (import "wasi_snapshot_preview1" "fd_write"
+                    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "args_sizes_get"
+                    (func $args_sizes_get (param i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 16) "hello\n")
+                (func (export "_start")
+                    (i32.store (i32.const 0) (i32.const 16))
+                    (i32.store (i32.const 4) (i32.const 6))
+                    (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))
+                    (drop (call $args_sizes_get (i32.const 32) (i32.const 36)))
+                    (call $proc_exit (i32.add (i32.load (i32.const 32)) (i32.load (i32.const 36))))))"#,
+            &mut env,
+        );
+        assert_eq!(result.unwrap_err().trap(), Some(&Trap::Env("proc_exit")));
+        assert_eq!(stdout.0.borrow().as_slice(), b"hello\n");
+        // 2 arguments, "hello\0world\0"
+        assert_eq!(env.exit_code(), Some(14));
+    }
+
+    #[test]
+    fn fd_write_overflow() {
+        let stdout = Capture::default();
+        let mut env = WasiEnv::new(vec![]);
+        env.set_stdout(Box::new(stdout.clone()));
+        let result = run(
+            r#"(module
+                (import "wasi_snapshot_preview1" "fd_write"
+                    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (memory 1)
+                (data (i32.const 32) "x")
+                (func (export "_start") (result i32)
+                    (i32.store (i32.const 0) (i32.const 32))
+                    (i32.store (i32.const 4) (i32.const 1))
+                    (i32.store (i32.const 8) (i32.const 32))
+                    (i32.store (i32.const 12) (i32.const -1))
+                    (call $fd_write (i32.const 1) (i32.const 0) (i32.const 2) (i32.const 16))))"#,
+            &mut env,
+        );
+        assert_eq!(result, Ok(vec![Value::I32(61)]));
+        assert_eq!(stdout.0.borrow().as_slice(), b"x");
+    }
+
+    #[test]
+    fn preopened_dir() {
+        let dir = std::env::temp_dir();
+        let path = dir.join("wasper-wasi-test.txt");
+        std::fs::remove_file(&path).ok();
+        let mut env = WasiEnv::new(vec![]);
+        env.preopen_dir(&dir, "/tmp").unwrap();
+        let result = run(
+            r#"(module
+                (import "wasi_snapshot_preview1" "path_open"
+                    (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_write"
+                    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (memory 1)
+                (data (i32.const 16) "wasper-wasi-test.txt")
+                (data (i32.const 48) "../escape")
+                (data (i32.const 64) "data")
+                (func (export "_start") (result i32 i32 i32)
+                    (call $path_open (i32.const 3) (i32.const 0) (i32.const 48) (i32.const 9)
+                        (i32.const 1) (i64.const 64) (i64.const 0) (i32.const 0) (i32.const 0))
+                    (call $path_open (i32.const 3) (i32.const 0) (i32.const 16) (i32.const 20)
+                        (i32.const 1) (i64.const 64) (i64.const 0) (i32.const 0) (i32.const 0))
+                    (i32.store (i32.const 4) (i32.const 64))
+                    (i32.store (i32.const 8) (i32.const 4))
+                    (call $fd_write (i32.load (i32.const 0)) (i32.const 4) (i32.const 1) (i32.const 12))))"#,
+            &mut env,
+        );
+        drop(env);
+        assert_eq!(
+            result,
+            Ok(vec![Value::I32(76), Value::I32(0), Value::I32(0)])
+        );
+        assert_eq!(std::fs::read(&path).unwrap(), b"data");
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_escape() {
+        let dir = std::env::temp_dir().join("wasper-wasi-symlink");
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(dir.join("root")).unwrap();
+        std::fs::write(dir.join("secret.txt"), b"secret").unwrap();
+        std::os::unix::fs::symlink(dir.join("secret.txt"), dir.join("root/file")).unwrap();
+        std::os::unix::fs::symlink(&dir, dir.join("root/dir")).unwrap();
+        let mut env = WasiEnv::new(vec![]);
+        env.preopen_dir(dir.join("root"), "/").unwrap();
+        let result = run(
+            r#"(module
+                (import "wasi_snapshot_preview1" "path_open"
+                    (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+                (memory 1)
+                (data (i32.const 16) "file")
+                (data (i32.const 32) "dir/new.txt")
+                (func (export "_start") (result i32 i32)
+                    (call $path_open (i32.const 3) (i32.const 0) (i32.const 16) (i32.const 4)
+                        (i32.const 0) (i64.const 2) (i64.const 0) (i32.const 0) (i32.const 0))
+                    (call $path_open (i32.const 3) (i32.const 0) (i32.const 32) (i32.const 11)
+                        (i32.const 1) (i64.const 64) (i64.const 0) (i32.const 0) (i32.const 0))))"#,
+            &mut env,
+        );
+        assert_eq!(result, Ok(vec![Value::I32(76), Value::I32(76)]));
+        assert!(!dir.join("new.txt").exists());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
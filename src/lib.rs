@@ -30,6 +30,7 @@ pub mod lib {
 }
 
 pub mod binary;
+pub mod debug;
 pub mod exec;
 pub mod loader;
 
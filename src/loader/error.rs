@@ -3,15 +3,79 @@ use super::leb128::Type;
 use crate::lib::*;
 use core::str::Utf8Error;
 
+/// A malformed module, with the position at which it was detected.
 #[derive(Debug, PartialEq, Eq)]
-pub enum Error {
+pub struct Error {
+    pub kind: ErrorKind,
+    /// Byte offset from the start of the module.
+    pub offset: usize,
+    /// Id of the enclosing section, if any.
+    pub section: Option<u8>,
+    /// Index of the function whose body was being parsed, if any.
+    pub func: Option<u32>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ErrorKind {
     InvalidMagicNumber,
     InvalidVersion,
     InvalidSectionHeader,
+    /// A section is out of order, duplicated, or followed by junk.
+    UnexpectedContent,
+    SectionSizeMismatch,
+    FuncCodeMismatch,
+    DataCountMismatch,
+    /// A LEB128 integer is encoded with more bytes than its type allows.
+    IntTooLong(Type),
+    /// The last byte of a LEB128 integer has bits beyond its type.
     IntOverflow(Type),
     InvalidUtf8(Utf8Error),
     UnexpectedEof(String),
+    /// An unknown opcode, with the secondary opcode of prefixed instructions.
+    IllegalOpcode(u8, Option<u32>),
+    ZeroByteExpected,
     Expected(String),
     Other(String),
-    Or(Box<Error>, Box<Error>),
+}
+
+impl core::fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ErrorKind::InvalidMagicNumber => write!(f, "magic header not detected"),
+            ErrorKind::InvalidVersion => write!(f, "unknown binary version"),
+            ErrorKind::InvalidSectionHeader => write!(f, "malformed section id"),
+            ErrorKind::UnexpectedContent => write!(f, "unexpected content after last section"),
+            ErrorKind::SectionSizeMismatch => write!(f, "section size mismatch"),
+            ErrorKind::FuncCodeMismatch => {
+                write!(f, "function and code section have inconsistent lengths")
+            }
+            ErrorKind::DataCountMismatch => {
+                write!(f, "data count and data section have inconsistent lengths")
+            }
+            ErrorKind::IntTooLong(_) => write!(f, "integer representation too long"),
+            ErrorKind::IntOverflow(_) => write!(f, "integer too large"),
+            ErrorKind::InvalidUtf8(_) => write!(f, "malformed UTF-8 encoding"),
+            ErrorKind::UnexpectedEof(_) => write!(f, "unexpected end"),
+            ErrorKind::IllegalOpcode(op, None) => write!(f, "illegal opcode {:02x}", op),
+            ErrorKind::IllegalOpcode(op, Some(sub)) => {
+                write!(f, "illegal opcode {:02x} {}", op, sub)
+            }
+            ErrorKind::ZeroByteExpected => write!(f, "zero byte expected"),
+            ErrorKind::Expected(what) => write!(f, "malformed {}", what),
+            ErrorKind::Other(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{} (at offset 0x{:x}", self.kind, self.offset)?;
+        if let Some(id) = self.section {
+            write!(f, ", section {}", id)?;
+        }
+        if let Some(idx) = self.func {
+            write!(f, ", function {}", idx)?;
+        }
+        write!(f, ")")
+    }
 }
@@ -1,4 +1,7 @@
-use super::{error::Error, parser::Parser};
+use super::{
+    error::{Error, ErrorKind},
+    parser::Parser,
+};
 use crate::binary::*;
 #[cfg(not(feature = "std"))]
 use crate::lib::*;
@@ -10,62 +13,107 @@ impl<'a> Parser<'a> {
                 self.next();
                 Ok(Block::Empty)
             }
-            Some(t) if self.is_valtype(t) => {
-                self.next();
-                Ok(Block::ValType(ValType::from_byte(t).unwrap()))
-            }
+            Some(t) if self.is_valtype(t) => Ok(Block::ValType(self.valtype()?)),
             // TODO
             // It is treated as a 33 bit signed integer.
-            Some(_) => Ok(Block::TypeIdx(self.s32()? as u32)),
-            None => Err(Error::UnexpectedEof(format!("blocktype"))),
+            Some(_) => match self.s32()? {
+                idx if idx >= 0 => Ok(Block::TypeIdx(idx as u32)),
+                _ => Err(self.error(ErrorKind::Expected(format!("block type")))),
+            },
+            None => Err(self.error(ErrorKind::UnexpectedEof(format!("block type")))),
         }
     }
 
     pub fn memarg(&mut self) -> Result<MemArg, Error> {
+        // Bit 6 of the alignment flags that an explicit memory index follows.
+        let flags = self.u32()?;
+        let (align, memidx) = if flags & 0x40 != 0 {
+            (flags & !0x40, self.memidx()?)
+        } else {
+            (flags, 0)
+        };
         Ok(MemArg {
-            align: self.u32()?,
-            offset: self.u32()?,
+            align,
+            offset: self.u64()?,
+            memidx,
         })
     }
 
+    pub fn catch(&mut self) -> Result<Catch, Error> {
+        match self.byte() {
+            Some(0x00) => Ok(Catch::Catch(self.tagidx()?, self.labelidx()?)),
+            Some(0x01) => Ok(Catch::CatchRef(self.tagidx()?, self.labelidx()?)),
+            Some(0x02) => Ok(Catch::CatchAll(self.labelidx()?)),
+            Some(0x03) => Ok(Catch::CatchAllRef(self.labelidx()?)),
+            Some(_) => Err(self.error(ErrorKind::Expected(format!("catch clause")))),
+            None => Err(self.error(ErrorKind::UnexpectedEof(format!("catch clause")))),
+        }
+    }
+
     pub fn expr(&mut self) -> Result<Expr, Error> {
         Ok(Expr(
-            self.take_while0(Self::instr, |b| b == 0x0B)?
+            self.instrs_until(0x0B)?
                 .into_iter()
-                .flatten()
+                .map(|(instr, _)| instr)
                 .collect(),
         ))
     }
 
-    pub fn instr(&mut self) -> Result<Vec<Instr>, Error> {
+    /// Instructions up to and including the terminator `end`, with the
+    /// offset each one was decoded from. See `instr`.
+    pub fn instrs_until(&mut self, end: u8) -> Result<Vec<(Instr, usize)>, Error> {
+        Ok(self
+            .take_while0(Self::instr, |b| b == end)?
+            .into_iter()
+            .flatten()
+            .collect())
+    }
+
+    /// Decodes one instruction into the instructions it is executed as,
+    /// paired with the offset of their opcode. `PopLabel` and `RJump`
+    /// inserted for the end of a block and `else` have the offset of
+    /// those.
+    pub fn instr(&mut self) -> Result<Vec<(Instr, usize)>, Error> {
+        let start = self.offset();
         let instr = match self.next() {
             // Control Instructions
             Some(0x00) => Instr::Unreachable,
             Some(0x01) => Instr::Nop,
             Some(0x02) => {
                 let bt = self.blocktype()?;
-                let mut inner: Vec<Instr> = self
-                    .take_while0(Self::instr, |b| b == 0x0B)?
-                    .into_iter()
-                    .flatten()
-                    .collect();
-                inner.push(Instr::PopLabel);
-                let mut instrs = vec![Instr::Block {
-                    bt,
-                    end_offset: inner.len() + 1,
-                }];
+                let mut inner = self.instrs_until(0x0B)?;
+                inner.push((Instr::PopLabel, self.offset() - 1));
+                let mut instrs = vec![(
+                    Instr::Block {
+                        bt,
+                        end_offset: inner.len() + 1,
+                    },
+                    start,
+                )];
                 instrs.extend(inner.into_iter());
                 return Ok(instrs);
             }
             Some(0x03) => {
                 let bt = self.blocktype()?;
-                let mut inner: Vec<Instr> = self
-                    .take_while0(Self::instr, |b| b == 0x0B)?
-                    .into_iter()
-                    .flatten()
-                    .collect();
-                inner.push(Instr::PopLabel);
-                let mut instrs = vec![Instr::Loop { bt }];
+                let mut inner = self.instrs_until(0x0B)?;
+                inner.push((Instr::PopLabel, self.offset() - 1));
+                let mut instrs = vec![(Instr::Loop { bt }, start)];
+                instrs.extend(inner.into_iter());
+                return Ok(instrs);
+            }
+            Some(0x1F) => {
+                let bt = self.blocktype()?;
+                let catches = self.vec(Self::catch)?;
+                let mut inner = self.instrs_until(0x0B)?;
+                inner.push((Instr::PopLabel, self.offset() - 1));
+                let mut instrs = vec![(
+                    Instr::TryTable {
+                        bt,
+                        catches,
+                        end_offset: inner.len() + 1,
+                    },
+                    start,
+                )];
                 instrs.extend(inner.into_iter());
                 return Ok(instrs);
             }
@@ -73,49 +121,45 @@ impl<'a> Parser<'a> {
                 return self.or(
                     |p| {
                         let bt = p.blocktype()?;
-                        let mut then_instrs: Vec<Instr> = p
-                            .take_while0(Self::instr, |b| b == 0x05)?
-                            .into_iter()
-                            .flatten()
-                            .collect();
+                        let mut then_instrs = p.instrs_until(0x05)?;
+                        let else_ = p.offset() - 1;
+                        then_instrs.push((Instr::PopLabel, else_));
 
-                        then_instrs.push(Instr::PopLabel);
+                        let mut else_instrs = p.instrs_until(0x0B)?;
+                        else_instrs.push((Instr::PopLabel, p.offset() - 1));
 
-                        let mut else_instrs: Vec<Instr> = p
-                            .take_while0(Self::instr, |b| b == 0x0B)?
-                            .into_iter()
-                            .flatten()
-                            .collect();
-                        else_instrs.push(Instr::PopLabel);
-
-                        then_instrs.push(Instr::RJump(else_instrs.len() + 1));
-                        let mut instrs = vec![Instr::If {
-                            bt,
-                            else_offset: Some(then_instrs.len() + 1),
-                            end_offset: then_instrs.len() + else_instrs.len() + 1,
-                        }];
+                        then_instrs.push((Instr::RJump(else_instrs.len() + 1), else_));
+                        let mut instrs = vec![(
+                            Instr::If {
+                                bt,
+                                else_offset: Some(then_instrs.len() + 1),
+                                end_offset: then_instrs.len() + else_instrs.len() + 1,
+                            },
+                            start,
+                        )];
                         instrs.extend(then_instrs.into_iter());
                         instrs.extend(else_instrs.into_iter());
                         Ok(instrs)
                     },
                     |p| {
                         let bt = p.blocktype()?;
-                        let mut then_instrs: Vec<Instr> = p
-                            .take_while0(Self::instr, |b| b == 0x0B)?
-                            .into_iter()
-                            .flatten()
-                            .collect();
-                        then_instrs.push(Instr::PopLabel);
-                        let mut instrs = vec![Instr::If {
-                            bt,
-                            else_offset: None,
-                            end_offset: then_instrs.len() + 1,
-                        }];
+                        let mut then_instrs = p.instrs_until(0x0B)?;
+                        then_instrs.push((Instr::PopLabel, p.offset() - 1));
+                        let mut instrs = vec![(
+                            Instr::If {
+                                bt,
+                                else_offset: None,
+                                end_offset: then_instrs.len() + 1,
+                            },
+                            start,
+                        )];
                         instrs.extend(then_instrs.into_iter());
                         Ok(instrs)
                     },
                 );
             }
+            Some(0x08) => Instr::Throw(self.tagidx()?),
+            Some(0x0A) => Instr::ThrowRef,
             Some(0x0C) => Instr::Br(self.labelidx()?),
             Some(0x0D) => Instr::BrIf(self.labelidx()?),
             Some(0x0E) => Instr::BrTable {
@@ -125,13 +169,33 @@ impl<'a> Parser<'a> {
             Some(0x0F) => Instr::Return,
             Some(0x10) => Instr::Call(self.funcidx()?),
             Some(0x11) => Instr::CallIndirect(self.typeidx()?, self.tableidx()?),
+            // A tail call to a host function returns to the next instruction,
+            // which returns from the caller.
+            Some(0x12) => {
+                let instr = Instr::ReturnCall(self.funcidx()?);
+                return Ok(vec![(instr, start), (Instr::Return, start)]);
+            }
+            Some(0x13) => {
+                let instr = Instr::ReturnCallIndirect(self.typeidx()?, self.tableidx()?);
+                return Ok(vec![(instr, start), (Instr::Return, start)]);
+            }
+            Some(0x14) => Instr::CallRef(self.typeidx()?),
+            Some(0x15) => {
+                let instr = Instr::ReturnCallRef(self.typeidx()?);
+                return Ok(vec![(instr, start), (Instr::Return, start)]);
+            }
+            Some(0xD5) => Instr::BrOnNull(self.labelidx()?),
+            Some(0xD6) => Instr::BrOnNonNull(self.labelidx()?),
             // Reference Instructions
-            Some(0xD0) => Instr::RefNull(self.reftype()?),
+            Some(0xD0) => Instr::RefNull(self.heaptype()?),
             Some(0xD1) => Instr::RefIsNull,
             Some(0xD2) => Instr::RefFunc(self.funcidx()?),
+            Some(0xD3) => Instr::RefEq,
+            Some(0xD4) => Instr::RefAsNonNull,
             // Parametric Instructions
             Some(0x1A) => Instr::Drop,
             Some(0x1B) => Instr::Select,
+            Some(0x1C) => Instr::SelectT(self.vec(Self::valtype)?),
             // Variable Instructions
             Some(0x20) => Instr::LocalGet(self.localidx()?),
             Some(0x21) => Instr::LocalSet(self.localidx()?),
@@ -166,14 +230,8 @@ impl<'a> Parser<'a> {
             Some(0x3C) => Instr::I64Store8(self.memarg()?),
             Some(0x3D) => Instr::I64Store16(self.memarg()?),
             Some(0x3E) => Instr::I64Store32(self.memarg()?),
-            Some(0x3F) => {
-                self.target(0x00).ok_or(Error::Expected(format!("0x00")))?;
-                Instr::MemorySize
-            }
-            Some(0x40) => {
-                self.target(0x00).ok_or(Error::Expected(format!("0x00")))?;
-                Instr::MemoryGrow
-            }
+            Some(0x3F) => Instr::MemorySize(self.memidx()?),
+            Some(0x40) => Instr::MemoryGrow(self.memidx()?),
             // Numeric Instructions
             Some(0x41) => Instr::I32Const(self.i32()?),
             Some(0x42) => Instr::I64Const(self.i64()?),
@@ -318,6 +376,58 @@ impl<'a> Parser<'a> {
             Some(0xC3) => Instr::I64Extend16S,
             Some(0xC4) => Instr::I64Extend32S,
 
+            // 0xFB Instructions
+            Some(0xFB) => match self.u32() {
+                // Aggregate Instructions
+                Ok(0) => Instr::StructNew(self.typeidx()?),
+                Ok(1) => Instr::StructNewDefault(self.typeidx()?),
+                Ok(2) => Instr::StructGet(self.typeidx()?, self.u32()?),
+                Ok(3) => Instr::StructGetS(self.typeidx()?, self.u32()?),
+                Ok(4) => Instr::StructGetU(self.typeidx()?, self.u32()?),
+                Ok(5) => Instr::StructSet(self.typeidx()?, self.u32()?),
+                Ok(6) => Instr::ArrayNew(self.typeidx()?),
+                Ok(7) => Instr::ArrayNewDefault(self.typeidx()?),
+                Ok(8) => Instr::ArrayNewFixed(self.typeidx()?, self.u32()?),
+                Ok(9) => Instr::ArrayNewData(self.typeidx()?, self.dataidx()?),
+                Ok(10) => Instr::ArrayNewElem(self.typeidx()?, self.elemidx()?),
+                Ok(11) => Instr::ArrayGet(self.typeidx()?),
+                Ok(12) => Instr::ArrayGetS(self.typeidx()?),
+                Ok(13) => Instr::ArrayGetU(self.typeidx()?),
+                Ok(14) => Instr::ArraySet(self.typeidx()?),
+                Ok(15) => Instr::ArrayLen,
+                Ok(16) => Instr::ArrayFill(self.typeidx()?),
+                Ok(17) => Instr::ArrayCopy(self.typeidx()?, self.typeidx()?),
+                Ok(18) => Instr::ArrayInitData(self.typeidx()?, self.dataidx()?),
+                Ok(19) => Instr::ArrayInitElem(self.typeidx()?, self.elemidx()?),
+                Ok(20) => Instr::RefTest(RefType::new(false, self.heaptype()?)),
+                Ok(21) => Instr::RefTest(RefType::new(true, self.heaptype()?)),
+                Ok(22) => Instr::RefCast(RefType::new(false, self.heaptype()?)),
+                Ok(23) => Instr::RefCast(RefType::new(true, self.heaptype()?)),
+                Ok(op @ (24 | 25)) => {
+                    // Bits 0 and 1 of the flags make the source and target types nullable.
+                    let flags = match self.byte() {
+                        Some(flags) if flags & !0x03 == 0 => flags,
+                        _ => return Err(self.error(ErrorKind::Expected(format!("cast flags")))),
+                    };
+                    let l = self.labelidx()?;
+                    let rt1 = RefType::new(flags & 0x01 != 0, self.heaptype()?);
+                    let rt2 = RefType::new(flags & 0x02 != 0, self.heaptype()?);
+                    if op == 24 {
+                        Instr::BrOnCast(l, rt1, rt2)
+                    } else {
+                        Instr::BrOnCastFail(l, rt1, rt2)
+                    }
+                }
+                Ok(26) => Instr::AnyConvertExtern,
+                Ok(27) => Instr::ExternConvertAny,
+                Ok(28) => Instr::RefI31,
+                Ok(29) => Instr::I31GetS,
+                Ok(30) => Instr::I31GetU,
+                Ok(op) => {
+                    return Err(self.error_at(start, ErrorKind::IllegalOpcode(0xFB, Some(op))))
+                }
+                Err(err) => return Err(err),
+            },
             // 0xFC Instructions
             Some(0xFC) => match self.u32() {
                 // Numeric Instructions
@@ -330,21 +440,10 @@ impl<'a> Parser<'a> {
                 Ok(6) => Instr::I64TruncSatF64S,
                 Ok(7) => Instr::I64TruncSatF64U,
                 // Memory Instructions
-                Ok(8) => {
-                    let ret = Instr::MemoryInit(self.dataidx()?);
-                    self.target(0x00).ok_or(Error::Expected(format!("0x00")))?;
-                    ret
-                }
+                Ok(8) => Instr::MemoryInit(self.dataidx()?, self.memidx()?),
                 Ok(9) => Instr::DataDrop(self.dataidx()?),
-                Ok(10) => {
-                    self.target(0x00).ok_or(Error::Expected(format!("0x00")))?;
-                    self.target(0x00).ok_or(Error::Expected(format!("0x00")))?;
-                    Instr::MemoryCopy
-                }
-                Ok(11) => {
-                    self.target(0x00).ok_or(Error::Expected(format!("0x00")))?;
-                    Instr::MemoryFill
-                }
+                Ok(10) => Instr::MemoryCopy(self.memidx()?, self.memidx()?),
+                Ok(11) => Instr::MemoryFill(self.memidx()?),
                 // Table Instructions
                 Ok(12) => Instr::TableInit(self.elemidx()?, self.tableidx()?),
                 Ok(13) => Instr::ElemDrop(self.elemidx()?),
@@ -352,18 +451,343 @@ impl<'a> Parser<'a> {
                 Ok(15) => Instr::TableGrow(self.tableidx()?),
                 Ok(16) => Instr::TableSize(self.tableidx()?),
                 Ok(17) => Instr::TableFill(self.tableidx()?),
-                _ => unreachable!(),
+                Ok(op) => {
+                    return Err(self.error_at(start, ErrorKind::IllegalOpcode(0xFC, Some(op))))
+                }
+                Err(err) => return Err(err),
+            },
+            // 0xFD Instructions
+            Some(0xFD) => match self.u32() {
+                // Vector Instructions
+                Ok(0x00) => Instr::V128Load(self.memarg()?),
+                Ok(0x01) => Instr::V128Load8x8S(self.memarg()?),
+                Ok(0x02) => Instr::V128Load8x8U(self.memarg()?),
+                Ok(0x03) => Instr::V128Load16x4S(self.memarg()?),
+                Ok(0x04) => Instr::V128Load16x4U(self.memarg()?),
+                Ok(0x05) => Instr::V128Load32x2S(self.memarg()?),
+                Ok(0x06) => Instr::V128Load32x2U(self.memarg()?),
+                Ok(0x07) => Instr::V128Load8Splat(self.memarg()?),
+                Ok(0x08) => Instr::V128Load16Splat(self.memarg()?),
+                Ok(0x09) => Instr::V128Load32Splat(self.memarg()?),
+                Ok(0x0A) => Instr::V128Load64Splat(self.memarg()?),
+                Ok(0x0B) => Instr::V128Store(self.memarg()?),
+                Ok(0x0C) => Instr::V128Const(self.v128()?),
+                Ok(0x0D) => Instr::I8x16Shuffle(self.v128()?.to_le_bytes()),
+                Ok(0x0E) => Instr::I8x16Swizzle,
+                Ok(0x0F) => Instr::I8x16Splat,
+                Ok(0x10) => Instr::I16x8Splat,
+                Ok(0x11) => Instr::I32x4Splat,
+                Ok(0x12) => Instr::I64x2Splat,
+                Ok(0x13) => Instr::F32x4Splat,
+                Ok(0x14) => Instr::F64x2Splat,
+                Ok(0x15) => Instr::I8x16ExtractLaneS(self.laneidx()?),
+                Ok(0x16) => Instr::I8x16ExtractLaneU(self.laneidx()?),
+                Ok(0x17) => Instr::I8x16ReplaceLane(self.laneidx()?),
+                Ok(0x18) => Instr::I16x8ExtractLaneS(self.laneidx()?),
+                Ok(0x19) => Instr::I16x8ExtractLaneU(self.laneidx()?),
+                Ok(0x1A) => Instr::I16x8ReplaceLane(self.laneidx()?),
+                Ok(0x1B) => Instr::I32x4ExtractLane(self.laneidx()?),
+                Ok(0x1C) => Instr::I32x4ReplaceLane(self.laneidx()?),
+                Ok(0x1D) => Instr::I64x2ExtractLane(self.laneidx()?),
+                Ok(0x1E) => Instr::I64x2ReplaceLane(self.laneidx()?),
+                Ok(0x1F) => Instr::F32x4ExtractLane(self.laneidx()?),
+                Ok(0x20) => Instr::F32x4ReplaceLane(self.laneidx()?),
+                Ok(0x21) => Instr::F64x2ExtractLane(self.laneidx()?),
+                Ok(0x22) => Instr::F64x2ReplaceLane(self.laneidx()?),
+                Ok(0x23) => Instr::I8x16Eq,
+                Ok(0x24) => Instr::I8x16Ne,
+                Ok(0x25) => Instr::I8x16LtS,
+                Ok(0x26) => Instr::I8x16LtU,
+                Ok(0x27) => Instr::I8x16GtS,
+                Ok(0x28) => Instr::I8x16GtU,
+                Ok(0x29) => Instr::I8x16LeS,
+                Ok(0x2A) => Instr::I8x16LeU,
+                Ok(0x2B) => Instr::I8x16GeS,
+                Ok(0x2C) => Instr::I8x16GeU,
+                Ok(0x2D) => Instr::I16x8Eq,
+                Ok(0x2E) => Instr::I16x8Ne,
+                Ok(0x2F) => Instr::I16x8LtS,
+                Ok(0x30) => Instr::I16x8LtU,
+                Ok(0x31) => Instr::I16x8GtS,
+                Ok(0x32) => Instr::I16x8GtU,
+                Ok(0x33) => Instr::I16x8LeS,
+                Ok(0x34) => Instr::I16x8LeU,
+                Ok(0x35) => Instr::I16x8GeS,
+                Ok(0x36) => Instr::I16x8GeU,
+                Ok(0x37) => Instr::I32x4Eq,
+                Ok(0x38) => Instr::I32x4Ne,
+                Ok(0x39) => Instr::I32x4LtS,
+                Ok(0x3A) => Instr::I32x4LtU,
+                Ok(0x3B) => Instr::I32x4GtS,
+                Ok(0x3C) => Instr::I32x4GtU,
+                Ok(0x3D) => Instr::I32x4LeS,
+                Ok(0x3E) => Instr::I32x4LeU,
+                Ok(0x3F) => Instr::I32x4GeS,
+                Ok(0x40) => Instr::I32x4GeU,
+                Ok(0x41) => Instr::F32x4Eq,
+                Ok(0x42) => Instr::F32x4Ne,
+                Ok(0x43) => Instr::F32x4Lt,
+                Ok(0x44) => Instr::F32x4Gt,
+                Ok(0x45) => Instr::F32x4Le,
+                Ok(0x46) => Instr::F32x4Ge,
+                Ok(0x47) => Instr::F64x2Eq,
+                Ok(0x48) => Instr::F64x2Ne,
+                Ok(0x49) => Instr::F64x2Lt,
+                Ok(0x4A) => Instr::F64x2Gt,
+                Ok(0x4B) => Instr::F64x2Le,
+                Ok(0x4C) => Instr::F64x2Ge,
+                Ok(0x4D) => Instr::V128Not,
+                Ok(0x4E) => Instr::V128And,
+                Ok(0x4F) => Instr::V128Andnot,
+                Ok(0x50) => Instr::V128Or,
+                Ok(0x51) => Instr::V128Xor,
+                Ok(0x52) => Instr::V128Bitselect,
+                Ok(0x53) => Instr::V128AnyTrue,
+                Ok(0x54) => Instr::V128Load8Lane(self.memarg()?, self.laneidx()?),
+                Ok(0x55) => Instr::V128Load16Lane(self.memarg()?, self.laneidx()?),
+                Ok(0x56) => Instr::V128Load32Lane(self.memarg()?, self.laneidx()?),
+                Ok(0x57) => Instr::V128Load64Lane(self.memarg()?, self.laneidx()?),
+                Ok(0x58) => Instr::V128Store8Lane(self.memarg()?, self.laneidx()?),
+                Ok(0x59) => Instr::V128Store16Lane(self.memarg()?, self.laneidx()?),
+                Ok(0x5A) => Instr::V128Store32Lane(self.memarg()?, self.laneidx()?),
+                Ok(0x5B) => Instr::V128Store64Lane(self.memarg()?, self.laneidx()?),
+                Ok(0x5C) => Instr::V128Load32Zero(self.memarg()?),
+                Ok(0x5D) => Instr::V128Load64Zero(self.memarg()?),
+                Ok(0x5E) => Instr::F32x4DemoteF64x2Zero,
+                Ok(0x5F) => Instr::F64x2PromoteLowF32x4,
+                Ok(0x60) => Instr::I8x16Abs,
+                Ok(0x61) => Instr::I8x16Neg,
+                Ok(0x62) => Instr::I8x16Popcnt,
+                Ok(0x63) => Instr::I8x16AllTrue,
+                Ok(0x64) => Instr::I8x16Bitmask,
+                Ok(0x65) => Instr::I8x16NarrowI16x8S,
+                Ok(0x66) => Instr::I8x16NarrowI16x8U,
+                Ok(0x67) => Instr::F32x4Ceil,
+                Ok(0x68) => Instr::F32x4Floor,
+                Ok(0x69) => Instr::F32x4Trunc,
+                Ok(0x6A) => Instr::F32x4Nearest,
+                Ok(0x6B) => Instr::I8x16Shl,
+                Ok(0x6C) => Instr::I8x16ShrS,
+                Ok(0x6D) => Instr::I8x16ShrU,
+                Ok(0x6E) => Instr::I8x16Add,
+                Ok(0x6F) => Instr::I8x16AddSatS,
+                Ok(0x70) => Instr::I8x16AddSatU,
+                Ok(0x71) => Instr::I8x16Sub,
+                Ok(0x72) => Instr::I8x16SubSatS,
+                Ok(0x73) => Instr::I8x16SubSatU,
+                Ok(0x74) => Instr::F64x2Ceil,
+                Ok(0x75) => Instr::F64x2Floor,
+                Ok(0x76) => Instr::I8x16MinS,
+                Ok(0x77) => Instr::I8x16MinU,
+                Ok(0x78) => Instr::I8x16MaxS,
+                Ok(0x79) => Instr::I8x16MaxU,
+                Ok(0x7A) => Instr::F64x2Trunc,
+                Ok(0x7B) => Instr::I8x16AvgrU,
+                Ok(0x7C) => Instr::I16x8ExtaddPairwiseI8x16S,
+                Ok(0x7D) => Instr::I16x8ExtaddPairwiseI8x16U,
+                Ok(0x7E) => Instr::I32x4ExtaddPairwiseI16x8S,
+                Ok(0x7F) => Instr::I32x4ExtaddPairwiseI16x8U,
+                Ok(0x80) => Instr::I16x8Abs,
+                Ok(0x81) => Instr::I16x8Neg,
+                Ok(0x82) => Instr::I16x8Q15mulrSatS,
+                Ok(0x83) => Instr::I16x8AllTrue,
+                Ok(0x84) => Instr::I16x8Bitmask,
+                Ok(0x85) => Instr::I16x8NarrowI32x4S,
+                Ok(0x86) => Instr::I16x8NarrowI32x4U,
+                Ok(0x87) => Instr::I16x8ExtendLowI8x16S,
+                Ok(0x88) => Instr::I16x8ExtendHighI8x16S,
+                Ok(0x89) => Instr::I16x8ExtendLowI8x16U,
+                Ok(0x8A) => Instr::I16x8ExtendHighI8x16U,
+                Ok(0x8B) => Instr::I16x8Shl,
+                Ok(0x8C) => Instr::I16x8ShrS,
+                Ok(0x8D) => Instr::I16x8ShrU,
+                Ok(0x8E) => Instr::I16x8Add,
+                Ok(0x8F) => Instr::I16x8AddSatS,
+                Ok(0x90) => Instr::I16x8AddSatU,
+                Ok(0x91) => Instr::I16x8Sub,
+                Ok(0x92) => Instr::I16x8SubSatS,
+                Ok(0x93) => Instr::I16x8SubSatU,
+                Ok(0x94) => Instr::F64x2Nearest,
+                Ok(0x95) => Instr::I16x8Mul,
+                Ok(0x96) => Instr::I16x8MinS,
+                Ok(0x97) => Instr::I16x8MinU,
+                Ok(0x98) => Instr::I16x8MaxS,
+                Ok(0x99) => Instr::I16x8MaxU,
+                Ok(0x9B) => Instr::I16x8AvgrU,
+                Ok(0x9C) => Instr::I16x8ExtmulLowI8x16S,
+                Ok(0x9D) => Instr::I16x8ExtmulHighI8x16S,
+                Ok(0x9E) => Instr::I16x8ExtmulLowI8x16U,
+                Ok(0x9F) => Instr::I16x8ExtmulHighI8x16U,
+                Ok(0xA0) => Instr::I32x4Abs,
+                Ok(0xA1) => Instr::I32x4Neg,
+                Ok(0xA3) => Instr::I32x4AllTrue,
+                Ok(0xA4) => Instr::I32x4Bitmask,
+                Ok(0xA7) => Instr::I32x4ExtendLowI16x8S,
+                Ok(0xA8) => Instr::I32x4ExtendHighI16x8S,
+                Ok(0xA9) => Instr::I32x4ExtendLowI16x8U,
+                Ok(0xAA) => Instr::I32x4ExtendHighI16x8U,
+                Ok(0xAB) => Instr::I32x4Shl,
+                Ok(0xAC) => Instr::I32x4ShrS,
+                Ok(0xAD) => Instr::I32x4ShrU,
+                Ok(0xAE) => Instr::I32x4Add,
+                Ok(0xB1) => Instr::I32x4Sub,
+                Ok(0xB5) => Instr::I32x4Mul,
+                Ok(0xB6) => Instr::I32x4MinS,
+                Ok(0xB7) => Instr::I32x4MinU,
+                Ok(0xB8) => Instr::I32x4MaxS,
+                Ok(0xB9) => Instr::I32x4MaxU,
+                Ok(0xBA) => Instr::I32x4DotI16x8S,
+                Ok(0xBC) => Instr::I32x4ExtmulLowI16x8S,
+                Ok(0xBD) => Instr::I32x4ExtmulHighI16x8S,
+                Ok(0xBE) => Instr::I32x4ExtmulLowI16x8U,
+                Ok(0xBF) => Instr::I32x4ExtmulHighI16x8U,
+                Ok(0xC0) => Instr::I64x2Abs,
+                Ok(0xC1) => Instr::I64x2Neg,
+                Ok(0xC3) => Instr::I64x2AllTrue,
+                Ok(0xC4) => Instr::I64x2Bitmask,
+                Ok(0xC7) => Instr::I64x2ExtendLowI32x4S,
+                Ok(0xC8) => Instr::I64x2ExtendHighI32x4S,
+                Ok(0xC9) => Instr::I64x2ExtendLowI32x4U,
+                Ok(0xCA) => Instr::I64x2ExtendHighI32x4U,
+                Ok(0xCB) => Instr::I64x2Shl,
+                Ok(0xCC) => Instr::I64x2ShrS,
+                Ok(0xCD) => Instr::I64x2ShrU,
+                Ok(0xCE) => Instr::I64x2Add,
+                Ok(0xD1) => Instr::I64x2Sub,
+                Ok(0xD5) => Instr::I64x2Mul,
+                Ok(0xD6) => Instr::I64x2Eq,
+                Ok(0xD7) => Instr::I64x2Ne,
+                Ok(0xD8) => Instr::I64x2LtS,
+                Ok(0xD9) => Instr::I64x2GtS,
+                Ok(0xDA) => Instr::I64x2LeS,
+                Ok(0xDB) => Instr::I64x2GeS,
+                Ok(0xDC) => Instr::I64x2ExtmulLowI32x4S,
+                Ok(0xDD) => Instr::I64x2ExtmulHighI32x4S,
+                Ok(0xDE) => Instr::I64x2ExtmulLowI32x4U,
+                Ok(0xDF) => Instr::I64x2ExtmulHighI32x4U,
+                Ok(0xE0) => Instr::F32x4Abs,
+                Ok(0xE1) => Instr::F32x4Neg,
+                Ok(0xE3) => Instr::F32x4Sqrt,
+                Ok(0xE4) => Instr::F32x4Add,
+                Ok(0xE5) => Instr::F32x4Sub,
+                Ok(0xE6) => Instr::F32x4Mul,
+                Ok(0xE7) => Instr::F32x4Div,
+                Ok(0xE8) => Instr::F32x4Min,
+                Ok(0xE9) => Instr::F32x4Max,
+                Ok(0xEA) => Instr::F32x4Pmin,
+                Ok(0xEB) => Instr::F32x4Pmax,
+                Ok(0xEC) => Instr::F64x2Abs,
+                Ok(0xED) => Instr::F64x2Neg,
+                Ok(0xEF) => Instr::F64x2Sqrt,
+                Ok(0xF0) => Instr::F64x2Add,
+                Ok(0xF1) => Instr::F64x2Sub,
+                Ok(0xF2) => Instr::F64x2Mul,
+                Ok(0xF3) => Instr::F64x2Div,
+                Ok(0xF4) => Instr::F64x2Min,
+                Ok(0xF5) => Instr::F64x2Max,
+                Ok(0xF6) => Instr::F64x2Pmin,
+                Ok(0xF7) => Instr::F64x2Pmax,
+                Ok(0xF8) => Instr::I32x4TruncSatF32x4S,
+                Ok(0xF9) => Instr::I32x4TruncSatF32x4U,
+                Ok(0xFA) => Instr::F32x4ConvertI32x4S,
+                Ok(0xFB) => Instr::F32x4ConvertI32x4U,
+                Ok(0xFC) => Instr::I32x4TruncSatF64x2SZero,
+                Ok(0xFD) => Instr::I32x4TruncSatF64x2UZero,
+                Ok(0xFE) => Instr::F64x2ConvertLowI32x4S,
+                Ok(0xFF) => Instr::F64x2ConvertLowI32x4U,
+                Ok(op) => {
+                    return Err(self.error_at(start, ErrorKind::IllegalOpcode(0xFD, Some(op))))
+                }
+                Err(err) => return Err(err),
+            },
+            // 0xFE Instructions
+            Some(0xFE) => match self.u32() {
+                Ok(0x00) => Instr::MemoryAtomicNotify(self.memarg()?),
+                Ok(0x01) => Instr::MemoryAtomicWait32(self.memarg()?),
+                Ok(0x02) => Instr::MemoryAtomicWait64(self.memarg()?),
+                Ok(0x03) => match self.byte() {
+                    Some(0x00) => Instr::AtomicFence,
+                    _ => return Err(self.error(ErrorKind::ZeroByteExpected)),
+                },
+                Ok(0x10) => Instr::I32AtomicLoad(self.memarg()?),
+                Ok(0x11) => Instr::I64AtomicLoad(self.memarg()?),
+                Ok(0x12) => Instr::I32AtomicLoad8U(self.memarg()?),
+                Ok(0x13) => Instr::I32AtomicLoad16U(self.memarg()?),
+                Ok(0x14) => Instr::I64AtomicLoad8U(self.memarg()?),
+                Ok(0x15) => Instr::I64AtomicLoad16U(self.memarg()?),
+                Ok(0x16) => Instr::I64AtomicLoad32U(self.memarg()?),
+                Ok(0x17) => Instr::I32AtomicStore(self.memarg()?),
+                Ok(0x18) => Instr::I64AtomicStore(self.memarg()?),
+                Ok(0x19) => Instr::I32AtomicStore8(self.memarg()?),
+                Ok(0x1A) => Instr::I32AtomicStore16(self.memarg()?),
+                Ok(0x1B) => Instr::I64AtomicStore8(self.memarg()?),
+                Ok(0x1C) => Instr::I64AtomicStore16(self.memarg()?),
+                Ok(0x1D) => Instr::I64AtomicStore32(self.memarg()?),
+                Ok(0x1E) => Instr::I32AtomicRmwAdd(self.memarg()?),
+                Ok(0x1F) => Instr::I64AtomicRmwAdd(self.memarg()?),
+                Ok(0x20) => Instr::I32AtomicRmw8AddU(self.memarg()?),
+                Ok(0x21) => Instr::I32AtomicRmw16AddU(self.memarg()?),
+                Ok(0x22) => Instr::I64AtomicRmw8AddU(self.memarg()?),
+                Ok(0x23) => Instr::I64AtomicRmw16AddU(self.memarg()?),
+                Ok(0x24) => Instr::I64AtomicRmw32AddU(self.memarg()?),
+                Ok(0x25) => Instr::I32AtomicRmwSub(self.memarg()?),
+                Ok(0x26) => Instr::I64AtomicRmwSub(self.memarg()?),
+                Ok(0x27) => Instr::I32AtomicRmw8SubU(self.memarg()?),
+                Ok(0x28) => Instr::I32AtomicRmw16SubU(self.memarg()?),
+                Ok(0x29) => Instr::I64AtomicRmw8SubU(self.memarg()?),
+                Ok(0x2A) => Instr::I64AtomicRmw16SubU(self.memarg()?),
+                Ok(0x2B) => Instr::I64AtomicRmw32SubU(self.memarg()?),
+                Ok(0x2C) => Instr::I32AtomicRmwAnd(self.memarg()?),
+                Ok(0x2D) => Instr::I64AtomicRmwAnd(self.memarg()?),
+                Ok(0x2E) => Instr::I32AtomicRmw8AndU(self.memarg()?),
+                Ok(0x2F) => Instr::I32AtomicRmw16AndU(self.memarg()?),
+                Ok(0x30) => Instr::I64AtomicRmw8AndU(self.memarg()?),
+                Ok(0x31) => Instr::I64AtomicRmw16AndU(self.memarg()?),
+                Ok(0x32) => Instr::I64AtomicRmw32AndU(self.memarg()?),
+                Ok(0x33) => Instr::I32AtomicRmwOr(self.memarg()?),
+                Ok(0x34) => Instr::I64AtomicRmwOr(self.memarg()?),
+                Ok(0x35) => Instr::I32AtomicRmw8OrU(self.memarg()?),
+                Ok(0x36) => Instr::I32AtomicRmw16OrU(self.memarg()?),
+                Ok(0x37) => Instr::I64AtomicRmw8OrU(self.memarg()?),
+                Ok(0x38) => Instr::I64AtomicRmw16OrU(self.memarg()?),
+                Ok(0x39) => Instr::I64AtomicRmw32OrU(self.memarg()?),
+                Ok(0x3A) => Instr::I32AtomicRmwXor(self.memarg()?),
+                Ok(0x3B) => Instr::I64AtomicRmwXor(self.memarg()?),
+                Ok(0x3C) => Instr::I32AtomicRmw8XorU(self.memarg()?),
+                Ok(0x3D) => Instr::I32AtomicRmw16XorU(self.memarg()?),
+                Ok(0x3E) => Instr::I64AtomicRmw8XorU(self.memarg()?),
+                Ok(0x3F) => Instr::I64AtomicRmw16XorU(self.memarg()?),
+                Ok(0x40) => Instr::I64AtomicRmw32XorU(self.memarg()?),
+                Ok(0x41) => Instr::I32AtomicRmwXchg(self.memarg()?),
+                Ok(0x42) => Instr::I64AtomicRmwXchg(self.memarg()?),
+                Ok(0x43) => Instr::I32AtomicRmw8XchgU(self.memarg()?),
+                Ok(0x44) => Instr::I32AtomicRmw16XchgU(self.memarg()?),
+                Ok(0x45) => Instr::I64AtomicRmw8XchgU(self.memarg()?),
+                Ok(0x46) => Instr::I64AtomicRmw16XchgU(self.memarg()?),
+                Ok(0x47) => Instr::I64AtomicRmw32XchgU(self.memarg()?),
+                Ok(0x48) => Instr::I32AtomicRmwCmpxchg(self.memarg()?),
+                Ok(0x49) => Instr::I64AtomicRmwCmpxchg(self.memarg()?),
+                Ok(0x4A) => Instr::I32AtomicRmw8CmpxchgU(self.memarg()?),
+                Ok(0x4B) => Instr::I32AtomicRmw16CmpxchgU(self.memarg()?),
+                Ok(0x4C) => Instr::I64AtomicRmw8CmpxchgU(self.memarg()?),
+                Ok(0x4D) => Instr::I64AtomicRmw16CmpxchgU(self.memarg()?),
+                Ok(0x4E) => Instr::I64AtomicRmw32CmpxchgU(self.memarg()?),
+                Ok(op) => {
+                    return Err(self.error_at(start, ErrorKind::IllegalOpcode(0xFE, Some(op))))
+                }
+                Err(err) => return Err(err),
             },
-            v => return Err(Error::Other(format!("not instruction {:?}", v))),
+            Some(op) => return Err(self.error_at(start, ErrorKind::IllegalOpcode(op, None))),
+            None => return Err(self.error(ErrorKind::UnexpectedEof(format!("instruction")))),
         };
-        Ok(vec![instr])
+        Ok(vec![(instr, start)])
     }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::{
-        binary::{Block, Expr, Instr},
+        binary::{Block, Expr, Instr, MemArg},
         loader::parser::Parser,
     };
 
@@ -392,4 +816,41 @@ mod tests {
             ]))
         );
     }
+
+    #[test]
+    fn memidx() {
+        // i32.load offset=8 align=4 from memory 1, memory.copy 1 0
+        let mut parser = Parser::new(&[0x28, 0x42, 0x01, 0x08, 0xFC, 0x0A, 0x01, 0x00, 0x0B]);
+        assert_eq!(
+            parser.expr(),
+            Ok(Expr(vec![
+                Instr::I32Load(MemArg {
+                    align: 2,
+                    offset: 8,
+                    memidx: 1
+                }),
+                Instr::MemoryCopy(1, 0),
+            ]))
+        );
+    }
+
+    #[test]
+    fn vector() {
+        // v128.const, i8x16.extract_lane_u 3, i32x4.add
+        let mut bytes = vec![0xFD, 0x0C];
+        bytes.extend(1u128.to_le_bytes());
+        bytes.extend([0xFD, 0x16, 0x03, 0xFD, 0xAE, 0x01, 0x0B]);
+        let mut parser = Parser::new(&bytes);
+        assert_eq!(
+            parser.expr(),
+            Ok(Expr(vec![
+                Instr::V128Const(1),
+                Instr::I8x16ExtractLaneU(3),
+                Instr::I32x4Add,
+            ]))
+        );
+        // 0x9A is not assigned
+        let mut parser = Parser::new(&[0xFD, 0x9A, 0x01, 0x0B]);
+        assert!(parser.expr().is_err());
+    }
 }
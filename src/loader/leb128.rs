@@ -1,4 +1,4 @@
-use super::error::Error;
+use super::error::ErrorKind;
 #[cfg(not(feature = "std"))]
 use crate::lib::*;
 
@@ -11,36 +11,37 @@ pub enum Type {
 }
 
 pub trait Leb128: Sized {
-    fn read_leb128(bytes: &[u8]) -> Result<(Self, usize), Error>;
+    fn read_leb128(bytes: &[u8]) -> Result<(Self, usize), ErrorKind>;
 }
 
 impl Leb128 for u64 {
-    fn read_leb128(bytes: &[u8]) -> Result<(Self, usize), Error> {
+    fn read_leb128(bytes: &[u8]) -> Result<(Self, usize), ErrorKind> {
         read_64(bytes, false)
     }
 }
 
 impl Leb128 for u32 {
-    fn read_leb128(bytes: &[u8]) -> Result<(Self, usize), Error> {
+    fn read_leb128(bytes: &[u8]) -> Result<(Self, usize), ErrorKind> {
         read_32(bytes, false)
     }
 }
 
 impl Leb128 for i64 {
-    fn read_leb128(bytes: &[u8]) -> Result<(Self, usize), Error> {
+    fn read_leb128(bytes: &[u8]) -> Result<(Self, usize), ErrorKind> {
         let (u, size) = read_64(bytes, true)?;
         Ok((u as i64, size))
     }
 }
 
 impl Leb128 for i32 {
-    fn read_leb128(bytes: &[u8]) -> Result<(Self, usize), Error> {
+    fn read_leb128(bytes: &[u8]) -> Result<(Self, usize), ErrorKind> {
         let (u, size) = read_32(bytes, true)?;
         Ok((u as i32, size))
     }
 }
 
-pub fn read_64(bytes: &[u8], signed: bool) -> Result<(u64, usize), Error> {
+pub fn read_64(bytes: &[u8], signed: bool) -> Result<(u64, usize), ErrorKind> {
+    let ty = if signed { Type::I64 } else { Type::U64 };
     let mut ret = 0;
 
     for (idx, b) in bytes.iter().copied().enumerate() {
@@ -49,9 +50,11 @@ pub fn read_64(bytes: &[u8], signed: bool) -> Result<(u64, usize), Error> {
         //   Next 1byte (idx=10) must be 0 or 1
         // signed:
         //   Next 1byte (idx=10) must be 0 (for max int) or 0b01111111 (for min int)
-        if idx > 9 || idx == 9 && (!signed && b > 1 || signed && b != 0 && b != 0x7f) {
-            let ty = if signed { Type::I64 } else { Type::U64 };
-            return Err(Error::IntOverflow(ty));
+        if idx == 9 && b & 0b1000_0000 != 0 {
+            return Err(ErrorKind::IntTooLong(ty));
+        }
+        if idx == 9 && (!signed && b > 1 || signed && b != 0 && b != 0x7f) {
+            return Err(ErrorKind::IntOverflow(ty));
         }
 
         ret |= ((b & 0b0111_1111) as u64) << (idx * 7);
@@ -66,12 +69,13 @@ pub fn read_64(bytes: &[u8], signed: bool) -> Result<(u64, usize), Error> {
         }
     }
 
-    Err(Error::UnexpectedEof(format!(
+    Err(ErrorKind::UnexpectedEof(format!(
         "part of LEB128-encoded integer"
     )))
 }
 
-pub fn read_32(bytes: &[u8], signed: bool) -> Result<(u32, usize), Error> {
+pub fn read_32(bytes: &[u8], signed: bool) -> Result<(u32, usize), ErrorKind> {
+    let ty = if signed { Type::I32 } else { Type::U32 };
     let mut ret = 0;
 
     for (idx, b) in bytes.iter().copied().enumerate() {
@@ -80,10 +84,11 @@ pub fn read_32(bytes: &[u8], signed: bool) -> Result<(u32, usize), Error> {
         //   Next byte must be <= 0b1111
         // signed:
         //   Next byte must be <= 0b0111 for positive values and >= 0b1111000 for negative values
-        if idx > 4 || idx == 4 && (!signed && b > 0b1111 || signed && b > 0b0111 && b < 0b111_1000)
-        {
-            let ty = if signed { Type::I32 } else { Type::U32 };
-            return Err(Error::IntOverflow(ty));
+        if idx == 4 && b & 0b1000_0000 != 0 {
+            return Err(ErrorKind::IntTooLong(ty));
+        }
+        if idx == 4 && (!signed && b > 0b1111 || signed && b > 0b0111 && b < 0b111_1000) {
+            return Err(ErrorKind::IntOverflow(ty));
         }
 
         ret |= ((b & 0b0111_1111) as u32) << (idx * 7);
@@ -98,7 +103,7 @@ pub fn read_32(bytes: &[u8], signed: bool) -> Result<(u32, usize), Error> {
         }
     }
 
-    Err(Error::UnexpectedEof(format!(
+    Err(ErrorKind::UnexpectedEof(format!(
         "part of LEB128-encoded integer"
     )))
 }
@@ -106,7 +111,7 @@ pub fn read_32(bytes: &[u8], signed: bool) -> Result<(u32, usize), Error> {
 #[cfg(test)]
 mod tests {
     use crate::loader::{
-        error::Error,
+        error::ErrorKind,
         leb128::{Leb128, Type},
     };
 
@@ -263,17 +268,27 @@ mod tests {
     #[test]
     fn overflow_error() {
         let b = [0xff, 0xff, 0xff, 0xff, 0x10]; // i32 max + 1
-        assert_eq!(i32::read_leb128(&b), Err(Error::IntOverflow(Type::I32)));
+        assert_eq!(i32::read_leb128(&b), Err(ErrorKind::IntOverflow(Type::I32)));
         let b = [0x80, 0x80, 0x80, 0x80, 0x77]; // i32 min - 1
-        assert_eq!(i32::read_leb128(&b), Err(Error::IntOverflow(Type::I32)));
+        assert_eq!(i32::read_leb128(&b), Err(ErrorKind::IntOverflow(Type::I32)));
         let b = [0xff, 0xff, 0xff, 0xff, 0x10]; // u32 max + 1
-        assert_eq!(u32::read_leb128(&b), Err(Error::IntOverflow(Type::U32)));
+        assert_eq!(u32::read_leb128(&b), Err(ErrorKind::IntOverflow(Type::U32)));
         let b = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x1]; // i64 max + 1
-        assert_eq!(i64::read_leb128(&b), Err(Error::IntOverflow(Type::I64)));
+        assert_eq!(i64::read_leb128(&b), Err(ErrorKind::IntOverflow(Type::I64)));
         let b = [0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x7e]; // i64 min - 1
-        assert_eq!(i64::read_leb128(&b), Err(Error::IntOverflow(Type::I64)));
+        assert_eq!(i64::read_leb128(&b), Err(ErrorKind::IntOverflow(Type::I64)));
         let b = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x2]; // u64 max + 1
-        assert_eq!(u64::read_leb128(&b), Err(Error::IntOverflow(Type::U64)));
+        assert_eq!(u64::read_leb128(&b), Err(ErrorKind::IntOverflow(Type::U64)));
+    }
+
+    #[test]
+    fn too_long_error() {
+        let b = [0x80, 0x80, 0x80, 0x80, 0x80, 0x00];
+        assert_eq!(i32::read_leb128(&b), Err(ErrorKind::IntTooLong(Type::I32)));
+        let b = [
+            0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x00,
+        ];
+        assert_eq!(u64::read_leb128(&b), Err(ErrorKind::IntTooLong(Type::U64)));
     }
 
     #[test]
@@ -281,12 +296,12 @@ mod tests {
         let b = [0xc0, 0xc4];
         assert!(matches!(
             i32::read_leb128(&b),
-            Err(Error::UnexpectedEof(..))
+            Err(ErrorKind::UnexpectedEof(..))
         ));
         let b = [0xff, 0xff, 0xff, 0xff];
         assert!(matches!(
             i64::read_leb128(&b),
-            Err(Error::UnexpectedEof(..))
+            Err(ErrorKind::UnexpectedEof(..))
         ));
     }
 }
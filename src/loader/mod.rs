@@ -2,14 +2,17 @@ pub mod error;
 pub mod instructions;
 pub mod leb128;
 pub mod module;
+pub mod names;
 pub mod parser;
 pub mod sections;
 pub mod types;
+pub mod validate;
 pub mod values;
 
 use super::binary::Module;
 use error::Error;
 use parser::Parser;
+pub use validate::{validate, ValidationError};
 
 pub fn parse(input: &[u8]) -> Result<Module, Error> {
     let mut parser = Parser::new(input);
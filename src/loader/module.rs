@@ -2,60 +2,53 @@
 use crate::lib::*;
 
 use crate::binary::*;
+#[cfg(feature = "std")]
+use crate::debug::dwarf::LineTable;
 
-use super::{error::Error, parser::Parser};
+use super::{
+    error::{Error, ErrorKind},
+    parser::Parser,
+};
 
 impl<'a> Parser<'a> {
     pub fn typeidx(&mut self) -> Result<TypeIdx, Error> {
-        Ok(self
-            .u32()
-            .map_err(|_| Error::Expected(format!("typeidx")))?)
+        self.u32()
     }
 
     pub fn funcidx(&mut self) -> Result<TypeIdx, Error> {
-        Ok(self
-            .u32()
-            .map_err(|_| Error::Expected(format!("funcidx")))?)
+        self.u32()
     }
 
     pub fn tableidx(&mut self) -> Result<TypeIdx, Error> {
-        Ok(self
-            .u32()
-            .map_err(|_| Error::Expected(format!("tableidx")))?)
+        self.u32()
     }
 
     pub fn memidx(&mut self) -> Result<TypeIdx, Error> {
-        Ok(self.u32().map_err(|_| Error::Expected(format!("memidx")))?)
+        self.u32()
     }
 
     pub fn globalidx(&mut self) -> Result<TypeIdx, Error> {
-        Ok(self
-            .u32()
-            .map_err(|_| Error::Expected(format!("globalidx")))?)
+        self.u32()
     }
 
     pub fn elemidx(&mut self) -> Result<TypeIdx, Error> {
-        Ok(self
-            .u32()
-            .map_err(|_| Error::Expected(format!("elemidx")))?)
+        self.u32()
     }
 
     pub fn dataidx(&mut self) -> Result<TypeIdx, Error> {
-        Ok(self
-            .u32()
-            .map_err(|_| Error::Expected(format!("dataidx")))?)
+        self.u32()
     }
 
     pub fn localidx(&mut self) -> Result<TypeIdx, Error> {
-        Ok(self
-            .u32()
-            .map_err(|_| Error::Expected(format!("localidx")))?)
+        self.u32()
+    }
+
+    pub fn tagidx(&mut self) -> Result<TagIdx, Error> {
+        self.u32()
     }
 
     pub fn labelidx(&mut self) -> Result<TypeIdx, Error> {
-        Ok(self
-            .u32()
-            .map_err(|_| Error::Expected(format!("labelidx")))?)
+        self.u32()
     }
 
     pub fn custom_sections(&mut self) -> Vec<Custom> {
@@ -70,190 +63,112 @@ impl<'a> Parser<'a> {
     }
 
     pub fn magic(&mut self) -> Result<(), Error> {
-        self.target(b"\0asm").ok_or(Error::InvalidMagicNumber)
+        self.target(b"\0asm")
+            .ok_or_else(|| self.error(ErrorKind::InvalidMagicNumber))
     }
 
     pub fn version(&mut self) -> Result<u8, Error> {
         self.target(&[0x01, 0x00, 0x00, 0x00])
             .map(|_| 1)
-            .ok_or(Error::InvalidVersion)
+            .ok_or_else(|| self.error(ErrorKind::InvalidVersion))
     }
 
     pub fn module(&mut self) -> Result<Module, Error> {
-        // magic
-        self.magic()?;
-        // version
-        let version = self.version()?;
-        self.ignore_custom_sections();
-
-        // types
-        let types = self.many0(Self::typesec).into_iter().flatten().collect();
-        self.ignore_custom_sections();
-
-        // imports
-        let imports = self.many0(Self::importsec).into_iter().flatten().collect();
-        self.ignore_custom_sections();
-
-        // funcs 1
-        let funcs = self
-            .many0(Self::funcsec)
-            .into_iter()
-            .flatten()
-            .collect::<Vec<_>>();
-        self.ignore_custom_sections();
-
-        // tables
-        let tables = self.many0(Self::tablesec).into_iter().flatten().collect();
-        self.ignore_custom_sections();
-
-        // mems
-        let mems = self.many0(Self::memsec).into_iter().flatten().collect();
-        self.ignore_custom_sections();
-
-        // globals
-        let globals = self.many0(Self::globalsec).into_iter().flatten().collect();
-        self.ignore_custom_sections();
-
-        // exports
-        let exports = self.many0(Self::exportsec).into_iter().flatten().collect();
-        self.ignore_custom_sections();
-
-        // start
-        let start = self.startsec()?.map(|s| s.value);
-        self.ignore_custom_sections();
-
-        // elems
-        let elems = self.many0(Self::elemsec).into_iter().flatten().collect();
-        self.ignore_custom_sections();
-
-        // datacount
-        let data_count = self.datacountsec()?.map(|s| s.value);
-        self.ignore_custom_sections();
-
-        // funcs 2
-        let codes = self
-            .many0(Self::codesec)
-            .into_iter()
-            .flatten()
-            .collect::<Vec<_>>();
-        self.ignore_custom_sections();
-
-        // funcs validation
-        if funcs.len() != codes.len() {
-            return Err(Error::Other(format!("functypes length != codes length")));
-        }
-
-        let funcs = funcs
-            .into_iter()
-            .zip(codes.into_iter())
-            .map(|(typeidx, code)| Func {
-                typeidx,
-                locals: code
-                    .func
-                    .locals
-                    .into_iter()
-                    .map(|local| vec![local.type_; local.n as usize])
-                    .flatten()
-                    .collect(),
-                body: code.func.body,
-            })
-            .collect();
-
-        // data
-        let data = self
-            .many0(Self::datasec)
-            .into_iter()
-            .flatten()
-            .collect::<Vec<_>>();
-        self.ignore_custom_sections();
-
-        // data validation
-        if let Some(count) = data_count {
-            if count as usize != data.len() {
-                return Err(Error::Other(format!("datacount != data length")));
-            }
-        }
-
-        Ok(Module {
-            version,
-            types,
-            funcs,
-            tables,
-            mems,
-            globals,
-            elems,
-            datas: data,
-            start,
-            imports,
-            exports,
-        })
+        self.sections(&mut CustomSecList::default())
     }
 
     pub fn module_with_customs(&mut self) -> Result<(Module, CustomSecList), Error> {
+        let mut customs = CustomSecList::default();
+        let module = self.sections(&mut customs)?;
+        Ok((module, customs))
+    }
+
+    /// Parses the sections of a module in their required order, collecting
+    /// custom sections into `customs` by the section they follow.
+    fn sections(&mut self, customs: &mut CustomSecList) -> Result<Module, Error> {
+        // Ids of the non-custom sections in the order they must appear.
+        const ORDER: [u8; 13] = [1, 2, 3, 4, 5, 13, 6, 7, 8, 9, 12, 10, 11];
+
         // magic
         self.magic()?;
         // version
         let version = self.version()?;
-        let sec1 = self.custom_sections();
-
-        // types
-        let types = self.many0(Self::typesec).into_iter().flatten().collect();
-        let sec2 = self.custom_sections();
-
-        // imports
-        let imports = self.many0(Self::importsec).into_iter().flatten().collect();
-        let sec3 = self.custom_sections();
-
-        // funcs 1
-        let funcs = self
-            .many0(Self::funcsec)
-            .into_iter()
-            .flatten()
-            .collect::<Vec<_>>();
-        let sec4 = self.custom_sections();
-
-        // tables
-        let tables = self.many0(Self::tablesec).into_iter().flatten().collect();
-        let sec5 = self.custom_sections();
-
-        // mems
-        let mems = self.many0(Self::memsec).into_iter().flatten().collect();
-        let sec6 = self.custom_sections();
-
-        // globals
-        let globals = self.many0(Self::globalsec).into_iter().flatten().collect();
-        let sec7 = self.custom_sections();
-
-        // exports
-        let exports = self.many0(Self::exportsec).into_iter().flatten().collect();
-        let sec8 = self.custom_sections();
-
-        // start
-        let start = self.startsec()?.map(|s| s.value);
-        let sec9 = self.custom_sections();
 
-        // elems
-        let elems = self.many0(Self::elemsec).into_iter().flatten().collect();
-        let sec10 = self.custom_sections();
-
-        // datacount
-        let data_count = self.datacountsec()?.map(|s| s.value);
-        let sec11 = self.custom_sections();
+        let mut module = Module {
+            version,
+            ..Module::default()
+        };
+        let mut funcs = vec![];
+        let mut codes = vec![];
+        // Position in `ORDER` after the last section, 0 before the first one.
+        let mut last = 0;
+        while let Some(id) = self.peek() {
+            if id == 0 {
+                let custom = self.custom_section()?.value;
+                // Custom sections never make a module malformed.
+                if custom.name == "name" {
+                    module.names = Parser::new(&custom.bytes).names().unwrap_or_default();
+                }
+                match last {
+                    0 => customs.sec1.push(custom),
+                    1 => customs.sec2.push(custom),
+                    2 => customs.sec3.push(custom),
+                    3 => customs.sec4.push(custom),
+                    4 => customs.sec5.push(custom),
+                    5 | 6 => customs.sec6.push(custom),
+                    7 => customs.sec7.push(custom),
+                    8 => customs.sec8.push(custom),
+                    9 => customs.sec9.push(custom),
+                    10 => customs.sec10.push(custom),
+                    11 => customs.sec11.push(custom),
+                    12 => customs.sec12.push(custom),
+                    _ => customs.sec13.push(custom),
+                }
+                continue;
+            }
+            let pos = match ORDER.iter().position(|&x| x == id) {
+                Some(pos) => pos + 1,
+                None => return Err(self.error(ErrorKind::InvalidSectionHeader)),
+            };
+            if pos <= last {
+                return Err(self.error(ErrorKind::UnexpectedContent));
+            }
+            last = pos;
+            match id {
+                1 => module.types = self.typesec()?.value,
+                2 => {
+                    module.imports = self.importsec()?.value;
+                    self.imported_funcs = module
+                        .imports
+                        .iter()
+                        .filter(|import| matches!(import.desc, ImportDesc::Func(_)))
+                        .count() as u32;
+                }
+                3 => funcs = self.funcsec()?.value,
+                4 => module.tables = self.tablesec()?.value,
+                5 => module.mems = self.memsec()?.value,
+                13 => module.tags = self.tagsec()?.value,
+                6 => module.globals = self.globalsec()?.value,
+                7 => module.exports = self.exportsec()?.value,
+                8 => module.start = self.startsec()?.map(|s| s.value),
+                9 => module.elems = self.elemsec()?.value,
+                12 => module.data_count = self.datacountsec()?.map(|s| s.value),
+                10 => codes = self.codesec()?.value,
+                _ => module.datas = self.datasec()?.value,
+            }
+        }
 
-        // funcs 2
-        let codes = self
-            .many0(Self::codesec)
-            .into_iter()
-            .flatten()
-            .collect::<Vec<_>>();
-        let sec12 = self.custom_sections();
+        #[cfg(feature = "std")]
+        {
+            module.lines = LineTable::from_customs(customs.iter()).and_then(Result::ok);
+        }
 
         // funcs validation
         if funcs.len() != codes.len() {
-            return Err(Error::Other(format!("functypes length != codes length")));
+            return Err(self.error(ErrorKind::FuncCodeMismatch));
         }
 
-        let funcs = funcs
+        module.funcs = funcs
             .into_iter()
             .zip(codes.into_iter())
             .map(|(typeidx, code)| Func {
@@ -266,60 +181,28 @@ impl<'a> Parser<'a> {
                     .flatten()
                     .collect(),
                 body: code.func.body,
+                offsets: code.func.offsets,
             })
             .collect();
 
-        // data
-        let data = self
-            .many0(Self::datasec)
-            .into_iter()
-            .flatten()
-            .collect::<Vec<_>>();
-        let sec13 = self.custom_sections();
-
         // data validation
-        if let Some(count) = data_count {
-            if count as usize != data.len() {
-                return Err(Error::Other(format!("datacount != data length")));
+        if let Some(count) = module.data_count {
+            if count as usize != module.datas.len() {
+                return Err(self.error(ErrorKind::DataCountMismatch));
             }
         }
 
-        Ok((
-            Module {
-                version,
-                types,
-                funcs,
-                tables,
-                mems,
-                globals,
-                elems,
-                datas: data,
-                start,
-                imports,
-                exports,
-            },
-            CustomSecList {
-                sec1,
-                sec2,
-                sec3,
-                sec4,
-                sec5,
-                sec6,
-                sec7,
-                sec8,
-                sec9,
-                sec10,
-                sec11,
-                sec12,
-                sec13,
-            },
-        ))
+        Ok(module)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::loader::{module::Module, parser::Parser};
+    use crate::loader::{
+        error::{Error, ErrorKind},
+        module::Module,
+        parser::Parser,
+    };
     use crate::tests::wat2wasm;
 
     #[test]
@@ -345,6 +228,28 @@ mod tests {
         assert_eq!(parser.rest(), &[0x73, 0x6D, 0x61, 0x99]);
     }
 
+    /// Malformed custom sections are ignored.
+    #[cfg(feature = "std")]
+    #[test]
+    fn malformed_debug_line() {
+        let mut unit = vec![4, 0, 20, 0, 0, 0];
+        // line_range of 0 and no directories or files
+        unit.extend([
+            1, 1, 1, 0xfb, 0, 13, 0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1, 0, 0,
+        ]);
+        // special opcode
+        unit.push(13);
+        let mut custom = vec![11];
+        custom.extend(b".debug_line");
+        custom.extend((unit.len() as u32).to_le_bytes());
+        custom.extend(unit);
+        let mut wasm = b"\0asm\x01\0\0\0\0".to_vec();
+        wasm.push(custom.len() as u8);
+        wasm.extend(custom);
+        let module = crate::loader::parse(&wasm).unwrap();
+        assert!(module.lines.is_none());
+    }
+
     #[test]
     fn integer_ok() {
         let mut parser = Parser::new(&[0xc0, 0xbb, 0x78, 0x12, 0x34, 0xff]);
@@ -455,4 +360,57 @@ mod tests {
                 && types.len() == 1
         ));
     }
+
+    #[test]
+    fn malformed() {
+        const HEADER: [u8; 8] = [0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00];
+        let parse = |bytes: &[u8]| Parser::new(&[&HEADER, bytes].concat()).module();
+
+        // unknown section id
+        assert_eq!(
+            parse(&[0x0e, 0x00]),
+            Err(Error {
+                kind: ErrorKind::InvalidSectionHeader,
+                offset: 8,
+                section: None,
+                func: None,
+            })
+        );
+
+        // type section declaring more bytes than its content
+        let err = parse(&[0x01, 0x05, 0x01, 0x60, 0x00, 0x00]).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::SectionSizeMismatch);
+        assert_eq!(err.section, Some(1));
+
+        // function section after the code section
+        let err = parse(&[0x0a, 0x01, 0x00, 0x03, 0x01, 0x00]).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::UnexpectedContent);
+        assert_eq!(err.offset, 11);
+
+        // unknown opcode in the body of the second function
+        let err = parse(&[
+            0x01, 0x04, 0x01, 0x60, 0x00, 0x00, // type section
+            0x03, 0x03, 0x02, 0x00, 0x00, // function section
+            0x0a, 0x08, 0x02, 0x02, 0x00, 0x0b, 0x03, 0x00, 0xff, 0x0b, // code section
+        ])
+        .unwrap_err();
+        assert_eq!(
+            err,
+            Error {
+                kind: ErrorKind::IllegalOpcode(0xff, None),
+                offset: 27,
+                section: Some(10),
+                func: Some(1),
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "illegal opcode ff (at offset 0x1b, section 10, function 1)"
+        );
+
+        assert_eq!(
+            parse(&[0x03, 0x02, 0x01, 0x00]).unwrap_err().kind,
+            ErrorKind::FuncCodeMismatch
+        );
+    }
 }
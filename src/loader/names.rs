@@ -0,0 +1,131 @@
+#[cfg(not(feature = "std"))]
+use crate::lib::*;
+
+use crate::binary::{IndirectNameMap, NameMap, Names};
+
+use super::{
+    error::{Error, ErrorKind},
+    parser::Parser,
+};
+
+impl<'a> Parser<'a> {
+    /// Contents of the `name` custom section. Subsections must appear at
+    /// most once and in order of their ids, unknown ones are skipped.
+    pub fn names(&mut self) -> Result<Names, Error> {
+        let mut names = Names::default();
+        let mut last = None;
+        while let Some(id) = self.peek() {
+            if last.map_or(false, |last| id <= last) {
+                return Err(self.error(ErrorKind::UnexpectedContent));
+            }
+            self.skip(1);
+            last = Some(id);
+            let size = self.u32()? as usize;
+            if size > self.rest().len() {
+                return Err(self.error(ErrorKind::UnexpectedEof("name subsection".into())));
+            }
+            let start = self.offset();
+            match id {
+                0 => names.module = Some(self.name()?),
+                1 => names.funcs = self.namemap()?,
+                2 => names.locals = self.indirectnamemap()?,
+                3 => names.labels = self.indirectnamemap()?,
+                4 => names.types = self.namemap()?,
+                5 => names.tables = self.namemap()?,
+                6 => names.mems = self.namemap()?,
+                7 => names.globals = self.namemap()?,
+                8 => names.elems = self.namemap()?,
+                9 => names.datas = self.namemap()?,
+                10 => names.fields = self.indirectnamemap()?,
+                11 => names.tags = self.namemap()?,
+                _ => self.skip(size),
+            }
+            if self.offset() - start != size {
+                return Err(self.error(ErrorKind::SectionSizeMismatch));
+            }
+        }
+        Ok(names)
+    }
+
+    /// Name map whose indices must be strictly increasing.
+    pub fn namemap(&mut self) -> Result<NameMap, Error> {
+        self.sorted(|p| Ok((p.u32()?, p.name()?)))
+    }
+
+    pub fn indirectnamemap(&mut self) -> Result<IndirectNameMap, Error> {
+        self.sorted(|p| Ok((p.u32()?, p.namemap()?)))
+    }
+
+    fn sorted<T, F>(&mut self, f: F) -> Result<Vec<(u32, T)>, Error>
+    where
+        F: FnMut(&mut Self) -> Result<(u32, T), Error>,
+    {
+        let start = self.offset();
+        let map = self.vec(f)?;
+        if map.windows(2).any(|w| w[0].0 >= w[1].0) {
+            return Err(self.error_at(start, ErrorKind::Expected("name map order".into())));
+        }
+        Ok(map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::loader::error::ErrorKind;
+    use crate::loader::parser::Parser;
+    use crate::tests::wat2wasm;
+
+    #[test]
+    fn names() {
+        let wasm = wat2wasm(
+            r#"(module $m
+                  (type $unary (func (param i32)))
+                  (import "env" "log" (func $log (type $unary)))
+                  (table $tab 1 funcref)
+                  (memory $mem 1)
+                  (global $g i32 (i32.const 0))
+                  (elem $e (table $tab) (i32.const 0) func $log)
+                  (data $d (memory $mem) (i32.const 0) "")
+                  (func)
+                  (func $main (param $x i32) (local $y i32)
+                      (block $exit (loop $again (br $exit)))))"#,
+        )
+        .unwrap();
+        let names = Parser::new(&wasm).module().unwrap().names;
+        assert_eq!(names.module.as_deref(), Some("m"));
+        assert_eq!(names.func(0), Some("log"));
+        assert_eq!(names.func(1), None);
+        assert_eq!(names.func(2), Some("main"));
+        assert_eq!(names.local(2, 0), Some("x"));
+        assert_eq!(names.local(2, 1), Some("y"));
+        assert_eq!(names.local(1, 0), None);
+        assert_eq!(names.label(2, 0), Some("exit"));
+        assert_eq!(names.label(2, 1), Some("again"));
+        assert_eq!(names.type_(0), Some("unary"));
+        assert_eq!(names.table(0), Some("tab"));
+        assert_eq!(names.mem(0), Some("mem"));
+        assert_eq!(names.global(0), Some("g"));
+        assert_eq!(names.elem(0), Some("e"));
+        assert_eq!(names.data(0), Some("d"));
+    }
+
+    #[test]
+    fn malformed() {
+        // function names out of order
+        let bytes = [0x01, 0x07, 0x02, 0x01, 0x01, b'b', 0x00, 0x01, b'a'];
+        assert_eq!(
+            Parser::new(&bytes).names().unwrap_err().kind,
+            ErrorKind::Expected("name map order".into())
+        );
+        // module name after function names
+        let bytes = [0x01, 0x01, 0x00, 0x00, 0x02, 0x01, b'm'];
+        assert_eq!(
+            Parser::new(&bytes).names().unwrap_err().kind,
+            ErrorKind::UnexpectedContent
+        );
+        // unknown subsections are skipped
+        let bytes = [0x00, 0x02, 0x01, b'm', 0x7f, 0x02, 0xff, 0xff];
+        let names = Parser::new(&bytes).names().unwrap();
+        assert_eq!(names.module.as_deref(), Some("m"));
+    }
+}
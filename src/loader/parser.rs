@@ -1,11 +1,18 @@
 #[cfg(not(feature = "std"))]
 use crate::lib::*;
 
-use super::error::Error;
+use super::error::{Error, ErrorKind};
 
 pub struct Parser<'a> {
     bytes: &'a [u8],
     cursor: usize,
+    /// Id of the section being parsed, recorded in errors.
+    pub section: Option<u8>,
+    /// Index of the function whose body is being parsed, recorded in errors.
+    pub func: Option<u32>,
+    /// Number of imported functions, which precede the functions of the
+    /// code section in the function index space.
+    pub imported_funcs: u32,
 }
 
 pub trait Target {
@@ -45,7 +52,33 @@ impl Target for u8 {
 
 impl<'a> Parser<'a> {
     pub fn new(bytes: &'a [u8]) -> Self {
-        Self { bytes, cursor: 0 }
+        Self {
+            bytes,
+            cursor: 0,
+            section: None,
+            func: None,
+            imported_funcs: 0,
+        }
+    }
+
+    /// Byte offset of the cursor from the start of the module.
+    pub fn offset(&self) -> usize {
+        self.cursor
+    }
+
+    /// An error of `kind` at the current position.
+    pub fn error(&self, kind: ErrorKind) -> Error {
+        self.error_at(self.cursor, kind)
+    }
+
+    /// An error of `kind` at `offset` in the current section and function.
+    pub fn error_at(&self, offset: usize, kind: ErrorKind) -> Error {
+        Error {
+            kind,
+            offset,
+            section: self.section,
+            func: self.func,
+        }
     }
 
     pub fn next(&mut self) -> Option<u8> {
@@ -104,12 +137,18 @@ impl<'a> Parser<'a> {
                 Some(_) => {
                     vec.push(f(self)?);
                 }
-                None => return Err(Error::Expected(format!("next element or terminator"))),
+                None => {
+                    return Err(self.error(ErrorKind::UnexpectedEof(format!(
+                        "next element or terminator"
+                    ))))
+                }
             }
         }
         Ok(vec)
     }
 
+    /// Tries `a`, then `b` from the same position. If both fail, returns the
+    /// error of the one which got further.
     pub fn or<A, B, T>(&mut self, mut a: A, mut b: B) -> Result<T, Error>
     where
         A: FnMut(&mut Self) -> Result<T, Error>,
@@ -122,7 +161,8 @@ impl<'a> Parser<'a> {
                 self.cursor = cursor;
                 match b(self) {
                     Ok(ok) => Ok(ok),
-                    Err(err2) => Err(Error::Or(Box::new(err1), Box::new(err2))),
+                    Err(err2) if err1.offset > err2.offset => Err(err1),
+                    Err(err2) => Err(err2),
                 }
             }
         }
@@ -145,7 +185,7 @@ impl<'a> Parser<'a> {
 
 #[cfg(test)]
 mod tests {
-    use crate::loader::{error::Error, parser::Parser};
+    use crate::loader::{error::ErrorKind, parser::Parser};
 
     #[test]
     fn test_target() {
@@ -170,7 +210,9 @@ mod tests {
         let mut parser = Parser::new(b"abcabcabce");
         assert_eq!(
             parser.take_while0(
-                |p| p.target(b"abc").ok_or(Error::Expected(format!("abc"))),
+                |p| p
+                    .target(b"abc")
+                    .ok_or(p.error(ErrorKind::Expected(format!("abc")))),
                 |b| b == b'e'
             ),
             Ok(vec![(), (), ()])
@@ -182,16 +224,24 @@ mod tests {
         let mut parser = Parser::new(b"abcdef");
         assert_eq!(
             parser.or(
-                |p| p.target(b"def").ok_or(Error::Expected(format!("def"))),
-                |p| p.target(b"abc").ok_or(Error::Expected(format!("abc")))
+                |p| p
+                    .target(b"def")
+                    .ok_or(p.error(ErrorKind::Expected(format!("def")))),
+                |p| p
+                    .target(b"abc")
+                    .ok_or(p.error(ErrorKind::Expected(format!("abc"))))
             ),
             Ok(())
         );
         assert_eq!(parser.rest(), b"def");
         assert_eq!(
             parser.or(
-                |p| p.target(b"abc").ok_or(Error::Expected(format!("abc"))),
-                |p| p.target(b"def").ok_or(Error::Expected(format!("def"))),
+                |p| p
+                    .target(b"abc")
+                    .ok_or(p.error(ErrorKind::Expected(format!("abc")))),
+                |p| p
+                    .target(b"def")
+                    .ok_or(p.error(ErrorKind::Expected(format!("def")))),
             ),
             Ok(())
         );
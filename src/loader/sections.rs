@@ -3,27 +3,41 @@ use crate::lib::*;
 
 use crate::binary::*;
 
-use super::{error::Error, parser::Parser};
+use super::{
+    error::{Error, ErrorKind},
+    parser::Parser,
+};
 
 impl<'a> Parser<'a> {
+    /// Parses the contents of section `id` with `f` and checks that they span
+    /// the size declared in the section header.
+    pub fn section<T, F>(&mut self, id: u8, f: F) -> Result<Section<T>, Error>
+    where
+        F: FnOnce(&mut Self) -> Result<T, Error>,
+    {
+        self.target(id)
+            .ok_or_else(|| self.error(ErrorKind::InvalidSectionHeader))?;
+        self.section = Some(id);
+        let size = self.u32()?;
+        let start = self.offset();
+        let value = f(self)?;
+        if self.offset() - start != size as usize {
+            return Err(self.error(ErrorKind::SectionSizeMismatch));
+        }
+        self.section = None;
+        Ok(Section { size, value })
+    }
+
     /// 1. Type Section
     pub fn typesec(&mut self) -> Result<TypeSec, Error> {
-        self.target(1)
-            .ok_or(Error::Expected(format!("section id: 1")))?;
-        Ok(Section {
-            size: self.u32()?,
-            value: self.vec(Self::functype)?,
+        self.section(1, |p| {
+            Ok(p.vec(Self::rectype)?.into_iter().flatten().collect())
         })
     }
 
     /// 2. Import Section
     pub fn importsec(&mut self) -> Result<ImportSec, Error> {
-        self.target(2)
-            .ok_or(Error::Expected(format!("section id: 2")))?;
-        Ok(Section {
-            size: self.u32()?,
-            value: self.vec(Self::import)?,
-        })
+        self.section(2, |p| p.vec(Self::import))
     }
 
     pub fn import(&mut self) -> Result<Import, Error> {
@@ -40,64 +54,56 @@ impl<'a> Parser<'a> {
             Some(0x01) => Ok(ImportDesc::Table(self.table()?)),
             Some(0x02) => Ok(ImportDesc::Mem(self.memory()?)),
             Some(0x03) => Ok(ImportDesc::Global(self.globaltype()?)),
-            Some(_) => Err(Error::Expected(format!("importdesc"))),
-            None => Err(Error::UnexpectedEof(format!("importdesc"))),
+            Some(0x04) => Ok(ImportDesc::Tag(self.tag()?)),
+            Some(_) => Err(self.error(ErrorKind::Expected(format!("import kind")))),
+            None => Err(self.error(ErrorKind::UnexpectedEof(format!("import kind")))),
         }
     }
 
     /// 3. Function Section
     pub fn funcsec(&mut self) -> Result<FuncSec, Error> {
-        self.target(3)
-            .ok_or(Error::Expected(format!("section id: 3")))?;
-        Ok(Section {
-            size: self.u32()?,
-            value: self.vec(Self::u32)?,
-        })
+        self.section(3, |p| p.vec(Self::u32))
     }
 
     /// 4. Table Section
     pub fn tablesec(&mut self) -> Result<TableSec, Error> {
-        self.target(4)
-            .ok_or(Error::Expected(format!("section id: 4")))?;
-        Ok(Section {
-            size: self.u32()?,
-            value: self.vec(Self::table)?,
-        })
+        self.section(4, |p| p.vec(Self::table))
     }
 
     /// 5. Memory Section
     pub fn memsec(&mut self) -> Result<MemSec, Error> {
-        self.target(5)
-            .ok_or(Error::Expected(format!("section id: 5")))?;
-        Ok(Section {
-            size: self.u32()?,
-            value: self.vec(Self::memory)?,
+        self.section(5, |p| p.vec(Self::memory))
+    }
+
+    /// 13. Tag Section
+    pub fn tagsec(&mut self) -> Result<TagSec, Error> {
+        self.section(13, |p| p.vec(Self::tag))
+    }
+
+    pub fn tag(&mut self) -> Result<Tag, Error> {
+        // The only attribute is 0, exception.
+        self.target(0x00)
+            .ok_or_else(|| self.error(ErrorKind::Expected(format!("tag attribute"))))?;
+        Ok(Tag {
+            typeidx: self.typeidx()?,
         })
     }
 
     /// 6. Global Section
     pub fn globalsec(&mut self) -> Result<GlobalSec, Error> {
-        self.target(6)
-            .ok_or(Error::Expected(format!("section id: 6")))?;
-        Ok(Section {
-            size: self.u32()?,
-            value: self.vec(|p| {
+        self.section(6, |p| {
+            p.vec(|p| {
                 Ok(Global {
                     type_: p.globaltype()?,
                     value: p.expr()?,
                 })
-            })?,
+            })
         })
     }
 
     /// 7. Export Section
     pub fn exportsec(&mut self) -> Result<ExportSec, Error> {
-        self.target(7)
-            .ok_or(Error::Expected(format!("section id: 7")))?;
-        Ok(Section {
-            size: self.u32()?,
-            value: self.vec(Self::export)?,
-        })
+        self.section(7, |p| p.vec(Self::export))
     }
 
     pub fn export(&mut self) -> Result<Export, Error> {
@@ -113,18 +119,16 @@ impl<'a> Parser<'a> {
             Some(0x01) => Ok(ExportDesc::Table(self.tableidx()?)),
             Some(0x02) => Ok(ExportDesc::Mem(self.memidx()?)),
             Some(0x03) => Ok(ExportDesc::Global(self.globalidx()?)),
-            _ => unreachable!(),
+            Some(0x04) => Ok(ExportDesc::Tag(self.tagidx()?)),
+            Some(_) => Err(self.error(ErrorKind::Expected(format!("export kind")))),
+            None => Err(self.error(ErrorKind::UnexpectedEof(format!("export kind")))),
         }
     }
 
     /// 8. Start Section
     pub fn startsec(&mut self) -> Result<Option<StartSec>, Error> {
         if let Some(8) = self.peek() {
-            self.next();
-            Ok(Some(Section {
-                size: self.u32()?,
-                value: self.funcidx()?,
-            }))
+            self.section(8, Self::funcidx).map(Some)
         } else {
             Ok(None)
         }
@@ -132,12 +136,7 @@ impl<'a> Parser<'a> {
 
     /// 9. Element Section
     pub fn elemsec(&mut self) -> Result<ElemSec, Error> {
-        self.target(9)
-            .ok_or(Error::Expected(format!("section id: 9")))?;
-        Ok(Section {
-            size: self.u32()?,
-            value: self.vec(Self::elem)?,
-        })
+        self.section(9, |p| p.vec(Self::elem))
     }
 
     pub fn elem(&mut self) -> Result<Elem, Error> {
@@ -230,14 +229,15 @@ impl<'a> Parser<'a> {
                     mode: ElemMode::Declarative,
                 })
             }
-            _ => unreachable!(),
+            Some(_) => Err(self.error(ErrorKind::Expected(format!("elements segment kind")))),
+            None => Err(self.error(ErrorKind::UnexpectedEof(format!("elements segment kind")))),
         }
     }
 
     pub fn elemkind(&mut self) -> Result<RefType, Error> {
         Ok(self
             .target(0x00)
-            .ok_or(Error::Expected(format!("0x00")))
+            .ok_or_else(|| self.error(ErrorKind::ZeroByteExpected))
             .map(|_| RefType::FuncRef)?)
     }
 
@@ -251,11 +251,20 @@ impl<'a> Parser<'a> {
 
     /// 10. Code Section
     pub fn codesec(&mut self) -> Result<CodeSec, Error> {
-        self.target(10)
-            .ok_or(Error::Expected(format!("section id: 10")))?;
-        Ok(Section {
-            size: self.u32()?,
-            value: self.vec(Self::code)?,
+        self.section(10, |p| {
+            let start = p.offset() as u32;
+            let len = p.u32()?;
+            let mut codes = Vec::new();
+            for i in 0..len {
+                p.func = Some(p.imported_funcs + i);
+                let mut code = p.code()?;
+                for offset in code.func.offsets.iter_mut() {
+                    *offset -= start;
+                }
+                codes.push(code);
+            }
+            p.func = None;
+            Ok(codes)
         })
     }
 
@@ -266,10 +275,20 @@ impl<'a> Parser<'a> {
         })
     }
 
+    /// Function body, whose `offsets` are relative to the start of the
+    /// module.
     pub fn func0(&mut self) -> Result<Func0, Error> {
+        let locals = self.vec(Self::local)?;
+        let (body, mut offsets): (Vec<_>, Vec<_>) = self
+            .instrs_until(0x0B)?
+            .into_iter()
+            .map(|(instr, offset)| (instr, offset as u32))
+            .unzip();
+        offsets.push(self.offset() as u32 - 1);
         Ok(Func0 {
-            locals: self.vec(Self::local)?,
-            body: self.expr()?,
+            locals,
+            body: Expr(body),
+            offsets,
         })
     }
 
@@ -282,26 +301,27 @@ impl<'a> Parser<'a> {
 
     /// 11. Data Section
     pub fn datasec(&mut self) -> Result<DataSec, Error> {
-        self.target(11)
-            .ok_or(Error::Expected(format!("section id: 11")))?;
-        Ok(Section {
-            size: self.u32()?,
-            value: self.vec(Self::data)?,
-        })
+        self.section(11, |p| p.vec(Self::data))
     }
 
     pub fn data(&mut self) -> Result<Data, Error> {
         match self.byte() {
             Some(0) => {
                 let offset = self.expr()?;
-                let init = self.vec(|p| p.byte().ok_or(Error::Expected(format!("byte"))))?;
+                let init = self.vec(|p| {
+                    p.byte()
+                        .ok_or_else(|| p.error(ErrorKind::UnexpectedEof(format!("byte"))))
+                })?;
                 Ok(Data {
                     init,
                     mode: DataMode::Active { memidx: 0, offset },
                 })
             }
             Some(1) => {
-                let init = self.vec(|p| p.byte().ok_or(Error::Expected(format!("byte"))))?;
+                let init = self.vec(|p| {
+                    p.byte()
+                        .ok_or_else(|| p.error(ErrorKind::UnexpectedEof(format!("byte"))))
+                })?;
                 Ok(Data {
                     init,
                     mode: DataMode::Passive,
@@ -310,7 +330,10 @@ impl<'a> Parser<'a> {
             Some(2) => {
                 let memory = self.memidx()?;
                 let offset = self.expr()?;
-                let init = self.vec(|p| p.byte().ok_or(Error::Expected(format!("byte"))))?;
+                let init = self.vec(|p| {
+                    p.byte()
+                        .ok_or_else(|| p.error(ErrorKind::UnexpectedEof(format!("byte"))))
+                })?;
                 Ok(Data {
                     init,
                     mode: DataMode::Active {
@@ -319,18 +342,15 @@ impl<'a> Parser<'a> {
                     },
                 })
             }
-            _ => unreachable!(),
+            Some(_) => Err(self.error(ErrorKind::Expected(format!("data segment kind")))),
+            None => Err(self.error(ErrorKind::UnexpectedEof(format!("data segment kind")))),
         }
     }
 
     /// 12. Data Count Section
     pub fn datacountsec(&mut self) -> Result<Option<DataCountSec>, Error> {
         if let Some(12) = self.peek() {
-            self.next();
-            Ok(Some(Section {
-                size: self.u32()?,
-                value: self.u32()?,
-            }))
+            self.section(12, Self::u32).map(Some)
         } else {
             Ok(None)
         }
@@ -339,16 +359,20 @@ impl<'a> Parser<'a> {
     /// 0. Custom Section
     pub fn custom_section(&mut self) -> Result<CustomSec, Error> {
         self.target(0)
-            .ok_or(Error::Expected(format!("section id: 0")))?;
-        let (size, bytes) = self.u32_bytes()?;
+            .ok_or_else(|| self.error(ErrorKind::InvalidSectionHeader))?;
+        self.section = Some(0);
+        let size = self.u32()?;
+        let end = self.offset() + size as usize;
         let name = self.name()?;
-        let name_len = name.len();
+        if self.offset() > end || end > self.offset() + self.rest().len() {
+            return Err(self.error(ErrorKind::UnexpectedEof(format!("custom section"))));
+        }
+        let bytes = self.rest()[..end - self.offset()].into();
+        self.skip(end - self.offset());
+        self.section = None;
         Ok(Section {
             size,
-            value: Custom {
-                name,
-                bytes: (&self.rest()[..(size as usize - name_len - bytes)]).into(),
-            },
+            value: Custom { name, bytes },
         })
     }
 }
@@ -384,7 +408,8 @@ mod tests {
                 value: vec![FuncType(
                     ResultType(vec![ValType::I32, ValType::I32]),
                     ResultType(vec![ValType::I32])
-                )]
+                )
+                .into()]
             })
         );
     }
@@ -482,7 +507,11 @@ mod tests {
             parser.memsec(),
             Ok(Section {
                 size: 4,
-                value: vec![Memory(Limits::MinMax(1, 2))]
+                value: vec![Memory {
+                    idxtype: IdxType::I32,
+                    limits: Limits::MinMax(1, 2),
+                    shared: false,
+                }]
             })
         );
     }
@@ -649,6 +678,7 @@ mod tests {
                             Instr::LocalGet(0,),
                             Instr::I32Add,
                         ],),
+                        offsets: vec![5, 7, 9, 10],
                     },
                 },],
             },)
@@ -1,33 +1,51 @@
-use super::{error::Error, parser::Parser};
+use super::{
+    error::{Error, ErrorKind},
+    parser::Parser,
+};
 use crate::binary::*;
 #[cfg(not(feature = "std"))]
 use crate::lib::*;
 
 impl<'a> Parser<'a> {
+    pub fn heaptype(&mut self) -> Result<HeapType, Error> {
+        match self.peek() {
+            Some(byte) if HeapType::from_byte(byte).is_some() => {
+                self.next();
+                Ok(HeapType::from_byte(byte).unwrap())
+            }
+            // A type index is encoded as a non-negative 33 bit signed integer.
+            Some(_) => match self.i64()? {
+                idx if (0..=u32::MAX as i64).contains(&idx) => Ok(HeapType::Type(idx as u32)),
+                _ => Err(self.error(ErrorKind::Expected(format!("heap type")))),
+            },
+            None => Err(self.error(ErrorKind::UnexpectedEof(format!("heap type")))),
+        }
+    }
+
     pub fn reftype(&mut self) -> Result<RefType, Error> {
-        if let Some(byte) = self.byte() {
-            FromByte::from_byte(byte).ok_or(Error::Expected(format!("reftype")))
-        } else {
-            Err(Error::UnexpectedEof(format!("reftype")))
+        match self.byte() {
+            Some(0x64) => Ok(RefType::new(false, self.heaptype()?)),
+            Some(0x63) => Ok(RefType::new(true, self.heaptype()?)),
+            Some(byte) => FromByte::from_byte(byte)
+                .ok_or_else(|| self.error(ErrorKind::Expected(format!("reference type")))),
+            None => Err(self.error(ErrorKind::UnexpectedEof(format!("reference type")))),
         }
     }
 
     pub fn valtype(&mut self) -> Result<ValType, Error> {
-        if let Some(byte) = self.byte() {
-            FromByte::from_byte(byte).ok_or(Error::Expected(format!("valtype")))
-        } else {
-            Err(Error::UnexpectedEof(format!("valtype")))
+        match self.peek() {
+            Some(0x63 | 0x64) => Ok(self.reftype()?.into()),
+            Some(byte) => {
+                self.next();
+                FromByte::from_byte(byte)
+                    .ok_or_else(|| self.error(ErrorKind::Expected(format!("value type"))))
+            }
+            None => Err(self.error(ErrorKind::UnexpectedEof(format!("value type")))),
         }
     }
 
     pub fn is_valtype(&self, byte: u8) -> bool {
-        byte == 0x7F
-            || byte == 0x7E
-            || byte == 0x7D
-            || byte == 0x7c
-            || byte == 0x7B
-            || byte == 0x70
-            || byte == 0x6F
+        byte == 0x63 || byte == 0x64 || ValType::from_byte(byte).is_some()
     }
 
     pub fn result_types(&mut self) -> Result<ResultType, Error> {
@@ -37,24 +55,113 @@ impl<'a> Parser<'a> {
     pub fn functype(&mut self) -> Result<FuncType, Error> {
         if let Some(byte) = self.byte() {
             if byte != 0x60 {
-                return Err(Error::Expected(format!("0x60")));
+                return Err(self.error(ErrorKind::Expected(format!("function type"))));
             }
         }
 
         Ok(FuncType(self.result_types()?, self.result_types()?))
     }
 
+    pub fn fieldtype(&mut self) -> Result<FieldType, Error> {
+        let storage = match self.peek() {
+            Some(0x78) => {
+                self.next();
+                StorageType::I8
+            }
+            Some(0x77) => {
+                self.next();
+                StorageType::I16
+            }
+            _ => StorageType::Val(self.valtype()?),
+        };
+        Ok(FieldType {
+            storage,
+            mut_: self.mut_()?,
+        })
+    }
+
+    pub fn comptype(&mut self) -> Result<CompType, Error> {
+        match self.peek() {
+            Some(0x60) => Ok(CompType::Func(self.functype()?)),
+            Some(0x5F) => {
+                self.next();
+                Ok(CompType::Struct(self.vec(Self::fieldtype)?))
+            }
+            Some(0x5E) => {
+                self.next();
+                Ok(CompType::Array(self.fieldtype()?))
+            }
+            Some(_) => Err(self.error(ErrorKind::Expected(format!("composite type")))),
+            None => Err(self.error(ErrorKind::UnexpectedEof(format!("composite type")))),
+        }
+    }
+
+    pub fn subtype(&mut self) -> Result<SubType, Error> {
+        let final_ = match self.peek() {
+            Some(0x50) => false,
+            Some(0x4F) => true,
+            _ => return Ok(SubType::from_comp(self.comptype()?)),
+        };
+        self.next();
+        Ok(SubType {
+            final_,
+            supertypes: self.vec(Self::typeidx)?,
+            comp: self.comptype()?,
+        })
+    }
+
+    /// Parses a recursion group, or a single type which forms a group of its own.
+    pub fn rectype(&mut self) -> Result<Vec<SubType>, Error> {
+        if let Some(0x4E) = self.peek() {
+            self.next();
+            self.vec(Self::subtype)
+        } else {
+            Ok(vec![self.subtype()?])
+        }
+    }
+
     pub fn limits(&mut self) -> Result<Limits, Error> {
         match self.byte() {
-            Some(0x00) => Ok(Limits::Min(self.u32()?)),
-            Some(0x01) => Ok(Limits::MinMax(self.u32()?, self.u32()?)),
-            Some(_) => Err(Error::Expected(format!("limits"))),
-            None => Err(Error::UnexpectedEof(format!("limits"))),
+            Some(0x00) => Ok(Limits::Min(self.u32()? as u64)),
+            Some(0x01) => Ok(Limits::MinMax(self.u32()? as u64, self.u32()? as u64)),
+            Some(_) => Err(self.error(ErrorKind::Expected(format!("limits flags")))),
+            None => Err(self.error(ErrorKind::UnexpectedEof(format!("limits flags")))),
         }
     }
 
     pub fn memory(&mut self) -> Result<Memory, Error> {
-        Ok(Memory(self.limits()?))
+        // Bit 0 of the limits flags tells that a maximum follows, bit 1 marks
+        // shared memories and bit 2 selects 64-bit addresses and limits.
+        let flags = match self.byte() {
+            Some(flags) if flags & !0x07 == 0 => flags,
+            Some(_) => return Err(self.error(ErrorKind::Expected(format!("limits flags")))),
+            None => return Err(self.error(ErrorKind::UnexpectedEof(format!("limits flags")))),
+        };
+        let (idxtype, min, max) = if flags & 0x04 == 0 {
+            let min = self.u32()? as u64;
+            let max = if flags & 0x01 != 0 {
+                Some(self.u32()? as u64)
+            } else {
+                None
+            };
+            (IdxType::I32, min, max)
+        } else {
+            let min = self.u64()?;
+            let max = if flags & 0x01 != 0 {
+                Some(self.u64()?)
+            } else {
+                None
+            };
+            (IdxType::I64, min, max)
+        };
+        Ok(Memory {
+            idxtype,
+            limits: match max {
+                Some(max) => Limits::MinMax(min, max),
+                None => Limits::Min(min),
+            },
+            shared: flags & 0x02 != 0,
+        })
     }
 
     pub fn table(&mut self) -> Result<Table, Error> {
@@ -68,7 +175,7 @@ impl<'a> Parser<'a> {
         match self.byte() {
             Some(0x00) => Ok(Mut::Const),
             Some(0x01) => Ok(Mut::Var),
-            _ => Err(Error::Expected(format!("0x00 or 0x01"))),
+            _ => Err(self.error(ErrorKind::Expected(format!("mutability")))),
         }
     }
 
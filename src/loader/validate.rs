@@ -0,0 +1,2331 @@
+#[cfg(not(feature = "std"))]
+use crate::lib::*;
+
+use crate::binary::*;
+use core::fmt;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ValidationError {
+    TypeMismatch,
+    UnknownType(TypeIdx),
+    UnknownFunc(FuncIdx),
+    UnknownTable(TableIdx),
+    UnknownMemory(MemIdx),
+    UnknownGlobal(GlobalIdx),
+    UnknownLocal(LocalIdx),
+    UnknownLabel(LabelIdx),
+    UnknownElem(ElemIdx),
+    UnknownData(DataIdx),
+    UnknownTag(TagIdx),
+    NonEmptyTagResult,
+    DataCountRequired,
+    InvalidAlignment,
+    InvalidAtomicAlignment,
+    InvalidOffset,
+    InvalidLaneIndex,
+    GlobalIsImmutable,
+    ConstantExpressionRequired,
+    UndeclaredFuncRef(FuncIdx),
+    InvalidLimits,
+    MemorySizeLimit,
+    Memory64SizeLimit,
+    SharedMemoryMaximum,
+    InvalidStartFunction,
+    DuplicateExportName(String),
+    UnknownField(FieldIdx),
+    ImmutableField,
+    ImmutableArray,
+    InvalidSubtype(TypeIdx),
+    UninitializedLocal(LocalIdx),
+    InvalidResultArity,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::TypeMismatch => write!(f, "type mismatch"),
+            ValidationError::UnknownType(idx) => write!(f, "unknown type {}", idx),
+            ValidationError::UnknownFunc(idx) => write!(f, "unknown function {}", idx),
+            ValidationError::UnknownTable(idx) => write!(f, "unknown table {}", idx),
+            ValidationError::UnknownMemory(idx) => write!(f, "unknown memory {}", idx),
+            ValidationError::UnknownGlobal(idx) => write!(f, "unknown global {}", idx),
+            ValidationError::UnknownLocal(idx) => write!(f, "unknown local {}", idx),
+            ValidationError::UnknownLabel(idx) => write!(f, "unknown label {}", idx),
+            ValidationError::UnknownElem(idx) => write!(f, "unknown elem segment {}", idx),
+            ValidationError::UnknownData(idx) => write!(f, "unknown data segment {}", idx),
+            ValidationError::UnknownTag(idx) => write!(f, "unknown tag {}", idx),
+            ValidationError::NonEmptyTagResult => write!(f, "non-empty tag result type"),
+            ValidationError::DataCountRequired => write!(f, "data count section required"),
+            ValidationError::InvalidAlignment => {
+                write!(f, "alignment must not be larger than natural")
+            }
+            ValidationError::InvalidAtomicAlignment => {
+                write!(f, "alignment must be equal to natural")
+            }
+            ValidationError::InvalidOffset => write!(f, "offset out of range"),
+            ValidationError::InvalidLaneIndex => write!(f, "invalid lane index"),
+            ValidationError::GlobalIsImmutable => write!(f, "global is immutable"),
+            ValidationError::ConstantExpressionRequired => {
+                write!(f, "constant expression required")
+            }
+            ValidationError::UndeclaredFuncRef(idx) => {
+                write!(f, "undeclared function reference {}", idx)
+            }
+            ValidationError::InvalidLimits => {
+                write!(f, "size minimum must not be greater than maximum")
+            }
+            ValidationError::MemorySizeLimit => {
+                write!(f, "memory size must be at most 65536 pages (4GiB)")
+            }
+            ValidationError::SharedMemoryMaximum => write!(f, "shared memory must have maximum"),
+            ValidationError::Memory64SizeLimit => {
+                write!(f, "memory size must be at most 2^48 pages")
+            }
+            ValidationError::InvalidStartFunction => write!(f, "start function"),
+            ValidationError::DuplicateExportName(name) => {
+                write!(f, "duplicate export name {:?}", name)
+            }
+            ValidationError::UnknownField(idx) => write!(f, "unknown field {}", idx),
+            ValidationError::ImmutableField => write!(f, "field is immutable"),
+            ValidationError::ImmutableArray => write!(f, "array is immutable"),
+            ValidationError::InvalidSubtype(idx) => write!(f, "sub type {}", idx),
+            ValidationError::InvalidResultArity => write!(f, "invalid result arity"),
+            ValidationError::UninitializedLocal(idx) => write!(f, "uninitialized local {}", idx),
+        }
+    }
+}
+
+const MAX_PAGES: u64 = 65536;
+const MAX_PAGES_64: u64 = 1 << 48;
+
+/// Validation context, the `C` of the specification.
+struct Context<'a> {
+    types: &'a [SubType],
+    funcs: Vec<TypeIdx>,
+    tables: Vec<&'a Table>,
+    mems: Vec<&'a Memory>,
+    globals: Vec<&'a GlobalType>,
+    tags: Vec<TypeIdx>,
+    elems: Vec<ValType>,
+    data_count: Option<u32>,
+    refs: Vec<FuncIdx>,
+}
+
+impl<'a> Context<'a> {
+    fn comptype(&self, idx: TypeIdx) -> Result<&'a CompType, ValidationError> {
+        self.types
+            .get(idx as usize)
+            .map(|t| &t.comp)
+            .ok_or(ValidationError::UnknownType(idx))
+    }
+
+    fn functype(&self, idx: TypeIdx) -> Result<&'a FuncType, ValidationError> {
+        match self.comptype(idx)? {
+            CompType::Func(functype) => Ok(functype),
+            _ => Err(ValidationError::TypeMismatch),
+        }
+    }
+
+    fn struct_type(&self, idx: TypeIdx) -> Result<&'a [FieldType], ValidationError> {
+        match self.comptype(idx)? {
+            CompType::Struct(fields) => Ok(fields),
+            _ => Err(ValidationError::TypeMismatch),
+        }
+    }
+
+    fn field(&self, x: TypeIdx, y: FieldIdx) -> Result<&'a FieldType, ValidationError> {
+        self.struct_type(x)?
+            .get(y as usize)
+            .ok_or(ValidationError::UnknownField(y))
+    }
+
+    fn array_type(&self, idx: TypeIdx) -> Result<&'a FieldType, ValidationError> {
+        match self.comptype(idx)? {
+            CompType::Array(field) => Ok(field),
+            _ => Err(ValidationError::TypeMismatch),
+        }
+    }
+
+    fn heaptype(&self, t: HeapType) -> Result<(), ValidationError> {
+        if let HeapType::Type(idx) = t {
+            self.comptype(idx)?;
+        }
+        Ok(())
+    }
+
+    fn valtype(&self, t: ValType) -> Result<(), ValidationError> {
+        match t.reftype() {
+            Some(t) => self.heaptype(t.heap()),
+            None => Ok(()),
+        }
+    }
+
+    /// The abstract heap type at the top of the hierarchy of `t`.
+    fn top(&self, t: HeapType) -> HeapType {
+        match t {
+            HeapType::Func | HeapType::NoFunc => HeapType::Func,
+            HeapType::Extern | HeapType::NoExtern => HeapType::Extern,
+            HeapType::Exn | HeapType::NoExn => HeapType::Exn,
+            HeapType::Type(idx) => match self.comptype(idx) {
+                Ok(CompType::Func(_)) => HeapType::Func,
+                _ => HeapType::Any,
+            },
+            _ => HeapType::Any,
+        }
+    }
+
+    /// Subtyping of heap types. Defined types match their declared
+    /// supertypes and types with the same definition.
+    fn heap_matches(&self, a: HeapType, b: HeapType) -> bool {
+        match (a, b) {
+            _ if a == b => true,
+            (HeapType::Type(x), HeapType::Type(y)) => {
+                self.types.get(x as usize).map_or(false, |t| {
+                    Some(t) == self.types.get(y as usize)
+                        || t.supertypes
+                            .iter()
+                            .any(|&s| s < x && self.heap_matches(HeapType::Type(s), b))
+                })
+            }
+            (HeapType::Type(x), _) => match self.comptype(x) {
+                Ok(CompType::Func(_)) => b == HeapType::Func,
+                Ok(CompType::Struct(_)) => {
+                    matches!(b, HeapType::Struct | HeapType::Eq | HeapType::Any)
+                }
+                Ok(CompType::Array(_)) => {
+                    matches!(b, HeapType::Array | HeapType::Eq | HeapType::Any)
+                }
+                Err(_) => false,
+            },
+            (HeapType::None, _) => self.top(b) == HeapType::Any,
+            (HeapType::NoFunc, _) => self.top(b) == HeapType::Func,
+            (HeapType::NoExtern, _) => b == HeapType::Extern,
+            (HeapType::NoExn, _) => b == HeapType::Exn,
+            (HeapType::I31 | HeapType::Struct | HeapType::Array, HeapType::Eq | HeapType::Any)
+            | (HeapType::Eq, HeapType::Any) => true,
+            _ => false,
+        }
+    }
+
+    /// Subtyping of value types, which only reference types have.
+    fn matches(&self, a: ValType, b: ValType) -> bool {
+        match (a.reftype(), b.reftype()) {
+            _ if a == b => true,
+            (Some(a), Some(b)) => {
+                (!a.nullable() || b.nullable()) && self.heap_matches(a.heap(), b.heap())
+            }
+            _ => false,
+        }
+    }
+
+    fn storage_matches(&self, a: &StorageType, b: &StorageType) -> bool {
+        match (a, b) {
+            (StorageType::Val(a), StorageType::Val(b)) => self.matches(*a, *b),
+            (a, b) => a == b,
+        }
+    }
+
+    fn field_matches(&self, a: &FieldType, b: &FieldType) -> bool {
+        a.mut_ == b.mut_
+            && match a.mut_ {
+                Mut::Const => self.storage_matches(&a.storage, &b.storage),
+                Mut::Var => a.storage == b.storage,
+            }
+    }
+
+    /// Checks the declared supertypes of type `idx`, which must come
+    /// before it, not be final and have a matching definition.
+    fn subtype(&self, idx: TypeIdx) -> Result<(), ValidationError> {
+        let t = &self.types[idx as usize];
+        match &t.comp {
+            CompType::Func(FuncType(params, results)) => {
+                for t in params.0.iter().chain(results.0.iter()) {
+                    self.valtype(*t)?;
+                }
+            }
+            CompType::Struct(fields) => {
+                for field in fields.iter() {
+                    self.valtype(field.storage.unpacked())?;
+                }
+            }
+            CompType::Array(field) => self.valtype(field.storage.unpacked())?,
+        }
+        if t.supertypes.len() > 1 {
+            return Err(ValidationError::InvalidSubtype(idx));
+        }
+        for &s in t.supertypes.iter() {
+            let sup = match self.types.get(s as usize) {
+                Some(sup) if s < idx && !sup.final_ => sup,
+                _ => return Err(ValidationError::InvalidSubtype(idx)),
+            };
+            let valid = match (&t.comp, &sup.comp) {
+                (CompType::Func(FuncType(p1, r1)), CompType::Func(FuncType(p2, r2))) => {
+                    p1.0.len() == p2.0.len()
+                        && r1.0.len() == r2.0.len()
+                        && p2
+                            .0
+                            .iter()
+                            .zip(p1.0.iter())
+                            .all(|(a, b)| self.matches(*a, *b))
+                        && r1
+                            .0
+                            .iter()
+                            .zip(r2.0.iter())
+                            .all(|(a, b)| self.matches(*a, *b))
+                }
+                (CompType::Struct(f1), CompType::Struct(f2)) => {
+                    f1.len() >= f2.len()
+                        && f1
+                            .iter()
+                            .zip(f2.iter())
+                            .all(|(a, b)| self.field_matches(a, b))
+                }
+                (CompType::Array(a), CompType::Array(b)) => self.field_matches(a, b),
+                _ => false,
+            };
+            if !valid {
+                return Err(ValidationError::InvalidSubtype(idx));
+            }
+        }
+        Ok(())
+    }
+
+    fn func(&self, idx: FuncIdx) -> Result<&'a FuncType, ValidationError> {
+        let typeidx = *self
+            .funcs
+            .get(idx as usize)
+            .ok_or(ValidationError::UnknownFunc(idx))?;
+        self.functype(typeidx)
+    }
+
+    fn table(&self, idx: TableIdx) -> Result<&'a Table, ValidationError> {
+        self.tables
+            .get(idx as usize)
+            .copied()
+            .ok_or(ValidationError::UnknownTable(idx))
+    }
+
+    fn mem(&self, idx: MemIdx) -> Result<&'a Memory, ValidationError> {
+        self.mems
+            .get(idx as usize)
+            .copied()
+            .ok_or(ValidationError::UnknownMemory(idx))
+    }
+
+    fn addrtype(&self, idx: MemIdx) -> Result<ValType, ValidationError> {
+        Ok(self.mem(idx)?.idxtype.valtype())
+    }
+
+    fn global(&self, idx: GlobalIdx) -> Result<&'a GlobalType, ValidationError> {
+        self.globals
+            .get(idx as usize)
+            .copied()
+            .ok_or(ValidationError::UnknownGlobal(idx))
+    }
+
+    fn tag(&self, idx: TagIdx) -> Result<&'a FuncType, ValidationError> {
+        let typeidx = *self
+            .tags
+            .get(idx as usize)
+            .ok_or(ValidationError::UnknownTag(idx))?;
+        self.functype(typeidx)
+    }
+
+    fn elem(&self, idx: ElemIdx) -> Result<ValType, ValidationError> {
+        self.elems
+            .get(idx as usize)
+            .copied()
+            .ok_or(ValidationError::UnknownElem(idx))
+    }
+
+    fn data(&self, idx: DataIdx) -> Result<(), ValidationError> {
+        match self.data_count {
+            None => Err(ValidationError::DataCountRequired),
+            Some(count) if idx >= count => Err(ValidationError::UnknownData(idx)),
+            Some(_) => Ok(()),
+        }
+    }
+
+    fn block_type(&self, bt: &Block) -> Result<(Vec<ValType>, Vec<ValType>), ValidationError> {
+        match bt {
+            Block::Empty => Ok((vec![], vec![])),
+            Block::ValType(t) => Ok((vec![], vec![*t])),
+            Block::TypeIdx(idx) => {
+                let FuncType(params, results) = self.functype(*idx)?;
+                Ok((params.0.clone(), results.0.clone()))
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum CtrlKind {
+    Block,
+    Loop,
+    If,
+    Else,
+}
+
+#[derive(Debug)]
+struct Ctrl {
+    kind: CtrlKind,
+    start_types: Vec<ValType>,
+    end_types: Vec<ValType>,
+    height: usize,
+    /// Length of `FuncValidator::inits` when the block was entered.
+    init_height: usize,
+    unreachable: bool,
+    // For `if` with an `else` branch: the position of the `PopLabel` that
+    // closes the `then` branch.
+    else_at: Option<usize>,
+}
+
+impl Ctrl {
+    fn label_types(&self) -> &[ValType] {
+        if self.kind == CtrlKind::Loop {
+            &self.start_types
+        } else {
+            &self.end_types
+        }
+    }
+}
+
+/// Operand and control stacks of the validation algorithm.
+/// An operand of `None` is the unknown type produced by stack-polymorphic instructions.
+struct FuncValidator<'a, 'b> {
+    ctx: &'b Context<'a>,
+    locals: Vec<ValType>,
+    /// Whether each local is initialized, and the non-defaultable locals
+    /// set so far, which are uninitialized again at the end of their block.
+    initialized: Vec<bool>,
+    inits: Vec<LocalIdx>,
+    returns: Vec<ValType>,
+    operands: Vec<Option<ValType>>,
+    ctrls: Vec<Ctrl>,
+}
+
+fn is_ref(t: ValType) -> bool {
+    t.reftype().is_some()
+}
+
+impl<'a, 'b> FuncValidator<'a, 'b> {
+    /// Parameters are initialized, other locals only if they have a default value.
+    fn new(
+        ctx: &'b Context<'a>,
+        params: &[ValType],
+        locals: &[ValType],
+        returns: Vec<ValType>,
+    ) -> Self {
+        let initialized = params
+            .iter()
+            .map(|_| true)
+            .chain(locals.iter().map(ValType::defaultable))
+            .collect();
+        Self {
+            ctx,
+            initialized,
+            inits: vec![],
+            locals: params.iter().chain(locals.iter()).copied().collect(),
+            returns,
+            operands: vec![],
+            ctrls: vec![],
+        }
+    }
+
+    fn push(&mut self, t: ValType) {
+        self.operands.push(Some(t));
+    }
+
+    fn push_operand(&mut self, t: Option<ValType>) {
+        self.operands.push(t);
+    }
+
+    fn push_all(&mut self, types: &[ValType]) {
+        for t in types {
+            self.push(*t);
+        }
+    }
+
+    fn pop_operand(&mut self) -> Result<Option<ValType>, ValidationError> {
+        let ctrl = self.ctrls.last().unwrap();
+        if self.operands.len() == ctrl.height {
+            if ctrl.unreachable {
+                return Ok(None);
+            }
+            return Err(ValidationError::TypeMismatch);
+        }
+        Ok(self.operands.pop().unwrap())
+    }
+
+    /// Pops an operand matching `expect` and returns its actual type, which
+    /// is unknown after stack-polymorphic instructions.
+    fn pop(&mut self, expect: ValType) -> Result<Option<ValType>, ValidationError> {
+        match self.pop_operand()? {
+            Some(actual) if !self.ctx.matches(actual, expect) => Err(ValidationError::TypeMismatch),
+            actual => Ok(actual),
+        }
+    }
+
+    fn pop_all(&mut self, types: &[ValType]) -> Result<(), ValidationError> {
+        for t in types.iter().rev() {
+            self.pop(*t)?;
+        }
+        Ok(())
+    }
+
+    fn pop_ref(&mut self) -> Result<Option<ValType>, ValidationError> {
+        match self.pop_operand()? {
+            Some(t) if !is_ref(t) => Err(ValidationError::TypeMismatch),
+            t => Ok(t),
+        }
+    }
+
+    /// Pops a reference whose type is unknown after stack-polymorphic instructions.
+    fn pop_reftype(&mut self) -> Result<Option<RefType>, ValidationError> {
+        Ok(self.pop_ref()?.and_then(|t| t.reftype()))
+    }
+
+    /// Pushes `t` without null, or an unknown type if `t` is unknown.
+    fn push_non_null(&mut self, t: Option<RefType>) {
+        self.push_operand(t.map(|t| RefType::new(false, t.heap()).into()));
+    }
+
+    /// Checks that the label of a `br_on_*` instruction ends with `t`, any
+    /// reference if unknown, and takes the operands below it.
+    fn branch_on(&mut self, l: LabelIdx, t: Option<ValType>) -> Result<(), ValidationError> {
+        let mut types = self.label(l)?;
+        match (types.pop(), t) {
+            (Some(last), Some(t)) if self.ctx.matches(t, last) => {}
+            (Some(last), None) if is_ref(last) => {}
+            _ => return Err(ValidationError::TypeMismatch),
+        }
+        self.pop_all(&types)?;
+        self.push_all(&types);
+        Ok(())
+    }
+
+    fn push_ctrl(&mut self, kind: CtrlKind, start_types: Vec<ValType>, end_types: Vec<ValType>) {
+        // The params belong to the block, above its height.
+        self.ctrls.push(Ctrl {
+            kind,
+            start_types: start_types.clone(),
+            end_types,
+            height: self.operands.len(),
+            init_height: self.inits.len(),
+            unreachable: false,
+            else_at: None,
+        });
+        self.push_all(&start_types);
+    }
+
+    fn pop_ctrl(&mut self) -> Result<Ctrl, ValidationError> {
+        let end_types = self.ctrls.last().unwrap().end_types.clone();
+        self.pop_all(&end_types)?;
+        let ctrl = self.ctrls.pop().unwrap();
+        if self.operands.len() != ctrl.height {
+            return Err(ValidationError::TypeMismatch);
+        }
+        for x in self.inits.drain(ctrl.init_height..) {
+            self.initialized[x as usize] = false;
+        }
+        Ok(ctrl)
+    }
+
+    fn unreachable(&mut self) {
+        let ctrl = self.ctrls.last_mut().unwrap();
+        self.operands.truncate(ctrl.height);
+        ctrl.unreachable = true;
+    }
+
+    fn label(&self, l: LabelIdx) -> Result<Vec<ValType>, ValidationError> {
+        if l as usize >= self.ctrls.len() {
+            return Err(ValidationError::UnknownLabel(l));
+        }
+        Ok(self.ctrls[self.ctrls.len() - 1 - l as usize]
+            .label_types()
+            .to_vec())
+    }
+
+    fn local(&self, l: LocalIdx) -> Result<ValType, ValidationError> {
+        self.locals
+            .get(l as usize)
+            .copied()
+            .ok_or(ValidationError::UnknownLocal(l))
+    }
+
+    fn init_local(&mut self, l: LocalIdx) {
+        if !self.initialized[l as usize] {
+            self.initialized[l as usize] = true;
+            self.inits.push(l);
+        }
+    }
+
+    /// Checks `memarg` and returns the type of the address operand.
+    fn memarg(&self, memarg: &MemArg, natural: u32) -> Result<ValType, ValidationError> {
+        let mem = self.ctx.mem(memarg.memidx)?;
+        if 1u64.checked_shl(memarg.align).unwrap_or(u64::MAX) > natural as u64 {
+            return Err(ValidationError::InvalidAlignment);
+        }
+        if mem.idxtype == IdxType::I32 && memarg.offset > u32::MAX as u64 {
+            return Err(ValidationError::InvalidOffset);
+        }
+        Ok(mem.idxtype.valtype())
+    }
+
+    /// Checks that the label of a catch clause takes the values it passes.
+    fn catch(&self, catch: &Catch) -> Result<(), ValidationError> {
+        let (l, mut types) = match catch {
+            Catch::Catch(x, l) | Catch::CatchRef(x, l) => (*l, self.ctx.tag(*x)?.0 .0.clone()),
+            Catch::CatchAll(l) | Catch::CatchAllRef(l) => (*l, vec![]),
+        };
+        if let Catch::CatchRef(..) | Catch::CatchAllRef(_) = catch {
+            types.push(ValType::ExnRef);
+        }
+        if self.label(l)? != types {
+            return Err(ValidationError::TypeMismatch);
+        }
+        Ok(())
+    }
+
+    /// The callee of a tail call returns to the caller of the current function.
+    fn tail_call(
+        &mut self,
+        params: &[ValType],
+        results: &[ValType],
+    ) -> Result<(), ValidationError> {
+        if results.len() != self.returns.len()
+            || results
+                .iter()
+                .zip(self.returns.iter())
+                .any(|(a, b)| !self.ctx.matches(*a, *b))
+        {
+            return Err(ValidationError::TypeMismatch);
+        }
+        self.pop_all(params)?;
+        self.unreachable();
+        Ok(())
+    }
+
+    fn validate(mut self, instrs: &[Instr]) -> Result<(), ValidationError> {
+        let returns = self.returns.clone();
+        self.push_ctrl(CtrlKind::Block, vec![], returns);
+
+        let mut pc = 0;
+        while pc < instrs.len() {
+            if let Instr::PopLabel = instrs[pc] {
+                let ctrl = self.pop_ctrl()?;
+                if ctrl.kind == CtrlKind::If && ctrl.else_at == Some(pc) {
+                    // The `then` branch is followed by `RJump` over the `else` branch.
+                    self.push_ctrl(CtrlKind::Else, ctrl.start_types, ctrl.end_types);
+                    pc += 2;
+                    continue;
+                }
+                if ctrl.kind == CtrlKind::If && ctrl.start_types != ctrl.end_types {
+                    return Err(ValidationError::TypeMismatch);
+                }
+                self.push_all(&ctrl.end_types);
+            } else {
+                self.instr(&instrs[pc], pc)?;
+            }
+            pc += 1;
+        }
+
+        self.pop_ctrl()?;
+        if !self.ctrls.is_empty() {
+            return Err(ValidationError::TypeMismatch);
+        }
+        Ok(())
+    }
+
+    fn instr(&mut self, instr: &Instr, pc: usize) -> Result<(), ValidationError> {
+        use ValType::*;
+
+        if let Some((params, results)) = numeric(instr) {
+            self.pop_all(params)?;
+            self.push_all(results);
+            return Ok(());
+        }
+
+        if let Some((memarg, natural, t, load)) = memory_access(instr) {
+            let at = self.memarg(memarg, natural)?;
+            if load {
+                self.pop(at)?;
+                self.push(t);
+            } else {
+                self.pop(t)?;
+                self.pop(at)?;
+            }
+            return Ok(());
+        }
+
+        if let Some((memarg, natural, l, lanes, load)) = lane_memory_access(instr) {
+            let at = self.memarg(memarg, natural)?;
+            if l >= lanes {
+                return Err(ValidationError::InvalidLaneIndex);
+            }
+            self.pop(V128)?;
+            self.pop(at)?;
+            if load {
+                self.push(V128);
+            }
+            return Ok(());
+        }
+
+        if let Some((memarg, natural, (params, results))) = atomic(instr) {
+            let at = self.memarg(memarg, natural)?;
+            if 1u64 << memarg.align != natural as u64 {
+                return Err(ValidationError::InvalidAtomicAlignment);
+            }
+            self.pop_all(params)?;
+            self.pop(at)?;
+            self.push_all(results);
+            return Ok(());
+        }
+
+        if let Some((params, results)) = vector(instr) {
+            match (instr, lane_index(instr)) {
+                (Instr::I8x16Shuffle(lanes), _) if lanes.iter().any(|l| *l >= 32) => {
+                    return Err(ValidationError::InvalidLaneIndex);
+                }
+                (_, Some((l, lanes))) if l >= lanes => {
+                    return Err(ValidationError::InvalidLaneIndex);
+                }
+                _ => {}
+            }
+            self.pop_all(params)?;
+            self.push_all(results);
+            return Ok(());
+        }
+
+        match instr {
+            // Control Instructions
+            Instr::Unreachable => self.unreachable(),
+            Instr::Nop => {}
+            Instr::Block { bt, .. } => {
+                let (params, results) = self.ctx.block_type(bt)?;
+                self.pop_all(&params)?;
+                self.push_ctrl(CtrlKind::Block, params, results);
+            }
+            Instr::TryTable { bt, catches, .. } => {
+                for catch in catches.iter() {
+                    self.catch(catch)?;
+                }
+                let (params, results) = self.ctx.block_type(bt)?;
+                self.pop_all(&params)?;
+                self.push_ctrl(CtrlKind::Block, params, results);
+            }
+            Instr::Throw(x) => {
+                let FuncType(params, _) = self.ctx.tag(*x)?;
+                self.pop_all(&params.0)?;
+                self.unreachable();
+            }
+            Instr::ThrowRef => {
+                self.pop(ExnRef)?;
+                self.unreachable();
+            }
+            Instr::Loop { bt } => {
+                let (params, results) = self.ctx.block_type(bt)?;
+                self.pop_all(&params)?;
+                self.push_ctrl(CtrlKind::Loop, params, results);
+            }
+            Instr::If {
+                bt, else_offset, ..
+            } => {
+                let (params, results) = self.ctx.block_type(bt)?;
+                self.pop(I32)?;
+                self.pop_all(&params)?;
+                self.push_ctrl(CtrlKind::If, params, results);
+                self.ctrls.last_mut().unwrap().else_at = else_offset.map(|e| pc + e - 2);
+            }
+            Instr::Br(l) => {
+                let types = self.label(*l)?;
+                self.pop_all(&types)?;
+                self.unreachable();
+            }
+            Instr::BrIf(l) => {
+                self.pop(I32)?;
+                let types = self.label(*l)?;
+                self.pop_all(&types)?;
+                self.push_all(&types);
+            }
+            Instr::BrTable { indexs, default } => {
+                self.pop(I32)?;
+                let default_types = self.label(*default)?;
+                for l in indexs {
+                    let types = self.label(*l)?;
+                    if types.len() != default_types.len() {
+                        return Err(ValidationError::TypeMismatch);
+                    }
+                    let mut popped = vec![];
+                    for t in types.iter().rev() {
+                        popped.push(self.pop(*t)?);
+                    }
+                    for t in popped.into_iter().rev() {
+                        self.push_operand(t);
+                    }
+                }
+                self.pop_all(&default_types)?;
+                self.unreachable();
+            }
+            Instr::Return => {
+                let returns = self.returns.clone();
+                self.pop_all(&returns)?;
+                self.unreachable();
+            }
+            Instr::Call(x) => {
+                let FuncType(params, results) = self.ctx.func(*x)?;
+                self.pop_all(&params.0)?;
+                self.push_all(&results.0);
+            }
+            Instr::CallIndirect(x, y) => {
+                if !self
+                    .ctx
+                    .matches(self.ctx.table(*y)?.reftype.into(), FuncRef)
+                {
+                    return Err(ValidationError::TypeMismatch);
+                }
+                let FuncType(params, results) = self.ctx.functype(*x)?;
+                self.pop(I32)?;
+                self.pop_all(&params.0)?;
+                self.push_all(&results.0);
+            }
+            Instr::ReturnCall(x) => {
+                let FuncType(params, results) = self.ctx.func(*x)?;
+                self.tail_call(&params.0, &results.0)?;
+            }
+            Instr::ReturnCallIndirect(x, y) => {
+                if !self
+                    .ctx
+                    .matches(self.ctx.table(*y)?.reftype.into(), FuncRef)
+                {
+                    return Err(ValidationError::TypeMismatch);
+                }
+                let FuncType(params, results) = self.ctx.functype(*x)?;
+                self.pop(I32)?;
+                self.tail_call(&params.0, &results.0)?;
+            }
+            Instr::CallRef(x) => {
+                let FuncType(params, results) = self.ctx.functype(*x)?;
+                self.pop(RefType::new(true, HeapType::Type(*x)).into())?;
+                self.pop_all(&params.0)?;
+                self.push_all(&results.0);
+            }
+            Instr::ReturnCallRef(x) => {
+                let FuncType(params, results) = self.ctx.functype(*x)?;
+                self.pop(RefType::new(true, HeapType::Type(*x)).into())?;
+                self.tail_call(&params.0, &results.0)?;
+            }
+            Instr::BrOnNull(l) => {
+                let t = self.pop_reftype()?;
+                let types = self.label(*l)?;
+                self.pop_all(&types)?;
+                self.push_all(&types);
+                self.push_non_null(t);
+            }
+            Instr::BrOnNonNull(l) => {
+                let t = self.pop_reftype()?;
+                self.branch_on(*l, t.map(|t| RefType::new(false, t.heap()).into()))?;
+            }
+            Instr::BrOnCast(l, rt1, rt2) | Instr::BrOnCastFail(l, rt1, rt2) => {
+                self.ctx.heaptype(rt1.heap())?;
+                self.ctx.heaptype(rt2.heap())?;
+                if !self.ctx.matches((*rt2).into(), (*rt1).into()) {
+                    return Err(ValidationError::TypeMismatch);
+                }
+                self.pop((*rt1).into())?;
+                // The value passed on the other path is known not to have type `rt2`,
+                // which excludes null if `rt2` is nullable.
+                let rest = RefType::new(rt1.nullable() && !rt2.nullable(), rt1.heap());
+                if let Instr::BrOnCast(..) = instr {
+                    self.branch_on(*l, Some((*rt2).into()))?;
+                    self.push(rest.into());
+                } else {
+                    self.branch_on(*l, Some(rest.into()))?;
+                    self.push((*rt2).into());
+                }
+            }
+            // Reference Instructions
+            Instr::RefNull(t) => {
+                self.ctx.heaptype(*t)?;
+                self.push(RefType::new(true, *t).into());
+            }
+            Instr::RefAsNonNull => {
+                let t = self.pop_reftype()?;
+                self.push_non_null(t);
+            }
+            Instr::RefEq => {
+                let eqref = RefType::new(true, HeapType::Eq).into();
+                self.pop_all(&[eqref, eqref])?;
+                self.push(I32);
+            }
+            Instr::RefIsNull => {
+                self.pop_ref()?;
+                self.push(I32);
+            }
+            Instr::RefFunc(x) => {
+                self.ctx.func(*x)?;
+                if !self.ctx.refs.contains(x) {
+                    return Err(ValidationError::UndeclaredFuncRef(*x));
+                }
+                let typeidx = self.ctx.funcs[*x as usize];
+                self.push(RefType::new(false, HeapType::Type(typeidx)).into());
+            }
+            // Parametric Instructions
+            Instr::Drop => {
+                self.pop_operand()?;
+            }
+            Instr::Select => {
+                self.pop(I32)?;
+                let t1 = self.pop_operand()?;
+                let t2 = self.pop_operand()?;
+                if t1.map_or(false, is_ref) || t2.map_or(false, is_ref) {
+                    return Err(ValidationError::TypeMismatch);
+                }
+                match (t1, t2) {
+                    (Some(t1), Some(t2)) if t1 != t2 => return Err(ValidationError::TypeMismatch),
+                    (None, t) | (t, _) => self.push_operand(t),
+                }
+            }
+            Instr::SelectT(ts) => {
+                if ts.len() != 1 {
+                    return Err(ValidationError::InvalidResultArity);
+                }
+                let t = ts[0];
+                self.ctx.valtype(t)?;
+                self.pop(I32)?;
+                self.pop(t)?;
+                self.pop(t)?;
+                self.push(t);
+            }
+            // Variable Instructions
+            Instr::LocalGet(x) => {
+                let t = self.local(*x)?;
+                if !self.initialized[*x as usize] {
+                    return Err(ValidationError::UninitializedLocal(*x));
+                }
+                self.push(t);
+            }
+            Instr::LocalSet(x) => {
+                let t = self.local(*x)?;
+                self.pop(t)?;
+                self.init_local(*x);
+            }
+            Instr::LocalTee(x) => {
+                let t = self.local(*x)?;
+                self.pop(t)?;
+                self.init_local(*x);
+                self.push(t);
+            }
+            Instr::GlobalGet(x) => {
+                let t = self.ctx.global(*x)?.valtype;
+                self.push(t);
+            }
+            Instr::GlobalSet(x) => {
+                let global = self.ctx.global(*x)?;
+                if global.mut_ != Mut::Var {
+                    return Err(ValidationError::GlobalIsImmutable);
+                }
+                self.pop(global.valtype)?;
+            }
+            // Table Instructions
+            Instr::TableGet(x) => {
+                let t = self.ctx.table(*x)?.reftype.into();
+                self.pop(I32)?;
+                self.push(t);
+            }
+            Instr::TableSet(x) => {
+                let t = self.ctx.table(*x)?.reftype.into();
+                self.pop(t)?;
+                self.pop(I32)?;
+            }
+            Instr::TableInit(y, x) => {
+                let t1: ValType = self.ctx.table(*x)?.reftype.into();
+                let t2 = self.ctx.elem(*y)?;
+                if !self.ctx.matches(t2, t1) {
+                    return Err(ValidationError::TypeMismatch);
+                }
+                self.pop_all(&[I32, I32, I32])?;
+            }
+            Instr::ElemDrop(x) => {
+                self.ctx.elem(*x)?;
+            }
+            Instr::TableCopy(x, y) => {
+                let t1 = self.ctx.table(*x)?.reftype.into();
+                let t2 = self.ctx.table(*y)?.reftype.into();
+                if !self.ctx.matches(t2, t1) {
+                    return Err(ValidationError::TypeMismatch);
+                }
+                self.pop_all(&[I32, I32, I32])?;
+            }
+            Instr::TableGrow(x) => {
+                let t = self.ctx.table(*x)?.reftype.into();
+                self.pop_all(&[t, I32])?;
+                self.push(I32);
+            }
+            Instr::TableSize(x) => {
+                self.ctx.table(*x)?;
+                self.push(I32);
+            }
+            Instr::TableFill(x) => {
+                let t = self.ctx.table(*x)?.reftype.into();
+                self.pop_all(&[I32, t, I32])?;
+            }
+            // Memory Instructions
+            Instr::AtomicFence => {}
+            Instr::MemorySize(m) => {
+                let at = self.ctx.addrtype(*m)?;
+                self.push(at);
+            }
+            Instr::MemoryGrow(m) => {
+                let at = self.ctx.addrtype(*m)?;
+                self.pop(at)?;
+                self.push(at);
+            }
+            Instr::MemoryInit(x, m) => {
+                let at = self.ctx.addrtype(*m)?;
+                self.ctx.data(*x)?;
+                self.pop_all(&[at, I32, I32])?;
+            }
+            Instr::DataDrop(x) => {
+                self.ctx.data(*x)?;
+            }
+            Instr::MemoryCopy(d, s) => {
+                let (d, s) = (self.ctx.addrtype(*d)?, self.ctx.addrtype(*s)?);
+                let n = if d == I64 && s == I64 { I64 } else { I32 };
+                self.pop_all(&[d, s, n])?;
+            }
+            Instr::MemoryFill(m) => {
+                let at = self.ctx.addrtype(*m)?;
+                self.pop_all(&[at, I32, at])?;
+            }
+            // Aggregate Instructions
+            Instr::StructNew(x) => {
+                for field in self.ctx.struct_type(*x)?.iter().rev() {
+                    self.pop(field.storage.unpacked())?;
+                }
+                self.push(RefType::new(false, HeapType::Type(*x)).into());
+            }
+            Instr::StructNewDefault(x) => {
+                let fields = self.ctx.struct_type(*x)?;
+                if !fields.iter().all(|f| f.storage.unpacked().defaultable()) {
+                    return Err(ValidationError::TypeMismatch);
+                }
+                self.push(RefType::new(false, HeapType::Type(*x)).into());
+            }
+            Instr::StructGet(x, y) | Instr::StructGetS(x, y) | Instr::StructGetU(x, y) => {
+                let field = self.ctx.field(*x, *y)?;
+                let packed = field.storage != StorageType::Val(field.storage.unpacked());
+                if packed == matches!(instr, Instr::StructGet(..)) {
+                    return Err(ValidationError::TypeMismatch);
+                }
+                self.pop(RefType::new(true, HeapType::Type(*x)).into())?;
+                self.push(field.storage.unpacked());
+            }
+            Instr::StructSet(x, y) => {
+                let field = self.ctx.field(*x, *y)?;
+                if field.mut_ != Mut::Var {
+                    return Err(ValidationError::ImmutableField);
+                }
+                self.pop(field.storage.unpacked())?;
+                self.pop(RefType::new(true, HeapType::Type(*x)).into())?;
+            }
+            Instr::ArrayNew(x) => {
+                let t = self.ctx.array_type(*x)?.storage.unpacked();
+                self.pop_all(&[t, I32])?;
+                self.push(RefType::new(false, HeapType::Type(*x)).into());
+            }
+            Instr::ArrayNewDefault(x) => {
+                if !self.ctx.array_type(*x)?.storage.unpacked().defaultable() {
+                    return Err(ValidationError::TypeMismatch);
+                }
+                self.pop(I32)?;
+                self.push(RefType::new(false, HeapType::Type(*x)).into());
+            }
+            Instr::ArrayNewFixed(x, n) => {
+                let t = self.ctx.array_type(*x)?.storage.unpacked();
+                for _ in 0..*n {
+                    self.pop(t)?;
+                }
+                self.push(RefType::new(false, HeapType::Type(*x)).into());
+            }
+            Instr::ArrayNewData(x, y) => {
+                if is_ref(self.ctx.array_type(*x)?.storage.unpacked()) {
+                    return Err(ValidationError::TypeMismatch);
+                }
+                self.ctx.data(*y)?;
+                self.pop_all(&[I32, I32])?;
+                self.push(RefType::new(false, HeapType::Type(*x)).into());
+            }
+            Instr::ArrayNewElem(x, y) => {
+                let t = self.ctx.array_type(*x)?.storage.unpacked();
+                if !self.ctx.matches(self.ctx.elem(*y)?, t) {
+                    return Err(ValidationError::TypeMismatch);
+                }
+                self.pop_all(&[I32, I32])?;
+                self.push(RefType::new(false, HeapType::Type(*x)).into());
+            }
+            Instr::ArrayGet(x) | Instr::ArrayGetS(x) | Instr::ArrayGetU(x) => {
+                let field = self.ctx.array_type(*x)?;
+                let packed = field.storage != StorageType::Val(field.storage.unpacked());
+                if packed == matches!(instr, Instr::ArrayGet(_)) {
+                    return Err(ValidationError::TypeMismatch);
+                }
+                self.pop_all(&[RefType::new(true, HeapType::Type(*x)).into(), I32])?;
+                self.push(field.storage.unpacked());
+            }
+            Instr::ArraySet(x) => {
+                let field = self.ctx.array_type(*x)?;
+                if field.mut_ != Mut::Var {
+                    return Err(ValidationError::ImmutableArray);
+                }
+                let t = field.storage.unpacked();
+                self.pop_all(&[RefType::new(true, HeapType::Type(*x)).into(), I32, t])?;
+            }
+            Instr::ArrayLen => {
+                self.pop(RefType::new(true, HeapType::Array).into())?;
+                self.push(I32);
+            }
+            Instr::ArrayFill(x) => {
+                let field = self.ctx.array_type(*x)?;
+                if field.mut_ != Mut::Var {
+                    return Err(ValidationError::ImmutableArray);
+                }
+                let t = field.storage.unpacked();
+                self.pop_all(&[RefType::new(true, HeapType::Type(*x)).into(), I32, t, I32])?;
+            }
+            Instr::ArrayCopy(x, y) => {
+                let (dst, src) = (self.ctx.array_type(*x)?, self.ctx.array_type(*y)?);
+                if dst.mut_ != Mut::Var {
+                    return Err(ValidationError::ImmutableArray);
+                }
+                if !self.ctx.storage_matches(&src.storage, &dst.storage) {
+                    return Err(ValidationError::TypeMismatch);
+                }
+                let (dst, src) = (HeapType::Type(*x), HeapType::Type(*y));
+                self.pop_all(&[
+                    RefType::new(true, dst).into(),
+                    I32,
+                    RefType::new(true, src).into(),
+                    I32,
+                    I32,
+                ])?;
+            }
+            Instr::ArrayInitData(x, y) | Instr::ArrayInitElem(x, y) => {
+                let field = self.ctx.array_type(*x)?;
+                if field.mut_ != Mut::Var {
+                    return Err(ValidationError::ImmutableArray);
+                }
+                let t = field.storage.unpacked();
+                if let Instr::ArrayInitData(..) = instr {
+                    if is_ref(t) {
+                        return Err(ValidationError::TypeMismatch);
+                    }
+                    self.ctx.data(*y)?;
+                } else if !self.ctx.matches(self.ctx.elem(*y)?, t) {
+                    return Err(ValidationError::TypeMismatch);
+                }
+                self.pop_all(&[RefType::new(true, HeapType::Type(*x)).into(), I32, I32, I32])?;
+            }
+            Instr::RefTest(rt) | Instr::RefCast(rt) => {
+                self.ctx.heaptype(rt.heap())?;
+                let top = self.ctx.top(rt.heap());
+                self.pop(RefType::new(true, top).into())?;
+                if let Instr::RefTest(_) = instr {
+                    self.push(I32);
+                } else {
+                    self.push((*rt).into());
+                }
+            }
+            Instr::AnyConvertExtern | Instr::ExternConvertAny => {
+                let (from, to) = if let Instr::AnyConvertExtern = instr {
+                    (HeapType::Extern, HeapType::Any)
+                } else {
+                    (HeapType::Any, HeapType::Extern)
+                };
+                let nullable = self.pop(RefType::new(true, from).into())?;
+                let nullable = nullable
+                    .and_then(|t| t.reftype())
+                    .map_or(true, |t| t.nullable());
+                self.push(RefType::new(nullable, to).into());
+            }
+            Instr::RefI31 => {
+                self.pop(I32)?;
+                self.push(RefType::new(false, HeapType::I31).into());
+            }
+            Instr::I31GetS | Instr::I31GetU => {
+                self.pop(RefType::new(true, HeapType::I31).into())?;
+                self.push(I32);
+            }
+            // `PopLabel` is handled by the caller and `RJump` only follows a `then` branch.
+            Instr::PopLabel | Instr::RJump(_) => {}
+            _ => unreachable!("{:?}", instr),
+        }
+        Ok(())
+    }
+}
+
+type Signature = (&'static [ValType], &'static [ValType]);
+
+fn numeric(instr: &Instr) -> Option<Signature> {
+    use ValType::*;
+    Some(match instr {
+        Instr::I32Const(_) => (&[], &[I32]),
+        Instr::I64Const(_) => (&[], &[I64]),
+        Instr::F32Const(_) => (&[], &[F32]),
+        Instr::F64Const(_) => (&[], &[F64]),
+
+        Instr::I32Eqz => (&[I32], &[I32]),
+        Instr::I32Eq
+        | Instr::I32Ne
+        | Instr::I32LtS
+        | Instr::I32LtU
+        | Instr::I32GtS
+        | Instr::I32GtU
+        | Instr::I32LeS
+        | Instr::I32LeU
+        | Instr::I32GeS
+        | Instr::I32GeU => (&[I32, I32], &[I32]),
+
+        Instr::I64Eqz => (&[I64], &[I32]),
+        Instr::I64Eq
+        | Instr::I64Ne
+        | Instr::I64LtS
+        | Instr::I64LtU
+        | Instr::I64GtS
+        | Instr::I64GtU
+        | Instr::I64LeS
+        | Instr::I64LeU
+        | Instr::I64GeS
+        | Instr::I64GeU => (&[I64, I64], &[I32]),
+
+        Instr::F32Eq | Instr::F32Ne | Instr::F32Lt | Instr::F32Gt | Instr::F32Le | Instr::F32Ge => {
+            (&[F32, F32], &[I32])
+        }
+        Instr::F64Eq | Instr::F64Ne | Instr::F64Lt | Instr::F64Gt | Instr::F64Le | Instr::F64Ge => {
+            (&[F64, F64], &[I32])
+        }
+
+        Instr::I32Clz
+        | Instr::I32Ctz
+        | Instr::I32Popcnt
+        | Instr::I32Extend8S
+        | Instr::I32Extend16S => (&[I32], &[I32]),
+        Instr::I32Add
+        | Instr::I32Sub
+        | Instr::I32Mul
+        | Instr::I32DivS
+        | Instr::I32DivU
+        | Instr::I32RemS
+        | Instr::I32RemU
+        | Instr::I32And
+        | Instr::I32Or
+        | Instr::I32Xor
+        | Instr::I32Shl
+        | Instr::I32ShrS
+        | Instr::I32ShrU
+        | Instr::I32RotL
+        | Instr::I32RotR => (&[I32, I32], &[I32]),
+
+        Instr::I64Clz
+        | Instr::I64Ctz
+        | Instr::I64Popcnt
+        | Instr::I64Extend8S
+        | Instr::I64Extend16S
+        | Instr::I64Extend32S => (&[I64], &[I64]),
+        Instr::I64Add
+        | Instr::I64Sub
+        | Instr::I64Mul
+        | Instr::I64DivS
+        | Instr::I64DivU
+        | Instr::I64RemS
+        | Instr::I64RemU
+        | Instr::I64And
+        | Instr::I64Or
+        | Instr::I64Xor
+        | Instr::I64Shl
+        | Instr::I64ShrS
+        | Instr::I64ShrU
+        | Instr::I64RotL
+        | Instr::I64RotR => (&[I64, I64], &[I64]),
+
+        Instr::F32Abs
+        | Instr::F32Neg
+        | Instr::F32Ceil
+        | Instr::F32Floor
+        | Instr::F32Trunc
+        | Instr::F32Nearest
+        | Instr::F32Sqrt => (&[F32], &[F32]),
+        Instr::F32Add
+        | Instr::F32Sub
+        | Instr::F32Mul
+        | Instr::F32Div
+        | Instr::F32Min
+        | Instr::F32Max
+        | Instr::F32Copysign => (&[F32, F32], &[F32]),
+
+        Instr::F64Abs
+        | Instr::F64Neg
+        | Instr::F64Ceil
+        | Instr::F64Floor
+        | Instr::F64Trunc
+        | Instr::F64Nearest
+        | Instr::F64Sqrt => (&[F64], &[F64]),
+        Instr::F64Add
+        | Instr::F64Sub
+        | Instr::F64Mul
+        | Instr::F64Div
+        | Instr::F64Min
+        | Instr::F64Max
+        | Instr::F64Copysign => (&[F64, F64], &[F64]),
+
+        Instr::I32WrapI64 => (&[I64], &[I32]),
+        Instr::I32TruncF32S
+        | Instr::I32TruncF32U
+        | Instr::I32TruncSatF32S
+        | Instr::I32TruncSatF32U
+        | Instr::I32ReinterpretF32 => (&[F32], &[I32]),
+        Instr::I32TruncF64S
+        | Instr::I32TruncF64U
+        | Instr::I32TruncSatF64S
+        | Instr::I32TruncSatF64U => (&[F64], &[I32]),
+        Instr::I64ExtendI32S | Instr::I64ExtendI32U => (&[I32], &[I64]),
+        Instr::I64TruncF32S
+        | Instr::I64TruncF32U
+        | Instr::I64TruncSatF32S
+        | Instr::I64TruncSatF32U => (&[F32], &[I64]),
+        Instr::I64TruncF64S
+        | Instr::I64TruncF64U
+        | Instr::I64TruncSatF64S
+        | Instr::I64TruncSatF64U
+        | Instr::I64ReinterpretF64 => (&[F64], &[I64]),
+        Instr::F32ConvertI32S | Instr::F32ConvertI32U | Instr::F32ReinterpretI32 => {
+            (&[I32], &[F32])
+        }
+        Instr::F32ConvertI64S | Instr::F32ConvertI64U => (&[I64], &[F32]),
+        Instr::F32DemoteF64 => (&[F64], &[F32]),
+        Instr::F64ConvertI32S | Instr::F64ConvertI32U => (&[I32], &[F64]),
+        Instr::F64ConvertI64S | Instr::F64ConvertI64U | Instr::F64ReinterpretI64 => {
+            (&[I64], &[F64])
+        }
+        Instr::F64PromoteF32 => (&[F32], &[F64]),
+        _ => return None,
+    })
+}
+
+/// Returns the memarg, the natural alignment in bytes, the value type
+/// and whether the instruction is a load.
+fn memory_access(instr: &Instr) -> Option<(&MemArg, u32, ValType, bool)> {
+    use ValType::*;
+    Some(match instr {
+        Instr::I32Load(m) => (m, 4, I32, true),
+        Instr::I64Load(m) => (m, 8, I64, true),
+        Instr::F32Load(m) => (m, 4, F32, true),
+        Instr::F64Load(m) => (m, 8, F64, true),
+        Instr::I32Load8S(m) | Instr::I32Load8U(m) => (m, 1, I32, true),
+        Instr::I32Load16S(m) | Instr::I32Load16U(m) => (m, 2, I32, true),
+        Instr::I64Load8S(m) | Instr::I64Load8U(m) => (m, 1, I64, true),
+        Instr::I64Load16S(m) | Instr::I64Load16U(m) => (m, 2, I64, true),
+        Instr::I64Load32S(m) | Instr::I64Load32U(m) => (m, 4, I64, true),
+        Instr::I32Store(m) => (m, 4, I32, false),
+        Instr::I64Store(m) => (m, 8, I64, false),
+        Instr::F32Store(m) => (m, 4, F32, false),
+        Instr::F64Store(m) => (m, 8, F64, false),
+        Instr::I32Store8(m) => (m, 1, I32, false),
+        Instr::I32Store16(m) => (m, 2, I32, false),
+        Instr::I64Store8(m) => (m, 1, I64, false),
+        Instr::I64Store16(m) => (m, 2, I64, false),
+        Instr::I64Store32(m) => (m, 4, I64, false),
+        Instr::V128Load(m) => (m, 16, V128, true),
+        Instr::V128Load8x8S(m) => (m, 8, V128, true),
+        Instr::V128Load8x8U(m) => (m, 8, V128, true),
+        Instr::V128Load16x4S(m) => (m, 8, V128, true),
+        Instr::V128Load16x4U(m) => (m, 8, V128, true),
+        Instr::V128Load32x2S(m) => (m, 8, V128, true),
+        Instr::V128Load32x2U(m) => (m, 8, V128, true),
+        Instr::V128Load8Splat(m) => (m, 1, V128, true),
+        Instr::V128Load16Splat(m) => (m, 2, V128, true),
+        Instr::V128Load32Splat(m) => (m, 4, V128, true),
+        Instr::V128Load64Splat(m) => (m, 8, V128, true),
+        Instr::V128Store(m) => (m, 16, V128, false),
+        Instr::V128Load32Zero(m) => (m, 4, V128, true),
+        Instr::V128Load64Zero(m) => (m, 8, V128, true),
+        _ => return None,
+    })
+}
+
+/// Returns the memarg, the natural alignment in bytes, the lane index, the
+/// number of lanes and whether the instruction is a load.
+fn lane_memory_access(instr: &Instr) -> Option<(&MemArg, u32, u8, u8, bool)> {
+    Some(match instr {
+        Instr::V128Load8Lane(m, l) => (m, 1, *l, 16, true),
+        Instr::V128Load16Lane(m, l) => (m, 2, *l, 8, true),
+        Instr::V128Load32Lane(m, l) => (m, 4, *l, 4, true),
+        Instr::V128Load64Lane(m, l) => (m, 8, *l, 2, true),
+        Instr::V128Store8Lane(m, l) => (m, 1, *l, 16, false),
+        Instr::V128Store16Lane(m, l) => (m, 2, *l, 8, false),
+        Instr::V128Store32Lane(m, l) => (m, 4, *l, 4, false),
+        Instr::V128Store64Lane(m, l) => (m, 8, *l, 2, false),
+        _ => return None,
+    })
+}
+
+/// Memory argument, natural alignment and signature without the address
+/// operand of an atomic memory instruction.
+fn atomic(instr: &Instr) -> Option<(&MemArg, u32, Signature)> {
+    use ValType::*;
+    Some(match instr {
+        Instr::MemoryAtomicNotify(m)
+        | Instr::I32AtomicRmwAdd(m)
+        | Instr::I32AtomicRmwSub(m)
+        | Instr::I32AtomicRmwAnd(m)
+        | Instr::I32AtomicRmwOr(m)
+        | Instr::I32AtomicRmwXor(m)
+        | Instr::I32AtomicRmwXchg(m) => (m, 4, (&[I32], &[I32])),
+        Instr::MemoryAtomicWait32(m) => (m, 4, (&[I32, I64], &[I32])),
+        Instr::MemoryAtomicWait64(m) => (m, 8, (&[I64, I64], &[I32])),
+        Instr::I32AtomicLoad(m) => (m, 4, (&[], &[I32])),
+        Instr::I64AtomicLoad(m) => (m, 8, (&[], &[I64])),
+        Instr::I32AtomicLoad8U(m) => (m, 1, (&[], &[I32])),
+        Instr::I32AtomicLoad16U(m) => (m, 2, (&[], &[I32])),
+        Instr::I64AtomicLoad8U(m) => (m, 1, (&[], &[I64])),
+        Instr::I64AtomicLoad16U(m) => (m, 2, (&[], &[I64])),
+        Instr::I64AtomicLoad32U(m) => (m, 4, (&[], &[I64])),
+        Instr::I32AtomicStore(m) => (m, 4, (&[I32], &[])),
+        Instr::I64AtomicStore(m) => (m, 8, (&[I64], &[])),
+        Instr::I32AtomicStore8(m) => (m, 1, (&[I32], &[])),
+        Instr::I32AtomicStore16(m) => (m, 2, (&[I32], &[])),
+        Instr::I64AtomicStore8(m) => (m, 1, (&[I64], &[])),
+        Instr::I64AtomicStore16(m) => (m, 2, (&[I64], &[])),
+        Instr::I64AtomicStore32(m) => (m, 4, (&[I64], &[])),
+        Instr::I64AtomicRmwAdd(m)
+        | Instr::I64AtomicRmwSub(m)
+        | Instr::I64AtomicRmwAnd(m)
+        | Instr::I64AtomicRmwOr(m)
+        | Instr::I64AtomicRmwXor(m)
+        | Instr::I64AtomicRmwXchg(m) => (m, 8, (&[I64], &[I64])),
+        Instr::I32AtomicRmw8AddU(m)
+        | Instr::I32AtomicRmw8SubU(m)
+        | Instr::I32AtomicRmw8AndU(m)
+        | Instr::I32AtomicRmw8OrU(m)
+        | Instr::I32AtomicRmw8XorU(m)
+        | Instr::I32AtomicRmw8XchgU(m) => (m, 1, (&[I32], &[I32])),
+        Instr::I32AtomicRmw16AddU(m)
+        | Instr::I32AtomicRmw16SubU(m)
+        | Instr::I32AtomicRmw16AndU(m)
+        | Instr::I32AtomicRmw16OrU(m)
+        | Instr::I32AtomicRmw16XorU(m)
+        | Instr::I32AtomicRmw16XchgU(m) => (m, 2, (&[I32], &[I32])),
+        Instr::I64AtomicRmw8AddU(m)
+        | Instr::I64AtomicRmw8SubU(m)
+        | Instr::I64AtomicRmw8AndU(m)
+        | Instr::I64AtomicRmw8OrU(m)
+        | Instr::I64AtomicRmw8XorU(m)
+        | Instr::I64AtomicRmw8XchgU(m) => (m, 1, (&[I64], &[I64])),
+        Instr::I64AtomicRmw16AddU(m)
+        | Instr::I64AtomicRmw16SubU(m)
+        | Instr::I64AtomicRmw16AndU(m)
+        | Instr::I64AtomicRmw16OrU(m)
+        | Instr::I64AtomicRmw16XorU(m)
+        | Instr::I64AtomicRmw16XchgU(m) => (m, 2, (&[I64], &[I64])),
+        Instr::I64AtomicRmw32AddU(m)
+        | Instr::I64AtomicRmw32SubU(m)
+        | Instr::I64AtomicRmw32AndU(m)
+        | Instr::I64AtomicRmw32OrU(m)
+        | Instr::I64AtomicRmw32XorU(m)
+        | Instr::I64AtomicRmw32XchgU(m) => (m, 4, (&[I64], &[I64])),
+        Instr::I32AtomicRmwCmpxchg(m) => (m, 4, (&[I32, I32], &[I32])),
+        Instr::I64AtomicRmwCmpxchg(m) => (m, 8, (&[I64, I64], &[I64])),
+        Instr::I32AtomicRmw8CmpxchgU(m) => (m, 1, (&[I32, I32], &[I32])),
+        Instr::I32AtomicRmw16CmpxchgU(m) => (m, 2, (&[I32, I32], &[I32])),
+        Instr::I64AtomicRmw8CmpxchgU(m) => (m, 1, (&[I64, I64], &[I64])),
+        Instr::I64AtomicRmw16CmpxchgU(m) => (m, 2, (&[I64, I64], &[I64])),
+        Instr::I64AtomicRmw32CmpxchgU(m) => (m, 4, (&[I64, I64], &[I64])),
+        _ => return None,
+    })
+}
+
+fn vector(instr: &Instr) -> Option<Signature> {
+    use ValType::*;
+    Some(match instr {
+        Instr::V128Const(_) => (&[], &[V128]),
+        Instr::I8x16Shuffle(_)
+        | Instr::I8x16Swizzle
+        | Instr::I8x16Eq
+        | Instr::I8x16Ne
+        | Instr::I8x16LtS
+        | Instr::I8x16LtU
+        | Instr::I8x16GtS
+        | Instr::I8x16GtU
+        | Instr::I8x16LeS
+        | Instr::I8x16LeU
+        | Instr::I8x16GeS
+        | Instr::I8x16GeU
+        | Instr::I16x8Eq
+        | Instr::I16x8Ne
+        | Instr::I16x8LtS
+        | Instr::I16x8LtU
+        | Instr::I16x8GtS
+        | Instr::I16x8GtU
+        | Instr::I16x8LeS
+        | Instr::I16x8LeU
+        | Instr::I16x8GeS
+        | Instr::I16x8GeU
+        | Instr::I32x4Eq
+        | Instr::I32x4Ne
+        | Instr::I32x4LtS
+        | Instr::I32x4LtU
+        | Instr::I32x4GtS
+        | Instr::I32x4GtU
+        | Instr::I32x4LeS
+        | Instr::I32x4LeU
+        | Instr::I32x4GeS
+        | Instr::I32x4GeU
+        | Instr::F32x4Eq
+        | Instr::F32x4Ne
+        | Instr::F32x4Lt
+        | Instr::F32x4Gt
+        | Instr::F32x4Le
+        | Instr::F32x4Ge
+        | Instr::F64x2Eq
+        | Instr::F64x2Ne
+        | Instr::F64x2Lt
+        | Instr::F64x2Gt
+        | Instr::F64x2Le
+        | Instr::F64x2Ge
+        | Instr::V128And
+        | Instr::V128Andnot
+        | Instr::V128Or
+        | Instr::V128Xor
+        | Instr::I8x16NarrowI16x8S
+        | Instr::I8x16NarrowI16x8U
+        | Instr::I8x16Add
+        | Instr::I8x16AddSatS
+        | Instr::I8x16AddSatU
+        | Instr::I8x16Sub
+        | Instr::I8x16SubSatS
+        | Instr::I8x16SubSatU
+        | Instr::I8x16MinS
+        | Instr::I8x16MinU
+        | Instr::I8x16MaxS
+        | Instr::I8x16MaxU
+        | Instr::I8x16AvgrU
+        | Instr::I16x8Q15mulrSatS
+        | Instr::I16x8NarrowI32x4S
+        | Instr::I16x8NarrowI32x4U
+        | Instr::I16x8Add
+        | Instr::I16x8AddSatS
+        | Instr::I16x8AddSatU
+        | Instr::I16x8Sub
+        | Instr::I16x8SubSatS
+        | Instr::I16x8SubSatU
+        | Instr::I16x8Mul
+        | Instr::I16x8MinS
+        | Instr::I16x8MinU
+        | Instr::I16x8MaxS
+        | Instr::I16x8MaxU
+        | Instr::I16x8AvgrU
+        | Instr::I16x8ExtmulLowI8x16S
+        | Instr::I16x8ExtmulHighI8x16S
+        | Instr::I16x8ExtmulLowI8x16U
+        | Instr::I16x8ExtmulHighI8x16U
+        | Instr::I32x4Add
+        | Instr::I32x4Sub
+        | Instr::I32x4Mul
+        | Instr::I32x4MinS
+        | Instr::I32x4MinU
+        | Instr::I32x4MaxS
+        | Instr::I32x4MaxU
+        | Instr::I32x4DotI16x8S
+        | Instr::I32x4ExtmulLowI16x8S
+        | Instr::I32x4ExtmulHighI16x8S
+        | Instr::I32x4ExtmulLowI16x8U
+        | Instr::I32x4ExtmulHighI16x8U
+        | Instr::I64x2Add
+        | Instr::I64x2Sub
+        | Instr::I64x2Mul
+        | Instr::I64x2Eq
+        | Instr::I64x2Ne
+        | Instr::I64x2LtS
+        | Instr::I64x2GtS
+        | Instr::I64x2LeS
+        | Instr::I64x2GeS
+        | Instr::I64x2ExtmulLowI32x4S
+        | Instr::I64x2ExtmulHighI32x4S
+        | Instr::I64x2ExtmulLowI32x4U
+        | Instr::I64x2ExtmulHighI32x4U
+        | Instr::F32x4Add
+        | Instr::F32x4Sub
+        | Instr::F32x4Mul
+        | Instr::F32x4Div
+        | Instr::F32x4Min
+        | Instr::F32x4Max
+        | Instr::F32x4Pmin
+        | Instr::F32x4Pmax
+        | Instr::F64x2Add
+        | Instr::F64x2Sub
+        | Instr::F64x2Mul
+        | Instr::F64x2Div
+        | Instr::F64x2Min
+        | Instr::F64x2Max
+        | Instr::F64x2Pmin
+        | Instr::F64x2Pmax => (&[V128, V128], &[V128]),
+        Instr::I8x16Splat | Instr::I16x8Splat | Instr::I32x4Splat => (&[I32], &[V128]),
+        Instr::I64x2Splat => (&[I64], &[V128]),
+        Instr::F32x4Splat => (&[F32], &[V128]),
+        Instr::F64x2Splat => (&[F64], &[V128]),
+        Instr::I8x16ExtractLaneS(_)
+        | Instr::I8x16ExtractLaneU(_)
+        | Instr::I16x8ExtractLaneS(_)
+        | Instr::I16x8ExtractLaneU(_)
+        | Instr::I32x4ExtractLane(_)
+        | Instr::V128AnyTrue
+        | Instr::I8x16AllTrue
+        | Instr::I8x16Bitmask
+        | Instr::I16x8AllTrue
+        | Instr::I16x8Bitmask
+        | Instr::I32x4AllTrue
+        | Instr::I32x4Bitmask
+        | Instr::I64x2AllTrue
+        | Instr::I64x2Bitmask => (&[V128], &[I32]),
+        Instr::I8x16ReplaceLane(_)
+        | Instr::I16x8ReplaceLane(_)
+        | Instr::I32x4ReplaceLane(_)
+        | Instr::I8x16Shl
+        | Instr::I8x16ShrS
+        | Instr::I8x16ShrU
+        | Instr::I16x8Shl
+        | Instr::I16x8ShrS
+        | Instr::I16x8ShrU
+        | Instr::I32x4Shl
+        | Instr::I32x4ShrS
+        | Instr::I32x4ShrU
+        | Instr::I64x2Shl
+        | Instr::I64x2ShrS
+        | Instr::I64x2ShrU => (&[V128, I32], &[V128]),
+        Instr::I64x2ExtractLane(_) => (&[V128], &[I64]),
+        Instr::I64x2ReplaceLane(_) => (&[V128, I64], &[V128]),
+        Instr::F32x4ExtractLane(_) => (&[V128], &[F32]),
+        Instr::F32x4ReplaceLane(_) => (&[V128, F32], &[V128]),
+        Instr::F64x2ExtractLane(_) => (&[V128], &[F64]),
+        Instr::F64x2ReplaceLane(_) => (&[V128, F64], &[V128]),
+        Instr::V128Not
+        | Instr::F32x4DemoteF64x2Zero
+        | Instr::F64x2PromoteLowF32x4
+        | Instr::I8x16Abs
+        | Instr::I8x16Neg
+        | Instr::I8x16Popcnt
+        | Instr::F32x4Ceil
+        | Instr::F32x4Floor
+        | Instr::F32x4Trunc
+        | Instr::F32x4Nearest
+        | Instr::F64x2Ceil
+        | Instr::F64x2Floor
+        | Instr::F64x2Trunc
+        | Instr::I16x8ExtaddPairwiseI8x16S
+        | Instr::I16x8ExtaddPairwiseI8x16U
+        | Instr::I32x4ExtaddPairwiseI16x8S
+        | Instr::I32x4ExtaddPairwiseI16x8U
+        | Instr::I16x8Abs
+        | Instr::I16x8Neg
+        | Instr::I16x8ExtendLowI8x16S
+        | Instr::I16x8ExtendHighI8x16S
+        | Instr::I16x8ExtendLowI8x16U
+        | Instr::I16x8ExtendHighI8x16U
+        | Instr::F64x2Nearest
+        | Instr::I32x4Abs
+        | Instr::I32x4Neg
+        | Instr::I32x4ExtendLowI16x8S
+        | Instr::I32x4ExtendHighI16x8S
+        | Instr::I32x4ExtendLowI16x8U
+        | Instr::I32x4ExtendHighI16x8U
+        | Instr::I64x2Abs
+        | Instr::I64x2Neg
+        | Instr::I64x2ExtendLowI32x4S
+        | Instr::I64x2ExtendHighI32x4S
+        | Instr::I64x2ExtendLowI32x4U
+        | Instr::I64x2ExtendHighI32x4U
+        | Instr::F32x4Abs
+        | Instr::F32x4Neg
+        | Instr::F32x4Sqrt
+        | Instr::F64x2Abs
+        | Instr::F64x2Neg
+        | Instr::F64x2Sqrt
+        | Instr::I32x4TruncSatF32x4S
+        | Instr::I32x4TruncSatF32x4U
+        | Instr::F32x4ConvertI32x4S
+        | Instr::F32x4ConvertI32x4U
+        | Instr::I32x4TruncSatF64x2SZero
+        | Instr::I32x4TruncSatF64x2UZero
+        | Instr::F64x2ConvertLowI32x4S
+        | Instr::F64x2ConvertLowI32x4U => (&[V128], &[V128]),
+        Instr::V128Bitselect => (&[V128, V128, V128], &[V128]),
+        _ => return None,
+    })
+}
+
+/// Returns the lane index and the number of lanes of a lane instruction.
+fn lane_index(instr: &Instr) -> Option<(u8, u8)> {
+    Some(match instr {
+        Instr::I8x16ExtractLaneS(l) => (*l, 16),
+        Instr::I8x16ExtractLaneU(l) => (*l, 16),
+        Instr::I8x16ReplaceLane(l) => (*l, 16),
+        Instr::I16x8ExtractLaneS(l) => (*l, 8),
+        Instr::I16x8ExtractLaneU(l) => (*l, 8),
+        Instr::I16x8ReplaceLane(l) => (*l, 8),
+        Instr::I32x4ExtractLane(l) => (*l, 4),
+        Instr::I32x4ReplaceLane(l) => (*l, 4),
+        Instr::I64x2ExtractLane(l) => (*l, 2),
+        Instr::I64x2ReplaceLane(l) => (*l, 2),
+        Instr::F32x4ExtractLane(l) => (*l, 4),
+        Instr::F32x4ReplaceLane(l) => (*l, 4),
+        Instr::F64x2ExtractLane(l) => (*l, 2),
+        Instr::F64x2ReplaceLane(l) => (*l, 2),
+        _ => return None,
+    })
+}
+
+fn limits(limits: &Limits, bound: u64) -> Result<(), ValidationError> {
+    if !limits.valid() {
+        return Err(ValidationError::InvalidLimits);
+    }
+    if limits.min() > bound || limits.max().map_or(false, |max| max > bound) {
+        return Err(ValidationError::MemorySizeLimit);
+    }
+    Ok(())
+}
+
+fn memory(mem: &Memory) -> Result<(), ValidationError> {
+    if mem.shared && mem.limits.max().is_none() {
+        return Err(ValidationError::SharedMemoryMaximum);
+    }
+    match mem.idxtype {
+        IdxType::I32 => limits(&mem.limits, MAX_PAGES),
+        IdxType::I64 => limits(&mem.limits, MAX_PAGES_64).map_err(|err| match err {
+            ValidationError::MemorySizeLimit => ValidationError::Memory64SizeLimit,
+            err => err,
+        }),
+    }
+}
+
+fn table(table: &Table) -> Result<(), ValidationError> {
+    limits(&table.limits, u32::MAX as u64)
+}
+
+/// Checks that `expr` is constant in `ctx` and evaluates to a single value of type `t`.
+fn const_expr(ctx: &Context, expr: &Expr, t: ValType) -> Result<(), ValidationError> {
+    for instr in expr.0.iter() {
+        match instr {
+            Instr::I32Const(_)
+            | Instr::I64Const(_)
+            | Instr::F32Const(_)
+            | Instr::F64Const(_)
+            | Instr::V128Const(_)
+            | Instr::RefNull(_)
+            | Instr::RefFunc(_)
+            | Instr::I32Add
+            | Instr::I32Sub
+            | Instr::I32Mul
+            | Instr::I64Add
+            | Instr::I64Sub
+            | Instr::I64Mul
+            | Instr::StructNew(_)
+            | Instr::StructNewDefault(_)
+            | Instr::ArrayNew(_)
+            | Instr::ArrayNewDefault(_)
+            | Instr::ArrayNewFixed(..)
+            | Instr::RefI31
+            | Instr::AnyConvertExtern
+            | Instr::ExternConvertAny => {}
+            Instr::GlobalGet(x) => {
+                if ctx.global(*x)?.mut_ != Mut::Const {
+                    return Err(ValidationError::ConstantExpressionRequired);
+                }
+            }
+            _ => return Err(ValidationError::ConstantExpressionRequired),
+        }
+    }
+    FuncValidator::new(ctx, &[], &[], vec![t]).validate(&expr.0)
+}
+
+fn collect_refs(expr: &Expr, refs: &mut Vec<FuncIdx>) {
+    for instr in expr.0.iter() {
+        if let Instr::RefFunc(x) = instr {
+            refs.push(*x);
+        }
+    }
+}
+
+/// Validates `module` according to the WebAssembly specification.
+pub fn validate(module: &Module) -> Result<(), ValidationError> {
+    let mut ctx = Context {
+        types: &module.types,
+        funcs: vec![],
+        tables: vec![],
+        mems: vec![],
+        globals: vec![],
+        tags: vec![],
+        elems: module.elems.iter().map(|e| e.type_.into()).collect(),
+        data_count: module.data_count,
+        refs: vec![],
+    };
+
+    for idx in 0..module.types.len() {
+        ctx.subtype(idx as TypeIdx)?;
+    }
+
+    for import in module.imports.iter() {
+        match &import.desc {
+            ImportDesc::Func(x) => {
+                ctx.functype(*x)?;
+                ctx.funcs.push(*x);
+            }
+            ImportDesc::Table(t) => {
+                table(t)?;
+                ctx.tables.push(t);
+            }
+            ImportDesc::Mem(m) => {
+                memory(m)?;
+                ctx.mems.push(m);
+            }
+            ImportDesc::Global(g) => ctx.globals.push(g),
+            ImportDesc::Tag(t) => ctx.tags.push(t.typeidx),
+        }
+    }
+
+    for func in module.funcs.iter() {
+        ctx.functype(func.typeidx)?;
+        ctx.funcs.push(func.typeidx);
+    }
+
+    for t in module.tables.iter() {
+        table(t)?;
+        ctx.tables.push(t);
+    }
+
+    for m in module.mems.iter() {
+        memory(m)?;
+        ctx.mems.push(m);
+    }
+
+    for t in module.tags.iter() {
+        ctx.tags.push(t.typeidx);
+    }
+    for &typeidx in ctx.tags.iter() {
+        if !ctx.functype(typeidx)?.1 .0.is_empty() {
+            return Err(ValidationError::NonEmptyTagResult);
+        }
+    }
+
+    for global in module.globals.iter() {
+        collect_refs(&global.value, &mut ctx.refs);
+    }
+    for elem in module.elems.iter() {
+        for init in elem.init.iter() {
+            collect_refs(init, &mut ctx.refs);
+        }
+    }
+    for export in module.exports.iter() {
+        if let ExportDesc::Func(x) = export.desc {
+            ctx.refs.push(x);
+        }
+    }
+
+    // Global initializers may refer to imported and previously defined globals.
+    for global in module.globals.iter() {
+        const_expr(&ctx, &global.value, global.type_.valtype)?;
+        ctx.globals.push(&global.type_);
+    }
+
+    for elem in module.elems.iter() {
+        let t = elem.type_.into();
+        for init in elem.init.iter() {
+            const_expr(&ctx, init, t)?;
+        }
+        if let ElemMode::Active { tableidx, offset } = &elem.mode {
+            let table_type: ValType = ctx.table(*tableidx)?.reftype.into();
+            if !ctx.matches(t, table_type) {
+                return Err(ValidationError::TypeMismatch);
+            }
+            const_expr(&ctx, offset, ValType::I32)?;
+        }
+    }
+
+    for data in module.datas.iter() {
+        if let DataMode::Active { memidx, offset } = &data.mode {
+            const_expr(&ctx, offset, ctx.addrtype(*memidx)?)?;
+        }
+    }
+
+    for func in module.funcs.iter() {
+        let FuncType(params, results) = ctx.functype(func.typeidx)?;
+        for t in func.locals.iter() {
+            ctx.valtype(*t)?;
+        }
+        FuncValidator::new(&ctx, &params.0, &func.locals, results.0.clone())
+            .validate(&func.body.0)?;
+    }
+
+    if let Some(start) = module.start {
+        let FuncType(params, results) = ctx.func(start)?;
+        if !params.0.is_empty() || !results.0.is_empty() {
+            return Err(ValidationError::InvalidStartFunction);
+        }
+    }
+
+    let mut names: Vec<&str> = vec![];
+    for export in module.exports.iter() {
+        match export.desc {
+            ExportDesc::Func(x) => {
+                ctx.func(x)?;
+            }
+            ExportDesc::Table(x) => {
+                ctx.table(x)?;
+            }
+            ExportDesc::Mem(x) => {
+                ctx.mem(x)?;
+            }
+            ExportDesc::Global(x) => {
+                ctx.global(x)?;
+            }
+            ExportDesc::Tag(x) => {
+                ctx.tag(x)?;
+            }
+        }
+        if names.contains(&export.name.as_str()) {
+            return Err(ValidationError::DuplicateExportName(export.name.clone()));
+        }
+        names.push(&export.name);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{validate, ValidationError};
+    use crate::loader::parse;
+    use crate::tests::wat2wasm;
+
+    fn check(wat: &str) -> Result<(), ValidationError> {
+        validate(&parse(&wat2wasm(wat).unwrap()).unwrap())
+    }
+
+    #[test]
+    fn valid_module() {
+        assert_eq!(
+            check(
+                r#"(module
+                    (memory 1)
+                    (table 2 funcref)
+                    (global $g (mut i32) (i32.const 0))
+                    (func $f (param i32) (result i32)
+                        (block (result i32)
+                            (if (result i32) (local.get 0)
+                                (then (i32.const 1))
+                                (else (br 1 (i32.const 2)))))
+                        (loop $l (br_if $l (i32.const 0)))
+                        (i32.load (i32.const 0))
+                        i32.add)
+                    (func (export "g") (param i32 i32)
+                        (global.set $g (select (local.get 0) (local.get 1) (i32.const 1)))
+                        (drop (call_indirect (param i32) (result i32) (i32.const 0) (i32.const 0)))
+                        (drop (ref.func $f)))
+                    (elem (i32.const 0) $f))"#
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn constant_expressions() {
+        assert_eq!(
+            check(
+                r#"(module
+                    (import "env" "base" (global $base i32))
+                    (global $a i32 (i32.add (global.get $base) (i32.const 1)))
+                    (global $b i32 (i32.mul (global.get $a) (i32.const 2)))
+                    (memory 1)
+                    (data (offset (i32.sub (global.get $b) (i32.const 1))) "x"))"#
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            check(
+                r#"(module
+                    (type $pair (struct (field i32) (field i64)))
+                    (type $bytes (array i8))
+                    (global (ref $pair) (struct.new $pair (i32.const 1) (i64.const 2)))
+                    (global (ref $pair) (struct.new_default $pair))
+                    (global (ref $bytes) (array.new $bytes (i32.const 1) (i32.const 2)))
+                    (global (ref $bytes) (array.new_default $bytes (i32.const 2)))
+                    (global (ref $bytes) (array.new_fixed $bytes 2 (i32.const 1) (i32.const 2)))
+                    (global $i31 (ref i31) (ref.i31 (i32.const 1)))
+                    (global (ref extern) (extern.convert_any (global.get $i31)))
+                    (global (ref null any) (any.convert_extern (ref.null extern))))"#
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            check(r#"(module (global (ref any) (any.convert_extern (ref.null extern))))"#),
+            Err(ValidationError::TypeMismatch)
+        );
+        assert_eq!(
+            check(r#"(module (global i32 (i32.div_s (i32.const 4) (i32.const 2))))"#),
+            Err(ValidationError::ConstantExpressionRequired)
+        );
+        assert_eq!(
+            check(
+                r#"(module
+                    (global $g (mut i32) (i32.const 0))
+                    (global i32 (global.get $g)))"#
+            ),
+            Err(ValidationError::ConstantExpressionRequired)
+        );
+    }
+
+    #[test]
+    fn unreachable_is_polymorphic() {
+        assert_eq!(
+            check(r#"(module (func (result i32 i64) unreachable i32.add drop i64.const 0))"#),
+            Ok(())
+        );
+        assert_eq!(
+            check(r#"(module (func unreachable (i64.const 0) i32.add drop))"#),
+            Err(ValidationError::TypeMismatch)
+        );
+        assert_eq!(
+            check(r#"(module (func (result i32) (block (br 0)) unreachable))"#),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn block_params() {
+        assert_eq!(
+            check(r#"(module (func (i32.const 1) (block (param i32) drop)))"#),
+            Ok(())
+        );
+        assert_eq!(
+            check(
+                r#"(module (func (result i32)
+                    (i32.const 1) (i32.const 2)
+                    (block (param i32 i32) (result i32) i32.add)))"#
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            check(
+                r#"(module (func (param i32) (result i32)
+                    (i32.const 10)
+                    (loop $l (param i32) (result i32)
+                        (i32.sub (i32.const 1))
+                        (br_if $l (local.tee 0 (i32.sub (local.get 0) (i32.const 1))))
+                        (br 0))))"#
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            check(
+                r#"(module (func (result i32)
+                    (i32.const 1)
+                    (if (param i32) (result i32) (i32.const 0)
+                        (then (i32.add (i32.const 1)))
+                        (else (i32.sub (i32.const 1))))))"#
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            check(r#"(module (func (i64.const 1) (block (param i32) drop)))"#),
+            Err(ValidationError::TypeMismatch)
+        );
+    }
+
+    #[test]
+    fn br_table() {
+        // Unknown operands match the types of every label.
+        assert_eq!(
+            check(
+                r#"(module (func
+                    (block (result f64)
+                        (block (result f32)
+                            unreachable
+                            (br_table 0 1 1 (i32.const 1)))
+                        drop
+                        (f64.const 0))
+                    drop))"#
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            check(
+                r#"(module (func
+                    (block (result f64)
+                        (block (result f32)
+                            (f32.const 0)
+                            (br_table 0 1 (i32.const 1)))
+                        drop
+                        (f64.const 0))
+                    drop))"#
+            ),
+            Err(ValidationError::TypeMismatch)
+        );
+        assert_eq!(
+            check(
+                r#"(module (func
+                    (block (result i32)
+                        (block
+                            unreachable
+                            (br_table 0 1 (i32.const 1)))
+                        (i32.const 0))
+                    drop))"#
+            ),
+            Err(ValidationError::TypeMismatch)
+        );
+    }
+
+    #[test]
+    fn type_mismatch() {
+        assert_eq!(
+            check(r#"(module (func (result i32) (i64.const 0)))"#),
+            Err(ValidationError::TypeMismatch)
+        );
+        assert_eq!(
+            check(r#"(module (func (if (i32.const 1) (then (i32.const 1)))))"#),
+            Err(ValidationError::TypeMismatch)
+        );
+        assert_eq!(
+            check(
+                r#"(module (func (result i32) (if (result i32) (i32.const 1) (then (i32.const 1)))))"#
+            ),
+            Err(ValidationError::TypeMismatch)
+        );
+        assert_eq!(
+            check(
+                r#"(module (func $f (result i64) (i64.const 0)) (func (result i32) (return_call $f)))"#
+            ),
+            Err(ValidationError::TypeMismatch)
+        );
+    }
+
+    #[test]
+    fn vector() {
+        assert_eq!(
+            check(
+                r#"(module (memory 1) (func (result i32)
+                    (i32x4.extract_lane 3
+                        (i32x4.add (v128.load (i32.const 0)) (i32x4.splat (i32.const 1))))))"#
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            check(r#"(module (func (drop (i32x4.add (v128.const i64x2 0 0) (i32.const 0)))))"#),
+            Err(ValidationError::TypeMismatch)
+        );
+        assert_eq!(
+            check(r#"(module (memory 1) (func (drop (v128.load32_zero align=8 (i32.const 0)))))"#),
+            Err(ValidationError::InvalidAlignment)
+        );
+        assert_eq!(
+            check(r#"(module (func (drop (i32x4.extract_lane 4 (v128.const i64x2 0 0)))))"#),
+            Err(ValidationError::InvalidLaneIndex)
+        );
+    }
+
+    #[test]
+    fn select() {
+        assert_eq!(
+            check(
+                r#"(module (func (param externref) (result externref)
+                    (select (result externref) (local.get 0) (ref.null extern) (i32.const 1))))"#
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            check(
+                r#"(module (func (drop (select (ref.null func) (ref.null func) (i32.const 1)))))"#
+            ),
+            Err(ValidationError::TypeMismatch)
+        );
+        assert_eq!(
+            check(
+                r#"(module (func (drop (select (result i32 i32)
+                    (i32.const 0) (i32.const 0) (i32.const 1)))))"#
+            ),
+            Err(ValidationError::InvalidResultArity)
+        );
+    }
+
+    #[test]
+    fn exceptions() {
+        assert_eq!(
+            check(
+                r#"(module (tag $e (param i32)) (func (result i32)
+                    (block $h (result i32 exnref)
+                        (try_table (catch_ref $e $h) (throw $e (i32.const 1)))
+                        (unreachable))
+                    (drop)))"#
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            check(
+                r#"(module (tag $e (param i32)) (func
+                    (block $h (try_table (catch $e $h) (nop)))))"#
+            ),
+            Err(ValidationError::TypeMismatch)
+        );
+        assert_eq!(
+            check(r#"(module (func (throw 0)))"#),
+            Err(ValidationError::UnknownTag(0))
+        );
+    }
+
+    #[test]
+    fn gc() {
+        assert_eq!(
+            check(
+                r#"(module
+                    (type $fn (func (param i32) (result i32)))
+                    (type $base (sub (struct (field (mut i32)))))
+                    (type $derived (sub $base (struct (field (mut i32)) (field i8))))
+                    (type $bytes (array (mut i8)))
+                    (elem declare func $f)
+                    (func $f (type $fn) (local.get 0))
+                    (func (param (ref null $base)) (result i32)
+                        (local $d (ref $derived))
+                        (local.set $d (struct.new $derived (i32.const 1) (i32.const 2)))
+                        (drop (ref.cast (ref $derived) (local.get 0)))
+                        (struct.set $base 0 (local.get $d) (i32.const 3))
+                        (drop (array.len (array.new_default $bytes (i32.const 4))))
+                        (drop (ref.eq (local.get $d) (ref.i31 (i32.const 5))))
+                        (block $null (result (ref $base))
+                            (br_on_non_null $null (local.get 0))
+                            (local.get $d))
+                        (drop)
+                        (call_ref $fn (struct.get_u $derived 1 (local.get $d)) (ref.func $f))))"#
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            check(
+                r#"(module (type $s (struct (field i32)))
+                    (func (param (ref $s)) (struct.set $s 0 (local.get 0) (i32.const 0))))"#
+            ),
+            Err(ValidationError::ImmutableField)
+        );
+        assert_eq!(
+            check(
+                r#"(module (table 1 funcref) (func $f)
+                    (elem (table 0) (i32.const 0) (ref func) (ref.func $f)))"#
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            check(
+                r#"(module (table 1 (ref null $t)) (type $t (func))
+                    (elem (table 0) (i32.const 0) funcref (ref.null func)))"#
+            ),
+            Err(ValidationError::TypeMismatch)
+        );
+        assert_eq!(
+            check(
+                r#"(module (type $s (struct)) (func $g (param (ref $s)))
+                    (func (call $g (ref.null $s))))"#
+            ),
+            Err(ValidationError::TypeMismatch)
+        );
+        assert_eq!(
+            check(
+                r#"(module (type $s (struct))
+                    (func (local (ref $s)) (block (local.set 0 (struct.new $s)))
+                        (drop (local.get 0))))"#
+            ),
+            Err(ValidationError::UninitializedLocal(0))
+        );
+        assert_eq!(
+            check(r#"(module (type $a (struct)) (type $b (sub $a (struct))))"#),
+            Err(ValidationError::InvalidSubtype(1))
+        );
+    }
+
+    #[test]
+    fn memory64() {
+        assert_eq!(
+            check(
+                r#"(module (memory i64 1) (func (result i64)
+                    (i64.store offset=0x100000000 (i64.const 0) (i64.const 1))
+                    (memory.grow (memory.size))))"#
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            check(r#"(module (memory i64 1) (func (drop (i32.load (i32.const 0)))))"#),
+            Err(ValidationError::TypeMismatch)
+        );
+        assert_eq!(
+            check(r#"(module (memory i64 0x1000000000000 0x1000000000001))"#),
+            Err(ValidationError::Memory64SizeLimit)
+        );
+    }
+
+    #[test]
+    fn atomics() {
+        assert_eq!(
+            check(
+                r#"(module (memory 1 1 shared) (func (result i32)
+                    (atomic.fence)
+                    (drop (i64.atomic.rmw32.cmpxchg_u (i32.const 0) (i64.const 0) (i64.const 1)))
+                    (memory.atomic.wait64 (i32.const 0) (i64.const 0) (i64.const -1))))"#
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            check(r#"(module (memory 1 1) (func (drop (i32.atomic.load align=2 (i32.const 0)))))"#),
+            Err(ValidationError::InvalidAtomicAlignment)
+        );
+        assert_eq!(
+            check(r#"(module (memory 1 shared))"#),
+            Err(ValidationError::SharedMemoryMaximum)
+        );
+    }
+
+    #[test]
+    fn unknown_index() {
+        assert_eq!(
+            check(r#"(module (func (local.get 0) drop))"#),
+            Err(ValidationError::UnknownLocal(0))
+        );
+        assert_eq!(
+            check(r#"(module (func (call 3)))"#),
+            Err(ValidationError::UnknownFunc(3))
+        );
+        assert_eq!(
+            check(r#"(module (func (drop (i32.load (i32.const 0)))))"#),
+            Err(ValidationError::UnknownMemory(0))
+        );
+    }
+
+    #[test]
+    fn module_level() {
+        assert_eq!(
+            check(r#"(module (global i32 (i32.const 0)) (func (global.set 0 (i32.const 1))))"#),
+            Err(ValidationError::GlobalIsImmutable)
+        );
+        assert_eq!(
+            check(r#"(module (func (param i32)) (start 0))"#),
+            Err(ValidationError::InvalidStartFunction)
+        );
+        assert_eq!(
+            check(r#"(module (func) (export "a" (func 0)) (export "a" (func 0)))"#),
+            Err(ValidationError::DuplicateExportName("a".into()))
+        );
+        assert_eq!(
+            check(r#"(module (global i32 (global.get 1)) (global i32 (i32.const 0)))"#),
+            Err(ValidationError::UnknownGlobal(1))
+        );
+        assert_eq!(
+            check(r#"(module (func (drop (ref.func 0))))"#),
+            Err(ValidationError::UndeclaredFuncRef(0))
+        );
+    }
+}
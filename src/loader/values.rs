@@ -1,7 +1,11 @@
 #[cfg(not(feature = "std"))]
 use crate::lib::*;
 
-use super::{error::Error, leb128::*, parser::Parser};
+use super::{
+    error::{Error, ErrorKind},
+    leb128::*,
+    parser::Parser,
+};
 
 impl<'a> Parser<'a> {
     pub fn byte(&mut self) -> Option<u8> {
@@ -9,31 +13,31 @@ impl<'a> Parser<'a> {
     }
 
     pub fn u32(&mut self) -> Result<u32, Error> {
-        let (value, bytes) = u32::read_leb128(self.rest())?;
+        let (value, bytes) = u32::read_leb128(self.rest()).map_err(|kind| self.error(kind))?;
         self.skip(bytes);
         Ok(value)
     }
 
     pub fn u32_bytes(&mut self) -> Result<(u32, usize), Error> {
-        let (value, bytes) = u32::read_leb128(self.rest())?;
+        let (value, bytes) = u32::read_leb128(self.rest()).map_err(|kind| self.error(kind))?;
         self.skip(bytes);
         Ok((value, bytes))
     }
 
     pub fn s32(&mut self) -> Result<i32, Error> {
-        let (value, bytes) = i32::read_leb128(self.rest())?;
+        let (value, bytes) = i32::read_leb128(self.rest()).map_err(|kind| self.error(kind))?;
         self.skip(bytes);
         Ok(value)
     }
 
     pub fn u64(&mut self) -> Result<u64, Error> {
-        let (value, bytes) = u64::read_leb128(self.rest())?;
+        let (value, bytes) = u64::read_leb128(self.rest()).map_err(|kind| self.error(kind))?;
         self.skip(bytes);
         Ok(value)
     }
 
     pub fn s64(&mut self) -> Result<i64, Error> {
-        let (value, bytes) = i64::read_leb128(self.rest())?;
+        let (value, bytes) = i64::read_leb128(self.rest()).map_err(|kind| self.error(kind))?;
         self.skip(bytes);
         Ok(value)
     }
@@ -52,7 +56,7 @@ impl<'a> Parser<'a> {
             self.skip(4);
             Ok(f32::from_le_bytes(bytes))
         } else {
-            Err(Error::UnexpectedEof(format!("f32")))
+            Err(self.error(ErrorKind::UnexpectedEof(format!("f32"))))
         }
     }
 
@@ -62,20 +66,37 @@ impl<'a> Parser<'a> {
             self.skip(8);
             Ok(f64::from_le_bytes(bytes))
         } else {
-            Err(Error::UnexpectedEof(format!("f64")))
+            Err(self.error(ErrorKind::UnexpectedEof(format!("f64"))))
         }
     }
 
+    pub fn v128(&mut self) -> Result<u128, Error> {
+        if self.rest().len() >= 16 {
+            let bytes: [u8; 16] = self.rest()[0..16].try_into().unwrap();
+            self.skip(16);
+            Ok(u128::from_le_bytes(bytes))
+        } else {
+            Err(self.error(ErrorKind::UnexpectedEof(format!("v128"))))
+        }
+    }
+
+    pub fn laneidx(&mut self) -> Result<u8, Error> {
+        self.byte()
+            .ok_or_else(|| self.error(ErrorKind::UnexpectedEof(format!("laneidx"))))
+    }
+
     pub fn name(&mut self) -> Result<String, Error> {
         let byte = |self_: &mut Self| {
-            self_
-                .byte()
-                .ok_or(Error::UnexpectedEof(format!("part of utf8-encoded bytes")))
+            self_.byte().ok_or_else(|| {
+                self_.error(ErrorKind::UnexpectedEof(format!(
+                    "part of utf8-encoded bytes"
+                )))
+            })
         };
         let name = self.vec(byte)?;
         Ok(core::str::from_utf8(&name)
             .and_then(|v| Ok(v.to_string()))
-            .map_err(|e| Error::InvalidUtf8(e))?)
+            .map_err(|e| self.error(ErrorKind::InvalidUtf8(e)))?)
     }
 }
 
@@ -1,24 +1,573 @@
-// use std::{env, fs::File, io::Read};
-//
-// use wasp::loader::parser::Parser;
+use std::io::{self, BufRead, Write};
+#[cfg(feature = "gdb")]
+use std::net::TcpListener;
+use std::{env, fs, process};
+
+use wasper::binary::ValType;
+use wasper::debug::debugger::{Debugger, Stop};
+#[cfg(feature = "gdb")]
+use wasper::debug::gdb::{Exit, GdbServer};
+use wasper::exec::env::{DebugEnv, Env};
+use wasper::exec::importer::default::DefaultImporter;
+use wasper::exec::runtime::{Runtime, RuntimeError};
+use wasper::exec::store::Store;
+use wasper::exec::trap::Trap;
+use wasper::exec::value::{Ref, Value};
+use wasper::exec::wasi::{WasiEnv, WASI_MODULE};
+use wasper::loader::{parse, validate};
+
+const USAGE: &str = "\
+usage: wasper [debug | gdb <port>] [--dir <dir>]... [--env <key>[=<value>]]...
+              <file.wasm> [--invoke <name>] [args...]
+
+Runs the start function of the module, or the exported function <name>
+when --invoke is given. Arguments are parsed according to the parameter
+types of the function, v128 values as a 0x-prefixed hex number, and
+each result is printed on its own line.
+
+Modules importing wasi_snapshot_preview1 run their `_start` export with
+args passed as program arguments. --dir makes a host directory
+available to the guest under the same path. --env sets an environment
+variable for the guest, forwarding the host's value when no value is
+given; no other host variables are visible.
+
+With debug, the function (`_start` unless --invoke is given) runs under
+an interactive debugger reading commands from stdin. Type `help` for a
+list of commands. With gdb, built with the `gdb` feature, it is debugged
+by a GDB or LLDB client connecting to <port> on localhost instead, or
+talking the remote protocol over stdin and stdout when <port> is `-`.
+Output of WASI guests then goes to stderr, and modules importing from
+`env`, whose functions print to stdout, cannot be debugged this way.
+
+exit status:
+    0  success
+    1  usage or I/O error
+    2  the module is malformed or invalid
+    3  instantiation or invocation failed
+    4  execution trapped";
+
+const EXIT_USAGE: i32 = 1;
+const EXIT_INVALID: i32 = 2;
+const EXIT_RUNTIME: i32 = 3;
+const EXIT_TRAP: i32 = 4;
+
+/// Name of the module whose imports are provided by `DebugEnv`.
+const ENV_NAME: &str = "env";
+
+#[derive(PartialEq)]
+enum Mode {
+    Run,
+    Debug,
+    /// Serve the GDB remote protocol on a port or, for `-`, on stdio.
+    #[cfg(feature = "gdb")]
+    Gdb(String),
+}
+
+struct Options {
+    mode: Mode,
+    dirs: Vec<String>,
+    envs: Vec<(String, String)>,
+    path: String,
+    invoke: Option<String>,
+    args: Vec<String>,
+}
+
+fn parse_options(args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut args = args.peekable();
+    let mode = match args.peek().map(String::as_str) {
+        Some("debug") => {
+            args.next();
+            Mode::Debug
+        }
+        #[cfg(feature = "gdb")]
+        Some("gdb") => {
+            args.next();
+            Mode::Gdb(args.next().ok_or("gdb requires a port")?)
+        }
+        _ => Mode::Run,
+    };
+    let mut dirs = vec![];
+    let mut envs = vec![];
+    let path = loop {
+        match args.next() {
+            Some(flag) if flag == "--dir" => dirs.push(args.next().ok_or("--dir requires a path")?),
+            Some(flag) if flag == "--env" => {
+                let var = args.next().ok_or("--env requires a variable")?;
+                match var.split_once('=') {
+                    Some((key, value)) => envs.push((key.into(), value.into())),
+                    None => {
+                        if let Ok(value) = env::var(&var) {
+                            envs.push((var, value));
+                        }
+                    }
+                }
+            }
+            Some(path) => break path,
+            None => return Err("no input files".into()),
+        }
+    };
+    let mut args = args.peekable();
+    let invoke = match args.peek() {
+        Some(flag) if flag == "--invoke" => {
+            args.next();
+            Some(args.next().ok_or("--invoke requires a function name")?)
+        }
+        _ => None,
+    };
+    Ok(Options {
+        mode,
+        dirs,
+        envs,
+        path,
+        invoke,
+        args: args.collect(),
+    })
+}
+
+fn parse_int<T>(
+    arg: &str,
+    from_signed: fn(&str) -> Option<T>,
+    from_unsigned: fn(&str, u32) -> Option<T>,
+) -> Option<T> {
+    if let Some(hex) = arg.strip_prefix("0x") {
+        from_unsigned(hex, 16)
+    } else {
+        from_signed(arg).or_else(|| from_unsigned(arg, 10))
+    }
+}
+
+fn parse_arg(arg: &str, ty: &ValType) -> Result<Value, String> {
+    let value = match ty {
+        ValType::I32 => parse_int(
+            arg,
+            |s| s.parse::<i32>().ok(),
+            |s, radix| u32::from_str_radix(s, radix).ok().map(|v| v as i32),
+        )
+        .map(Value::I32),
+        ValType::I64 => parse_int(
+            arg,
+            |s| s.parse::<i64>().ok(),
+            |s, radix| u64::from_str_radix(s, radix).ok().map(|v| v as i64),
+        )
+        .map(Value::I64),
+        ValType::F32 => arg.parse::<f32>().ok().map(Value::F32),
+        ValType::F64 => arg.parse::<f64>().ok().map(Value::F64),
+        ValType::V128 => arg
+            .strip_prefix("0x")
+            .and_then(|hex| u128::from_str_radix(hex, 16).ok())
+            .map(Value::V128),
+        ValType::FuncRef | ValType::ExternRef if arg == "null" => Some(Value::Ref(Ref::Null)),
+        ValType::Ref { nullable: true, .. } if arg == "null" => Some(Value::Ref(Ref::Null)),
+        ValType::FuncRef | ValType::ExternRef | ValType::ExnRef | ValType::Ref { .. } => None,
+    };
+    value.ok_or_else(|| format!("invalid argument for {:?}: {}", ty, arg))
+}
+
+fn format_value(value: &Value) -> String {
+    match value {
+        Value::I32(v) => format!("{}", v),
+        Value::I64(v) => format!("{}", v),
+        Value::F32(v) => format!("{}", v),
+        Value::F64(v) => format!("{}", v),
+        Value::V128(v) => format!("0x{:032x}", v),
+        Value::Ref(Ref::Null) => "null".to_string(),
+        Value::Ref(Ref::Func(addr)) => format!("funcref:{}", addr),
+        Value::Ref(Ref::Extern(addr)) => format!("externref:{}", addr),
+        Value::Ref(Ref::Exn(addr)) => format!("exnref:{}", addr),
+        Value::Ref(Ref::Struct(addr)) => format!("structref:{}", addr),
+        Value::Ref(Ref::Array(addr)) => format!("arrayref:{}", addr),
+        Value::Ref(Ref::I31(v)) => format!("i31ref:{}", v),
+    }
+}
+
+fn exit_with(err: RuntimeError) -> ! {
+    match err {
+        RuntimeError::Trap(trap, backtrace) => {
+            eprintln!("error: trap: {}", trap);
+            if !backtrace.frames.is_empty() {
+                eprintln!("{}", backtrace);
+            }
+            process::exit(EXIT_TRAP)
+        }
+        err => {
+            eprintln!("error: {}", err);
+            process::exit(EXIT_RUNTIME)
+        }
+    }
+}
+
+fn fail(message: &str, code: i32) -> ! {
+    eprintln!("error: {}", message);
+    process::exit(code)
+}
+
+/// Parses `args` as the parameters of the exported function `name`.
+fn params(runtime: &Runtime, store: &Store, name: &str, args: &[String]) -> Vec<Value> {
+    let functype = runtime
+        .export_functype(store, name)
+        .unwrap_or_else(|| fail(&format!("function not found: {}", name), EXIT_RUNTIME));
+    let params = &functype.0 .0;
+    if params.len() != args.len() {
+        fail(
+            &format!(
+                "{} expects {} argument(s), {} given",
+                name,
+                params.len(),
+                args.len()
+            ),
+            EXIT_USAGE,
+        );
+    }
+    args.iter()
+        .zip(params.iter())
+        .map(|(arg, ty)| parse_arg(arg, ty))
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap_or_else(|message| fail(&message, EXIT_USAGE))
+}
+
+fn invoke<E: Env>(
+    runtime: &mut Runtime,
+    store: &mut Store,
+    env: &mut E,
+    name: &str,
+    args: &[String],
+) -> Result<Vec<Value>, RuntimeError> {
+    let params = params(runtime, store, name, args);
+    runtime.invoke(store, env, name, params)
+}
+
+const DEBUG_HELP: &str = "\
+commands:
+    b <func> [offset]   set a breakpoint, at the start of <func> by default
+    d <func> [offset]   delete a breakpoint
+    w <addr> [len]      stop when <len> bytes (default 4) of memory 0 change
+    dw <addr>           delete a watchpoint
+    s                   step one instruction, into calls
+    n                   step one instruction, over calls
+    o                   run until the current function returns
+    c                   continue to the next breakpoint or watchpoint
+    locals              print the locals of the current function
+    stack               print the value stack of the current function
+    g <idx>             print a global
+    x <addr> [len]      dump <len> bytes (default 16) of memory 0
+    bt                  print the call stack
+    q                   quit";
+
+fn parse_num<T: TryFrom<u64>>(arg: Option<&str>, default: Option<T>) -> Result<T, String> {
+    let arg = match (arg, default) {
+        (Some(arg), _) => arg,
+        (None, Some(default)) => return Ok(default),
+        (None, None) => return Err("missing argument".into()),
+    };
+    parse_int(
+        arg,
+        |s| s.parse::<u64>().ok(),
+        |s, radix| u64::from_str_radix(s, radix).ok(),
+    )
+    .and_then(|n| T::try_from(n).ok())
+    .ok_or_else(|| format!("invalid number: {}", arg))
+}
+
+fn print_position<E: Env>(debugger: &Debugger<E>) {
+    if let Some(frame) = debugger.backtrace().frames.first() {
+        let instr = &debugger.runtime.instrs[debugger.runtime.pc];
+        println!("{}: {:?}", frame, instr);
+    }
+}
+
+fn print_stop<E: Env>(debugger: &Debugger<E>, stop: Stop) {
+    match stop {
+        Stop::Paused => {}
+        Stop::Breakpoint(func, offset) => println!("breakpoint at func[{}] +{}", func, offset),
+        Stop::Watchpoint { addr, old, new, .. } => {
+            println!("watchpoint at {:#x}: {:02x?} -> {:02x?}", addr, old, new)
+        }
+        Stop::Returned(results) => {
+            for result in results.iter() {
+                println!("{}", format_value(result));
+            }
+            return;
+        }
+        Stop::NotRunning => {
+            println!("the function has returned");
+            return;
+        }
+    }
+    print_position(debugger);
+}
+
+/// Runs the exported function `name` under the debugger selected by the
+/// mode of `options`.
+fn debug<E: Env>(mut debugger: Debugger<E>, options: &Options, name: &str, args: &[String]) {
+    let params = params(&debugger.runtime, &debugger.store, name, args);
+    let stop = debugger
+        .invoke(name, params)
+        .unwrap_or_else(|err| exit_with(err));
+    match &options.mode {
+        Mode::Run | Mode::Debug => repl(debugger, stop),
+        #[cfg(feature = "gdb")]
+        Mode::Gdb(port) => gdb(debugger, port, &options.path),
+    }
+}
+
+/// Reads debugger commands from stdin until asked to quit.
+fn repl<E: Env>(mut debugger: Debugger<E>, stop: Stop) {
+    print_stop(&debugger, stop);
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("(wasper) ");
+        io::stdout().flush().ok();
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            _ => return,
+        };
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => continue,
+        };
+        let arg1 = words.next();
+        let arg2 = words.next();
+        let result = match command {
+            "b" | "break" | "d" | "delete" => parse_num(arg1, None).and_then(|func| {
+                let offset = parse_num(arg2, Some(0))?;
+                let ok = if command.starts_with('b') {
+                    debugger.set_breakpoint(func, offset)
+                } else {
+                    debugger.remove_breakpoint(func, offset)
+                };
+                ok.then(|| ())
+                    .ok_or_else(|| format!("no breakpoint at func[{}] +{}", func, offset))
+            }),
+            "w" | "watch" => parse_num(arg1, None).and_then(|addr| {
+                let len = parse_num(arg2, Some(4))?;
+                debugger
+                    .watch(0, addr, len)
+                    .then(|| ())
+                    .ok_or_else(|| "out of bounds".to_string())
+            }),
+            "dw" => parse_num(arg1, None).and_then(|addr| {
+                debugger
+                    .unwatch(0, addr)
+                    .then(|| ())
+                    .ok_or_else(|| format!("no watchpoint at {:#x}", addr))
+            }),
+            "s" | "step" | "n" | "next" | "o" | "finish" | "c" | "continue" => {
+                let stop = match command {
+                    "s" | "step" => debugger.step_in(),
+                    "n" | "next" => debugger.step_over(),
+                    "o" | "finish" => debugger.step_out(),
+                    _ => debugger.cont(),
+                };
+                match stop {
+                    Ok(stop) => print_stop(&debugger, stop),
+                    Err(err) => println!("error: {}", err),
+                }
+                Ok(())
+            }
+            "locals" => {
+                for (i, value) in debugger.locals().iter().enumerate() {
+                    println!("{:>3}: {}", i, format_value(value));
+                }
+                Ok(())
+            }
+            "stack" => {
+                for value in debugger.values().iter().rev() {
+                    println!("{}", format_value(value));
+                }
+                Ok(())
+            }
+            "g" | "global" => parse_num(arg1, None).and_then(|idx| {
+                let value = debugger
+                    .global(idx)
+                    .ok_or_else(|| format!("no global {}", idx))?;
+                println!("{}", format_value(&value));
+                Ok(())
+            }),
+            "x" => parse_num(arg1, None).and_then(|addr| {
+                let len = parse_num(arg2, Some(16))?;
+                let bytes = debugger
+                    .memory(0, addr, len)
+                    .ok_or_else(|| "out of bounds".to_string())?;
+                for (i, chunk) in bytes.chunks(16).enumerate() {
+                    println!("{:08x}: {:02x?}", addr + 16 * i as u64, chunk);
+                }
+                Ok(())
+            }),
+            "bt" | "backtrace" => {
+                for (i, frame) in debugger.backtrace().frames.iter().enumerate() {
+                    println!("{:>3}: {}", i, frame);
+                }
+                Ok(())
+            }
+            "q" | "quit" => return,
+            "h" | "help" => {
+                println!("{}", DEBUG_HELP);
+                Ok(())
+            }
+            _ => Err(format!("unknown command: {}", command)),
+        };
+        if let Err(message) = result {
+            println!("error: {}", message);
+        }
+    }
+}
+
+/// Lets a GDB or LLDB client debug the invocation started in `debugger`.
+#[cfg(feature = "gdb")]
+fn gdb<E: Env>(debugger: Debugger<E>, port: &str, path: &str) {
+    let bytes = fs::read(path)
+        .unwrap_or_else(|err| fail(&format!("failed to read {}: {}", path, err), EXIT_USAGE));
+    let mut server = GdbServer::new(debugger, path, bytes);
+    let exit = if port == "-" {
+        server.serve(io::stdin(), io::stdout())
+    } else {
+        let port = port
+            .parse::<u16>()
+            .unwrap_or_else(|_| fail(&format!("invalid port: {}", port), EXIT_USAGE));
+        TcpListener::bind(("127.0.0.1", port)).and_then(|listener| {
+            eprintln!("listening on {}", listener.local_addr()?);
+            let (stream, _) = listener.accept()?;
+            server.serve(stream.try_clone()?, stream)
+        })
+    };
+    match exit {
+        Ok(Exit::Returned(results)) => {
+            for result in results.iter() {
+                if port == "-" {
+                    eprintln!("{}", format_value(result));
+                } else {
+                    println!("{}", format_value(result));
+                }
+            }
+        }
+        Ok(Exit::Trapped(err)) => exit_with(err),
+        Ok(Exit::Detached) => {}
+        Err(err) => fail(&format!("gdb connection failed: {}", err), EXIT_USAGE),
+    }
+}
 
 fn main() {
-    // let mut args = env::args();
-    // if let Some(modname) = args.nth(1) {
-    //     let mut file = File::open(modname).expect("failed to read file");
-    //     let mut buffer = vec![];
-    //     file.read_to_end(&mut buffer).expect("failed to read file");
-    //     let module = Parser::new(&buffer)
-    //         .module()
-    //         .expect("failed to parse module");
-    //
-    //     let mut runtime = debug_runtime(module).expect("failed to load module");
-    //     match runtime.invoke("_start", vec![]) {
-    //         Ok(_) => {}
-    //         Err(err) => println!("{:?}", err),
-    //     }
-    // } else {
-    //     println!("error: no input files");
-    //     std::process::exit(1);
-    // }
+    let options = match parse_options(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("error: {}\n\n{}", message, USAGE);
+            process::exit(EXIT_USAGE);
+        }
+    };
+
+    let bytes = fs::read(&options.path).unwrap_or_else(|err| {
+        fail(
+            &format!("failed to read {}: {}", options.path, err),
+            EXIT_USAGE,
+        )
+    });
+    let module = parse(&bytes)
+        .unwrap_or_else(|err| fail(&format!("failed to parse module: {}", err), EXIT_INVALID));
+    if let Err(err) = validate(&module) {
+        fail(&format!("invalid module: {}", err), EXIT_INVALID);
+    }
+    let wasi = module
+        .imports
+        .iter()
+        .any(|import| import.module == WASI_MODULE);
+    #[cfg(feature = "gdb")]
+    if options.mode == Mode::Gdb("-".into())
+        && module
+            .imports
+            .iter()
+            .any(|import| import.module == ENV_NAME)
+    {
+        fail(
+            "gdb over stdio cannot debug modules importing from env",
+            EXIT_USAGE,
+        );
+    }
+
+    let mut store = Store::new();
+    let mut runtime = Runtime::new(if wasi { WASI_MODULE } else { ENV_NAME });
+    let mut importer = DefaultImporter::new();
+    importer.add_module(module, &options.path);
+
+    if wasi {
+        let mut args = vec![options.path.clone()];
+        args.extend(options.args.iter().cloned());
+        let mut env = WasiEnv::new(args);
+        // Keep stdout free for the remote protocol.
+        #[cfg(feature = "gdb")]
+        if options.mode == Mode::Gdb("-".into()) {
+            env.set_stdout(Box::new(io::stderr()));
+        }
+        for (key, value) in options.envs.iter() {
+            env.push_env(key, value);
+        }
+        for dir in options.dirs.iter() {
+            env.preopen_dir(dir, dir).unwrap_or_else(|err| {
+                fail(&format!("failed to open {}: {}", dir, err), EXIT_USAGE)
+            });
+        }
+        let name = options.invoke.as_deref().unwrap_or("_start");
+        let args = if options.invoke.is_some() {
+            &options.args[..]
+        } else {
+            &[]
+        };
+        if options.mode != Mode::Run {
+            runtime
+                .import_module(&mut store, &mut env, &mut importer, &options.path)
+                .unwrap_or_else(|err| exit_with(err));
+            return debug(Debugger::new(runtime, store, env), &options, name, args);
+        }
+        let result = runtime
+            .import_module(&mut store, &mut env, &mut importer, &options.path)
+            .and_then(|_| invoke(&mut runtime, &mut store, &mut env, name, args));
+        match result {
+            Ok(results) => results.iter().for_each(|r| println!("{}", format_value(r))),
+            Err(RuntimeError::Trap(Trap::Env("proc_exit"), _)) => {
+                let code = env.exit_code().unwrap_or(0);
+                drop(env);
+                process::exit(code)
+            }
+            Err(err) => exit_with(err),
+        }
+        return;
+    }
+
+    if options.invoke.is_none() && !options.args.is_empty() && options.mode == Mode::Run {
+        eprintln!(
+            "error: unexpected argument: {}\n\n{}",
+            options.args[0], USAGE
+        );
+        process::exit(EXIT_USAGE);
+    }
+
+    // Instantiation runs the start function.
+    let mut env = DebugEnv {};
+    runtime
+        .import_module(&mut store, &mut env, &mut importer, &options.path)
+        .unwrap_or_else(|err| exit_with(err));
+    if options.mode != Mode::Run {
+        let name = options.invoke.as_deref().unwrap_or("_start");
+        return debug(
+            Debugger::new(runtime, store, env),
+            &options,
+            name,
+            &options.args,
+        );
+    }
+    match options.invoke {
+        Some(name) => {
+            let results = invoke(&mut runtime, &mut store, &mut env, &name, &options.args)
+                .unwrap_or_else(|err| exit_with(err));
+            for result in results.iter() {
+                println!("{}", format_value(result));
+            }
+        }
+        None if runtime.instances[runtime.root].start.is_none() => {
+            exit_with(RuntimeError::NoStartFunction)
+        }
+        None => {}
+    }
 }
@@ -3,23 +3,27 @@ use log::info;
 use serde_json::Value;
 use std::io::Write;
 use std::{
+    collections::HashMap,
     fmt::Debug,
     fs::{self, File},
     io::Read,
     path::PathBuf,
     process::Command,
 };
+use wasper::binary::{GlobalType, IdxType, Limits, Memory, Mut, RefType, Table, ValType};
 use wasper::exec::importer::Importer;
+use wasper::exec::linker::Linker;
 use wasper::exec::runtime::RuntimeError;
 use wasper::exec::store::Store;
 use wasper::exec::value::LittleEndian;
 use wasper::{
     binary::Module,
     exec::{env::Env, runtime::Runtime, value::Value as WValue},
-    loader::parser::Parser,
+    loader::{parser::Parser, validate},
 };
 
 const WAST_DIR: &str = "./tests/testsuite";
+const SIMD_WAST_DIR: &str = "./tests/testsuite/simd";
 const WAST2JSON: &str = "wast2json";
 
 #[test]
@@ -35,18 +39,42 @@ fn main() {
 enum TestCommand<'a> {
     AssertReturn {
         action: Action<'a>,
-        expected: Vec<WValue>,
+        expected: Vec<&'a Value>,
     },
     AssertTrap {
         action: Action<'a>,
         text: &'a str,
     },
+    AssertException {
+        action: Action<'a>,
+    },
     Module {
         filename: &'a str,
+        name: Option<&'a str>,
+    },
+    Register {
+        name: Option<&'a str>,
+        as_: &'a str,
     },
     Action {
         action: Action<'a>,
     },
+    AssertInvalid {
+        filename: &'a str,
+        text: &'a str,
+    },
+    AssertMalformed {
+        filename: &'a str,
+        text: &'a str,
+    },
+    AssertUnlinkable {
+        filename: &'a str,
+        text: &'a str,
+    },
+    AssertUninstantiable {
+        filename: &'a str,
+        text: &'a str,
+    },
 }
 
 impl<'a> TestCommand<'a> {
@@ -61,11 +89,15 @@ impl<'a> TestCommand<'a> {
                     .as_array()
                     .unwrap()
                     .iter()
-                    .map(json_to_value)
                     .collect(),
             }),
             "module" => Some(TestCommand::Module {
                 filename: v.get("filename").unwrap().as_str().unwrap(),
+                name: v.get("name").and_then(|name| name.as_str()),
+            }),
+            "register" => Some(TestCommand::Register {
+                name: v.get("name").and_then(|name| name.as_str()),
+                as_: v.get("as").unwrap().as_str().unwrap(),
             }),
             "action" => Some(TestCommand::Action {
                 action: Action::from_value(v.get("action").unwrap())?,
@@ -74,6 +106,28 @@ impl<'a> TestCommand<'a> {
                 action: Action::from_value(v.get("action").unwrap())?,
                 text: v.get("text").unwrap().as_str().unwrap(),
             }),
+            "assert_exception" => Some(TestCommand::AssertException {
+                action: Action::from_value(v.get("action").unwrap())?,
+            }),
+            "assert_invalid" => Some(TestCommand::AssertInvalid {
+                filename: v.get("filename").unwrap().as_str().unwrap(),
+                text: v.get("text").unwrap().as_str().unwrap(),
+            }),
+            // Malformed modules in the text format are rejected by wast2json itself.
+            "assert_malformed" if v.get("module_type").unwrap().as_str() == Some("binary") => {
+                Some(TestCommand::AssertMalformed {
+                    filename: v.get("filename").unwrap().as_str().unwrap(),
+                    text: v.get("text").unwrap().as_str().unwrap(),
+                })
+            }
+            "assert_unlinkable" => Some(TestCommand::AssertUnlinkable {
+                filename: v.get("filename").unwrap().as_str().unwrap(),
+                text: v.get("text").unwrap().as_str().unwrap(),
+            }),
+            "assert_uninstantiable" => Some(TestCommand::AssertUninstantiable {
+                filename: v.get("filename").unwrap().as_str().unwrap(),
+                text: v.get("text").unwrap().as_str().unwrap(),
+            }),
             _ => None,
         }
     }
@@ -81,11 +135,18 @@ impl<'a> TestCommand<'a> {
 
 #[derive(Debug, PartialEq)]
 enum Action<'a> {
-    Invoke { fnname: &'a str, args: Vec<WValue> },
+    Invoke {
+        module: Option<&'a str>,
+        fnname: &'a str,
+        args: Vec<WValue>,
+    },
 }
 
 fn json_to_value(value: &Value) -> WValue {
     let ty = value.get("type").unwrap().as_str().unwrap();
+    if ty == "v128" {
+        return WValue::V128(json_to_v128(value));
+    }
     let value = value.get("value").unwrap().as_str().unwrap();
     if value.find("nan").is_some() {
         match ty {
@@ -108,11 +169,49 @@ fn json_to_value(value: &Value) -> WValue {
     }
 }
 
+fn json_to_v128(value: &Value) -> u128 {
+    let lane_type = value.get("lane_type").unwrap().as_str().unwrap();
+    let lanes = value.get("value").unwrap().as_array().unwrap();
+    let size = 16 / lanes.len();
+    let mut buf = vec![0u8; 16];
+    for (i, lane) in lanes.iter().enumerate() {
+        let lane = lane.as_str().unwrap();
+        let bits = match lane_type {
+            "f32" if lane.contains("nan") => f32::NAN.to_bits() as u64,
+            "f64" if lane.contains("nan") => f64::NAN.to_bits(),
+            _ => lane.parse::<u64>().unwrap(),
+        };
+        buf[i * size..(i + 1) * size].copy_from_slice(&bits.to_le_bytes()[..size]);
+    }
+    LittleEndian::read(&buf, 0)
+}
+
+/// Compares a result with an expected value of the test, where float lanes
+/// of a `v128` expected to be NaN match any NaN.
+fn matches_expected(ret: &WValue, expected: &Value) -> bool {
+    let lane_type = expected.get("lane_type").and_then(|t| t.as_str());
+    match (ret, lane_type) {
+        (WValue::V128(ret), Some(lane_type @ ("f32" | "f64"))) => {
+            let size = if lane_type == "f32" { 4 } else { 8 };
+            let (ret, expected) = (ret.to_le_bytes(), json_to_v128(expected).to_le_bytes());
+            ret.chunks(size).zip(expected.chunks(size)).all(|(a, b)| {
+                let nan = |lane: &[u8]| match size {
+                    4 => f32::from_le_bytes(lane.try_into().unwrap()).is_nan(),
+                    _ => f64::from_le_bytes(lane.try_into().unwrap()).is_nan(),
+                };
+                a == b || (nan(a) && nan(b))
+            })
+        }
+        _ => ret == &json_to_value(expected),
+    }
+}
+
 impl<'a> Action<'a> {
     fn from_value(v: &'a Value) -> Option<Self> {
         let ty = v.get("type").unwrap().as_str().unwrap();
         if ty == "invoke" {
             Some(Action::Invoke {
+                module: v.get("module").and_then(|module| module.as_str()),
                 fnname: v.get("field").unwrap().as_str().unwrap(),
                 args: v
                     .get("args")
@@ -141,14 +240,22 @@ fn get_test_case<'a>(v: &'a Value) -> Vec<TestCommand<'a>> {
 struct SpecTestImporter {}
 impl Importer for SpecTestImporter {
     fn import(&mut self, modname: &str) -> Option<Module> {
-        let mut file = File::open(&format!("{}/{}", WAST_DIR, modname)).unwrap();
-        let mut buf = vec![];
-        file.read_to_end(&mut buf).unwrap();
+        // Modules registered by name are resolved by the runtime itself.
+        let buf = fs::read(format!("{}/{}", WAST_DIR, modname)).ok()?;
         let mut parser = Parser::new(&buf);
-        Some(parser.module().unwrap())
+        let module = parser.module().unwrap();
+        validate(&module).unwrap();
+        Some(module)
     }
 }
 
+fn read_module(filename: &str) -> Vec<u8> {
+    let mut file = File::open(&format!("{}/{}", WAST_DIR, filename)).unwrap();
+    let mut buf = vec![];
+    file.read_to_end(&mut buf).unwrap();
+    buf
+}
+
 struct SpecTestEnv {}
 impl Env for SpecTestEnv {
     fn call(
@@ -162,45 +269,128 @@ impl Env for SpecTestEnv {
     }
 }
 
+/// Host objects of the `spectest` module other than the print functions,
+/// which are handled by `SpecTestEnv`.
+fn spectest_linker(store: &mut Store) -> Linker {
+    let mut linker = Linker::new();
+    let globals = [
+        ("global_i32", ValType::I32, WValue::I32(666)),
+        ("global_i64", ValType::I64, WValue::I64(666)),
+        ("global_f32", ValType::F32, WValue::F32(666.6)),
+        ("global_f64", ValType::F64, WValue::F64(666.6)),
+    ];
+    for (name, valtype, value) in globals {
+        let globaltype = GlobalType {
+            valtype,
+            mut_: Mut::Const,
+        };
+        linker.global(
+            "spectest",
+            name,
+            store.allocate_host_global(globaltype, value),
+        );
+    }
+    let table = store.allocate_table(Table {
+        reftype: RefType::FuncRef,
+        limits: Limits::MinMax(10, 20),
+    });
+    linker.table("spectest", "table", table);
+    let memory = store
+        .allocate_mem(&Memory {
+            idxtype: IdxType::I32,
+            limits: Limits::MinMax(1, 2),
+            shared: false,
+        })
+        .unwrap();
+    linker.memory("spectest", "memory", memory);
+    linker
+}
+
+/// Invokes `fnname` on the named module, or on the most recently
+/// instantiated one.
+fn invoke(
+    runtime: &mut Runtime,
+    store: &mut Store,
+    env: &mut SpecTestEnv,
+    names: &HashMap<String, usize>,
+    module: &Option<&str>,
+    fnname: &str,
+    args: Vec<WValue>,
+) -> Result<Vec<WValue>, RuntimeError> {
+    let root = runtime.root;
+    if let Some(module) = module {
+        runtime.root = names[*module];
+    }
+    let ret = runtime.invoke(store, env, fnname, args);
+    runtime.root = root;
+    ret
+}
+
 fn run_test(
     runtime: &mut Runtime,
     store: &mut Store,
     env: &mut SpecTestEnv,
+    names: &mut HashMap<String, usize>,
     command: &TestCommand,
 ) {
     match command {
         TestCommand::AssertReturn { action, expected } => match action {
-            Action::Invoke { fnname, args } => {
+            Action::Invoke {
+                module,
+                fnname,
+                args,
+            } => {
                 info!("{}({:?})", fnname, args);
-                let ret = runtime.invoke(store, env, fnname, args.clone()).unwrap();
-                assert_eq!(
-                    &ret, expected,
+                let ret = invoke(runtime, store, env, names, module, fnname, args.clone()).unwrap();
+                assert!(
+                    ret.len() == expected.len()
+                        && ret
+                            .iter()
+                            .zip(expected)
+                            .all(|(r, e)| matches_expected(r, e)),
                     "\nexpected {:?}, found {:?}\n fnname: {:?}",
-                    expected, ret, fnname
+                    expected
+                        .iter()
+                        .map(|e| json_to_value(e))
+                        .collect::<Vec<_>>(),
+                    ret,
+                    fnname
                 );
                 info!("    = {:?}", ret);
             }
         },
-        TestCommand::Module { filename } => {
-            *store = Store::new();
-            *runtime = Runtime::new("spectest");
+        TestCommand::Module { filename, name } => {
             let mut importer = SpecTestImporter {};
             runtime
-                .import_module(store, &mut importer, &filename)
+                .import_module(store, env, &mut importer, &filename)
                 .unwrap();
-            runtime.start(store, env).ok();
+            if let Some(name) = name {
+                names.insert(name.to_string(), runtime.root);
+            }
+        }
+        TestCommand::Register { name, as_ } => {
+            let instance = name.map_or(runtime.root, |name| names[name]);
+            runtime.register(as_, instance);
         }
         TestCommand::Action { action } => match action {
-            Action::Invoke { fnname, args } => {
+            Action::Invoke {
+                module,
+                fnname,
+                args,
+            } => {
                 info!("{}: {:?}", fnname, args);
-                runtime.invoke(store, env, fnname, args.clone()).unwrap();
+                invoke(runtime, store, env, names, module, fnname, args.clone()).unwrap();
             }
         },
         TestCommand::AssertTrap { action, text } => match action {
-            Action::Invoke { fnname, args } => {
+            Action::Invoke {
+                module,
+                fnname,
+                args,
+            } => {
                 info!("{}({:?})", fnname, args);
-                match runtime.invoke(store, env, fnname, args.clone()) {
-                    Err(RuntimeError::Trap(trap)) => {
+                match invoke(runtime, store, env, names, module, fnname, args.clone()) {
+                    Err(RuntimeError::Trap(trap, _)) => {
                         assert_eq!(&format!("{}", trap), text);
                         info!("    => trap: {}", text);
                     }
@@ -208,19 +398,76 @@ fn run_test(
                 }
             }
         },
+        TestCommand::AssertException { action } => match action {
+            Action::Invoke {
+                module,
+                fnname,
+                args,
+            } => {
+                info!("{}({:?})", fnname, args);
+                match invoke(runtime, store, env, names, module, fnname, args.clone()) {
+                    Err(RuntimeError::Exception(_)) => info!("    => exception"),
+                    result => panic!("{} should throw, found {:?}", fnname, result),
+                }
+            }
+        },
+        TestCommand::AssertInvalid { filename, text } => {
+            info!("assert_invalid: {}", filename);
+            let module = Parser::new(&read_module(filename)).module().unwrap();
+            match validate(&module) {
+                Err(err) => assert!(
+                    format!("{}", err).starts_with(text),
+                    "\nexpected {:?}, found {:?}\n filename: {:?}",
+                    text,
+                    err,
+                    filename
+                ),
+                Ok(_) => panic!("{} should be invalid: {}", filename, text),
+            }
+        }
+        TestCommand::AssertMalformed { filename, text } => {
+            info!("assert_malformed: {}", filename);
+            match Parser::new(&read_module(filename)).module() {
+                Err(err) => assert!(
+                    format!("{}", err.kind).starts_with(text),
+                    "\nexpected {:?}, found {}\n filename: {:?}",
+                    text,
+                    err,
+                    filename
+                ),
+                Ok(_) => panic!("{} should be malformed: {}", filename, text),
+            }
+        }
+        TestCommand::AssertUnlinkable { filename, text } => {
+            info!("assert_unlinkable: {}", filename);
+            let root = runtime.root;
+            match runtime.import_module(store, env, &mut SpecTestImporter {}, filename) {
+                Err(RuntimeError::NotFound(_)) | Err(RuntimeError::IncompatibleImport(..)) => {}
+                result => panic!(
+                    "{} should be unlinkable: {}, found {:?}",
+                    filename, text, result
+                ),
+            }
+            assert_eq!(runtime.root, root);
+        }
+        TestCommand::AssertUninstantiable { filename, text } => {
+            info!("assert_uninstantiable: {}", filename);
+            let root = runtime.root;
+            match runtime.import_module(store, env, &mut SpecTestImporter {}, filename) {
+                Err(RuntimeError::Trap(trap, _)) => assert_eq!(&format!("{}", trap), text),
+                result => panic!(
+                    "{} should trap on instantiation: {}, found {:?}",
+                    filename, text, result
+                ),
+            }
+            assert_eq!(runtime.root, root);
+        }
     }
 }
 
 fn skip(filename: &str) -> bool {
     // TODO
-    let skip_list = [
-        "./tests/testsuite/imports.wast",
-        "./tests/testsuite/exports.wast",
-        "./tests/testsuite/binary-leb128.wast",
-        "./tests/testsuite/data.wast",
-        "./tests/testsuite/elem.wast",
-        "./tests/testsuite/linking.wast",
-    ];
+    let skip_list = ["./tests/testsuite/binary-leb128.wast"];
     for s in skip_list.iter() {
         if filename == *s {
             return true;
@@ -230,7 +477,9 @@ fn skip(filename: &str) -> bool {
 }
 
 pub fn run_tests() {
-    let entries = fs::read_dir(WAST_DIR).unwrap();
+    let entries = fs::read_dir(WAST_DIR)
+        .unwrap()
+        .chain(fs::read_dir(SIMD_WAST_DIR).unwrap());
 
     for entry in entries {
         if let Ok(entry) = entry {
@@ -240,10 +489,7 @@ pub fn run_tests() {
                 }
 
                 info!("{:?}", entry.path());
-                wast2json(&entry.path());
-
-                let mut json = entry.path().clone();
-                json.set_extension("json");
+                let json = wast2json(&entry.path());
                 let mut file = File::open(json).unwrap();
                 let mut content = String::new();
                 file.read_to_string(&mut content).unwrap();
@@ -253,9 +499,11 @@ pub fn run_tests() {
 
                 let mut runtime = Runtime::new("spectest");
                 let mut store = Store::new();
+                runtime.linker = spectest_linker(&mut store);
                 let mut env = SpecTestEnv {};
+                let mut names = HashMap::new();
                 for command in commands.iter() {
-                    run_test(&mut runtime, &mut store, &mut env, command);
+                    run_test(&mut runtime, &mut store, &mut env, &mut names, command);
                 }
             }
         }
@@ -263,15 +511,17 @@ pub fn run_tests() {
     clean_up();
 }
 
-fn wast2json(input_file: &PathBuf) {
+/// Converts `input_file` into a json file in `WAST_DIR`, where the modules
+/// it refers to are written as well.
+fn wast2json(input_file: &PathBuf) -> PathBuf {
     let input = input_file.to_str().unwrap();
-    let mut output = input_file.clone();
+    let mut output = PathBuf::from(WAST_DIR).join(input_file.file_name().unwrap());
     output.set_extension("json");
-    let output = output.to_str().unwrap();
     Command::new(WAST2JSON)
-        .args(&[input, "-o", output])
+        .args(&[input, "-o", output.to_str().unwrap()])
         .output()
         .unwrap();
+    output
 }
 
 fn clean_up() {